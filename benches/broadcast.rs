@@ -0,0 +1,61 @@
+//! Cost of fanning one position update out to every player.
+//!
+//! Run with `cargo bench --bench broadcast`.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use server_dot::{
+    game_state::{GameState, Player, Position},
+    packet::{
+        position::{PlayerPosition, PositionBatch},
+        GamePacket, MessageType,
+    },
+};
+
+fn state_with_players(count: usize) -> GameState {
+    let mut state = GameState::with_capacity(1920, 1080, count);
+    for i in 0..count {
+        let player = Player {
+            id: format!("{i:018}"),
+            heartbeat: state.now(),
+            connected_at: state.now(),
+            ..Player::default()
+        };
+        state.add_player(player, format!("10.0.{}.{}:5000", i / 256, i % 256));
+    }
+    state
+}
+
+/// Builds and serializes the batch every other player receives for one update,
+/// as the simulation loop does before sending.
+fn fan_out(state: &mut GameState, update: &PlayerPosition) -> usize {
+    let mut bytes = 0;
+    for (_, player_id) in state.recipients() {
+        if update.id == player_id.as_bytes() {
+            continue;
+        }
+        let batch = PositionBatch::new(vec![update.clone()]);
+        let packet = GamePacket::new(
+            MessageType::PositionBatch,
+            state.next_outbound_seq(&player_id),
+            batch.serialize(),
+            player_id.as_bytes().to_vec(),
+        );
+        bytes += packet.serialize().len();
+    }
+    bytes
+}
+
+fn broadcast(c: &mut Criterion) {
+    let mut group = c.benchmark_group("position_fan_out");
+    for count in [10, 100, 1000] {
+        let mut state = state_with_players(count);
+        let update =
+            PlayerPosition::new(format!("{:018}", 0).into_bytes(), Position::new(1.0, 2.0));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, _| {
+            b.iter(|| fan_out(&mut state, &update));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, broadcast);
+criterion_main!(benches);
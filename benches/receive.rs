@@ -0,0 +1,50 @@
+//! Cost of reading a burst of datagrams one per wakeup versus in batches.
+//!
+//! Run with `cargo bench --bench receive`.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use server_dot::server::recv_batch;
+use tokio::{net::UdpSocket, runtime::Runtime};
+
+const BURST: usize = 64;
+
+/// Sends a burst of `BURST` datagrams to `socket` and reads them back `max_batch` at a time.
+async fn burst(socket: &UdpSocket, client: &UdpSocket, max_batch: usize) -> usize {
+    let addr = socket.local_addr().unwrap();
+    for _ in 0..BURST {
+        client.send_to(&[0; 32], addr).await.unwrap();
+    }
+    let mut buf = vec![0; 1025];
+    let mut batch = Vec::with_capacity(max_batch);
+    let mut read = 0;
+    while read < BURST {
+        read += recv_batch(socket, &mut buf, max_batch, &mut batch)
+            .await
+            .unwrap();
+        batch.clear();
+    }
+    read
+}
+
+fn receive(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let (socket, client) = runtime.block_on(async {
+        (
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        )
+    });
+    let mut group = c.benchmark_group("receive_burst");
+    for max_batch in [1, 8, 32] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(max_batch),
+            &max_batch,
+            |b, &max_batch| {
+                b.iter(|| runtime.block_on(burst(&socket, &client, max_batch)));
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, receive);
+criterion_main!(benches);
@@ -0,0 +1,75 @@
+//! Connects two clients and prints what they receive.
+//!
+//! Runs against the server at the address given as the first argument, or starts one
+//! on a free local port:
+//!
+//! ```sh
+//! cargo run --example two_clients --features client [-- 127.0.0.1:5000]
+//! ```
+use std::{sync::Arc, time::Duration};
+
+use server_dot::{
+    client::{ClientEvent, GameClient},
+    game_state::Position,
+    server::GameServer,
+};
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let server_addr = match std::env::args().nth(1) {
+        Some(addr) => addr.parse()?,
+        None => {
+            let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await?);
+            let addr = server.local_addr()?;
+            tokio::spawn({
+                let server = Arc::clone(&server);
+                async move { server.run().await }
+            });
+            server.ready().await;
+            println!("started a server on {addr}");
+            addr
+        }
+    };
+
+    let mut alice = GameClient::connect(server_addr).await?;
+    println!("alice joined as {}", alice.id());
+    let mut bob = GameClient::connect(server_addr).await?;
+    println!(
+        "bob joined as {}, seeing {} other players",
+        bob.id(),
+        bob.initial_players().len()
+    );
+
+    bob.send_position(&Position::new(100.0, 200.0)).await?;
+    bob.send_chat("hi alice").await?;
+    bob.send_whisper(alice.id(), "just between us").await?;
+
+    // Print what alice hears for a couple of seconds
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(2);
+    while let Ok(event) = tokio::time::timeout_at(deadline, alice.recv_event()).await {
+        match event? {
+            ClientEvent::PlayerJoined { id, position, .. } => {
+                println!("alice: {id} joined at ({}, {})", position.x, position.y);
+            }
+            ClientEvent::PlayerLeft { id } => println!("alice: {id} left"),
+            ClientEvent::Positions(positions) => {
+                for (id, position) in positions {
+                    println!("alice: {id} moved to ({}, {})", position.x, position.y);
+                }
+            }
+            ClientEvent::Chat { sender, message } => println!("alice: {sender} says {message:?}"),
+            ClientEvent::Whisper { sender, message } => {
+                println!("alice: {sender} whispers {message:?}");
+            }
+            ClientEvent::Error(error) => println!("alice: server error {error:?}"),
+            ClientEvent::ServerShutdown(notice) => {
+                println!("alice: server closing: {:?}", notice.reason);
+            }
+            ClientEvent::Other(packet) => println!("alice: {:?}", packet.msg_type),
+        }
+    }
+
+    bob.disconnect();
+    alice.disconnect();
+    Ok(())
+}
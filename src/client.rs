@@ -0,0 +1,426 @@
+//! Minimal client speaking the server's protocol, for tests, examples and as a reference
+//! for client authors. Enabled with the `client` feature.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), anyhow::Error> {
+//! use server_dot::{client::GameClient, game_state::Position};
+//!
+//! let mut client = GameClient::connect("127.0.0.1:5000".parse()?).await?;
+//! println!("joined as {}", client.id());
+//! client.send_position(&Position::new(10.0, 20.0)).await?;
+//! let event = client.recv_event().await?;
+//! println!("{event:?}");
+//! client.disconnect();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{anyhow, bail};
+use tokio::net::UdpSocket;
+
+use crate::{
+    game_state::{PlayerId, Position, PLAYER_ID_LEN},
+    num::{coord_to_f32, f32_to_coord},
+    packet::{
+        chat::{ChatPacket, WhisperPacket},
+        connection_init::{take_name, ChallengePacket, ReconnectToken, RECONNECT_TOKEN_LEN},
+        error::ErrorPacket,
+        ping::PlayerLeft,
+        position::POSITION_RECORD_SIZE,
+        shutdown::ServerShutdownPacket,
+        world::{WorldInfo, WORLD_INFO_SIZE},
+        GamePacket, MessageType, SeqNum,
+    },
+};
+
+/// How long [`GameClient::connect`] waits for each reply from the server.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A player and where it is.
+pub type PlayerRecord = (PlayerId, Position);
+
+/// Something the server told the client, decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientEvent {
+    /// Another player joined the room.
+    PlayerJoined {
+        id: PlayerId,
+        position: Position,
+        name: Option<String>,
+    },
+    /// A player left or was removed.
+    PlayerLeft { id: PlayerId },
+    /// New positions, from a tick's batch or a single respawn or teleport.
+    Positions(Vec<PlayerRecord>),
+    /// A chat line from another player.
+    Chat { sender: PlayerId, message: String },
+    /// A chat line another player sent only to this one.
+    Whisper { sender: PlayerId, message: String },
+    /// The server rejected one of the client's requests.
+    Error(ErrorPacket),
+    /// The server is shutting down, nothing more will be answered.
+    ServerShutdown(ServerShutdownPacket),
+    /// Anything else, e.g. a heartbeat.
+    Other(GamePacket),
+}
+
+/// A connected player.
+#[derive(Debug)]
+pub struct GameClient {
+    socket: UdpSocket,
+    server: SocketAddr,
+    id: PlayerId,
+    seq: SeqNum,
+    reconnect_token: ReconnectToken,
+    world: WorldInfo,
+    players: Vec<PlayerRecord>,
+}
+
+impl GameClient {
+    /// Joins the server at `server` in the default room, answering a challenge if the
+    /// server sends one.
+    ///
+    /// # Errors
+    /// Returns an error if the socket fails, the server doesn't answer within
+    /// [`CONNECT_TIMEOUT`] or it turns the client away.
+    pub async fn connect(server: SocketAddr) -> Result<GameClient, anyhow::Error> {
+        let local: SocketAddr = if server.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let socket = UdpSocket::bind(local).await?;
+        let mut seq = SeqNum(1);
+        let mut payload = Vec::new();
+        loop {
+            let init = GamePacket::new(
+                MessageType::ConnectionInit,
+                seq.0,
+                payload,
+                vec![0; PLAYER_ID_LEN],
+            );
+            socket.send_to(&init.serialize(), server).await?;
+            let reply =
+                tokio::time::timeout(CONNECT_TIMEOUT, recv_handshake_reply(&socket, server))
+                    .await
+                    .map_err(|_| anyhow!("no reply from {server}"))??;
+            match reply.msg_type {
+                MessageType::ConnectionInit => {
+                    let (reconnect_token, world, players) = parse_init_response(&reply.payload)
+                        .ok_or_else(|| anyhow!("malformed connection init response"))?;
+                    return Ok(GameClient {
+                        socket,
+                        server,
+                        id: String::from_utf8(reply.client_id)?,
+                        seq,
+                        reconnect_token,
+                        world,
+                        players,
+                    });
+                }
+                MessageType::Challenge => {
+                    let challenge = ChallengePacket::deserialize(&reply.payload)
+                        .ok_or_else(|| anyhow!("malformed challenge"))?;
+                    payload = challenge.serialize();
+                    seq = seq.next();
+                }
+                MessageType::Draining => bail!("server is draining"),
+                MessageType::Error => {
+                    let error = ErrorPacket::deserialize(&reply.payload);
+                    bail!("server rejected the connection: {error:?}");
+                }
+                msg_type => bail!("unexpected {msg_type:?} during the handshake"),
+            }
+        }
+    }
+    /// Id the server assigned to this player.
+    #[must_use]
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    /// Token to resume the session after an address change.
+    #[must_use]
+    pub fn reconnect_token(&self) -> &ReconnectToken {
+        &self.reconnect_token
+    }
+    /// The world as described when the client joined.
+    #[must_use]
+    pub fn world(&self) -> &WorldInfo {
+        &self.world
+    }
+    /// The other players in the room when the client joined.
+    #[must_use]
+    pub fn initial_players(&self) -> &[PlayerRecord] {
+        &self.players
+    }
+    /// Address of the client's socket.
+    ///
+    /// # Errors
+    /// Returns the socket's error.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+    /// Moves the player. Positions are sent little endian, as the server expects.
+    ///
+    /// # Errors
+    /// Returns the send error.
+    pub async fn send_position(&mut self, position: &Position) -> std::io::Result<()> {
+        let mut payload = Vec::with_capacity(8);
+        payload.extend_from_slice(&coord_to_f32(position.x).to_le_bytes());
+        payload.extend_from_slice(&coord_to_f32(position.y).to_le_bytes());
+        self.send(MessageType::PositionUpdate, payload).await
+    }
+    /// Tells the server the client is still there.
+    ///
+    /// # Errors
+    /// Returns the send error.
+    pub async fn send_heartbeat(&mut self) -> std::io::Result<()> {
+        self.send(MessageType::Heartbeat, vec![]).await
+    }
+    /// Sends a chat line to the room.
+    ///
+    /// # Errors
+    /// Returns the send error.
+    pub async fn send_chat(&mut self, message: &str) -> std::io::Result<()> {
+        let chat = ChatPacket::new(self.id.as_bytes().to_vec(), message.to_string());
+        self.send(MessageType::ChatMessage, chat.serialize()).await
+    }
+    /// Sends a chat line to the player `to` only.
+    ///
+    /// # Errors
+    /// Returns the send error.
+    pub async fn send_whisper(&mut self, to: &str, message: &str) -> std::io::Result<()> {
+        let whisper = WhisperPacket::new(to.as_bytes().to_vec(), message.to_string());
+        self.send(MessageType::Whisper, whisper.serialize()).await
+    }
+    /// Waits for the next event from the server. Pings are answered on the way.
+    ///
+    /// # Errors
+    /// Returns the socket's error.
+    pub async fn recv_event(&mut self) -> std::io::Result<ClientEvent> {
+        loop {
+            let packet = recv_packet(&self.socket, self.server).await?;
+            if packet.msg_type == MessageType::Ping {
+                let pong = GamePacket::new(
+                    MessageType::Pong,
+                    packet.seq_num,
+                    vec![],
+                    self.id.as_bytes().to_vec(),
+                );
+                self.socket.send_to(&pong.serialize(), self.server).await?;
+                continue;
+            }
+            return Ok(decode_event(packet));
+        }
+    }
+    /// Tells the server the player leaves and closes the client's socket. The
+    /// `Disconnect` is sent without waiting, if it's lost the server times the player out.
+    pub fn disconnect(self) {
+        let packet = GamePacket::new(
+            MessageType::Disconnect,
+            self.seq.next().0,
+            vec![],
+            self.id.as_bytes().to_vec(),
+        );
+        if let Err(e) = self.socket.try_send_to(&packet.serialize(), self.server) {
+            tracing::debug!("Error sending disconnect: {:?}", e);
+        }
+    }
+    async fn send(&mut self, msg_type: MessageType, payload: Vec<u8>) -> std::io::Result<()> {
+        self.seq = self.seq.next();
+        let packet = GamePacket::new(msg_type, self.seq.0, payload, self.id.as_bytes().to_vec());
+        self.socket
+            .send_to(&packet.serialize(), self.server)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Receives the next parseable packet from `server`, skipping anything else.
+async fn recv_packet(socket: &UdpSocket, server: SocketAddr) -> std::io::Result<GamePacket> {
+    let mut buf = vec![0; 65_536];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if from != server {
+            continue;
+        }
+        if let Some(packet) = GamePacket::deserialize(&buf[..len]) {
+            return Ok(packet);
+        }
+    }
+}
+
+/// Receives the server's answer to a `ConnectionInit`, skipping stray traffic such as a
+/// heartbeat racing the reply.
+async fn recv_handshake_reply(
+    socket: &UdpSocket,
+    server: SocketAddr,
+) -> std::io::Result<GamePacket> {
+    loop {
+        let packet = recv_packet(socket, server).await?;
+        if matches!(
+            packet.msg_type,
+            MessageType::ConnectionInit
+                | MessageType::Challenge
+                | MessageType::Draining
+                | MessageType::Error
+        ) {
+            return Ok(packet);
+        }
+    }
+}
+
+fn decode_event(packet: GamePacket) -> ClientEvent {
+    let event = match packet.msg_type {
+        MessageType::PlayerJoin => {
+            player_record(&packet.payload).map(|(id, position)| ClientEvent::PlayerJoined {
+                id,
+                position,
+                name: packet
+                    .payload
+                    .get(POSITION_RECORD_SIZE..)
+                    .and_then(take_name),
+            })
+        }
+        MessageType::PlayerLeft => PlayerLeft::deserialize(&packet.payload)
+            .map(|left| ClientEvent::PlayerLeft { id: left.player_id }),
+        MessageType::PositionUpdate => {
+            player_record(&packet.payload).map(|record| ClientEvent::Positions(vec![record]))
+        }
+        MessageType::PositionBatch => position_batch(&packet.payload).map(ClientEvent::Positions),
+        MessageType::ChatMessage => {
+            ChatPacket::deserialize(&packet.payload, usize::MAX).and_then(|chat| {
+                Some(ClientEvent::Chat {
+                    sender: String::from_utf8(chat.sender_id).ok()?,
+                    message: chat.message,
+                })
+            })
+        }
+        MessageType::Whisper => {
+            WhisperPacket::deserialize(&packet.payload, usize::MAX).and_then(|whisper| {
+                Some(ClientEvent::Whisper {
+                    sender: String::from_utf8(whisper.player_id).ok()?,
+                    message: whisper.message,
+                })
+            })
+        }
+        MessageType::Error => ErrorPacket::deserialize(&packet.payload).map(ClientEvent::Error),
+        MessageType::ServerShutdown => {
+            ServerShutdownPacket::deserialize(&packet.payload).map(ClientEvent::ServerShutdown)
+        }
+        _ => None,
+    };
+    event.unwrap_or(ClientEvent::Other(packet))
+}
+
+/// Reads an `(id, position)` record as the server writes it, positions big endian.
+fn player_record(data: &[u8]) -> Option<PlayerRecord> {
+    let record = data.get(..POSITION_RECORD_SIZE)?;
+    let (id, position) = record.split_at(PLAYER_ID_LEN);
+    let (x, y) = position.split_at(4);
+    Some((
+        String::from_utf8(id.to_vec()).ok()?,
+        Position::new(
+            f32_to_coord(f32::from_be_bytes(x.try_into().ok()?)),
+            f32_to_coord(f32::from_be_bytes(y.try_into().ok()?)),
+        ),
+    ))
+}
+
+fn position_batch(data: &[u8]) -> Option<Vec<PlayerRecord>> {
+    let (count, records) = data.split_first_chunk::<2>()?;
+    let count = usize::from(u16::from_be_bytes(*count));
+    let records = records.get(..count.checked_mul(POSITION_RECORD_SIZE)?)?;
+    records
+        .chunks_exact(POSITION_RECORD_SIZE)
+        .map(player_record)
+        .collect()
+}
+
+/// Splits a `ConnectionInit` response into the reconnect token, the world and the other
+/// players. The client sends no name, so player records are fixed size.
+fn parse_init_response(data: &[u8]) -> Option<(ReconnectToken, WorldInfo, Vec<PlayerRecord>)> {
+    let (token, rest) = data.split_at_checked(RECONNECT_TOKEN_LEN)?;
+    let (world, players) = rest.split_at_checked(WORLD_INFO_SIZE)?;
+    let players = players
+        .chunks_exact(POSITION_RECORD_SIZE)
+        .map(player_record)
+        .collect::<Option<Vec<_>>>()?;
+    Some((
+        token.try_into().ok()?,
+        WorldInfo::deserialize(world)?,
+        players,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::server::GameServer;
+
+    async fn next_non_heartbeat(client: &mut GameClient) -> ClientEvent {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), client.recv_event())
+                .await
+                .unwrap()
+                .unwrap();
+            if !matches!(&event, ClientEvent::Other(packet) if packet.msg_type == MessageType::Heartbeat)
+            {
+                return event;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clients_see_each_other_join_move_and_chat() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.local_addr().unwrap();
+        let server_handle = {
+            let server = Arc::clone(&server);
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let mut first = GameClient::connect(server_addr).await.unwrap();
+        assert_eq!(first.id().len(), PLAYER_ID_LEN);
+        assert!(first.initial_players().is_empty());
+        let mut second = GameClient::connect(server_addr).await.unwrap();
+        assert_eq!(second.initial_players().len(), 1);
+        assert_eq!(second.initial_players()[0].0, first.id());
+
+        match next_non_heartbeat(&mut first).await {
+            ClientEvent::PlayerJoined { id, position, name } => {
+                assert_eq!(id, second.id());
+                assert_eq!(position, second.world().spawn);
+                assert_eq!(name, None);
+            }
+            event => panic!("unexpected {event:?}"),
+        }
+
+        second
+            .send_position(&Position::new(100.0, 200.0))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_non_heartbeat(&mut first).await,
+            ClientEvent::Positions(vec![(second.id().to_string(), Position::new(100.0, 200.0))])
+        );
+
+        second.send_chat("hello").await.unwrap();
+        assert_eq!(
+            next_non_heartbeat(&mut first).await,
+            ClientEvent::Chat {
+                sender: second.id().to_string(),
+                message: "hello".to_string()
+            }
+        );
+
+        second.disconnect();
+        first.disconnect();
+        server_handle.abort();
+    }
+}
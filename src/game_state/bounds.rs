@@ -0,0 +1,135 @@
+use super::{Coord, Position};
+use crate::num::{f32_to_coord, u32_to_coord};
+
+/// Shape of the playable area. Positions outside it are projected back onto its edge.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WorldBounds {
+    /// `[0, width] x [0, height]`
+    Rect { width: Coord, height: Coord },
+    /// Every point within `radius` of `center`
+    Circle { center: Position, radius: Coord },
+}
+
+impl WorldBounds {
+    #[must_use]
+    pub fn rect(width: u32, height: u32) -> Self {
+        WorldBounds::Rect {
+            width: u32_to_coord(width),
+            height: u32_to_coord(height),
+        }
+    }
+    /// The same shape for a world resized from `old_width` x `old_height` to `width` x
+    /// `height`. A circle's center is scaled along each axis and its radius by the
+    /// smaller of the two factors, so it still fits the world.
+    #[must_use]
+    pub fn resized(&self, old_width: u32, old_height: u32, width: u32, height: u32) -> Self {
+        match self {
+            WorldBounds::Rect { .. } => WorldBounds::rect(width, height),
+            WorldBounds::Circle { center, radius } => {
+                let factor = |old: u32, new: u32| {
+                    if old == 0 {
+                        1.0
+                    } else {
+                        u32_to_coord(new) / u32_to_coord(old)
+                    }
+                };
+                let (x_factor, y_factor) = (factor(old_width, width), factor(old_height, height));
+                WorldBounds::Circle {
+                    center: Position::new(center.x * x_factor, center.y * y_factor),
+                    radius: radius * x_factor.min(y_factor),
+                }
+            }
+        }
+    }
+    #[must_use]
+    pub fn contains(&self, position: &Position) -> bool {
+        match self {
+            WorldBounds::Rect { width, height } => {
+                (0.0..=*width).contains(&position.x) && (0.0..=*height).contains(&position.y)
+            }
+            WorldBounds::Circle { center, radius } => {
+                f32_to_coord(center.distance_squared(position)) <= radius * radius
+            }
+        }
+    }
+    /// Returns `position` if it is inside the bounds, otherwise the closest point on the edge.
+    #[must_use]
+    pub fn clamp(&self, position: &Position) -> Position {
+        match self {
+            WorldBounds::Rect { width, height } => Position::new(
+                position.x.clamp(0.0, width.max(0.0)),
+                position.y.clamp(0.0, height.max(0.0)),
+            ),
+            WorldBounds::Circle { center, radius } => {
+                let distance = f32_to_coord(center.distance(position));
+                if distance <= *radius {
+                    return position.clone();
+                }
+                let scale = radius / distance;
+                Position::new(
+                    (position.x - center.x).mul_add(scale, center.x),
+                    (position.y - center.y).mul_add(scale, center.y),
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clamp_outside_rect_onto_edge() {
+        let bounds = WorldBounds::rect(100, 50);
+        let clamped = bounds.clamp(&Position::new(150.0, -10.0));
+        assert!((clamped.x - 100.0).abs() < Coord::EPSILON);
+        assert!(clamped.y.abs() < Coord::EPSILON);
+
+        let inside = bounds.clamp(&Position::new(20.0, 30.0));
+        assert!((inside.x - 20.0).abs() < Coord::EPSILON);
+        assert!((inside.y - 30.0).abs() < Coord::EPSILON);
+    }
+
+    #[test]
+    fn test_clamp_outside_circle_onto_edge() {
+        let bounds = WorldBounds::Circle {
+            center: Position::new(10.0, 10.0),
+            radius: 5.0,
+        };
+        // 3-4-5 direction scaled to twice the radius
+        let clamped = bounds.clamp(&Position::new(16.0, 18.0));
+        assert!((clamped.x - 13.0).abs() < 1e-5);
+        assert!((clamped.y - 14.0).abs() < 1e-5);
+        assert!((Position::new(10.0, 10.0).distance(&clamped) - 5.0).abs() < 1e-5);
+
+        let inside = Position::new(11.0, 9.0);
+        assert!(bounds.contains(&inside));
+        let unchanged = bounds.clamp(&inside);
+        assert!((unchanged.x - 11.0).abs() < Coord::EPSILON);
+        assert!((unchanged.y - 9.0).abs() < Coord::EPSILON);
+    }
+
+    #[test]
+    fn test_resized_keeps_the_shape() {
+        let circle = WorldBounds::Circle {
+            center: Position::new(50.0, 50.0),
+            radius: 40.0,
+        };
+        let WorldBounds::Circle { center, radius } = circle.resized(100, 100, 200, 400) else {
+            panic!("a circle stays a circle");
+        };
+        assert!((center.x - 100.0).abs() < Coord::EPSILON);
+        assert!((center.y - 200.0).abs() < Coord::EPSILON);
+        assert!((radius - 80.0).abs() < Coord::EPSILON);
+
+        let WorldBounds::Rect { width, height } =
+            WorldBounds::rect(100, 100).resized(100, 100, 30, 20)
+        else {
+            panic!("a rect stays a rect");
+        };
+        assert!((width - 30.0).abs() < Coord::EPSILON);
+        assert!((height - 20.0).abs() < Coord::EPSILON);
+    }
+}
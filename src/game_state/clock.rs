@@ -0,0 +1,99 @@
+use std::{
+    fmt::Debug,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Milliseconds since the UNIX epoch, as reported by a [`Clock`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    #[must_use]
+    pub fn from_millis(millis: u64) -> Self {
+        Timestamp(millis)
+    }
+    #[must_use]
+    pub fn as_millis(self) -> u64 {
+        self.0
+    }
+    /// Time elapsed from `earlier` to `self`, zero if `earlier` is later.
+    #[must_use]
+    pub fn duration_since(self, earlier: Timestamp) -> Duration {
+        Duration::from_millis(self.0.saturating_sub(earlier.0))
+    }
+    #[must_use]
+    pub fn saturating_add(self, duration: Duration) -> Timestamp {
+        Timestamp(self.0.saturating_add(duration_millis(duration)))
+    }
+}
+
+fn duration_millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Source of the current time for everything that tracks player activity.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Timestamp;
+}
+
+/// Wall clock anchored at creation and advanced by a monotonic [`Instant`],
+/// so it never goes backwards while the process runs but stays comparable
+/// across restarts.
+#[derive(Debug, Clone)]
+pub struct SystemClock {
+    anchor: Timestamp,
+    started: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        SystemClock {
+            anchor: Timestamp(duration_millis(since_epoch)),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        self.anchor.saturating_add(self.started.elapsed())
+    }
+}
+
+/// Manually driven clock for deterministic tests.
+#[derive(Debug, Default)]
+pub struct MockClock {
+    now: AtomicU64,
+}
+
+impl MockClock {
+    #[must_use]
+    pub fn new(start: Timestamp) -> Self {
+        MockClock {
+            now: AtomicU64::new(start.0),
+        }
+    }
+    pub fn advance(&self, duration: Duration) {
+        let millis = duration_millis(duration);
+        // fetch_update with Some never fails
+        let _ = self
+            .now
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |now| {
+                Some(now.saturating_add(millis))
+            });
+    }
+    pub fn set(&self, now: Timestamp) {
+        self.now.store(now.0, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Timestamp {
+        Timestamp(self.now.load(Ordering::SeqCst))
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use tokio::{sync::Notify, time::Instant};
+
+use crate::packet::{
+    bundle::{BundlePacket, BUNDLE_ENTRY_OVERHEAD},
+    MessageType,
+};
+
+/// Outbound datagrams held back per address for up to a window, then sent together as
+/// one `Bundle` datagram.
+///
+/// [`GameState::send_datagram`](super::GameState::send_datagram) pushes here for players
+/// that negotiated bundles; a flusher task sends what is due. Latency sensitive packets,
+/// see [`MessageType::is_latency_sensitive`], go out right away along with anything held
+/// for the same address, and so does a bundle about to outgrow the datagram size limit.
+#[derive(Debug)]
+pub struct Coalescer {
+    window: Duration,
+    max_size: usize,
+    pending: Mutex<HashMap<String, Held>>,
+    notify: Notify,
+}
+
+/// Datagrams held for one address.
+#[derive(Debug)]
+struct Held {
+    packets: Vec<Vec<u8>>,
+    /// Size of the bundle holding `packets`.
+    size: usize,
+    /// When the first of them was held plus the window.
+    deadline: Instant,
+}
+
+/// A bundle of `packets`, or the packet itself if there is only one.
+fn bundle(mut packets: Vec<Vec<u8>>) -> Vec<u8> {
+    if packets.len() == 1 {
+        return packets.remove(0);
+    }
+    BundlePacket::new(packets).to_datagram()
+}
+
+impl Coalescer {
+    /// Holds datagrams for up to `window`, in bundles of at most `max_size` bytes.
+    #[must_use]
+    pub fn new(window: Duration, max_size: usize) -> Self {
+        Coalescer {
+            window,
+            max_size,
+            pending: Mutex::default(),
+            notify: Notify::new(),
+        }
+    }
+    /// Holds `data` for `addr`. Returns the datagrams to send right away instead, in
+    /// order, empty if `data` was held.
+    pub fn push(&self, addr: &str, data: &[u8]) -> Vec<Vec<u8>> {
+        let latency_sensitive = data
+            .first()
+            .copied()
+            .and_then(MessageType::from_byte)
+            .is_some_and(MessageType::is_latency_sensitive);
+        let entry_size = BUNDLE_ENTRY_OVERHEAD.saturating_add(data.len());
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut send_now = Vec::new();
+        let fits = |held: &Held| held.size.saturating_add(entry_size) <= self.max_size;
+        if pending.get(addr).is_some_and(|held| !fits(held)) {
+            send_now.extend(pending.remove(addr).map(|held| bundle(held.packets)));
+        }
+        let alone = BundlePacket::size_with(&[]).saturating_add(entry_size);
+        if latency_sensitive || alone > self.max_size {
+            let mut packets = pending
+                .remove(addr)
+                .map_or_else(Vec::new, |held| held.packets);
+            packets.push(data.to_vec());
+            if alone > self.max_size && packets.len() > 1 {
+                // Too large to bundle at all, the held ones go first on their own
+                let data = packets.pop();
+                send_now.push(bundle(packets));
+                send_now.extend(data);
+            } else {
+                send_now.push(bundle(packets));
+            }
+            return send_now;
+        }
+        match pending.entry(addr.to_string()) {
+            Entry::Occupied(mut held) => {
+                let held = held.get_mut();
+                held.packets.push(data.to_vec());
+                held.size = held.size.saturating_add(entry_size);
+            }
+            Entry::Vacant(slot) => {
+                let now = Instant::now();
+                slot.insert(Held {
+                    packets: vec![data.to_vec()],
+                    size: alone,
+                    deadline: now.checked_add(self.window).unwrap_or(now),
+                });
+                self.notify.notify_one();
+            }
+        }
+        send_now
+    }
+    /// Removes and returns the datagrams whose window is over at `now`, one per address.
+    pub fn take_due(&self, now: Instant) -> Vec<(String, Vec<u8>)> {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let due: Vec<String> = pending
+            .iter()
+            .filter(|(_, held)| held.deadline <= now)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        due.into_iter()
+            .filter_map(|addr| {
+                let held = pending.remove(&addr)?;
+                Some((addr, bundle(held.packets)))
+            })
+            .collect()
+    }
+    /// When the next held datagrams are due, `None` if nothing is held.
+    #[must_use]
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .map(|held| held.deadline)
+            .min()
+    }
+    /// Waits until a datagram is held for an address nothing was held for.
+    pub async fn notified(&self) {
+        self.notify.notified().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{game_state::PLAYER_ID_LEN, packet::GamePacket};
+
+    fn datagram(msg_type: MessageType, seq_num: u32, payload_len: usize) -> Vec<u8> {
+        GamePacket::new(
+            msg_type,
+            seq_num,
+            vec![0; payload_len],
+            vec![1; PLAYER_ID_LEN],
+        )
+        .serialize()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_held_datagrams_are_bundled_once_due() {
+        let coalescer = Coalescer::new(Duration::from_millis(20), 1200);
+        let first = datagram(MessageType::ChatMessage, 1, 20);
+        let second = datagram(MessageType::ChatMessage, 2, 20);
+        assert!(coalescer.push("a", &first).is_empty());
+        assert!(coalescer.push("a", &second).is_empty());
+        let only = datagram(MessageType::ChatMessage, 3, 20);
+        assert!(coalescer.push("b", &only).is_empty());
+        let deadline = coalescer.next_deadline().unwrap();
+        assert!(coalescer.take_due(Instant::now()).is_empty());
+
+        tokio::time::advance(Duration::from_millis(20)).await;
+        assert_eq!(deadline, Instant::now());
+        let mut due = coalescer.take_due(Instant::now());
+        due.sort();
+        assert_eq!(due[1], ("b".to_string(), only));
+        let outer = GamePacket::deserialize(&due[0].1).unwrap();
+        assert_eq!(outer.msg_type, MessageType::Bundle);
+        assert_eq!(
+            BundlePacket::deserialize(&outer.payload).unwrap().packets,
+            vec![first, second]
+        );
+        assert_eq!(coalescer.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_latency_sensitive_and_oversize_datagrams_flush() {
+        let coalescer = Coalescer::new(Duration::from_millis(20), 200);
+        let held = datagram(MessageType::ChatMessage, 1, 20);
+        assert!(coalescer.push("a", &held).is_empty());
+        // Goes out right away, with what was held
+        let error = datagram(MessageType::Error, 2, 1);
+        let sent = coalescer.push("a", &error);
+        assert_eq!(sent.len(), 1);
+        let outer = GamePacket::deserialize(&sent[0]).unwrap();
+        assert_eq!(
+            BundlePacket::deserialize(&outer.payload).unwrap().packets,
+            vec![held.clone(), error.clone()]
+        );
+        assert_eq!(coalescer.push("a", &error), vec![error]);
+
+        // The held one is sent once the next wouldn't fit beside it
+        let large = datagram(MessageType::ChatMessage, 3, 110);
+        assert!(coalescer.push("a", &held).is_empty());
+        assert_eq!(coalescer.push("a", &large), vec![held.clone()]);
+        assert!(coalescer.next_deadline().is_some());
+
+        // Too large to bundle at all: sent as is, after what was held
+        let huge = datagram(MessageType::ChatMessage, 4, 200);
+        assert_eq!(coalescer.push("a", &huge), vec![large, huge]);
+        assert_eq!(coalescer.next_deadline(), None);
+    }
+}
@@ -0,0 +1,144 @@
+use std::{collections::VecDeque, time::Duration};
+
+use super::{Position, Timestamp};
+
+/// Where a player recently was, oldest sample first, for lag compensation: rewinding
+/// the world to the time a shooter saw it to validate a hit.
+///
+/// Bounded both in samples and in age, see `ServerConfig::position_history_len` and
+/// `ServerConfig::position_history_retention`.
+#[derive(Debug, Clone, Default)]
+pub struct PositionHistory {
+    samples: VecDeque<(Timestamp, Position)>,
+}
+
+impl PositionHistory {
+    /// Appends `position` at `at`, dropping the oldest samples beyond `capacity` and
+    /// those older than `retention` before `at`. A `capacity` of zero records nothing.
+    pub fn record(
+        &mut self,
+        at: Timestamp,
+        position: Position,
+        capacity: usize,
+        retention: Duration,
+    ) {
+        if capacity == 0 {
+            return;
+        }
+        while self.samples.len() >= capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at, position));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(sampled, _)| at.duration_since(*sampled) > retention)
+        {
+            self.samples.pop_front();
+        }
+    }
+    /// Where the player was at `at`, interpolated between the samples either side of
+    /// it. Before the oldest or after the newest sample, that sample's position.
+    /// `None` without samples.
+    #[must_use]
+    pub fn position_at(&self, at: Timestamp) -> Option<Position> {
+        let after = self.samples.partition_point(|(sampled, _)| *sampled <= at);
+        let Some(before) = after.checked_sub(1) else {
+            return self.samples.front().map(|(_, position)| position.clone());
+        };
+        let (from_at, from) = self.samples.get(before)?;
+        let Some((to_at, to)) = self.samples.get(after) else {
+            return Some(from.clone());
+        };
+        let t = at.duration_since(*from_at).as_secs_f32()
+            / to_at.duration_since(*from_at).as_secs_f32();
+        Some(from.lerp(to, t))
+    }
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(samples: &[(u64, Position)]) -> PositionHistory {
+        let mut history = PositionHistory::default();
+        for (at, position) in samples {
+            history.record(Timestamp(*at), position.clone(), 8, Duration::from_secs(1));
+        }
+        history
+    }
+
+    #[test]
+    fn test_between_samples_is_interpolated() {
+        let history = history(&[
+            (1000, Position::new(0.0, 0.0)),
+            (1100, Position::new(10.0, 20.0)),
+            (1200, Position::new(30.0, 20.0)),
+        ]);
+        assert_eq!(
+            history.position_at(Timestamp(1050)),
+            Some(Position::new(5.0, 10.0))
+        );
+        assert_eq!(
+            history.position_at(Timestamp(1150)),
+            Some(Position::new(20.0, 20.0))
+        );
+        assert_eq!(
+            history.position_at(Timestamp(1100)),
+            Some(Position::new(10.0, 20.0))
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_is_the_nearest_sample() {
+        let history = history(&[
+            (1000, Position::new(1.0, 2.0)),
+            (1100, Position::new(3.0, 4.0)),
+        ]);
+        assert_eq!(
+            history.position_at(Timestamp(500)),
+            Some(Position::new(1.0, 2.0))
+        );
+        assert_eq!(
+            history.position_at(Timestamp(5000)),
+            Some(Position::new(3.0, 4.0))
+        );
+        assert_eq!(PositionHistory::default().position_at(Timestamp(0)), None);
+    }
+
+    #[test]
+    fn test_bounded_by_capacity_and_retention() {
+        let mut history = PositionHistory::default();
+        for at in 0..5 {
+            history.record(
+                Timestamp(at),
+                Position::new(0.0, 0.0),
+                3,
+                Duration::from_secs(1),
+            );
+        }
+        assert_eq!(history.len(), 3);
+        history.record(
+            Timestamp(2000),
+            Position::new(0.0, 0.0),
+            3,
+            Duration::from_secs(1),
+        );
+        assert_eq!(history.len(), 1);
+        history.record(
+            Timestamp(3000),
+            Position::new(0.0, 0.0),
+            0,
+            Duration::from_secs(1),
+        );
+        assert_eq!(history.len(), 1);
+    }
+}
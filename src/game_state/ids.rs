@@ -0,0 +1,148 @@
+use std::{
+    fmt::Debug,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use super::{generate_player_id, PlayerId, PLAYER_ID_LEN};
+use crate::packet::connection_init::parse_player_id;
+
+/// Mints the ids of new players, see `ServerConfig::id_generator`.
+///
+/// Ids travel as the 18 byte client id of every packet header, so a generator must
+/// return [`PLAYER_ID_LEN`] ASCII letters, digits, `_` or `-`. Use
+/// [`generate_checked`] to fall back to a random id when it doesn't.
+pub trait IdGenerator: Debug + Send + Sync {
+    fn generate(&self) -> PlayerId;
+}
+
+/// Random nanoids, the default, see [`generate_player_id`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NanoidGenerator;
+
+impl IdGenerator for NanoidGenerator {
+    fn generate(&self) -> PlayerId {
+        generate_player_id()
+    }
+}
+
+/// The first [`PLAYER_ID_LEN`] hex digits of a random (version 4) UUID. A full UUID
+/// doesn't fit the 18 byte id, what is kept holds 68 random bits.
+#[cfg(feature = "uuid")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UuidGenerator;
+
+#[cfg(feature = "uuid")]
+impl IdGenerator for UuidGenerator {
+    fn generate(&self) -> PlayerId {
+        let mut id = uuid::Uuid::new_v4().simple().to_string();
+        id.truncate(PLAYER_ID_LEN);
+        id
+    }
+}
+
+/// Zero padded decimal ids counting up from a starting value, so tests can predict
+/// them. Not for production: anyone can guess the next player's id.
+#[derive(Debug, Default)]
+pub struct SequentialGenerator {
+    next: AtomicU64,
+}
+
+impl SequentialGenerator {
+    #[must_use]
+    pub fn starting_at(first: u64) -> Self {
+        SequentialGenerator {
+            next: AtomicU64::new(first),
+        }
+    }
+}
+
+impl IdGenerator for SequentialGenerator {
+    fn generate(&self) -> PlayerId {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        format!("{id:0>PLAYER_ID_LEN$}")
+    }
+}
+
+/// How often [`generate_checked`] asks the generator again for an id nobody holds.
+const MAX_ATTEMPTS: usize = 8;
+
+/// An id from `generator` that `taken` doesn't report as held by a player. Falls back
+/// to a random free id, logged, if the generator returns a malformed id or keeps
+/// returning held ones.
+#[must_use]
+pub fn generate_checked(generator: &dyn IdGenerator, taken: impl Fn(&str) -> bool) -> PlayerId {
+    for _ in 0..MAX_ATTEMPTS {
+        let id = generator.generate();
+        if parse_player_id(id.as_bytes()).is_none() {
+            tracing::error!(
+                "{:?} generated the malformed player id {:?}, using a random one",
+                generator,
+                id
+            );
+            break;
+        }
+        if !taken(&id) {
+            return id;
+        }
+        tracing::warn!("{:?} generated the held player id {}", generator, id);
+    }
+    loop {
+        let id = generate_player_id();
+        if !taken(&id) {
+            return id;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct TooLong;
+
+    impl IdGenerator for TooLong {
+        fn generate(&self) -> PlayerId {
+            "x".repeat(PLAYER_ID_LEN + 1)
+        }
+    }
+
+    #[test]
+    fn test_sequential_ids_count_up() {
+        let generator = SequentialGenerator::starting_at(7);
+        assert_eq!(
+            generate_checked(&generator, |_| false),
+            "000000000000000007"
+        );
+        assert_eq!(
+            generate_checked(&generator, |_| false),
+            "000000000000000008"
+        );
+    }
+
+    #[test]
+    fn test_malformed_ids_are_replaced() {
+        let id = generate_checked(&TooLong, |_| false);
+        assert_eq!(id.len(), PLAYER_ID_LEN);
+        assert_ne!(id, "x".repeat(PLAYER_ID_LEN));
+        assert!(
+            parse_player_id(generate_checked(&NanoidGenerator, |_| false).as_bytes()).is_some()
+        );
+    }
+
+    #[test]
+    fn test_held_ids_are_skipped() {
+        let generator = SequentialGenerator::starting_at(7);
+        let held = "000000000000000007";
+        assert_eq!(
+            generate_checked(&generator, |id| id == held),
+            "000000000000000008"
+        );
+
+        // A generator stuck on a held id falls back to a random one
+        let generator = SequentialGenerator::starting_at(9);
+        let id = generate_checked(&generator, |id| id.starts_with("0000000"));
+        assert!(parse_player_id(id.as_bytes()).is_some());
+        assert!(!id.starts_with("0000000"));
+    }
+}
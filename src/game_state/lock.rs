@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use tokio::{
+    sync::{Mutex, MutexGuard},
+    time::Instant,
+};
+
+use super::GameState;
+
+/// Default for `ServerConfig::lock_wait_threshold`.
+pub const DEFAULT_LOCK_WAIT_THRESHOLD: Duration = Duration::from_millis(10);
+
+/// Locks `state`, recording the wait in the state's `ServerMetrics` and warning when it
+/// exceeds [`GameState::lock_wait_threshold`]. `site` names the caller in the warning.
+pub async fn lock_timed<'a>(
+    state: &'a Mutex<GameState>,
+    site: &'static str,
+) -> MutexGuard<'a, GameState> {
+    let started = Instant::now();
+    let guard = state.lock().await;
+    let waited = started.elapsed();
+    let long = waited > guard.lock_wait_threshold;
+    if long {
+        tracing::warn!("Waited {:?} for the game state lock in {}", waited, site);
+    }
+    guard.metrics.record_lock_wait(waited, long);
+    guard
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{server::ServerMetrics, testing::CapturedLogs};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_contended_lock_logs_long_wait() {
+        let captured = CapturedLogs::default();
+        let _default = captured.install();
+
+        let metrics = Arc::new(ServerMetrics::default());
+        let state = Arc::new(Mutex::new(GameState {
+            metrics: Arc::clone(&metrics),
+            ..GameState::new(800, 600)
+        }));
+        drop(lock_timed(&state, "uncontended").await);
+        assert_eq!(metrics.long_lock_waits(), 0);
+
+        let held = state.lock().await;
+        let contender = {
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                drop(lock_timed(&state, "contender").await);
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(held);
+        contender.await.unwrap();
+
+        assert_eq!(metrics.long_lock_waits(), 1);
+        // 50ms lands in the bucket up to 100ms, the uncontended lock in the fastest one
+        assert_eq!(metrics.lock_wait_histogram(), vec![1, 0, 0, 0, 1, 0]);
+        let logs = captured.contents();
+        assert!(logs.contains("WARN"), "{logs}");
+        assert!(
+            logs.contains("for the game state lock in contender"),
+            "{logs}"
+        );
+        assert!(!logs.contains("uncontended"), "{logs}");
+    }
+}
@@ -1,31 +1,212 @@
+pub mod bounds;
+pub mod clock;
+pub mod coalesce;
+pub mod history;
+pub mod ids;
+pub mod lock;
+pub mod outbound;
+pub mod scope;
+pub mod snapshot;
+
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    ops::{Add, Mul, Sub},
     sync::Arc,
-    time::{Duration, Instant},
+    time::Duration,
 };
 
-use tokio::net::UdpSocket;
+use rand::Rng;
+use tokio::{
+    net::{ToSocketAddrs, UdpSocket},
+    sync::Notify,
+};
 pub const CLEANUP_INTERVAL_SECS: u64 = 5;
 const PLAYER_TIMEOUT_SECS: u64 = 10;
-use crate::packet::{ping::PlayerLeft, GamePacket, MessageType};
-#[derive(Debug, Clone)]
+/// How long a handshake challenge can be answered after it was issued.
+pub const CHALLENGE_TIMEOUT_SECS: u64 = 5;
+/// Default for [`GameState::pending_entry_ttl`].
+pub const DEFAULT_PENDING_ENTRY_TTL: Duration = Duration::from_secs(30);
+/// Default for [`GameState::position_history_retention`].
+pub const DEFAULT_POSITION_HISTORY_RETENTION: Duration = Duration::from_secs(1);
+/// Default for [`GameState::unknown_heartbeat_window`].
+pub const DEFAULT_UNKNOWN_HEARTBEAT_WINDOW: Duration = Duration::from_secs(10);
+/// World size of `GameState::default()` and of the state a `GameServer` starts with.
+pub const DEFAULT_WORLD_WIDTH: u32 = 1920;
+pub const DEFAULT_WORLD_HEIGHT: u32 = 1080;
+/// Upper bound on the summed key and value lengths of a player's metadata.
+pub const MAX_PLAYER_METADATA_BYTES: usize = 1024;
+/// Metadata key that also sets [`Player::team`]: a one byte value joins that team, any
+/// other value leaves the player without one.
+pub const TEAM_METADATA_KEY: &str = "team";
+pub use bounds::WorldBounds;
+pub use clock::{Clock, MockClock, SystemClock, Timestamp};
+pub use coalesce::Coalescer;
+pub use history::PositionHistory;
+#[cfg(feature = "uuid")]
+pub use ids::UuidGenerator;
+pub use ids::{IdGenerator, NanoidGenerator, SequentialGenerator};
+pub use lock::{lock_timed, DEFAULT_LOCK_WAIT_THRESHOLD};
+pub use outbound::OutboundQueue;
+pub use scope::BroadcastScope;
+pub use snapshot::{SnapshotBytes, SnapshotError};
 
+use crate::{
+    num::{coord_to_f32, coord_to_f64, f32_to_coord, f64_to_coord, f64_to_f32},
+    packet::{
+        chat::ChatPacket,
+        connection_init::{ChallengeNonce, ReconnectToken},
+        crypto::{self, SessionKey},
+        error::{ErrorCode, ErrorPacket},
+        features::Features,
+        ping::{LeaveReason, PlayerLeft},
+        position::PlayerPosition,
+        world::WorldInfo,
+        GamePacket, MessageType, PositionGamePacket, ReplayWindow, SeqNum, MAX_DATAGRAM_SIZE,
+    },
+    server::ServerMetrics,
+};
+/// Unique id assigned to a player on connect.
+pub type PlayerId = String;
+/// Length in bytes of every player id, and of the client id in a packet header. Ids
+/// are sliced out of payloads at this length, see [`generate_player_id`].
+pub const PLAYER_ID_LEN: usize = 18;
+/// Length in bytes of a position in the format of [`Position::serialize_f64`].
+pub const POSITION_F64_SIZE: usize = 16;
+
+/// Generates a fresh random player id of [`PLAYER_ID_LEN`] bytes.
+///
+/// # Panics
+/// Panics if the generator's output isn't [`PLAYER_ID_LEN`] bytes long, which would
+/// otherwise corrupt every packet slicing ids at that length.
+#[must_use]
+pub fn generate_player_id() -> PlayerId {
+    let id = nanoid::nanoid!(PLAYER_ID_LEN);
+    assert_eq!(id.len(), PLAYER_ID_LEN, "generated player id {id:?}");
+    id
+}
+/// Team a [`TEAM_METADATA_KEY`] metadata value stands for.
+#[must_use]
+pub fn team_from_metadata(value: &[u8]) -> Option<u8> {
+    match value {
+        [team] => Some(*team),
+        _ => None,
+    }
+}
+/// Name of a room. Players only see and hear players in the same room; the empty
+/// string is the room players join when they don't ask for one.
+pub type RoomId = String;
+/// Longest room id accepted from clients, in bytes.
+pub const MAX_ROOM_ID_LEN: usize = 32;
+/// Id of a server tracked [`Entity`], unique for the lifetime of a `GameState`.
+pub type EntityId = u32;
+/// What an [`Entity`] is, e.g. a pickup or a projectile. Opaque to the server.
+pub type EntityKind = u16;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GameState {
-    pub players: HashMap<String, Player>,
+    pub players: HashMap<PlayerId, Player>,
+    /// Maps a player's network address to their id.
+    pub addr_to_id: HashMap<String, PlayerId>,
+    /// Reverse of `addr_to_id`: the address each player is reachable at.
+    pub id_to_addr: HashMap<PlayerId, SocketAddr>,
+    /// Number of addresses in `addr_to_id` on each IP, whatever their port.
+    pub players_per_ip: HashMap<IpAddr, usize>,
     pub width: u32,
     pub height: u32,
+    /// Playable area, a `width` x `height` rectangle unless replaced.
+    pub bounds: WorldBounds,
+    /// Where new players appear.
+    pub spawn: Position,
+    /// Number of simulation ticks run so far.
+    pub tick: u64,
+    /// Position updates received since the last tick, keyed by client id.
+    /// Only the latest update per client is kept.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub pending_position_updates: HashMap<Vec<u8>, PositionGamePacket>,
+    /// Reconnect tokens handed out on connect, mapped to the player id they reclaim.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub reconnect_tokens: HashMap<ReconnectToken, String>,
+    /// Handshake challenges awaiting an answer, keyed by address, with when they were issued.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub pending_challenges: HashMap<String, (ChallengeNonce, Timestamp)>,
+    /// When heartbeats from each unknown address were last reported, see
+    /// [`GameState::report_unknown_heartbeat`].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub unknown_heartbeats: HashMap<String, Timestamp>,
+    /// Players controlled by a connection besides its own, mapped to that connection's
+    /// address. See [`GameState::add_avatar`].
+    pub avatar_owners: HashMap<PlayerId, String>,
+    /// Players currently within each player's interest radius, keyed by observer id.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub interest: HashMap<PlayerId, HashSet<PlayerId>>,
+    /// Connections watching the game without playing, keyed by address.
+    pub spectators: HashMap<String, Spectator>,
+    /// Server tracked objects that aren't players, see [`GameState::spawn_entity`].
+    pub entities: HashMap<EntityId, Entity>,
+    /// Id the next spawned entity gets.
+    pub next_entity_id: EntityId,
+    /// Most recent chat messages with the room they were sent in, oldest first.
+    pub chat_history: VecDeque<(RoomId, ChatPacket)>,
+    /// Largest datagram [`GameState::send_datagram`] sends.
+    pub max_datagram_size: usize,
+    /// When set, [`GameState::send_datagram`] queues datagrams here for a sender task
+    /// instead of sending them itself.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub outbound: Option<Arc<OutboundQueue>>,
+    /// When set, [`GameState::send_datagram`] holds datagrams to players that negotiated
+    /// bundles here, to be sent together by a flusher task.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub coalescer: Option<Arc<Coalescer>>,
+    /// Payloads larger than this are compressed for players that support it.
+    /// `None` never compresses.
+    pub compression_threshold: Option<usize>,
+    /// Optional features players may negotiate on connect, see
+    /// [`GameState::negotiate_features`].
+    pub supported_features: Features,
+    /// Signaled whenever a player or spectator is added, waking maintenance tasks
+    /// paused while the state was idle.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub joined: Arc<Notify>,
+    /// Waits longer than this in [`lock_timed`] are logged.
+    pub lock_wait_threshold: Duration,
+    /// Age after which an unanswered handshake challenge is dropped by
+    /// [`GameState::expire_stale_entries`].
+    pub pending_entry_ttl: Duration,
+    /// Samples kept in each player's [`Player::position_history`], zero keeps none.
+    pub position_history_len: usize,
+    /// Age after which samples are dropped from each player's [`Player::position_history`].
+    pub position_history_retention: Duration,
+    /// Shortest time between two reports of heartbeats from the same unknown address.
+    pub unknown_heartbeat_window: Duration,
+    /// When set, players that send nothing after their `ConnectionInit` for this long
+    /// are removed by [`GameState::cleanup_inactive_players`] without waiting for the
+    /// heartbeat timeout. Players controlled through another connection are exempt.
+    pub connect_grace_period: Option<Duration>,
+    /// Counters shared with the server, see `GameServer::metrics`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub metrics: Arc<ServerMetrics>,
+    /// Time source for heartbeats and timeouts.
+    #[cfg_attr(feature = "serde", serde(skip, default = "default_clock"))]
+    pub clock: Arc<dyn Clock>,
+}
+
+fn default_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock::default())
 }
 impl Default for GameState {
-    #[must_use]
     fn default() -> Self {
-        GameState::new(1920, 1080)
+        GameState::new(DEFAULT_WORLD_WIDTH, DEFAULT_WORLD_HEIGHT)
     }
 }
 /// Represents the current state of the game, managing players and game dimensions.
 ///
 /// # Fields
 ///
-/// * `players` - A `HashMap` containing all active players, keyed by their network address
+/// * `players` - A `HashMap` containing all active players, keyed by their id
+/// * `addr_to_id` - An index from each player's network address to their id
+/// * `id_to_addr` - The reverse index, from each player's id to their address
 /// * `width` - The width of the game world
 /// * `height` - The height of the game world
 ///
@@ -41,57 +222,629 @@ impl Default for GameState {
 /// # Examples
 ///
 /// ```
+/// # use server_dot::game_state::{GameState, Player};
 /// let mut game = GameState::new(800, 600);
-/// let player = Player::new("player1");
+/// let player = Player {
+///     id: "player1".to_string(),
+///     heartbeat: game.now(),
+///     connected_at: game.now(),
+///     ..Player::default()
+/// };
 /// game.add_player(player, "127.0.0.1:8080".to_string());
 /// ```
 impl GameState {
     #[must_use]
     pub fn new(width: u32, height: u32) -> Self {
+        GameState::with_clock(width, height, default_clock())
+    }
+    /// Like [`GameState::new`], with the player maps sized for `capacity` players
+    /// so they don't reallocate as players join.
+    #[must_use]
+    pub fn with_capacity(width: u32, height: u32, capacity: usize) -> Self {
+        GameState {
+            players: HashMap::with_capacity(capacity),
+            addr_to_id: HashMap::with_capacity(capacity),
+            id_to_addr: HashMap::with_capacity(capacity),
+            ..GameState::new(width, height)
+        }
+    }
+    /// Grows the player maps so they hold `capacity` players without reallocating.
+    pub fn reserve(&mut self, capacity: usize) {
+        self.players
+            .reserve(capacity.saturating_sub(self.players.len()));
+        self.addr_to_id
+            .reserve(capacity.saturating_sub(self.addr_to_id.len()));
+        self.id_to_addr
+            .reserve(capacity.saturating_sub(self.id_to_addr.len()));
+    }
+    #[must_use]
+    pub fn with_clock(width: u32, height: u32, clock: Arc<dyn Clock>) -> Self {
         GameState {
             players: HashMap::new(),
+            addr_to_id: HashMap::new(),
+            id_to_addr: HashMap::new(),
+            players_per_ip: HashMap::new(),
             width,
             height,
+            bounds: WorldBounds::rect(width, height),
+            spawn: Position::new(600.0, 700.0),
+            tick: 0,
+            pending_position_updates: HashMap::new(),
+            reconnect_tokens: HashMap::new(),
+            pending_challenges: HashMap::new(),
+            unknown_heartbeats: HashMap::new(),
+            avatar_owners: HashMap::new(),
+            interest: HashMap::new(),
+            spectators: HashMap::new(),
+            entities: HashMap::new(),
+            next_entity_id: 1,
+            chat_history: VecDeque::new(),
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+            outbound: None,
+            coalescer: None,
+            compression_threshold: None,
+            supported_features: Features::implemented(),
+            joined: Arc::default(),
+            lock_wait_threshold: DEFAULT_LOCK_WAIT_THRESHOLD,
+            pending_entry_ttl: DEFAULT_PENDING_ENTRY_TTL,
+            position_history_len: 0,
+            position_history_retention: DEFAULT_POSITION_HISTORY_RETENTION,
+            unknown_heartbeat_window: DEFAULT_UNKNOWN_HEARTBEAT_WINDOW,
+            connect_grace_period: None,
+            metrics: Arc::default(),
+            clock,
+        }
+    }
+    /// The features a client advertising `advertised` gets: those this server supports
+    /// and implements, without compression when [`GameState::compression_threshold`]
+    /// is `None` and without bundles when there is no [`GameState::coalescer`].
+    #[must_use]
+    pub fn negotiate_features(&self, advertised: Features) -> Features {
+        let mut negotiated = advertised & self.supported_features & Features::implemented();
+        if self.compression_threshold.is_none() {
+            negotiated = negotiated.without(Features::COMPRESSION);
+        }
+        if self.coalescer.is_none() {
+            negotiated = negotiated.without(Features::BUNDLES);
+        }
+        negotiated
+    }
+    /// Whether `player_id` negotiated [`Features::F64_POSITIONS`], so positions sent to
+    /// it go in the 16 byte format of [`Position::serialize_f64`].
+    #[must_use]
+    pub fn uses_f64_positions(&self, player_id: &str) -> bool {
+        self.players
+            .get(player_id)
+            .is_some_and(|player| player.features.contains(Features::F64_POSITIONS))
+    }
+    /// Serializes `packet` for `player_id` with the features negotiated for it: the
+    /// payload compressed when it exceeds [`GameState::compression_threshold`], and a
+    /// trailing checksum.
+    #[must_use]
+    pub fn encode_for(&self, player_id: &str, mut packet: GamePacket) -> Vec<u8> {
+        let features = self
+            .players
+            .get(player_id)
+            .map_or(Features::NONE, |player| player.features);
+        if let Some(threshold) = self.compression_threshold {
+            if features.contains(Features::COMPRESSION) {
+                packet = packet.compress(threshold);
+            }
+        }
+        if features.contains(Features::CHECKSUM) {
+            packet = packet.with_checksum();
+        }
+        packet.serialize()
+    }
+    /// Sends `data` to `addr` over `socket`.
+    ///
+    /// To a player that negotiated bundles, `data` is held in the [`Coalescer`] if there
+    /// is one and sent later as part of a `Bundle`, returning its length.
+    ///
+    /// Datagrams over `max_datagram_size` would be fragmented or dropped along the way,
+    /// so they are counted in the metrics and skipped instead, returning `Ok(0)`.
+    ///
+    /// With an [`OutboundQueue`] the datagram is queued for `addr` rather than sent, and
+    /// `socket` is unused; send errors are then only logged by the sender task.
+    ///
+    /// Datagrams to a player with an encrypted session are sealed with its key first.
+    ///
+    /// # Errors
+    /// Returns the error of the underlying send.
+    pub async fn send_datagram<A: ToSocketAddrs + std::fmt::Debug + std::fmt::Display>(
+        &self,
+        socket: &UdpSocket,
+        data: &[u8],
+        addr: A,
+    ) -> std::io::Result<usize> {
+        let address = addr.to_string();
+        let Some(coalescer) = self.coalescer.as_ref().filter(|_| {
+            self.get_player_by_addr(&address)
+                .is_some_and(|player| player.features.contains(Features::BUNDLES))
+        }) else {
+            return self.transmit(socket, data, addr).await;
+        };
+        for datagram in coalescer.push(&address, data) {
+            self.transmit(socket, &datagram, &addr).await?;
+        }
+        Ok(data.len())
+    }
+    /// Sends `data` to `addr` without coalescing it, see [`GameState::send_datagram`].
+    ///
+    /// # Errors
+    /// Returns the error of the underlying send.
+    pub async fn transmit<A: ToSocketAddrs + std::fmt::Debug + std::fmt::Display>(
+        &self,
+        socket: &UdpSocket,
+        data: &[u8],
+        addr: A,
+    ) -> std::io::Result<usize> {
+        let sealed;
+        let data = match self.session_key_of(&addr.to_string()) {
+            Some(key) => {
+                let Some(datagram) = crypto::seal(key, data) else {
+                    tracing::error!("Dropping datagram to {:?} that couldn't be sealed", addr);
+                    return Ok(0);
+                };
+                sealed = datagram;
+                &sealed
+            }
+            None => data,
+        };
+        if data.len() > self.max_datagram_size {
+            tracing::error!(
+                "Dropping {} byte datagram to {:?}, over the {} byte limit",
+                data.len(),
+                addr,
+                self.max_datagram_size
+            );
+            self.metrics.record_oversize_datagram();
+            return Ok(0);
+        }
+        if let Some(outbound) = &self.outbound {
+            outbound.push(addr.to_string(), data.to_vec());
+            return Ok(data.len());
         }
+        socket.send_to(data, addr).await
+    }
+    /// Key of the encrypted session of the player at `address`, if it has one.
+    #[must_use]
+    pub fn session_key_of(&self, address: &str) -> Option<&SessionKey> {
+        if !cfg!(feature = "crypto") {
+            return None;
+        }
+        self.get_player_by_addr(address)?.session_key.as_ref()
+    }
+    /// Decrypts a sealed datagram from the player at `address`, see [`crypto::open`].
+    /// `None` if the player has no encrypted session or the datagram doesn't open.
+    #[must_use]
+    pub fn open_datagram(&self, address: &str, data: &[u8]) -> Option<Vec<u8>> {
+        crypto::open(self.session_key_of(address)?, data)
+    }
+    /// Records the sequence number of an opened datagram from `address` in its player's
+    /// [`ReplayWindow`], returning `false` for a replay. Call only once the datagram
+    /// authenticated, or a forged one could shift the window.
+    pub fn accept_sealed_seq(&mut self, address: &str, seq_num: SeqNum) -> bool {
+        self.get_player_by_addr_mut(address)
+            .is_some_and(|player| player.replay_window.accept(seq_num))
+    }
+    /// Tells `addr` that its request with sequence number `seq_num` was rejected. Every
+    /// rejection goes through here; send errors are logged rather than returned.
+    ///
+    /// Only addresses with a player, a spectator or a pending handshake are answered,
+    /// anyone else could be a spoofed victim the server would be made to flood with
+    /// errors. Use [`GameState::send_error_to_unknown`] to turn away a handshake.
+    pub async fn send_error(
+        &self,
+        socket: &UdpSocket,
+        addr: SocketAddr,
+        seq_num: u32,
+        code: ErrorCode,
+        message: &str,
+    ) {
+        if !self.is_known_address(&addr.to_string()) {
+            tracing::debug!("Not answering {:?}, it has no player or handshake", addr);
+            return;
+        }
+        self.send_error_to_unknown(socket, addr, seq_num, code, message)
+            .await;
+    }
+    /// Whether `address` has a player, a spectator or a pending handshake.
+    #[must_use]
+    pub fn is_known_address(&self, address: &str) -> bool {
+        self.addr_to_id.contains_key(address)
+            || self.spectators.contains_key(address)
+            || self.pending_challenges.contains_key(address)
+    }
+    /// Like [`GameState::send_error`], but answers addresses the server doesn't know too.
+    /// Only for requests made to become known: handshakes being turned away and the
+    /// opt-in hint to connect again.
+    pub async fn send_error_to_unknown(
+        &self,
+        socket: &UdpSocket,
+        addr: SocketAddr,
+        seq_num: u32,
+        code: ErrorCode,
+        message: &str,
+    ) {
+        tracing::debug!("Rejecting request {} from {:?}: {:?}", seq_num, addr, code);
+        let packet = GamePacket::new(
+            MessageType::Error,
+            seq_num,
+            ErrorPacket::new(code, message).serialize(),
+            vec![0; PLAYER_ID_LEN],
+        );
+        if let Err(e) = self.send_datagram(socket, &packet.serialize(), addr).await {
+            tracing::error!("Error sending error packet: {:?}", e);
+        }
+    }
+    /// Current time according to the state's clock.
+    #[must_use]
+    pub fn now(&self) -> Timestamp {
+        self.clock.now()
     }
 
-    pub fn add_player(&mut self, player: Player, address: String) {
-        self.players.insert(address, player);
+    /// Adds `player` reachable at `address`.
+    /// A player previously bound to the same address is replaced and returned.
+    pub fn add_player(&mut self, player: Player, address: String) -> AddPlayerOutcome {
+        self.unbind_id(&player.id);
+        let replaced = self
+            .bind_addr(address, player.id.clone())
+            .filter(|previous_id| previous_id != &player.id)
+            .and_then(|previous_id| self.players.remove(&previous_id));
+        self.players.insert(player.id.clone(), player);
+        self.joined.notify_waiters();
+        match replaced {
+            Some(previous) => AddPlayerOutcome::Replaced(Box::new(previous)),
+            None => AddPlayerOutcome::Inserted,
+        }
+    }
+    /// Adds `player` as an avatar controlled by the connection at `address`, which
+    /// may already have a player of its own. Avatars aren't sent anything themselves,
+    /// they are moved with `BulkPositionUpdate`s from `address`.
+    pub fn add_avatar(&mut self, player: Player, address: String) {
+        self.avatar_owners.insert(player.id.clone(), address);
+        self.players.insert(player.id.clone(), player);
+        self.joined.notify_waiters();
+    }
+    /// Whether the connection at `address` may move `player_id`: its own player or
+    /// one of its avatars.
+    #[must_use]
+    pub fn controls(&self, address: &str, player_id: &str) -> bool {
+        self.addr_to_id
+            .get(address)
+            .is_some_and(|id| id == player_id)
+            || self
+                .avatar_owners
+                .get(player_id)
+                .is_some_and(|owner| owner == address)
+    }
+    /// Ids of the avatars controlled by the connection at `address`.
+    #[must_use]
+    pub fn avatars_of(&self, address: &str) -> Vec<PlayerId> {
+        self.avatar_owners
+            .iter()
+            .filter(|(_, owner)| owner.as_str() == address)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+    /// Removes `player_id` and returns it, or `None` if there was no such player.
+    pub fn remove_player(&mut self, player_id: &str) -> Option<Player> {
+        self.unbind_id(player_id);
+        self.reconnect_tokens.retain(|_, owner| owner != player_id);
+        self.avatar_owners.remove(player_id);
+        self.interest.remove(player_id);
+        for visible in self.interest.values_mut() {
+            visible.remove(player_id);
+        }
+        self.players.remove(player_id)
     }
-    pub fn remove_player(&mut self, player_id: &str) {
-        self.players.remove(player_id);
+    /// Number of players connected from `ip`, on any port.
+    #[must_use]
+    pub fn players_from_ip(&self, ip: IpAddr) -> usize {
+        self.players_per_ip.get(&ip).copied().unwrap_or(0)
+    }
+    /// Points `address` at `player_id`, returning the id it pointed at before.
+    /// Only socket addresses are indexed by id, see [`GameState::id_to_addr`].
+    fn bind_addr(&mut self, address: String, player_id: PlayerId) -> Option<PlayerId> {
+        let addr = address.parse::<SocketAddr>().ok();
+        let previous_id = self.addr_to_id.insert(address, player_id.clone());
+        if let Some(previous_id) = previous_id.as_ref().filter(|id| **id != player_id) {
+            self.id_to_addr.remove(previous_id);
+        }
+        if let Some(addr) = addr {
+            if previous_id.is_none() {
+                let count = self.players_per_ip.entry(addr.ip()).or_default();
+                *count = count.saturating_add(1);
+            }
+            self.id_to_addr.insert(player_id, addr);
+        }
+        previous_id
+    }
+    /// Drops the address pointing at `player_id`, if any.
+    fn unbind_id(&mut self, player_id: &str) {
+        let Some(addr) = self.id_to_addr.remove(player_id) else {
+            return;
+        };
+        self.addr_to_id.remove(&addr.to_string());
+        if let Some(count) = self.players_per_ip.get_mut(&addr.ip()) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                self.players_per_ip.remove(&addr.ip());
+            }
+        }
+    }
+    /// Adds the current position of `player_id` to its [`Player::position_history`].
+    /// Called for every accepted position update.
+    pub fn record_position_sample(&mut self, player_id: &str) {
+        let now = self.now();
+        let (capacity, retention) = (self.position_history_len, self.position_history_retention);
+        if let Some(player) = self.players.get_mut(player_id) {
+            let position = player.position.clone();
+            player
+                .position_history
+                .record(now, position, capacity, retention);
+        }
+    }
+    /// Where `player_id` was at `at` according to its [`Player::position_history`],
+    /// see [`PositionHistory::position_at`]. `None` for an unknown player or one
+    /// without history.
+    #[must_use]
+    pub fn position_at(&self, player_id: &PlayerId, at: Timestamp) -> Option<Position> {
+        self.players
+            .get(player_id)?
+            .position_history
+            .position_at(at)
     }
     pub fn update_player_position(&mut self, player_id: &str, new_position: Position) {
-        if let Some(player) = self.get_player_mut(player_id) {
+        if let Some(player) = self.get_player_by_id_mut(player_id) {
             player.position = new_position;
         }
     }
+    /// Applies position updates received together in one go, so a whole batch needs
+    /// the lock only once. Every position update goes through here, the checks below
+    /// are the only ones a move gets.
+    ///
+    /// Each update moves the player its `client_id` names to its `position`, clamped to
+    /// the world bounds and, with a `collision_radius`, stopped where it would touch
+    /// another player. The move is recorded in the player's
+    /// [`Player::position_history`] and staged to be broadcast on the next tick, see
+    /// [`GameState::stage_position_update`]. Updates for unknown players, and stale
+    /// ones whose sequence number isn't newer than the player's [`Player::seq_num`],
+    /// are skipped; an applied update advances [`Player::seq_num`], so a reordered or
+    /// duplicated update later in the batch is skipped too.
+    pub fn apply_position_batch(
+        &mut self,
+        updates: &[PositionGamePacket],
+        collision_radius: Option<f32>,
+    ) -> AppliedPositions {
+        let mut applied = AppliedPositions::default();
+        for (index, update) in updates.iter().enumerate() {
+            let Some(player) = std::str::from_utf8(&update.client_id)
+                .ok()
+                .and_then(|id| self.players.get(id))
+            else {
+                tracing::debug!("Position update for unknown player {:?}", update.client_id);
+                continue;
+            };
+            if !SeqNum(update.seq_num).is_newer_than(SeqNum(player.seq_num)) {
+                tracing::debug!(
+                    "Dropping stale position update {} for {}, at {}",
+                    update.seq_num,
+                    player.id,
+                    player.seq_num
+                );
+                continue;
+            }
+            let mut position = self.clamp_position(&update.position);
+            if position != update.position {
+                applied.clamped.push(index);
+            }
+            if let Some(radius) = collision_radius {
+                position = self.resolve_collision(&player.id, &player.position, &position, radius);
+            }
+            let player_id = player.id.clone();
+            let Some(player) = self.players.get_mut(&player_id) else {
+                continue;
+            };
+            player.seq_num = update.seq_num;
+            if player.position == position {
+                continue;
+            }
+            player.position = position.clone();
+            self.record_position_sample(&player_id);
+            self.stage_position_update(PositionGamePacket {
+                position: position.clone(),
+                ..update.clone()
+            });
+            applied
+                .changed
+                .push(PlayerPosition::new(player_id.into_bytes(), position));
+        }
+        applied
+    }
     #[must_use]
-    pub fn get_player(&self, player_id: &str) -> Option<&Player> {
+    pub fn get_player_by_id(&self, player_id: &str) -> Option<&Player> {
         self.players.get(player_id)
     }
     #[must_use]
-    pub fn get_player_mut(&mut self, player_id: &str) -> Option<&mut Player> {
+    pub fn get_player_by_id_mut(&mut self, player_id: &str) -> Option<&mut Player> {
         self.players.get_mut(player_id)
     }
     #[must_use]
+    pub fn get_player_by_addr(&self, address: &str) -> Option<&Player> {
+        self.addr_to_id
+            .get(address)
+            .and_then(|id| self.players.get(id))
+    }
+    #[must_use]
+    pub fn get_player_by_addr_mut(&mut self, address: &str) -> Option<&mut Player> {
+        self.addr_to_id
+            .get(address)
+            .and_then(|id| self.players.get_mut(id))
+    }
+    /// Address `player_id` is reachable at, for sending to a single player.
+    #[must_use]
+    pub fn addr_for_id(&self, player_id: &str) -> Option<SocketAddr> {
+        self.id_to_addr.get(player_id).copied()
+    }
+    #[must_use]
     pub fn get_player_position(&self, player_id: &str) -> Option<&Position> {
-        self.get_player(player_id).map(|p| &p.position)
+        self.get_player_by_id(player_id).map(|p| &p.position)
     }
     #[must_use]
     pub fn get_player_position_mut(&mut self, player_id: &str) -> Option<&mut Position> {
-        self.get_player_mut(player_id).map(|p| &mut p.position)
+        self.get_player_by_id_mut(player_id)
+            .map(|p| &mut p.position)
     }
     #[must_use]
     pub fn get_player_count(&self) -> usize {
         self.players.len()
     }
     #[must_use]
-    pub fn get_players(&self) -> &HashMap<String, Player> {
+    pub fn get_players(&self) -> &HashMap<PlayerId, Player> {
         &self.players
     }
-    pub fn get_players_mut(&mut self) -> &mut HashMap<String, Player> {
-        &mut self.players
+    /// All players ordered by id, for output that has to be reproducible.
+    #[must_use]
+    pub fn players_sorted(&self) -> Vec<&Player> {
+        let mut players = self.players.values().collect::<Vec<_>>();
+        players.sort_by(|a, b| a.id.cmp(&b.id));
+        players
+    }
+    /// All players, the longest connected first. Players that joined at the same time
+    /// are ordered by id.
+    #[must_use]
+    pub fn players_by_join_time(&self) -> Vec<&Player> {
+        let mut players = self.players.values().collect::<Vec<_>>();
+        players.sort_by(|a, b| {
+            a.connected_at
+                .cmp(&b.connected_at)
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        players
+    }
+    /// Iterates every player together with the address it is reachable at.
+    pub fn players_by_addr(&self) -> impl Iterator<Item = (&String, &Player)> {
+        self.addr_to_id
+            .iter()
+            .filter_map(|(addr, id)| self.players.get(id).map(|player| (addr, player)))
+    }
+    /// Snapshot of every player's address and id, for sends that also update player state.
+    #[must_use]
+    pub fn recipients(&self) -> Vec<(String, PlayerId)> {
+        self.players_by_addr()
+            .map(|(addr, player)| (addr.clone(), player.id.clone()))
+            .collect()
+    }
+    /// Like [`GameState::recipients`], limited to the players `scope` includes.
+    #[must_use]
+    pub fn scope_recipients(&self, scope: &BroadcastScope) -> Vec<(String, PlayerId)> {
+        self.players_by_addr()
+            .filter(|(addr, player)| scope.includes(addr, player))
+            .map(|(addr, player)| (addr.clone(), player.id.clone()))
+            .collect()
+    }
+    /// Sends `msg_type` packets to every player and spectator `scope` covers, each with
+    /// its own outbound sequence number and its player id as client id, zeros for
+    /// spectators. Returns how many sends succeeded.
+    ///
+    /// `payloads` gives the payloads for a recipient, the player's id or `None` for the
+    /// spectators, one packet each, so every recipient can be sent what it negotiated.
+    /// Packets to players are encoded as they negotiated, see [`GameState::encode_for`].
+    /// Failed sends are logged and counted against the player.
+    ///
+    /// Runs in a span carrying the message type and scope, with an event per packet and
+    /// a summary of the sent and failed counts, logged as a warning when any send failed.
+    #[tracing::instrument(
+        name = "Broadcast",
+        skip(self, socket, payloads),
+        fields(msg_type = ?msg_type, scope = ?scope)
+    )]
+    pub async fn broadcast(
+        &mut self,
+        socket: &UdpSocket,
+        scope: &BroadcastScope,
+        msg_type: MessageType,
+        mut payloads: impl FnMut(&GameState, Option<&str>) -> Vec<Vec<u8>>,
+    ) -> usize {
+        let mut sent = 0usize;
+        let mut failed = Vec::new();
+        for (send_addr, player_id) in self.scope_recipients(scope) {
+            for payload in payloads(self, Some(&player_id)) {
+                let seq = self.next_outbound_seq(&player_id);
+                let packet = GamePacket::new(msg_type, seq, payload, player_id.as_bytes().to_vec());
+                let data = self.encode_for(&player_id, packet);
+                match self.send_datagram(socket, &data, &send_addr).await {
+                    Ok(0) => {}
+                    Ok(_) => {
+                        tracing::trace!(addr = %send_addr, %player_id, seq, "Broadcast sent");
+                        sent = sent.saturating_add(1);
+                    }
+                    Err(e) => {
+                        tracing::error!(
+                            addr = %send_addr,
+                            %player_id,
+                            seq,
+                            "Error broadcasting: {:?}",
+                            e
+                        );
+                        failed.push(player_id.clone());
+                    }
+                }
+            }
+        }
+        let spectator_payloads = if scope.includes_spectators() {
+            payloads(self, None)
+        } else {
+            Vec::new()
+        };
+        let mut failed_spectators = 0usize;
+        for payload in spectator_payloads {
+            for (send_addr, seq) in self.spectator_recipients() {
+                let packet =
+                    GamePacket::new(msg_type, seq, payload.clone(), vec![0; PLAYER_ID_LEN]);
+                match self
+                    .send_datagram(socket, &packet.serialize(), &send_addr)
+                    .await
+                {
+                    Ok(0) => {}
+                    Ok(_) => {
+                        tracing::trace!(addr = %send_addr, seq, "Broadcast sent to spectator");
+                        sent = sent.saturating_add(1);
+                    }
+                    Err(e) => {
+                        tracing::error!(
+                            addr = %send_addr,
+                            seq,
+                            "Error broadcasting to spectator: {:?}",
+                            e
+                        );
+                        failed_spectators = failed_spectators.saturating_add(1);
+                    }
+                }
+            }
+        }
+        let failed_count = failed.len().saturating_add(failed_spectators);
+        if failed_count == 0 {
+            tracing::debug!(sent, failed = 0, "Broadcast done");
+        } else {
+            tracing::warn!(sent, failed = failed_count, "Broadcast partially failed");
+        }
+        for failed_id in failed {
+            self.record_send_failure(&failed_id);
+        }
+        sent
+    }
+    /// Room `player_id` is in, or `None` for an unknown player.
+    #[must_use]
+    pub fn room_of(&self, player_id: &str) -> Option<&RoomId> {
+        self.get_player_by_id(player_id).map(|player| &player.room)
+    }
+    /// Next outbound sequence number for `player_id`, or 0 for an unknown player.
+    pub fn next_outbound_seq(&mut self, player_id: &str) -> u32 {
+        self.get_player_by_id_mut(player_id)
+            .map_or(0, Player::next_outbound_seq)
     }
     #[must_use]
     pub fn get_width(&self) -> u32 {
@@ -101,78 +854,819 @@ impl GameState {
     pub fn get_height(&self) -> u32 {
         self.height
     }
+    /// World dimensions and spawn point as sent to clients.
+    #[must_use]
+    pub fn world_info(&self) -> WorldInfo {
+        WorldInfo::new(self.width, self.height, self.spawn.clone())
+    }
+    /// Changes the world to `width` x `height`, keeping the shape of its bounds, see
+    /// [`WorldBounds::resized`], and moving players and the spawn point that end up
+    /// outside them onto their edge. Returns the new world info for clients.
+    pub fn resize(&mut self, width: u32, height: u32) -> WorldInfo {
+        self.bounds = self.bounds.resized(self.width, self.height, width, height);
+        self.width = width;
+        self.height = height;
+        self.spawn = self.bounds.clamp(&self.spawn);
+        for player in self.players.values_mut() {
+            player.position = self.bounds.clamp(&player.position);
+        }
+        self.world_info()
+    }
+    /// Projects `position` onto the world bounds.
+    #[must_use]
+    pub fn clamp_position(&self, position: &Position) -> Position {
+        self.bounds.clamp(position)
+    }
+    /// Moves `mover_id` from `from` towards `to`, treating every player as a circle of
+    /// `radius`, and returns where it ends up: `to`, or the first point along the way
+    /// where it touches another player in the same room.
+    ///
+    /// Players that already overlap may move apart but not further into each other.
+    #[must_use]
+    pub fn resolve_collision(
+        &self,
+        mover_id: &str,
+        from: &Position,
+        to: &Position,
+        radius: f32,
+    ) -> Position {
+        let radius = f32_to_coord(radius);
+        let contact_squared = (2.0 * radius) * (2.0 * radius);
+        let (dx, dy) = (to.x - from.x, to.y - from.y);
+        let mut stop: Coord = 1.0;
+        let room = self.room_of(mover_id);
+        for other in self
+            .players
+            .values()
+            .filter(|other| other.id != mover_id && Some(&other.room) == room)
+        {
+            let target_distance = f32_to_coord(to.distance_squared(&other.position));
+            if target_distance >= contact_squared {
+                continue;
+            }
+            let start_distance = f32_to_coord(from.distance_squared(&other.position));
+            if start_distance < contact_squared {
+                if target_distance < start_distance {
+                    stop = 0.0;
+                }
+                continue;
+            }
+            // Earliest t in [0, 1] with |from + t * d - other| = contact distance
+            let (fx, fy) = (from.x - other.position.x, from.y - other.position.y);
+            let a = dx.mul_add(dx, dy * dy);
+            let b = 2.0 * fx.mul_add(dx, fy * dy);
+            let c = start_distance - contact_squared;
+            let discriminant = b.mul_add(b, -4.0 * a * c).max(0.0);
+            let t = ((-b - discriminant.sqrt()) / (2.0 * a)).clamp(0.0, 1.0);
+            stop = stop.min(t);
+        }
+        Position::new(dx.mul_add(stop, from.x), dy.mul_add(stop, from.y))
+    }
+    /// Stages a position update to be broadcast on the next simulation tick.
+    /// It replaces an update already staged for the same client unless that one has a
+    /// newer sequence number, so a reordered datagram doesn't move the player back.
+    pub fn stage_position_update(&mut self, update: PositionGamePacket) {
+        match self.pending_position_updates.get(&update.client_id) {
+            // Reordered on the way, the staged update is the player's latest
+            Some(staged) if SeqNum(staged.seq_num).is_newer_than(SeqNum(update.seq_num)) => {}
+            _ => {
+                self.pending_position_updates
+                    .insert(update.client_id.clone(), update);
+            }
+        }
+    }
+    /// Moves `player_id` back to the spawn point, offset by up to `jitter` on each axis.
+    /// Returns `None`, leaving the player in place, if the player is unknown or
+    /// respawned less than `cooldown` ago. A position update staged before the
+    /// respawn is discarded so the next tick doesn't undo it.
+    pub fn respawn(
+        &mut self,
+        player_id: &str,
+        cooldown: Duration,
+        jitter: f32,
+    ) -> Option<Position> {
+        let now = self.now();
+        let offset = if jitter > 0.0 {
+            let mut rng = rand::thread_rng();
+            Position::new(
+                f32_to_coord(rng.gen_range(-jitter..=jitter)),
+                f32_to_coord(rng.gen_range(-jitter..=jitter)),
+            )
+        } else {
+            Position::new(0.0, 0.0)
+        };
+        let position = self.clamp_position(&Position::new(
+            self.spawn.x + offset.x,
+            self.spawn.y + offset.y,
+        ));
+        let player = self.get_player_by_id_mut(player_id)?;
+        if player
+            .last_respawn
+            .is_some_and(|last| now.duration_since(last) < cooldown)
+        {
+            return None;
+        }
+        player.last_respawn = Some(now);
+        player.position = position.clone();
+        self.pending_position_updates.remove(player_id.as_bytes());
+        Some(position)
+    }
+    /// Removes and returns every position update staged since the last tick.
+    pub fn take_pending_position_updates(&mut self) -> Vec<PositionGamePacket> {
+        self.pending_position_updates
+            .drain()
+            .map(|(_, update)| update)
+            .collect()
+    }
+    /// Registers a spectator at `address`, or refreshes its heartbeat if already registered.
+    pub fn add_spectator(&mut self, address: String) {
+        let now = self.now();
+        self.spectators
+            .entry(address)
+            .and_modify(|spectator| spectator.heartbeat = now)
+            .or_insert(Spectator {
+                heartbeat: now,
+                outbound_seq: 0,
+            });
+        self.joined.notify_waiters();
+    }
+    /// Whether there is nothing to maintain: no players, no spectators and no handshake,
+    /// reconnect or unknown heartbeat state left to expire.
+    #[must_use]
+    pub fn is_idle(&self) -> bool {
+        self.players.is_empty()
+            && self.spectators.is_empty()
+            && self.pending_challenges.is_empty()
+            && self.reconnect_tokens.is_empty()
+            && self.unknown_heartbeats.is_empty()
+    }
+    /// Drops per-address state clients left behind: challenges unanswered for longer
+    /// than [`GameState::pending_entry_ttl`] and reconnect tokens of players that are
+    /// gone. Returns how many entries were dropped, also counted in the metrics.
+    ///
+    /// Unknown heartbeat reports older than [`GameState::unknown_heartbeat_window`] are
+    /// forgotten too, without being counted.
+    pub fn expire_stale_entries(&mut self) -> usize {
+        if !self.unknown_heartbeats.is_empty() {
+            let now = self.now();
+            let window = self.unknown_heartbeat_window;
+            self.unknown_heartbeats
+                .retain(|_, reported| now.duration_since(*reported) < window);
+        }
+        let before = self
+            .pending_challenges
+            .len()
+            .saturating_add(self.reconnect_tokens.len());
+        if !self.pending_challenges.is_empty() {
+            let now = self.now();
+            let ttl = self.pending_entry_ttl;
+            self.pending_challenges
+                .retain(|_, (_, issued)| now.duration_since(*issued) <= ttl);
+        }
+        let players = &self.players;
+        self.reconnect_tokens
+            .retain(|_, player_id| players.contains_key(player_id));
+        let expired = before.saturating_sub(
+            self.pending_challenges
+                .len()
+                .saturating_add(self.reconnect_tokens.len()),
+        );
+        if expired > 0 {
+            tracing::debug!("Expired {} stale challenges and reconnect tokens", expired);
+            self.metrics.record_expired_entries(expired);
+        }
+        expired
+    }
+    /// Adds an entity of `kind` at `position` in `room`, clamped to the world bounds, and
+    /// returns its id. Entities are visible to the players in their room and to spectators.
+    pub fn spawn_entity(
+        &mut self,
+        room: &str,
+        kind: EntityKind,
+        position: &Position,
+        owner: Option<PlayerId>,
+    ) -> EntityId {
+        let id = self.next_entity_id;
+        self.next_entity_id = id.wrapping_add(1).max(1);
+        let position = self.clamp_position(position);
+        self.entities.insert(
+            id,
+            Entity {
+                position,
+                kind,
+                owner,
+                room: room.to_string(),
+            },
+        );
+        id
+    }
+    /// Moves entity `id` to `position`, clamped to the world bounds.
+    /// Returns `false` if there is no such entity.
+    pub fn move_entity(&mut self, id: EntityId, position: &Position) -> bool {
+        let position = self.clamp_position(position);
+        match self.entities.get_mut(&id) {
+            Some(entity) => {
+                entity.position = position;
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn despawn_entity(&mut self, id: EntityId) -> Option<Entity> {
+        self.entities.remove(&id)
+    }
+    #[must_use]
+    pub fn get_entity(&self, id: EntityId) -> Option<&Entity> {
+        self.entities.get(&id)
+    }
+    /// Returns `false` if `address` wasn't spectating.
+    pub fn remove_spectator(&mut self, address: &str) -> bool {
+        self.spectators.remove(address).is_some()
+    }
+    #[must_use]
+    pub fn is_spectator(&self, address: &str) -> bool {
+        self.spectators.contains_key(address)
+    }
+    /// Refreshes the heartbeat of the spectator at `address`.
+    /// Returns `false` if `address` isn't spectating.
+    pub fn touch_spectator(&mut self, address: &str) -> bool {
+        let now = self.now();
+        let Some(spectator) = self.spectators.get_mut(address) else {
+            return false;
+        };
+        spectator.heartbeat = now;
+        true
+    }
+    /// Address of every spectator paired with the sequence number for the next packet to it.
+    pub fn spectator_recipients(&mut self) -> Vec<(String, u32)> {
+        self.spectators
+            .iter_mut()
+            .map(|(addr, spectator)| (addr.clone(), spectator.next_outbound_seq()))
+            .collect()
+    }
+    /// Appends `chat` sent in `room` to the history, dropping the oldest messages of
+    /// that room beyond `capacity`.
+    pub fn record_chat(&mut self, room: &str, chat: ChatPacket, capacity: usize) {
+        self.chat_history.push_back((room.to_string(), chat));
+        while self.chat_history_of(room).count() > capacity {
+            let Some(oldest) = self.chat_history.iter().position(|(r, _)| r == room) else {
+                break;
+            };
+            self.chat_history.remove(oldest);
+        }
+    }
+    /// Recorded chat messages sent in `room`, oldest first.
+    pub fn chat_history_of<'a>(&'a self, room: &'a str) -> impl Iterator<Item = &'a ChatPacket> {
+        self.chat_history
+            .iter()
+            .filter(move |(r, _)| r == room)
+            .map(|(_, chat)| chat)
+    }
+    /// Sets `key` on `player_id`'s metadata. Returns `false`, leaving the metadata
+    /// unchanged, if the player is unknown or the result would exceed
+    /// [`MAX_PLAYER_METADATA_BYTES`].
+    pub fn set_metadata(&mut self, player_id: &str, key: String, value: Vec<u8>) -> bool {
+        let Some(player) = self.get_player_by_id_mut(player_id) else {
+            return false;
+        };
+        let replaced = player
+            .metadata
+            .get(&key)
+            .map_or(0, |old| key.len().saturating_add(old.len()));
+        let size = player
+            .metadata_size()
+            .saturating_sub(replaced)
+            .saturating_add(key.len())
+            .saturating_add(value.len());
+        if size > MAX_PLAYER_METADATA_BYTES {
+            return false;
+        }
+        if key == TEAM_METADATA_KEY {
+            player.team = team_from_metadata(&value);
+        }
+        player.metadata.insert(key, value);
+        true
+    }
+    #[must_use]
+    pub fn get_metadata(&self, player_id: &str, key: &str) -> Option<&[u8]> {
+        self.get_player_by_id(player_id)?
+            .metadata
+            .get(key)
+            .map(Vec::as_slice)
+    }
+    /// Generates a new reconnect token for `player_id`.
+    pub fn issue_reconnect_token(&mut self, player_id: &str) -> ReconnectToken {
+        let token = rand::random::<ReconnectToken>();
+        self.reconnect_tokens.insert(token, player_id.to_string());
+        token
+    }
+    /// Whether a heartbeat from `address`, which has no player or spectator, should be
+    /// reported: `true` unless the last report for it is less than
+    /// [`GameState::unknown_heartbeat_window`] old, in which case it is suppressed.
+    pub fn report_unknown_heartbeat(&mut self, address: &str) -> bool {
+        let now = self.now();
+        if self
+            .unknown_heartbeats
+            .get(address)
+            .is_some_and(|reported| now.duration_since(*reported) < self.unknown_heartbeat_window)
+        {
+            return false;
+        }
+        self.unknown_heartbeats.insert(address.to_string(), now);
+        true
+    }
+    /// Generates a handshake challenge for `address`, replacing any earlier one.
+    pub fn issue_challenge(&mut self, address: String) -> ChallengeNonce {
+        let nonce = rand::random::<ChallengeNonce>();
+        let now = self.now();
+        self.pending_challenges.insert(address, (nonce, now));
+        nonce
+    }
+    /// Consumes the challenge issued to `address` and returns whether `nonce` answers it
+    /// within [`CHALLENGE_TIMEOUT_SECS`]. A wrong answer leaves the challenge in place.
+    pub fn answer_challenge(&mut self, address: &str, nonce: &ChallengeNonce) -> bool {
+        let Some((expected, issued)) = self.pending_challenges.get(address) else {
+            return false;
+        };
+        if expected != nonce {
+            return false;
+        }
+        let fresh =
+            self.now().duration_since(*issued) <= Duration::from_secs(CHALLENGE_TIMEOUT_SECS);
+        self.pending_challenges.remove(address);
+        fresh
+    }
+    /// Moves the player owning `token` to `address`, keeping its id and position.
+    /// Returns the player, or `None` if the token is unknown or its player is gone.
+    ///
+    /// Another player still bound to `address` is removed, and its room told it left.
+    pub async fn reconnect(
+        &mut self,
+        token: &ReconnectToken,
+        address: String,
+        socket: &Arc<UdpSocket>,
+    ) -> Option<&Player> {
+        let player_id = self.reconnect_tokens.get(token)?.clone();
+        if !self.players.contains_key(&player_id) {
+            return None;
+        }
+        if let Some(previous_id) = self
+            .addr_to_id
+            .get(&address)
+            .filter(|id| **id != player_id)
+            .cloned()
+        {
+            tracing::info!(
+                "Player {} reconnected from {}, dropping player {}",
+                player_id,
+                address,
+                previous_id
+            );
+            self.remove_player_and_notify(&previous_id, socket).await;
+        }
+        self.unbind_id(&player_id);
+        self.bind_addr(address, player_id.clone());
+        let now = self.now();
+        let player = self.players.get_mut(&player_id)?;
+        player.heartbeat = now;
+        player.last_activity = Some(now);
+        Some(player)
+    }
+    /// Starts a liveness probe of `player_id`: advances its outbound sequence
+    /// number and returns it as the `Ping` to expect an answer to.
+    pub fn start_probe(&mut self, player_id: &str) -> Option<u32> {
+        let player = self.get_player_by_id_mut(player_id)?;
+        let seq = player.next_outbound_seq();
+        player.pending_probe = Some(seq);
+        Some(seq)
+    }
+    /// Clears the probe state of the player at `address` if `seq` answers its
+    /// outstanding `Ping`. Returns whether it did.
+    pub fn answer_probe(&mut self, address: &str, seq: u32) -> bool {
+        let Some(player) = self.get_player_by_addr_mut(address) else {
+            return false;
+        };
+        if player.pending_probe != Some(seq) {
+            return false;
+        }
+        player.pending_probe = None;
+        player.missed_probes = 0;
+        true
+    }
+    /// Counts the outstanding probe of every player as missed and returns the players
+    /// that have now missed `max_missed` in a row.
+    pub fn expire_probes(&mut self, max_missed: u32) -> Vec<PlayerId> {
+        let mut unresponsive = Vec::new();
+        for player in self.players.values_mut() {
+            if player.pending_probe.take().is_some() {
+                player.missed_probes = player.missed_probes.saturating_add(1);
+                if player.missed_probes >= max_missed {
+                    unresponsive.push(player.id.clone());
+                }
+            }
+        }
+        unresponsive
+    }
+    /// Records a failed send to `player_id` and returns its consecutive failure count.
+    pub fn record_send_failure(&mut self, player_id: &str) -> u32 {
+        let Some(player) = self.get_player_by_id_mut(player_id) else {
+            return 0;
+        };
+        player.send_failures = player.send_failures.saturating_add(1);
+        player.send_failures
+    }
+    /// Clears the send failure count of the player at `address` after hearing from it,
+    /// and marks it active, see [`Player::last_activity`].
+    pub fn record_receive(&mut self, address: &str) {
+        let now = self.now();
+        if let Some(player) = self.get_player_by_addr_mut(address) {
+            player.send_failures = 0;
+            player.last_activity = Some(now);
+        }
+    }
+    /// Removes every player whose consecutive send failures reached `max_send_failures`
+    /// and tells the remaining players they left. Returns the removed ids.
+    pub async fn remove_unreachable_players(
+        &mut self,
+        max_send_failures: u32,
+        socket: &Arc<UdpSocket>,
+    ) -> Vec<PlayerId> {
+        let unreachable = self
+            .players
+            .values()
+            .filter(|player| player.send_failures >= max_send_failures)
+            .map(|player| player.id.clone())
+            .collect::<Vec<_>>();
+        let mut removed = Vec::new();
+        for player_id in &unreachable {
+            tracing::warn!("Removing unreachable player {player_id}");
+            removed.extend(self.remove_player(player_id));
+        }
+        for player in &removed {
+            self.broadcast_player_left(&player.id, &BroadcastScope::everyone(), socket)
+                .await;
+        }
+        unreachable
+    }
+    /// Sends `player_id` a `PlayerLeft` with its own id and `reason`, confirming its
+    /// departure. Call it before removing the player, whose address is looked up.
+    ///
+    /// # Errors
+    /// Returns the error of the underlying send.
+    pub async fn confirm_departure(
+        &mut self,
+        player_id: &str,
+        reason: LeaveReason,
+        socket: &UdpSocket,
+    ) -> std::io::Result<()> {
+        let Some(addr) = self.addr_for_id(player_id) else {
+            return Ok(());
+        };
+        let packet = GamePacket::new(
+            MessageType::PlayerLeft,
+            self.next_outbound_seq(player_id),
+            PlayerLeft::new(player_id.to_string())
+                .with_reason(reason)
+                .serialize(),
+            player_id.as_bytes().to_vec(),
+        );
+        self.send_datagram(socket, &packet.serialize(), addr)
+            .await
+            .map(drop)
+    }
+    /// Removes `player_id` and tells the remaining players and the spectators it left.
+    /// Returns `false` if there was no such player. Failed sends are logged and counted
+    /// against the recipient.
+    pub async fn remove_player_and_notify(
+        &mut self,
+        player_id: &str,
+        socket: &Arc<UdpSocket>,
+    ) -> bool {
+        if self.remove_player(player_id).is_none() {
+            return false;
+        }
+        self.broadcast_player_left(player_id, &BroadcastScope::everyone(), socket)
+            .await;
+        true
+    }
+    /// Tells the players and spectators `scope` covers that `left_id` left. Returns how
+    /// many sends succeeded.
+    pub(crate) async fn broadcast_player_left(
+        &mut self,
+        left_id: &str,
+        scope: &BroadcastScope,
+        socket: &UdpSocket,
+    ) -> usize {
+        let payload = PlayerLeft::new(left_id.to_string()).serialize();
+        self.broadcast(socket, scope, MessageType::PlayerLeft, |_, _| {
+            vec![payload.clone()]
+        })
+        .await
+    }
+    /// Sends `player_id`'s position right away, outside the simulation tick, to every
+    /// player, itself included, and to every spectator. Failed sends are logged and
+    /// counted against the recipient.
+    pub(crate) async fn broadcast_position(&mut self, player_id: &str, socket: &UdpSocket) {
+        self.send_position(player_id, socket, &BroadcastScope::everyone())
+            .await;
+    }
+    /// Like [`GameState::broadcast_position`], but only to `player_id`'s teammates, see
+    /// [`BroadcastScope::Team`], and not to spectators.
+    pub async fn broadcast_position_to_team(&mut self, player_id: &str, socket: &UdpSocket) {
+        let Some(player) = self.players.get(player_id) else {
+            return;
+        };
+        let scope = BroadcastScope::Team(player.team);
+        self.send_position(player_id, socket, &scope).await;
+    }
+    async fn send_position(&mut self, player_id: &str, socket: &UdpSocket, scope: &BroadcastScope) {
+        let Some(player) = self.players.get(player_id) else {
+            return;
+        };
+        let record = PlayerPosition::new(player_id.as_bytes().to_vec(), player.position.clone());
+        self.broadcast(
+            socket,
+            scope,
+            MessageType::PositionUpdate,
+            |state, recipient| {
+                if recipient.is_some_and(|id| state.uses_f64_positions(id)) {
+                    vec![record.serialize_f64()]
+                } else {
+                    vec![record.serialize()]
+                }
+            },
+        )
+        .await;
+    }
+    /// Sends an event of an entity to every player and spectator. Failed sends are
+    /// logged and counted against the recipient.
+    pub(crate) async fn broadcast_entity_event(
+        &mut self,
+        msg_type: MessageType,
+        payload: &[u8],
+        socket: &UdpSocket,
+    ) {
+        self.broadcast(socket, &BroadcastScope::everyone(), msg_type, |_, _| {
+            vec![payload.to_vec()]
+        })
+        .await;
+    }
+    /// Recomputes which players of the same room are within `radius` of each other and returns who
+    /// entered or left each player's view since the previous call.
+    pub fn update_interest(&mut self, radius: f32) -> Vec<InterestEvent> {
+        let radius_squared = radius * radius;
+        let mut events = Vec::new();
+        let mut interest = HashMap::with_capacity(self.players.len());
+        for observer in self.players.values() {
+            let visible = self
+                .players
+                .values()
+                .filter(|other| {
+                    other.id != observer.id
+                        && other.room == observer.room
+                        && observer.position.distance_squared(&other.position) <= radius_squared
+                })
+                .map(|other| other.id.clone())
+                .collect::<HashSet<_>>();
+            let previous = self.interest.get(&observer.id);
+            for target in &visible {
+                if !previous.is_some_and(|previous| previous.contains(target)) {
+                    events.push(InterestEvent::Enter {
+                        observer: observer.id.clone(),
+                        target: target.clone(),
+                    });
+                }
+            }
+            for target in previous.into_iter().flat_map(|p| p.difference(&visible)) {
+                events.push(InterestEvent::Exit {
+                    observer: observer.id.clone(),
+                    target: target.clone(),
+                });
+            }
+            interest.insert(observer.id.clone(), visible);
+        }
+        self.interest = interest;
+        events
+    }
+    /// Whether `target` was within `observer`'s interest radius at the last update.
+    #[must_use]
+    pub fn is_in_interest(&self, observer: &str, target: &str) -> bool {
+        self.interest
+            .get(observer)
+            .is_some_and(|visible| visible.contains(target))
+    }
+    /// Per-tick hook run by the simulation loop before broadcasting staged updates.
+    pub fn advance_tick(&mut self) {
+        self.tick = self.tick.wrapping_add(1);
+    }
     /// Cleans up inactive players by removing them from the game state.
     /// This method should be called periodically to ensure that players who have disconnected are removed.
     /// The cleanup interval is defined by the `CLEANUP_INTERVAL_SECS` constant.
     /// # Arguments
     /// * `socket` - A reference to the UDP socket used to send messages to clients
-    /// # Errors
-    /// This method returns an error if there is a problem sending a message to a client.
-    pub async fn cleanup_inactive_players(
-        &mut self,
-        socket: &Arc<UdpSocket>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let now = Instant::now();
+    pub async fn cleanup_inactive_players(&mut self, socket: &Arc<UdpSocket>) {
+        let now = self.now();
 
-        // Find inactive players
-        let inactive_players: Vec<(String, Player)> = self
+        // Drop inactive players and spectators first so they aren't notified about
+        // each other's departure.
+        let timeout = Duration::from_secs(PLAYER_TIMEOUT_SECS);
+        let inactive_players: Vec<PlayerId> = self
             .players
-            .iter()
-            .filter(|(_, player)| {
-                now.duration_since(player.heartbeat) > Duration::from_secs(PLAYER_TIMEOUT_SECS)
+            .values()
+            .filter(|player| {
+                player.is_timed_out(now, timeout)
+                    || self.connect_grace_period.is_some_and(|grace| {
+                        player.is_silent_since_connect(now, grace)
+                            && !self.avatar_owners.contains_key(&player.id)
+                    })
             })
-            .map(|(addr, player)| (addr.clone(), player.clone()))
+            .map(|player| player.id.clone())
             .collect();
+        let removed = inactive_players
+            .iter()
+            .filter_map(|player_id| self.remove_player(player_id))
+            .collect::<Vec<_>>();
+        self.spectators
+            .retain(|_, spectator| !spectator.is_timed_out(now, timeout));
 
-        // Notify others about players leaving
-        for (addr, player) in inactive_players {
-            let player_left_payload = PlayerLeft::new(player.id);
-
-            for (target_addr, p) in &self.players {
-                if target_addr != &addr {
-                    let packet = GamePacket::new(
-                        MessageType::PlayerLeft,
-                        0,
-                        player_left_payload.serialize(),
-                        p.id.as_bytes().to_vec(),
-                    );
-                    socket.send_to(&packet.serialize(), target_addr).await?;
-                }
-            }
+        // Notify the survivors
+        for player in &removed {
+            self.broadcast_player_left(&player.id, &BroadcastScope::everyone(), socket)
+                .await;
         }
-
-        // Remove inactive players
-        self.players.retain(|_, player| {
-            now.duration_since(player.heartbeat) <= Duration::from_secs(PLAYER_TIMEOUT_SECS)
-        });
-
-        Ok(())
     }
 }
+/// Result of [`GameState::add_player`].
 #[derive(Debug, Clone)]
+pub enum AddPlayerOutcome {
+    /// No player was bound to the address.
+    Inserted,
+    /// The address belonged to this other player, which has been removed.
+    Replaced(Box<Player>),
+}
 
+/// A change in which players another player can see, see [`GameState::update_interest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterestEvent {
+    Enter {
+        observer: PlayerId,
+        target: PlayerId,
+    },
+    Exit {
+        observer: PlayerId,
+        target: PlayerId,
+    },
+}
+
+/// What [`GameState::apply_position_batch`] made of a batch.
+#[derive(Debug, Default)]
+pub struct AppliedPositions {
+    /// Players whose position changed, with their new position, in batch order.
+    pub changed: Vec<PlayerPosition>,
+    /// Indexes of the updates outside the world, applied clamped to it.
+    pub clamped: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Player {
     pub id: String,
     pub seq_num: u32,
     pub position: Position,
-    pub heartbeat: Instant,
+    /// When the player was last heard from.
+    pub heartbeat: Timestamp,
+    /// Consecutive failed sends to this player, reset whenever a packet is received from it.
+    pub send_failures: u32,
+    /// Sequence number of the last server-originated packet sent to this player.
+    pub outbound_seq: u32,
+    /// Game specific attributes such as a name, opaque to the server except for
+    /// [`TEAM_METADATA_KEY`].
+    pub metadata: HashMap<String, Vec<u8>>,
+    /// When the player last respawned, used to enforce the respawn cooldown.
+    pub last_respawn: Option<Timestamp>,
+    /// Sequence number of the liveness `Ping` awaiting a `Pong`, if any.
+    pub pending_probe: Option<u32>,
+    /// Consecutive liveness probes left unanswered. A player with any is suspect.
+    pub missed_probes: u32,
+    /// Room the player plays in, chosen on connect.
+    pub room: RoomId,
+    /// Display name chosen on connect, if any.
+    pub name: Option<String>,
+    /// Optional features negotiated on connect, consulted by [`GameState::encode_for`].
+    pub features: Features,
+    /// Key of an encrypted session, set once the key exchange completed. Datagrams to
+    /// and from the player are then sealed, see [`GameState::send_datagram`].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub session_key: Option<SessionKey>,
+    /// Sequence numbers already received in the encrypted session, replays are dropped.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub replay_window: ReplayWindow,
+    /// Recent accepted positions, see [`GameState::position_at`].
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub position_history: PositionHistory,
+    /// When the player last sent anything after its `ConnectionInit`, `None` until it
+    /// does. See [`GameState::connect_grace_period`].
+    pub last_activity: Option<Timestamp>,
+    /// Team the player plays for, set through the [`TEAM_METADATA_KEY`] metadata. Team
+    /// chat and team position broadcasts only reach players of the same team, `None`
+    /// being a team of its own.
+    pub team: Option<u8>,
+    /// When the player's `ConnectionInit` was accepted, see
+    /// [`GameState::players_by_join_time`].
+    pub connected_at: Timestamp,
 }
 
+impl Player {
+    /// Advances and returns the outbound sequence number, wrapping on overflow.
+    pub fn next_outbound_seq(&mut self) -> u32 {
+        self.outbound_seq = SeqNum(self.outbound_seq).next().into();
+        self.outbound_seq
+    }
+    /// Whether the player was last heard from more than `timeout` before `now`.
+    /// A heartbeat exactly `timeout` old still counts.
+    #[must_use]
+    pub fn is_timed_out(&self, now: Timestamp, timeout: Duration) -> bool {
+        now.duration_since(self.heartbeat) > timeout
+    }
+    /// Whether the player sent nothing since its `ConnectionInit`, which was more than
+    /// `grace` before `now`. Until it sends something, its heartbeat is when it connected.
+    #[must_use]
+    pub fn is_silent_since_connect(&self, now: Timestamp, grace: Duration) -> bool {
+        self.last_activity.is_none() && now.duration_since(self.heartbeat) > grace
+    }
+    /// Summed length of every metadata key and value.
+    #[must_use]
+    pub fn metadata_size(&self) -> usize {
+        self.metadata
+            .iter()
+            .map(|(key, value)| key.len().saturating_add(value.len()))
+            .fold(0, usize::saturating_add)
+    }
+}
+
+/// A connection receiving position, join and leave broadcasts without being a player.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Spectator {
+    /// When the spectator was last heard from.
+    pub heartbeat: Timestamp,
+    /// Sequence number of the last packet sent to this spectator.
+    pub outbound_seq: u32,
+}
+
+impl Spectator {
+    /// Advances and returns the outbound sequence number, wrapping on overflow.
+    pub fn next_outbound_seq(&mut self) -> u32 {
+        self.outbound_seq = SeqNum(self.outbound_seq).next().into();
+        self.outbound_seq
+    }
+    /// Whether the spectator was last heard from more than `timeout` before `now`.
+    #[must_use]
+    pub fn is_timed_out(&self, now: Timestamp, timeout: Duration) -> bool {
+        now.duration_since(self.heartbeat) > timeout
+    }
+}
+
+/// A server tracked object that isn't a player, such as a pickup or a projectile.
+/// Kept apart from players, it never times out and is only moved by the embedder.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Entity {
+    pub position: Position,
+    pub kind: EntityKind,
+    /// Player the entity belongs to, e.g. who fired a projectile. Informational, the
+    /// entity outlives its owner.
+    pub owner: Option<PlayerId>,
+    /// Room the entity is in, only its players are sent the entity's events.
+    pub room: RoomId,
+}
+
+/// Type of a position coordinate: `f64` with the `f64-positions` feature, for maps large
+/// enough that `f32` coordinates drift by whole units, `f32` otherwise.
+#[cfg(feature = "f64-positions")]
+pub type Coord = f64;
+/// Type of a position coordinate: `f64` with the `f64-positions` feature, for maps large
+/// enough that `f32` coordinates drift by whole units, `f32` otherwise.
+#[cfg(not(feature = "f64-positions"))]
+pub type Coord = f32;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Position {
-    pub x: f32,
-    pub y: f32,
+    pub x: Coord,
+    pub y: Coord,
 }
 impl Position {
     #[must_use]
-    pub fn new(x: f32, y: f32) -> Self {
+    pub fn new(x: Coord, y: Coord) -> Self {
         Position { x, y }
     }
+    /// The 8 byte format, `x` and `y` as `f32`s.
     #[must_use]
     pub fn serialize(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(8);
-        buf.extend_from_slice(&self.x.to_be_bytes());
-        buf.extend_from_slice(&self.y.to_be_bytes());
+        buf.extend_from_slice(&coord_to_f32(self.x).to_be_bytes());
+        buf.extend_from_slice(&coord_to_f32(self.y).to_be_bytes());
         buf
     }
     #[must_use]
@@ -182,6 +1676,916 @@ impl Position {
         }
         let x = f32::from_be_bytes([data[3], data[2], data[1], data[0]]);
         let y = f32::from_be_bytes([data[7], data[6], data[5], data[4]]);
-        Some(Position { x, y })
+        Some(Position::new(f32_to_coord(x), f32_to_coord(y)))
+    }
+    /// The 16 byte format of players that negotiated `Features::F64_POSITIONS`, big
+    /// endian `x` and `y` as `f64`s.
+    #[must_use]
+    pub fn serialize_f64(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(POSITION_F64_SIZE);
+        buf.extend_from_slice(&coord_to_f64(self.x).to_be_bytes());
+        buf.extend_from_slice(&coord_to_f64(self.y).to_be_bytes());
+        buf
+    }
+    /// Reads the format of [`Position::serialize_f64`].
+    #[must_use]
+    pub fn deserialize_f64(data: &[u8]) -> Option<Position> {
+        let (x, rest) = data.split_first_chunk::<8>()?;
+        let y = rest.first_chunk::<8>()?;
+        Some(Position::new(
+            f64_to_coord(f64::from_be_bytes(*x)),
+            f64_to_coord(f64::from_be_bytes(*y)),
+        ))
+    }
+    /// Euclidean distance to `other`.
+    ///
+    /// Computed in `f64` so far apart points don't overflow while squaring;
+    /// a distance beyond `f32::MAX` saturates to infinity.
+    #[must_use]
+    pub fn distance(&self, other: &Position) -> f32 {
+        let (dx, dy) = self.delta_f64(other);
+        f64_to_f32(dx.hypot(dy))
+    }
+    /// Squared distance to `other`, cheaper than [`Position::distance`] for comparisons.
+    ///
+    /// Saturates to infinity instead of overflowing.
+    #[must_use]
+    pub fn distance_squared(&self, other: &Position) -> f32 {
+        let (dx, dy) = self.delta_f64(other);
+        f64_to_f32(dx.mul_add(dx, dy * dy))
+    }
+    fn delta_f64(&self, other: &Position) -> (f64, f64) {
+        (
+            coord_to_f64(self.x) - coord_to_f64(other.x),
+            coord_to_f64(self.y) - coord_to_f64(other.y),
+        )
+    }
+    /// Point a fraction `t` of the way from `self` to `other`. `t` is clamped to
+    /// `[0, 1]` and a NaN `t` is treated as 0, so the endpoints are returned exactly.
+    #[must_use]
+    pub fn lerp(&self, other: &Position, t: f32) -> Position {
+        let t = f32_to_coord(if t.is_nan() { 0.0 } else { t.clamp(0.0, 1.0) });
+        Position::new(
+            self.x.mul_add(1.0 - t, other.x * t),
+            self.y.mul_add(1.0 - t, other.y * t),
+        )
+    }
+}
+
+impl Add for Position {
+    type Output = Position;
+    fn add(self, other: Position) -> Position {
+        Position::new(self.x + other.x, self.y + other.y)
+    }
+}
+impl Add<&Position> for &Position {
+    type Output = Position;
+    fn add(self, other: &Position) -> Position {
+        Position::new(self.x + other.x, self.y + other.y)
+    }
+}
+impl Sub for Position {
+    type Output = Position;
+    fn sub(self, other: Position) -> Position {
+        Position::new(self.x - other.x, self.y - other.y)
+    }
+}
+impl Sub<&Position> for &Position {
+    type Output = Position;
+    fn sub(self, other: &Position) -> Position {
+        Position::new(self.x - other.x, self.y - other.y)
+    }
+}
+impl Mul<f32> for Position {
+    type Output = Position;
+    fn mul(self, scale: f32) -> Position {
+        let scale = f32_to_coord(scale);
+        Position::new(self.x * scale, self.y * scale)
+    }
+}
+impl Mul<f32> for &Position {
+    type Output = Position;
+    fn mul(self, scale: f32) -> Position {
+        let scale = f32_to_coord(scale);
+        Position::new(self.x * scale, self.y * scale)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        packet::{connection_init::ConnectionInitPacketSent, HEADER_SIZE},
+        testing::CapturedLogs,
+    };
+
+    fn player(id: &str) -> Player {
+        Player {
+            id: id.to_string(),
+            ..Player::default()
+        }
+    }
+
+    fn assert_close(a: &Position, b: &Position) {
+        assert!(a.distance(b) < 1e-6, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn test_position_arithmetic() {
+        let a = Position::new(1.0, 2.0);
+        let b = Position::new(3.0, -4.0);
+        assert_close(&(&a + &b), &Position::new(4.0, -2.0));
+        assert_close(&(&a - &b), &Position::new(-2.0, 6.0));
+        assert_close(&(&b * 0.5), &Position::new(1.5, -2.0));
+        assert_close(
+            &(a.clone() + b.clone() - b * 2.0),
+            &Position::new(-2.0, 6.0),
+        );
+    }
+
+    #[test]
+    fn test_position_lerp() {
+        let a = Position::new(0.0, 10.0);
+        let b = Position::new(10.0, 20.0);
+        assert_close(&a.lerp(&b, 0.0), &a);
+        assert_close(&a.lerp(&b, 0.5), &Position::new(5.0, 15.0));
+        assert_close(&a.lerp(&b, 1.0), &b);
+        assert_close(&a.lerp(&b, 2.0), &b);
+        assert_close(&a.lerp(&b, -1.0), &a);
+        assert_close(&a.lerp(&b, f32::NAN), &a);
+    }
+
+    #[test]
+    fn test_position_f64_round_trip() {
+        let position = Position::new(1920.5, -1080.25);
+        let data = position.serialize_f64();
+        assert_eq!(data.len(), POSITION_F64_SIZE);
+        assert_eq!(Position::deserialize_f64(&data), Some(position));
+        assert_eq!(
+            Position::deserialize_f64(&data[..POSITION_F64_SIZE - 1]),
+            None
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "f64-positions")]
+    fn test_large_coordinates_survive_only_the_f64_format() {
+        // Past 2^24 an f32 can't hold the fraction, or even every integer
+        let position = Position::new(16_777_217.5, -33_554_433.25);
+        assert_eq!(
+            Position::deserialize_f64(&position.serialize_f64()),
+            Some(position.clone())
+        );
+        // The 8 byte format keeps only the nearest f32
+        let narrowed = Position::new(
+            f32_to_coord(coord_to_f32(position.x)),
+            f32_to_coord(coord_to_f32(position.y)),
+        );
+        assert!(narrowed.distance(&position) >= 0.5);
+    }
+
+    #[test]
+    fn test_with_capacity_does_not_reallocate_up_to_capacity() {
+        let mut state = GameState::with_capacity(800, 600, 64);
+        let capacity = state.players.capacity();
+        assert!(capacity >= 64);
+        assert!(state.addr_to_id.capacity() >= 64);
+        for i in 0..64 {
+            state.add_player(player(&format!("p{i}")), format!("127.0.0.1:{}", 1000 + i));
+        }
+        assert_eq!(state.players.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_add_player_reports_replacement() {
+        let mut state = GameState::default();
+        assert!(matches!(
+            state.add_player(player("a"), "127.0.0.1:1000".to_string()),
+            AddPlayerOutcome::Inserted
+        ));
+        let AddPlayerOutcome::Replaced(previous) =
+            state.add_player(player("b"), "127.0.0.1:1000".to_string())
+        else {
+            panic!("expected a replacement");
+        };
+        assert_eq!(previous.id, "a");
+        assert_eq!(state.get_player_count(), 1);
+        assert!(state.get_player_by_id("a").is_none());
+    }
+
+    #[test]
+    fn test_resizing_a_circle_world_keeps_it_round() {
+        let mut state = GameState::new(100, 100);
+        state.bounds = WorldBounds::Circle {
+            center: Position::new(50.0, 50.0),
+            radius: 50.0,
+        };
+        state.spawn = Position::new(50.0, 50.0);
+        let mut corner = player("a");
+        corner.position = Position::new(90.0, 50.0);
+        state.add_player(corner, "127.0.0.1:1".to_string());
+
+        state.resize(50, 50);
+        assert!(matches!(state.bounds, WorldBounds::Circle { .. }));
+        // Pulled onto the edge of the circle, not into the corner of a square
+        let position = state.get_player_position("a").unwrap().clone();
+        assert!((position.distance(&Position::new(25.0, 25.0)) - 25.0).abs() < 1e-3);
+        assert!(!state.bounds.contains(&Position::new(0.0, 0.0)));
+    }
+
+    #[test]
+    fn test_players_indexed_by_id_and_addr() {
+        let mut state = GameState::default();
+        state.add_player(player("a"), "127.0.0.1:1000".to_string());
+        state.add_player(player("b"), "127.0.0.1:2000".to_string());
+
+        assert_eq!(state.get_player_by_id("a").unwrap().id, "a");
+        assert_eq!(state.get_player_by_addr("127.0.0.1:2000").unwrap().id, "b");
+        assert!(state.get_player_by_addr("127.0.0.1:3000").is_none());
+
+        state.remove_player("a");
+        assert!(state.get_player_by_id("a").is_none());
+        assert!(state.get_player_by_addr("127.0.0.1:1000").is_none());
+        assert_eq!(state.addr_to_id.len(), 1);
+        assert_eq!(state.id_to_addr.len(), 1);
+
+        // A player moving address leaves nothing behind, one replaced at its address
+        // drops out of the reverse index
+        state.add_player(player("b"), "127.0.0.1:3000".to_string());
+        state.add_player(player("c"), "127.0.0.1:3000".to_string());
+        assert_eq!(state.addr_to_id.len(), 1);
+        assert_eq!(
+            state.id_to_addr.keys().collect::<Vec<_>>(),
+            vec![&"c".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_players_counted_per_ip() {
+        let mut state = GameState::default();
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        state.add_player(player("a"), "127.0.0.1:1000".to_string());
+        state.add_player(player("b"), "127.0.0.1:2000".to_string());
+        state.add_player(player("c"), "127.0.0.1:2000".to_string());
+        state.add_player(player("d"), "127.0.0.2:1000".to_string());
+        assert_eq!(state.players_from_ip(ip), 2);
+        assert_eq!(state.players_from_ip(IpAddr::from([127, 0, 0, 2])), 1);
+
+        state.add_player(player("a"), "127.0.0.2:2000".to_string());
+        assert_eq!(state.players_from_ip(ip), 1);
+        state.remove_player("c");
+        assert_eq!(state.players_from_ip(ip), 0);
+        assert!(!state.players_per_ip.contains_key(&ip));
+    }
+
+    #[test]
+    fn test_addr_for_id() {
+        let mut state = GameState::default();
+        state.add_player(player("a"), "127.0.0.1:1000".to_string());
+        state.add_player(player("b"), "127.0.0.1:2000".to_string());
+
+        assert_eq!(
+            state.addr_for_id("b"),
+            Some(SocketAddr::from(([127, 0, 0, 1], 2000)))
+        );
+        assert_eq!(state.addr_for_id("c"), None);
+    }
+
+    #[test]
+    fn test_add_player_replaces_player_at_same_addr() {
+        let mut state = GameState::default();
+        state.add_player(player("a"), "127.0.0.1:1000".to_string());
+        state.add_player(player("b"), "127.0.0.1:1000".to_string());
+
+        assert_eq!(state.get_player_count(), 1);
+        assert_eq!(state.get_player_by_addr("127.0.0.1:1000").unwrap().id, "b");
+        assert!(state.get_player_by_id("a").is_none());
+    }
+
+    #[test]
+    fn test_distance_zero() {
+        let p = Position::new(12.5, -3.0);
+        assert!(p.distance(&p).abs() < f32::EPSILON);
+        assert!(p.distance_squared(&p).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn test_distance_three_four_five() {
+        let a = Position::new(1.0, 1.0);
+        let b = Position::new(4.0, 5.0);
+        assert!((a.distance(&b) - 5.0).abs() < f32::EPSILON);
+        assert!((a.distance_squared(&b) - 25.0).abs() < f32::EPSILON);
+        assert!((b.distance(&a) - 5.0).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn test_distance_saturates_instead_of_overflowing() {
+        let a = Position::new(f32_to_coord(f32::MAX), f32_to_coord(f32::MAX));
+        let b = Position::new(f32_to_coord(-f32::MAX), f32_to_coord(-f32::MAX));
+        assert!(a.distance(&b).is_infinite());
+        assert!(a.distance_squared(&b).is_infinite());
+
+        // Large but representable distances stay finite
+        let c = Position::new(f32_to_coord(f32::MAX / 2.0), 0.0);
+        let d = Position::new(0.0, 0.0);
+        assert!(c.distance(&d).is_finite());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_game_state_json_round_trip() {
+        let mut state = GameState::new(800, 600);
+        let mut a = player("a");
+        a.position = Position::new(1.0, 2.0);
+        state.add_player(a, "127.0.0.1:1000".to_string());
+        state.add_player(player("b"), "127.0.0.1:2000".to_string());
+
+        let json = serde_json::to_string(&state).unwrap();
+        let decoded: GameState = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(decoded.get_width(), 800);
+        assert_eq!(decoded.get_height(), 600);
+        assert_eq!(decoded.get_player_count(), 2);
+        let a = decoded.get_player_by_addr("127.0.0.1:1000").unwrap();
+        assert_eq!(a.id, "a");
+        assert!((a.position.x - 1.0).abs() < Coord::EPSILON);
+        assert!((a.position.y - 2.0).abs() < Coord::EPSILON);
+        assert_eq!(
+            decoded.get_player_by_addr("127.0.0.1:2000").unwrap().id,
+            "b"
+        );
+    }
+
+    #[test]
+    fn test_generated_ids_are_player_id_len_bytes() {
+        use crate::packet::{connection_init::parse_player_id, position::POSITION_RECORD_SIZE};
+
+        for _ in 0..100 {
+            let id = generate_player_id();
+            assert_eq!(id.len(), PLAYER_ID_LEN);
+            assert_eq!(parse_player_id(id.as_bytes()), Some(id));
+        }
+        // Ids of any other length are rejected rather than sliced apart
+        for len in [PLAYER_ID_LEN - 1, PLAYER_ID_LEN + 1] {
+            assert_eq!(parse_player_id(nanoid::nanoid!(len).as_bytes()), None);
+        }
+        // Wire sizes follow the id length
+        assert_eq!(HEADER_SIZE, 6 + PLAYER_ID_LEN);
+        assert_eq!(POSITION_RECORD_SIZE, PLAYER_ID_LEN + 8);
+    }
+
+    #[test]
+    fn test_player_timeout_boundary() {
+        let mut p = player("a");
+        p.heartbeat = Timestamp::from_millis(1_000);
+        let timeout = Duration::from_secs(10);
+        assert!(!p.is_timed_out(Timestamp::from_millis(10_999), timeout));
+        assert!(!p.is_timed_out(Timestamp::from_millis(11_000), timeout));
+        assert!(p.is_timed_out(Timestamp::from_millis(11_001), timeout));
+        // A heartbeat from the future never times out
+        assert!(!p.is_timed_out(Timestamp::from_millis(0), timeout));
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_uses_clock() {
+        let clock = Arc::new(MockClock::new(Timestamp::from_millis(1_000)));
+        let mut state = GameState::with_clock(800, 600, clock.clone());
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let mut a = player("a");
+        a.heartbeat = state.now();
+        state.add_player(a, "127.0.0.1:1000".to_string());
+
+        clock.advance(Duration::from_secs(PLAYER_TIMEOUT_SECS));
+        state.cleanup_inactive_players(&socket).await;
+        assert_eq!(state.get_player_count(), 1);
+
+        clock.advance(Duration::from_millis(1));
+        state.cleanup_inactive_players(&socket).await;
+        assert_eq!(state.get_player_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_players_silent_after_connecting_are_removed_after_the_grace() {
+        let clock = Arc::new(MockClock::new(Timestamp::from_millis(1_000)));
+        let mut state = GameState::with_clock(800, 600, clock.clone());
+        state.connect_grace_period = Some(Duration::from_secs(2));
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        for (id, addr) in [("silent", "127.0.0.1:1000"), ("active", "127.0.0.1:1001")] {
+            let mut p = player(id);
+            p.heartbeat = state.now();
+            state.add_player(p, addr.to_string());
+        }
+        let mut avatar = player("avatar");
+        avatar.heartbeat = state.now();
+        state.add_avatar(avatar, "127.0.0.1:1001".to_string());
+
+        clock.advance(Duration::from_secs(1));
+        state.record_receive("127.0.0.1:1001");
+        clock.advance(Duration::from_secs(1));
+        state.cleanup_inactive_players(&socket).await;
+        assert_eq!(state.get_player_count(), 3);
+
+        clock.advance(Duration::from_millis(1));
+        state.cleanup_inactive_players(&socket).await;
+        assert!(state.get_player_by_id("silent").is_none());
+        assert!(state.get_player_by_id("active").is_some());
+        assert!(state.get_player_by_id("avatar").is_some());
+
+        // Without a grace period only the heartbeat timeout applies
+        state.connect_grace_period = None;
+        state.add_player(player("late"), "127.0.0.1:1002".to_string());
+        state.get_player_by_id_mut("late").unwrap().heartbeat = state.now();
+        clock.advance(Duration::from_secs(5));
+        state.cleanup_inactive_players(&socket).await;
+        assert!(state.get_player_by_id("late").is_some());
+    }
+
+    #[test]
+    fn test_position_at_rewinds_recorded_moves() {
+        let clock = Arc::new(MockClock::new(Timestamp::from_millis(1_000)));
+        let mut state = GameState::with_clock(800, 600, clock.clone());
+        state.position_history_len = 4;
+        state.add_player(player("a"), "127.0.0.1:1000".to_string());
+        let id = "a".to_string();
+        assert_eq!(state.position_at(&id, Timestamp::from_millis(1_000)), None);
+
+        state.update_player_position(&id, Position::new(10.0, 10.0));
+        state.record_position_sample(&id);
+        clock.advance(Duration::from_millis(100));
+        state.update_player_position(&id, Position::new(20.0, 30.0));
+        state.record_position_sample(&id);
+
+        assert_eq!(
+            state.position_at(&id, Timestamp::from_millis(1_050)),
+            Some(Position::new(15.0, 20.0))
+        );
+        assert_eq!(
+            state.position_at(&id, Timestamp::from_millis(2_000)),
+            Some(Position::new(20.0, 30.0))
+        );
+        assert_eq!(state.position_at(&"b".to_string(), clock.now()), None);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_summary_counts_failed_recipients() {
+        let logs = CapturedLogs::default();
+        let _guard = logs.install();
+        let mut state = GameState::new(800, 600);
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (a, b) = ("a".repeat(PLAYER_ID_LEN), "b".repeat(PLAYER_ID_LEN));
+        state.add_player(player(&a), client.local_addr().unwrap().to_string());
+        // An IPv6 destination can't be reached from the IPv4 socket
+        state.add_player(player(&b), "[::1]:9".to_string());
+        state.add_spectator("[::1]:10".to_string());
+
+        let sent = state
+            .broadcast(
+                &socket,
+                &BroadcastScope::everyone(),
+                MessageType::Custom(0x90),
+                |_, _| vec![vec![7]],
+            )
+            .await;
+
+        assert_eq!(sent, 1);
+        let mut buf = vec![0; 64];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(packet.msg_type, MessageType::Custom(0x90));
+        assert_eq!(packet.client_id, a.as_bytes());
+        assert_eq!(packet.payload, vec![7]);
+        let logs = logs.contents();
+        let summary = logs
+            .lines()
+            .find(|line| line.contains("Broadcast partially failed"))
+            .unwrap();
+        assert!(summary.contains("msg_type=Custom(144)"), "{summary}");
+        assert!(summary.contains("sent=1 failed=2"), "{summary}");
+        let failure = logs
+            .lines()
+            .find(|line| line.contains("Error broadcasting:"))
+            .unwrap();
+        assert!(failure.contains("addr=[::1]:9"), "{failure}");
+        assert!(failure.contains(&format!("player_id={b}")), "{failure}");
+        assert!(failure.contains("seq=1"), "{failure}");
+        assert!(logs.contains("Error broadcasting to spectator"), "{logs}");
+        assert_eq!(state.get_player_by_id(&b).unwrap().send_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn test_player_left_is_broadcast_in_a_span() {
+        let logs = CapturedLogs::default();
+        let _guard = logs.install();
+        let mut state = GameState::new(800, 600);
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let (a, b) = ("a".repeat(PLAYER_ID_LEN), "b".repeat(PLAYER_ID_LEN));
+        state.add_player(player(&a), "[::1]:9".to_string());
+        state.add_player(player(&b), "127.0.0.1:9".to_string());
+
+        assert!(state.remove_player_and_notify(&b, &socket).await);
+
+        let logs = logs.contents();
+        let failure = logs
+            .lines()
+            .find(|line| line.contains("Error broadcasting:"))
+            .unwrap();
+        assert!(
+            failure.contains("Broadcast{msg_type=PlayerLeft"),
+            "{failure}"
+        );
+        assert!(failure.contains(&format!("player_id={a}")), "{failure}");
+        let summary = logs
+            .lines()
+            .find(|line| line.contains("Broadcast partially failed"))
+            .unwrap();
+        assert!(summary.contains("sent=0 failed=1"), "{summary}");
+    }
+
+    #[test]
+    fn test_position_batch_applies_only_fresh_updates() {
+        let mut state = GameState::new(800, 600);
+        for (id, port) in [("a", 1000), ("b", 1001)] {
+            let mut moved = player(id);
+            moved.seq_num = 10;
+            state.add_player(moved, format!("127.0.0.1:{port}"));
+        }
+        let update = |id: &str, seq_num, x, y| PositionGamePacket {
+            msg_type: MessageType::PositionUpdate,
+            version: 1,
+            client_id: id.as_bytes().to_vec(),
+            seq_num,
+            position: Position::new(x, y),
+        };
+
+        let applied = state.apply_position_batch(
+            &[
+                update("a", 9, 50.0, 50.0),
+                update("b", 11, 60.0, 70.0),
+                // Reordered behind the update just applied
+                update("b", 11, 1.0, 1.0),
+                update("gone", 20, 5.0, 5.0),
+            ],
+            None,
+        );
+
+        assert_eq!(applied.changed.len(), 1);
+        assert_eq!(applied.changed[0].id, b"b");
+        assert_eq!(applied.changed[0].position, Position::new(60.0, 70.0));
+        assert!(applied.clamped.is_empty());
+        assert_eq!(state.players["a"].position, Position::new(0.0, 0.0));
+        assert_eq!(state.players["b"].position, Position::new(60.0, 70.0));
+        assert_eq!(state.players["b"].seq_num, 11);
+        let staged = state.take_pending_position_updates();
+        assert_eq!(staged.len(), 1);
+        assert_eq!(staged[0].position, Position::new(60.0, 70.0));
+
+        // Out of bounds moves are clamped, and moving nowhere isn't a change
+        let applied = state.apply_position_batch(
+            &[update("a", 12, 0.0, 0.0), update("b", 12, 900.0, 70.0)],
+            None,
+        );
+        assert_eq!(applied.changed.len(), 1);
+        assert_eq!(applied.changed[0].position, Position::new(800.0, 70.0));
+        assert_eq!(applied.clamped, vec![1]);
+        assert_eq!(state.players["a"].seq_num, 12);
+    }
+
+    #[test]
+    fn test_stale_challenges_and_orphaned_tokens_expire() {
+        let clock = Arc::new(MockClock::new(Timestamp::from_millis(1_000)));
+        let mut state = GameState::with_clock(800, 600, clock.clone());
+        state.issue_challenge("127.0.0.1:1000".to_string());
+        state.add_player(player("a"), "127.0.0.1:1001".to_string());
+        let kept = state.issue_reconnect_token("a");
+        state.issue_reconnect_token("gone");
+        assert!(!state.is_idle());
+
+        // Only the token of the missing player goes until the TTL passes
+        clock.advance(DEFAULT_PENDING_ENTRY_TTL);
+        assert_eq!(state.expire_stale_entries(), 1);
+        assert!(state.pending_challenges.contains_key("127.0.0.1:1000"));
+        assert_eq!(
+            state.reconnect_tokens.keys().collect::<Vec<_>>(),
+            vec![&kept]
+        );
+
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(state.expire_stale_entries(), 1);
+        assert!(state.pending_challenges.is_empty());
+        assert_eq!(state.metrics.expired_entries(), 2);
+
+        // Removing a player drops its tokens right away
+        state.remove_player("a");
+        assert!(state.reconnect_tokens.is_empty());
+        assert_eq!(state.expire_stale_entries(), 0);
+        assert!(state.is_idle());
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_only_notifies_surviving_players() {
+        let clock = Arc::new(MockClock::new(Timestamp::from_millis(1_000)));
+        let mut state = GameState::with_clock(800, 600, clock.clone());
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let mut clients = Vec::new();
+        // Full length ids so the packets sent to them parse
+        let ids = ["a", "b", "c"].map(|c| c.repeat(PLAYER_ID_LEN));
+        for id in &ids {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut p = player(id);
+            p.heartbeat = state.now();
+            state.add_player(p, client.local_addr().unwrap().to_string());
+            clients.push(client);
+        }
+
+        clock.advance(Duration::from_secs(PLAYER_TIMEOUT_SECS + 1));
+        state.get_player_by_id_mut(&ids[2]).unwrap().heartbeat = state.now();
+        state.cleanup_inactive_players(&socket).await;
+        assert_eq!(state.get_player_count(), 1);
+
+        let mut buf = [0; 64];
+        let mut left = HashSet::new();
+        for _ in 0..2 {
+            let (len, _) =
+                tokio::time::timeout(Duration::from_secs(1), clients[2].recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            assert_eq!(packet.msg_type, MessageType::PlayerLeft);
+            left.insert(PlayerLeft::deserialize(&packet.payload).unwrap().player_id);
+        }
+        assert_eq!(left, HashSet::from([ids[0].clone(), ids[1].clone()]));
+        for client in &clients[..2] {
+            assert!(
+                tokio::time::timeout(Duration::from_millis(100), client.recv_from(&mut buf))
+                    .await
+                    .is_err()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_drops_and_announces_the_player_at_the_new_address() {
+        let mut state = GameState::default();
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let mut clients = Vec::new();
+        let ids = ["a", "b", "c"].map(|c| c.repeat(PLAYER_ID_LEN));
+        for id in &ids {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            state.add_player(player(id), client.local_addr().unwrap().to_string());
+            clients.push(client);
+        }
+        let token = state.issue_reconnect_token(&ids[0]);
+        state.issue_reconnect_token(&ids[1]);
+
+        // a takes over b's address, b is gone for good
+        let address = clients[1].local_addr().unwrap().to_string();
+        let reconnected = state.reconnect(&token, address.clone(), &socket).await;
+        assert_eq!(reconnected.unwrap().id, ids[0]);
+        assert_eq!(state.get_player_by_addr(&address).unwrap().id, ids[0]);
+        assert!(state.get_player_by_id(&ids[1]).is_none());
+        assert!(state
+            .reconnect_tokens
+            .values()
+            .all(|owner| owner != &ids[1]));
+
+        let mut buf = [0; 64];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(1), clients[2].recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(packet.msg_type, MessageType::PlayerLeft);
+        assert_eq!(
+            PlayerLeft::deserialize(&packet.payload).unwrap().player_id,
+            ids[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_failures_reset_on_receive() {
+        let mut state = GameState::default();
+        state.add_player(player("a"), "127.0.0.1:1000".to_string());
+        assert_eq!(state.record_send_failure("a"), 1);
+        assert_eq!(state.record_send_failure("a"), 2);
+        state.record_receive("127.0.0.1:1000");
+        assert_eq!(state.get_player_by_id("a").unwrap().send_failures, 0);
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        state.record_send_failure("a");
+        assert!(state
+            .remove_unreachable_players(2, &socket)
+            .await
+            .is_empty());
+        state.record_send_failure("a");
+        assert_eq!(
+            state.remove_unreachable_players(2, &socket).await,
+            vec!["a"]
+        );
+        assert_eq!(state.get_player_count(), 0);
+    }
+
+    #[test]
+    fn test_players_sorted_is_stable_across_identical_setups() {
+        let ids = ["m", "c", "x", "a", "q"];
+        let setup = || {
+            let mut state = GameState::default();
+            for (port, id) in (1000..).zip(ids) {
+                state.add_player(player(id), format!("127.0.0.1:{port}"));
+            }
+            state
+        };
+        let first = setup();
+        let second = setup();
+
+        let first_ids = first
+            .players_sorted()
+            .iter()
+            .map(|p| p.id.clone())
+            .collect::<Vec<_>>();
+        let second_ids = second
+            .players_sorted()
+            .iter()
+            .map(|p| p.id.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(first_ids, vec!["a", "c", "m", "q", "x"]);
+        assert_eq!(first_ids, second_ids);
+
+        let serialize = |state: &GameState| {
+            let players = state.players_sorted().into_iter().cloned().collect();
+            ConnectionInitPacketSent::new(
+                0,
+                vec![0; PLAYER_ID_LEN],
+                [0; 16],
+                state.world_info(),
+                players,
+            )
+            .serialize()
+            .serialize()
+        };
+        assert_eq!(serialize(&first), serialize(&second));
+    }
+
+    #[test]
+    fn test_connection_init_widens_positions_for_f64_sessions() {
+        use crate::packet::{
+            connection_init::RECONNECT_TOKEN_LEN,
+            position::{POSITION_RECORD_F64_SIZE, POSITION_RECORD_SIZE},
+            world::WORLD_INFO_SIZE,
+        };
+
+        let mut far = player("a".repeat(PLAYER_ID_LEN).as_str());
+        far.position = Position::new(1920.5, -1080.25);
+        let response = |f64_positions: bool| {
+            let sent = ConnectionInitPacketSent::new(
+                0,
+                vec![0; PLAYER_ID_LEN],
+                [0; 16],
+                GameState::default().world_info(),
+                vec![far.clone()],
+            );
+            if f64_positions {
+                sent.with_f64_positions().serialize().payload
+            } else {
+                sent.serialize().payload
+            }
+        };
+        let header = RECONNECT_TOKEN_LEN + WORLD_INFO_SIZE;
+        assert_eq!(response(false).len(), header + POSITION_RECORD_SIZE);
+        let wide = response(true);
+        assert_eq!(wide.len(), header + POSITION_RECORD_F64_SIZE);
+        assert_eq!(
+            Position::deserialize_f64(&wide[header + PLAYER_ID_LEN..]),
+            Some(far.position)
+        );
+    }
+
+    #[test]
+    fn test_outbound_seq_wraps() {
+        let mut state = GameState::default();
+        let mut p = player("a");
+        p.outbound_seq = u32::MAX;
+        state.add_player(p, "127.0.0.1:1".to_string());
+        assert_eq!(state.next_outbound_seq("a"), 0);
+        assert_eq!(state.next_outbound_seq("a"), 1);
+        assert_eq!(state.next_outbound_seq("missing"), 0);
+    }
+
+    #[test]
+    fn test_chat_history_drops_oldest() {
+        let mut state = GameState::default();
+        for message in ["one", "two", "three"] {
+            state.record_chat(
+                "",
+                ChatPacket::new(vec![0; PLAYER_ID_LEN], message.to_string()),
+                2,
+            );
+        }
+        state.record_chat(
+            "other",
+            ChatPacket::new(vec![0; PLAYER_ID_LEN], "elsewhere".to_string()),
+            2,
+        );
+        let history = state
+            .chat_history_of("")
+            .map(|chat| chat.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(history, vec!["two", "three"]);
+    }
+
+    #[test]
+    fn test_metadata_size_is_capped() {
+        let mut state = GameState::default();
+        state.add_player(player("a"), "127.0.0.1:1".to_string());
+        assert!(state.set_metadata("a", "name".to_string(), b"alice".to_vec()));
+        assert_eq!(state.get_metadata("a", "name"), Some(&b"alice"[..]));
+
+        let too_big = vec![0; MAX_PLAYER_METADATA_BYTES];
+        assert!(!state.set_metadata("a", "blob".to_string(), too_big));
+        assert_eq!(state.get_metadata("a", "blob"), None);
+
+        // Replacing a value only counts the new one
+        let fits = vec![0; MAX_PLAYER_METADATA_BYTES - "name".len()];
+        assert!(state.set_metadata("a", "name".to_string(), fits));
+        assert!(!state.set_metadata("missing", "name".to_string(), vec![]));
+    }
+
+    #[test]
+    fn test_team_follows_metadata() {
+        let mut state = GameState::default();
+        for (id, port) in [("a", 1), ("b", 2), ("c", 3)] {
+            state.add_player(player(id), format!("127.0.0.1:{port}"));
+        }
+        assert!(state.set_metadata("a", TEAM_METADATA_KEY.to_string(), vec![1]));
+        assert!(state.set_metadata("b", TEAM_METADATA_KEY.to_string(), vec![1]));
+        assert_eq!(state.get_player_by_id("a").unwrap().team, Some(1));
+
+        let mut team = state
+            .scope_recipients(&BroadcastScope::Team(Some(1)))
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect::<Vec<_>>();
+        team.sort();
+        assert_eq!(team, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(state.scope_recipients(&BroadcastScope::Team(None)).len(), 1);
+
+        // Anything but a single byte leaves the team
+        assert!(state.set_metadata("b", TEAM_METADATA_KEY.to_string(), b"12".to_vec()));
+        assert_eq!(state.get_player_by_id("b").unwrap().team, None);
+    }
+
+    #[test]
+    fn test_reordered_position_update_does_not_replace_newer() {
+        let update = |seq_num: u32, x: Coord| PositionGamePacket {
+            msg_type: MessageType::PositionUpdate,
+            version: 1,
+            client_id: vec![b'a'; PLAYER_ID_LEN],
+            seq_num,
+            position: Position::new(x, 0.0),
+        };
+        let mut state = GameState::default();
+        state.stage_position_update(update(u32::MAX, 1.0));
+        // Sent after the wrap, so newer
+        state.stage_position_update(update(0, 2.0));
+        // Delayed from before the wrap
+        state.stage_position_update(update(u32::MAX - 1, 3.0));
+        let staged = &state.pending_position_updates[&vec![b'a'; PLAYER_ID_LEN]];
+        assert_eq!(staged.seq_num, 0);
+        assert_eq!(staged.position, Position::new(2.0, 0.0));
+    }
+
+    #[test]
+    fn test_mover_stops_at_contact_distance() {
+        let mut state = GameState::default();
+        let mut mover = player("mover");
+        mover.position = Position::new(100.0, 100.0);
+        let mut blocker = player("blocker");
+        blocker.position = Position::new(200.0, 100.0);
+        state.add_player(mover, "127.0.0.1:1".to_string());
+        state.add_player(blocker, "127.0.0.1:2".to_string());
+
+        let resolved = state.resolve_collision(
+            "mover",
+            &Position::new(100.0, 100.0),
+            &Position::new(200.0, 100.0),
+            10.0,
+        );
+        assert!((resolved.x - 180.0).abs() < 1e-3);
+        assert!((resolved.y - 100.0).abs() < 1e-3);
+        let distance = resolved.distance(&Position::new(200.0, 100.0));
+        assert!((distance - 20.0).abs() < 1e-3);
+
+        // Moves that stay clear are left alone
+        let free = state.resolve_collision(
+            "mover",
+            &Position::new(100.0, 100.0),
+            &Position::new(100.0, 150.0),
+            10.0,
+        );
+        assert!((free.y - 150.0).abs() < Coord::EPSILON);
     }
 }
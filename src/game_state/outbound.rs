@@ -0,0 +1,105 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use tokio::sync::Notify;
+
+use crate::server::ServerMetrics;
+
+/// Datagrams waiting to be sent, one bounded queue per destination address.
+///
+/// Handlers enqueue through [`GameState::send_datagram`](super::GameState::send_datagram)
+/// instead of waiting on the socket; a sender task drains the queues. When a queue is
+/// full its oldest datagram is dropped and counted in [`ServerMetrics`].
+#[derive(Debug)]
+pub struct OutboundQueue {
+    capacity: usize,
+    queues: Mutex<HashMap<String, VecDeque<Vec<u8>>>>,
+    notify: Notify,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl OutboundQueue {
+    /// Queue holding up to `capacity` datagrams per address. Zero is treated as one.
+    #[must_use]
+    pub fn new(capacity: usize, metrics: Arc<ServerMetrics>) -> Self {
+        OutboundQueue {
+            capacity: capacity.max(1),
+            queues: Mutex::default(),
+            notify: Notify::new(),
+            metrics,
+        }
+    }
+    /// Queues `data` for `addr`, dropping the oldest datagram queued for it if full.
+    /// Returns `false` if a datagram was dropped.
+    pub fn push(&self, addr: String, data: Vec<u8>) -> bool {
+        let mut queues = self.queues.lock().unwrap_or_else(PoisonError::into_inner);
+        let queue = queues.entry(addr).or_default();
+        let dropped = queue.len() >= self.capacity;
+        if dropped {
+            queue.pop_front();
+            self.metrics.record_outbound_queue_drop();
+        }
+        queue.push_back(data);
+        let depth = queues.values().map(VecDeque::len).sum();
+        drop(queues);
+        self.metrics.set_outbound_queue_depth(depth);
+        self.notify.notify_one();
+        !dropped
+    }
+    /// Removes and returns everything queued, each address's datagrams in order.
+    pub fn take_all(&self) -> Vec<(String, Vec<u8>)> {
+        let taken =
+            std::mem::take(&mut *self.queues.lock().unwrap_or_else(PoisonError::into_inner));
+        self.metrics.set_outbound_queue_depth(0);
+        taken
+            .into_iter()
+            .flat_map(|(addr, queue)| queue.into_iter().map(move |data| (addr.clone(), data)))
+            .collect()
+    }
+    /// Datagrams currently queued across all addresses.
+    #[must_use]
+    pub fn depth(&self) -> usize {
+        self.queues
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .map(VecDeque::len)
+            .sum()
+    }
+    /// Waits until something is pushed after the last wait returned.
+    pub async fn notified(&self) {
+        self.notify.notified().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_full_queue_drops_oldest() {
+        let metrics = Arc::new(ServerMetrics::default());
+        let queue = OutboundQueue::new(2, Arc::clone(&metrics));
+        assert!(queue.push("a".to_string(), vec![1]));
+        assert!(queue.push("a".to_string(), vec![2]));
+        assert!(!queue.push("a".to_string(), vec![3]));
+        assert!(queue.push("b".to_string(), vec![4]));
+        assert_eq!(metrics.outbound_queue_drops(), 1);
+        assert_eq!(metrics.outbound_queue_depth(), 3);
+
+        let mut taken = queue.take_all();
+        taken.sort();
+        assert_eq!(
+            taken,
+            vec![
+                ("a".to_string(), vec![2]),
+                ("a".to_string(), vec![3]),
+                ("b".to_string(), vec![4]),
+            ]
+        );
+        assert_eq!(queue.depth(), 0);
+        assert_eq!(metrics.outbound_queue_depth(), 0);
+    }
+}
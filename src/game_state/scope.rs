@@ -0,0 +1,121 @@
+use std::net::SocketAddr;
+
+use super::{Player, PlayerId, Position};
+
+/// Which players and spectators a broadcast reaches, see
+/// [`GameState::broadcast`](super::GameState::broadcast).
+#[derive(Debug, Clone, PartialEq)]
+pub enum BroadcastScope {
+    /// Every player.
+    All,
+    /// Every player but the one at this address, usually the sender.
+    AllExcept(SocketAddr),
+    /// Players at most `radius` away from `center`.
+    Within { center: Position, radius: f32 },
+    /// Players of a team, `None` being the players without one. See [`Player::team`].
+    Team(Option<u8>),
+    /// The listed players.
+    Only(Vec<PlayerId>),
+    /// The players the inner scope includes and every spectator.
+    WithSpectators(Box<BroadcastScope>),
+}
+
+impl BroadcastScope {
+    /// Every player and every spectator.
+    #[must_use]
+    pub fn everyone() -> BroadcastScope {
+        BroadcastScope::WithSpectators(Box::new(BroadcastScope::All))
+    }
+    /// Whether `player`, reachable at `addr`, is in scope.
+    #[must_use]
+    pub fn includes(&self, addr: &str, player: &Player) -> bool {
+        match self {
+            BroadcastScope::All => true,
+            BroadcastScope::AllExcept(except) => addr.parse::<SocketAddr>().ok() != Some(*except),
+            BroadcastScope::Within { center, radius } => {
+                player.position.distance(center) <= *radius
+            }
+            BroadcastScope::Team(team) => player.team == *team,
+            BroadcastScope::Only(ids) => ids.contains(&player.id),
+            BroadcastScope::WithSpectators(players) => players.includes(addr, player),
+        }
+    }
+    /// Whether spectators are in scope.
+    #[must_use]
+    pub fn includes_spectators(&self) -> bool {
+        matches!(self, BroadcastScope::WithSpectators(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_state::GameState;
+
+    /// Four players keyed `127.0.0.1:<n>`.
+    fn state() -> GameState {
+        let mut state = GameState::default();
+        let players = [
+            ("a", 1, Position::new(0.0, 0.0), Some(1)),
+            ("b", 2, Position::new(3.0, 4.0), Some(1)),
+            ("c", 3, Position::new(100.0, 0.0), Some(2)),
+            ("d", 4, Position::new(1.0, 1.0), None),
+        ];
+        for (id, port, position, team) in players {
+            let player = Player {
+                id: id.to_string(),
+                position,
+                team,
+                ..Player::default()
+            };
+            state.add_player(player, format!("127.0.0.1:{port}"));
+        }
+        state
+    }
+
+    fn ids(state: &GameState, scope: &BroadcastScope) -> Vec<PlayerId> {
+        let mut ids = state
+            .scope_recipients(scope)
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_all_except_skips_one_address() {
+        let state = state();
+        let scope = BroadcastScope::AllExcept("127.0.0.1:2".parse().unwrap());
+        assert_eq!(ids(&state, &scope), ["a", "c", "d"]);
+        assert_eq!(ids(&state, &BroadcastScope::All), ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn test_within_includes_the_radius() {
+        let state = state();
+        let scope = BroadcastScope::Within {
+            center: Position::new(0.0, 0.0),
+            radius: 5.0,
+        };
+        assert_eq!(ids(&state, &scope), ["a", "b", "d"]);
+    }
+
+    #[test]
+    fn test_team_and_only() {
+        let state = state();
+        assert_eq!(ids(&state, &BroadcastScope::Team(Some(1))), ["a", "b"]);
+        assert_eq!(ids(&state, &BroadcastScope::Team(None)), ["d"]);
+        let scope = BroadcastScope::Only(vec!["c".to_string(), "e".to_string()]);
+        assert_eq!(ids(&state, &scope), ["c"]);
+    }
+
+    #[test]
+    fn test_with_spectators_keeps_the_players_of_its_scope() {
+        let state = state();
+        let scope = BroadcastScope::WithSpectators(Box::new(BroadcastScope::Team(Some(1))));
+        assert_eq!(ids(&state, &scope), ["a", "b"]);
+        assert!(scope.includes_spectators());
+        assert!(!BroadcastScope::All.includes_spectators());
+    }
+}
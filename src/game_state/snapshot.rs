@@ -0,0 +1,375 @@
+use std::{collections::HashMap, time::Duration};
+
+use bytes::{BufMut, BytesMut};
+
+use super::{
+    team_from_metadata, GameState, Player, Position, PositionHistory, Timestamp, TEAM_METADATA_KEY,
+};
+use crate::{
+    num::{coord_to_f64, f64_to_coord},
+    packet::{
+        features::Features,
+        world::{WorldInfo, WORLD_INFO_SIZE},
+        ReplayWindow,
+    },
+};
+
+/// Leading bytes of every snapshot, to reject files that aren't one.
+const SNAPSHOT_MAGIC: &[u8; 4] = b"SDSN";
+/// Version of the snapshot format written by [`GameState::export_snapshot`].
+pub const SNAPSHOT_VERSION: u8 = 3;
+
+/// A serialized [`GameState`], see [`GameState::export_snapshot`].
+///
+/// Layout, multi-byte numbers big endian: the magic `SDSN`, the version byte, the
+/// [`WorldInfo`], the tick as a `u64`, a `u32` player count, then for every player:
+/// the length prefixed id, address, room and name (empty for none), the position as two
+/// `f64`s, the inbound and outbound sequence numbers, the features, the milliseconds
+/// since the last heartbeat, since the last respawn (`u64::MAX` for never) and since
+/// joining, then a `u16` metadata count followed by each length prefixed key and `u16`
+/// prefixed value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotBytes(pub Vec<u8>);
+
+impl AsRef<[u8]> for SnapshotBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Why [`GameState::import_snapshot`] rejected a snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The data doesn't start with the snapshot magic.
+    NotASnapshot,
+    /// Written by a format version this build can't read.
+    UnsupportedVersion(u8),
+    /// Cut short or holding invalid values.
+    Malformed,
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::NotASnapshot => write!(f, "not a game state snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {version}")
+            }
+            SnapshotError::Malformed => write!(f, "malformed snapshot"),
+        }
+    }
+}
+impl std::error::Error for SnapshotError {}
+
+impl GameState {
+    /// Serializes the world and every connected player with its address, so a new process can
+    /// pick up where this one left off with [`GameState::import_snapshot`].
+    ///
+    /// Timestamps are stored as the time elapsed until now, the importing state rebases
+    /// them on its own clock. Transient state such as staged updates, pending probes,
+    /// send failures, challenges and avatars is left out, and so are session keys.
+    #[must_use]
+    pub fn export_snapshot(&self) -> SnapshotBytes {
+        let now = self.now();
+        let mut buf = BytesMut::new();
+        buf.put_slice(SNAPSHOT_MAGIC);
+        buf.put_u8(SNAPSHOT_VERSION);
+        buf.put_slice(&self.world_info().serialize());
+        buf.put_u64(self.tick);
+        let mut players = self.players_by_addr().collect::<Vec<_>>();
+        players.sort_by(|(_, a), (_, b)| a.id.cmp(&b.id));
+        buf.put_u32(u32::try_from(players.len()).unwrap_or(u32::MAX));
+        for (addr, player) in players {
+            put_str(&mut buf, &player.id);
+            put_str(&mut buf, addr);
+            put_str(&mut buf, &player.room);
+            put_str(&mut buf, player.name.as_deref().unwrap_or_default());
+            buf.put_f64(coord_to_f64(player.position.x));
+            buf.put_f64(coord_to_f64(player.position.y));
+            buf.put_u32(player.seq_num);
+            buf.put_u32(player.outbound_seq);
+            buf.put_u32(player.features.0);
+            buf.put_u64(millis(now.duration_since(player.heartbeat)));
+            buf.put_u64(
+                player
+                    .last_respawn
+                    .map_or(u64::MAX, |respawned| millis(now.duration_since(respawned))),
+            );
+            buf.put_u64(millis(now.duration_since(player.connected_at)));
+            let mut metadata = player.metadata.iter().collect::<Vec<_>>();
+            metadata.sort();
+            buf.put_u16(u16::try_from(metadata.len()).unwrap_or(u16::MAX));
+            for (key, value) in metadata {
+                put_str(&mut buf, key);
+                buf.put_u16(u16::try_from(value.len()).unwrap_or(u16::MAX));
+                buf.put_slice(value);
+            }
+        }
+        SnapshotBytes(buf.to_vec())
+    }
+    /// Restores the world and the players of a snapshot taken by
+    /// [`GameState::export_snapshot`], replacing players at the same addresses.
+    /// Returns how many players were imported.
+    ///
+    /// # Errors
+    /// Returns a [`SnapshotError`], leaving the state untouched, if `data` isn't a
+    /// snapshot this build can read.
+    pub fn import_snapshot(&mut self, data: &[u8]) -> Result<usize, SnapshotError> {
+        let mut reader = Reader(data);
+        if reader.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = reader.u8()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let world = WorldInfo::deserialize(reader.take(WORLD_INFO_SIZE)?)
+            .ok_or(SnapshotError::Malformed)?;
+        let tick = reader.u64()?;
+        let count = reader.u32()?;
+        let now = self.now();
+        let mut players = Vec::new();
+        for _ in 0..count {
+            let id = reader.string()?;
+            let addr = reader.string()?;
+            let room = reader.string()?;
+            let name = Some(reader.string()?).filter(|name| !name.is_empty());
+            let position = Position::new(f64_to_coord(reader.f64()?), f64_to_coord(reader.f64()?));
+            let seq_num = reader.u32()?;
+            let outbound_seq = reader.u32()?;
+            // Session keys aren't exported, encrypted sessions have to connect again
+            let features = Features(reader.u32()?).without(Features::ENCRYPTION);
+            let heartbeat = rebase(now, reader.u64()?);
+            let last_respawn = match reader.u64()? {
+                u64::MAX => None,
+                age => Some(rebase(now, age)),
+            };
+            let connected_at = rebase(now, reader.u64()?);
+            let mut metadata = HashMap::new();
+            for _ in 0..reader.u16()? {
+                let key = reader.string()?;
+                let len = reader.u16()?;
+                metadata.insert(key, reader.take(usize::from(len))?.to_vec());
+            }
+            let team = metadata
+                .get(TEAM_METADATA_KEY)
+                .and_then(|value| team_from_metadata(value));
+            let player = Player {
+                id,
+                seq_num,
+                position,
+                heartbeat,
+                send_failures: 0,
+                outbound_seq,
+                metadata,
+                last_respawn,
+                pending_probe: None,
+                missed_probes: 0,
+                room,
+                name,
+                features,
+                session_key: None,
+                replay_window: ReplayWindow::default(),
+                position_history: PositionHistory::default(),
+                // Players in a snapshot were already playing, the connect grace is over
+                last_activity: Some(heartbeat),
+                team,
+                connected_at,
+            };
+            players.push((player, addr));
+        }
+        if !reader.0.is_empty() {
+            return Err(SnapshotError::Malformed);
+        }
+        self.resize(world.width, world.height);
+        self.spawn = self.clamp_position(&world.spawn);
+        self.tick = tick;
+        let imported = players.len();
+        for (player, addr) in players {
+            self.add_player(player, addr);
+        }
+        Ok(imported)
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// The timestamp `age_millis` before `now`.
+fn rebase(now: Timestamp, age_millis: u64) -> Timestamp {
+    Timestamp(now.0.saturating_sub(age_millis))
+}
+
+/// Appends `s` prefixed with its length as one byte, cut to 255 bytes.
+fn put_str(buf: &mut BytesMut, s: &str) {
+    let bytes = s.as_bytes();
+    let bytes = bytes.get(..usize::from(u8::MAX)).unwrap_or(bytes);
+    buf.put_u8(u8::try_from(bytes.len()).unwrap_or(u8::MAX));
+    buf.put_slice(bytes);
+}
+
+/// Consumes a snapshot front to back, failing on truncation.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        let (taken, rest) = self
+            .0
+            .split_at_checked(len)
+            .ok_or(SnapshotError::Malformed)?;
+        self.0 = rest;
+        Ok(taken)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        self.take(N)?
+            .try_into()
+            .map_err(|_| SnapshotError::Malformed)
+    }
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        self.array().map(u8::from_be_bytes)
+    }
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        self.array().map(u16::from_be_bytes)
+    }
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        self.array().map(u32::from_be_bytes)
+    }
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        self.array().map(u64::from_be_bytes)
+    }
+    fn f64(&mut self) -> Result<f64, SnapshotError> {
+        self.array().map(f64::from_be_bytes)
+    }
+    fn string(&mut self) -> Result<String, SnapshotError> {
+        let len = self.u8()?;
+        String::from_utf8(self.take(usize::from(len))?.to_vec())
+            .map_err(|_| SnapshotError::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::game_state::{MockClock, PLAYER_ID_LEN};
+
+    fn player(id: &str, position: Position, heartbeat: Timestamp) -> Player {
+        Player {
+            id: id.to_string(),
+            seq_num: 7,
+            position,
+            heartbeat,
+            send_failures: 2,
+            outbound_seq: 40,
+            pending_probe: Some(39),
+            missed_probes: 1,
+            room: "red".to_string(),
+            features: Features::CHECKSUM,
+            connected_at: heartbeat,
+            ..Player::default()
+        }
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let clock = Arc::new(MockClock::new(Timestamp::from_millis(50_000)));
+        let mut state = GameState::with_clock(800, 600, clock.clone());
+        state.tick = 1234;
+        let mut alice = player(
+            "a".repeat(PLAYER_ID_LEN).as_str(),
+            Position::new(10.0, 20.0),
+            state.now(),
+        );
+        alice.name = Some("Alice".to_string());
+        alice.metadata.insert("team".to_string(), b"blue".to_vec());
+        alice.last_respawn = Some(Timestamp::from_millis(45_000));
+        let bob = player(
+            "b".repeat(PLAYER_ID_LEN).as_str(),
+            Position::new(300.5, 400.25),
+            Timestamp::from_millis(48_000),
+        );
+        state.add_player(alice, "127.0.0.1:1000".to_string());
+        state.add_player(bob, "127.0.0.1:1001".to_string());
+        let snapshot = state.export_snapshot();
+
+        // A new process with its clock somewhere else entirely
+        let clock = Arc::new(MockClock::new(Timestamp::from_millis(900_000)));
+        let mut restored = GameState::with_clock(100, 100, clock);
+        assert_eq!(restored.import_snapshot(snapshot.as_ref()), Ok(2));
+        assert_eq!((restored.width, restored.height), (800, 600));
+        assert_eq!(restored.tick, 1234);
+        assert_eq!(restored.get_player_count(), 2);
+
+        let alice = restored.get_player_by_addr("127.0.0.1:1000").unwrap();
+        assert_eq!(alice.id, "a".repeat(PLAYER_ID_LEN));
+        assert_eq!(alice.position, Position::new(10.0, 20.0));
+        assert_eq!(alice.name.as_deref(), Some("Alice"));
+        assert_eq!(alice.room, "red");
+        assert_eq!(alice.features, Features::CHECKSUM);
+        assert_eq!((alice.seq_num, alice.outbound_seq), (7, 40));
+        assert_eq!(alice.metadata["team"], b"blue");
+        assert_eq!(alice.heartbeat, Timestamp::from_millis(900_000));
+        assert_eq!(alice.last_respawn, Some(Timestamp::from_millis(895_000)));
+        assert_eq!(alice.connected_at, Timestamp::from_millis(900_000));
+        // Transient state starts over
+        assert_eq!((alice.send_failures, alice.missed_probes), (0, 0));
+        assert_eq!(alice.pending_probe, None);
+
+        let bob = restored.get_player_by_addr("127.0.0.1:1001").unwrap();
+        assert_eq!(bob.id, "b".repeat(PLAYER_ID_LEN));
+        assert_eq!(bob.position, Position::new(300.5, 400.25));
+        assert_eq!(bob.name, None);
+        assert_eq!(bob.heartbeat, Timestamp::from_millis(898_000));
+        assert_eq!(bob.last_respawn, None);
+        assert_eq!(bob.connected_at, Timestamp::from_millis(898_000));
+    }
+
+    #[test]
+    #[cfg(feature = "f64-positions")]
+    fn test_snapshot_keeps_large_coordinates() {
+        let far = Position::new(100_000_000.125, -16_777_217.5);
+        let mut state = GameState::new(800, 600);
+        state.add_player(
+            player("a", far.clone(), state.now()),
+            "127.0.0.1:1000".to_string(),
+        );
+
+        let mut restored = GameState::new(800, 600);
+        assert_eq!(
+            restored.import_snapshot(state.export_snapshot().as_ref()),
+            Ok(1)
+        );
+        assert_eq!(restored.get_player_by_id("a").unwrap().position, far);
+    }
+
+    #[test]
+    fn test_invalid_snapshots_are_rejected() {
+        let mut state = GameState::new(800, 600);
+        state.add_player(
+            player("a", Position::new(1.0, 2.0), state.now()),
+            "127.0.0.1:1000".to_string(),
+        );
+        let snapshot = state.export_snapshot().0;
+
+        let mut fresh = GameState::new(800, 600);
+        assert_eq!(
+            fresh.import_snapshot(b"nope"),
+            Err(SnapshotError::NotASnapshot)
+        );
+        let mut newer = snapshot.clone();
+        newer[4] = SNAPSHOT_VERSION + 1;
+        assert_eq!(
+            fresh.import_snapshot(&newer),
+            Err(SnapshotError::UnsupportedVersion(SNAPSHOT_VERSION + 1))
+        );
+        for len in [5, snapshot.len() - 1] {
+            assert_eq!(
+                fresh.import_snapshot(&snapshot[..len]),
+                Err(SnapshotError::Malformed)
+            );
+        }
+        assert_eq!(fresh.get_player_count(), 0);
+    }
+}
@@ -6,8 +6,13 @@
     clippy::as_conversions,
     clippy::integer_division
 )]
+#[cfg(feature = "client")]
+pub mod client;
 pub mod game_state;
+pub mod num;
 pub mod packet;
 pub mod server;
 pub mod tasks;
 pub mod telemetry;
+#[cfg(test)]
+pub(crate) mod testing;
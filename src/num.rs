@@ -0,0 +1,114 @@
+//! Numeric helpers for the strict arithmetic and conversion lints in `lib.rs`.
+//!
+//! Every `as` conversion in the crate lives here, each one audited for what it does
+//! with values that don't fit.
+
+use crate::game_state::Coord;
+
+/// Capacity for `fixed` bytes followed by `count` records of `record` bytes,
+/// saturating instead of overflowing.
+#[must_use]
+pub fn record_capacity(fixed: usize, record: usize, count: usize) -> usize {
+    record.saturating_mul(count).saturating_add(fixed)
+}
+
+/// Nearest `f32`, rounding values above 2^24.
+#[must_use]
+#[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+pub fn u32_to_f32(value: u32) -> f32 {
+    value as f32
+}
+
+/// Nearest `f32`, saturating to infinity beyond `f32::MAX`.
+#[must_use]
+#[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+pub fn f64_to_f32(value: f64) -> f32 {
+    value as f32
+}
+
+/// Nearest `f32` to a coordinate, for the 8 byte position format. Exact unless
+/// coordinates are `f64`, see [`Coord`].
+#[must_use]
+#[cfg(feature = "f64-positions")]
+pub fn coord_to_f32(value: Coord) -> f32 {
+    f64_to_f32(value)
+}
+/// Nearest `f32` to a coordinate, for the 8 byte position format. Exact unless
+/// coordinates are `f64`, see [`Coord`].
+#[must_use]
+#[cfg(not(feature = "f64-positions"))]
+pub fn coord_to_f32(value: Coord) -> f32 {
+    value
+}
+
+/// Nearest coordinate to `value`, see [`Coord`].
+#[must_use]
+#[cfg(feature = "f64-positions")]
+pub fn f64_to_coord(value: f64) -> Coord {
+    value
+}
+/// Nearest coordinate to `value`, see [`Coord`].
+#[must_use]
+#[cfg(not(feature = "f64-positions"))]
+pub fn f64_to_coord(value: f64) -> Coord {
+    f64_to_f32(value)
+}
+
+/// Coordinate `value`, always exact.
+#[must_use]
+#[cfg(feature = "f64-positions")]
+pub fn f32_to_coord(value: f32) -> Coord {
+    f64::from(value)
+}
+/// Coordinate `value`, always exact.
+#[must_use]
+#[cfg(not(feature = "f64-positions"))]
+pub fn f32_to_coord(value: f32) -> Coord {
+    value
+}
+
+/// Coordinate `value` as `f64`, always exact.
+#[must_use]
+#[cfg(feature = "f64-positions")]
+pub fn coord_to_f64(value: Coord) -> f64 {
+    value
+}
+/// Coordinate `value` as `f64`, always exact.
+#[must_use]
+#[cfg(not(feature = "f64-positions"))]
+pub fn coord_to_f64(value: Coord) -> f64 {
+    f64::from(value)
+}
+
+/// Coordinate `value`, exact as `f64` and rounded above 2^24 as `f32`.
+#[must_use]
+pub fn u32_to_coord(value: u32) -> Coord {
+    f64_to_coord(f64::from(value))
+}
+
+/// Low 32 bits of `value`, for counters that wrap on the wire.
+#[must_use]
+#[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+pub fn wrap_u32(value: u64) -> u32 {
+    value as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_capacity_saturates() {
+        assert_eq!(record_capacity(2, 26, 3), 80);
+        assert_eq!(record_capacity(2, 26, usize::MAX), usize::MAX);
+    }
+
+    #[test]
+    fn test_conversions() {
+        assert!((u32_to_f32(1920) - 1920.0).abs() < f32::EPSILON);
+        assert!(f64_to_f32(f64::MAX).is_infinite());
+        assert_eq!(wrap_u32(u64::from(u32::MAX) + 5), 4);
+        assert!((coord_to_f32(u32_to_coord(1920)) - 1920.0).abs() < f32::EPSILON);
+        assert!((coord_to_f64(f64_to_coord(0.5)) - 0.5).abs() < f64::EPSILON);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{
+    game_state::{Position, PLAYER_ID_LEN},
+    num::coord_to_f32,
+};
+
+use super::{
+    sizes::{MIN_KICK_PAYLOAD, MIN_TELEPORT_PAYLOAD},
+    MessageType,
+};
+
+/// Whether `token` equals `admin_token`, compared in constant time.
+fn token_matches(token: &[u8], admin_token: &str) -> bool {
+    let expected = admin_token.as_bytes();
+    token.len() == expected.len()
+        && token
+            .iter()
+            .zip(expected)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Operator command removing `target_id` from the game.
+///
+/// Payload layout: 18 byte target player id followed by the admin token bytes.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KickPacket {
+    pub msg_type: MessageType,
+    pub target_id: Vec<u8>,
+    pub token: Vec<u8>,
+}
+impl KickPacket {
+    #[must_use]
+    pub fn new(target_id: Vec<u8>, token: Vec<u8>) -> Self {
+        KickPacket {
+            msg_type: MessageType::Kick,
+            target_id,
+            token,
+        }
+    }
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.target_id.len().saturating_add(self.token.len()));
+        buf.extend_from_slice(&self.target_id);
+        buf.extend_from_slice(&self.token);
+        buf
+    }
+    #[must_use]
+    pub fn deserialize(data: &[u8]) -> Option<KickPacket> {
+        if data.len() < MIN_KICK_PAYLOAD {
+            return None;
+        }
+        let (target_id, token) = data.split_at(MIN_KICK_PAYLOAD);
+        Some(KickPacket::new(target_id.to_vec(), token.to_vec()))
+    }
+    /// Whether the packet carries `admin_token`, compared in constant time.
+    #[must_use]
+    pub fn is_authorized(&self, admin_token: &str) -> bool {
+        token_matches(&self.token, admin_token)
+    }
+}
+
+/// Operator command moving `target_id` to `position`, clamped to the world bounds.
+///
+/// Payload layout: 18 byte target player id, little endian `x` and `y` like a client's
+/// position update, then the admin token bytes.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TeleportPacket {
+    pub msg_type: MessageType,
+    pub target_id: Vec<u8>,
+    pub position: Position,
+    pub token: Vec<u8>,
+}
+impl TeleportPacket {
+    #[must_use]
+    pub fn new(target_id: Vec<u8>, position: Position, token: Vec<u8>) -> Self {
+        TeleportPacket {
+            msg_type: MessageType::Teleport,
+            target_id,
+            position,
+            token,
+        }
+    }
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.token.len().saturating_add(MIN_TELEPORT_PAYLOAD));
+        buf.extend_from_slice(&self.target_id);
+        buf.extend_from_slice(&coord_to_f32(self.position.x).to_le_bytes());
+        buf.extend_from_slice(&coord_to_f32(self.position.y).to_le_bytes());
+        buf.extend_from_slice(&self.token);
+        buf
+    }
+    #[must_use]
+    pub fn deserialize(data: &[u8]) -> Option<TeleportPacket> {
+        let (target_id, rest) = data.split_at_checked(PLAYER_ID_LEN)?;
+        let (position, token) = rest.split_at_checked(8)?;
+        Some(TeleportPacket::new(
+            target_id.to_vec(),
+            Position::deserialize(position)?,
+            token.to_vec(),
+        ))
+    }
+    /// Whether the packet carries `admin_token`, compared in constant time.
+    #[must_use]
+    pub fn is_authorized(&self, admin_token: &str) -> bool {
+        token_matches(&self.token, admin_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kick_round_trip_and_authorization() {
+        let kick = KickPacket::new(vec![7; PLAYER_ID_LEN], b"secret".to_vec());
+        let decoded = KickPacket::deserialize(&kick.serialize()).unwrap();
+        assert_eq!(decoded.target_id, vec![7; PLAYER_ID_LEN]);
+        assert!(decoded.is_authorized("secret"));
+        assert!(!decoded.is_authorized("secreT"));
+        assert!(!decoded.is_authorized("secret2"));
+        assert!(!decoded.is_authorized(""));
+    }
+
+    #[test]
+    fn test_teleport_round_trip_and_authorization() {
+        let teleport = TeleportPacket::new(
+            vec![7; PLAYER_ID_LEN],
+            Position::new(12.5, -3.0),
+            b"secret".to_vec(),
+        );
+        let decoded = TeleportPacket::deserialize(&teleport.serialize()).unwrap();
+        assert_eq!(decoded.target_id, vec![7; PLAYER_ID_LEN]);
+        assert_eq!(decoded.position, Position::new(12.5, -3.0));
+        assert!(decoded.is_authorized("secret"));
+        assert!(!decoded.is_authorized("secreT"));
+        assert!(TeleportPacket::deserialize(&[7; 25]).is_none());
+    }
+}
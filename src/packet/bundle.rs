@@ -0,0 +1,111 @@
+//! `Bundle` datagrams, several packets to one player coalesced into a single datagram.
+
+use super::{GamePacket, MessageType};
+use crate::game_state::PLAYER_ID_LEN;
+
+/// Bytes framing every packet in a bundle, its big endian `u16` length.
+pub const BUNDLE_ENTRY_OVERHEAD: usize = 2;
+
+/// Packets sent to the same player within the coalescing window.
+///
+/// Payload layout: every packet as serialized, with its own header and flags, prefixed
+/// with its big endian `u16` length. The bundle header has sequence number 0 and a
+/// zeroed client id, receivers only look at the packets inside.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BundlePacket {
+    pub packets: Vec<Vec<u8>>,
+}
+impl BundlePacket {
+    #[must_use]
+    pub fn new(packets: Vec<Vec<u8>>) -> Self {
+        BundlePacket { packets }
+    }
+    /// Serialized size of a bundle holding `packets`, header included.
+    #[must_use]
+    pub fn size_with(packets: &[Vec<u8>]) -> usize {
+        packets.iter().fold(super::HEADER_SIZE, |size, packet| {
+            size.saturating_add(BUNDLE_ENTRY_OVERHEAD)
+                .saturating_add(packet.len())
+        })
+    }
+    /// The payload. Packets longer than `u16::MAX` can't be framed and are left out.
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::size_with(&self.packets));
+        for packet in &self.packets {
+            let Ok(len) = u16::try_from(packet.len()) else {
+                tracing::error!("Leaving a {} byte packet out of a bundle", packet.len());
+                continue;
+            };
+            buf.extend_from_slice(&len.to_be_bytes());
+            buf.extend_from_slice(packet);
+        }
+        buf
+    }
+    /// The whole `Bundle` datagram.
+    #[must_use]
+    pub fn to_datagram(&self) -> Vec<u8> {
+        GamePacket::new(
+            MessageType::Bundle,
+            0,
+            self.serialize(),
+            vec![0; PLAYER_ID_LEN],
+        )
+        .serialize()
+    }
+    /// `None` if an entry is cut short.
+    #[must_use]
+    pub fn deserialize(mut data: &[u8]) -> Option<BundlePacket> {
+        let mut packets = Vec::new();
+        while let Some((len, rest)) = data.split_first_chunk::<BUNDLE_ENTRY_OVERHEAD>() {
+            let (packet, rest) = rest.split_at_checked(usize::from(u16::from_be_bytes(*len)))?;
+            packets.push(packet.to_vec());
+            data = rest;
+        }
+        data.is_empty().then_some(BundlePacket::new(packets))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundle_round_trip() {
+        let packets = vec![
+            GamePacket::new(
+                MessageType::ChatMessage,
+                1,
+                b"hi".to_vec(),
+                vec![1; PLAYER_ID_LEN],
+            )
+            .serialize(),
+            GamePacket::new(
+                MessageType::PlayerLeft,
+                2,
+                vec![2; PLAYER_ID_LEN],
+                vec![1; PLAYER_ID_LEN],
+            )
+            .serialize(),
+        ];
+        let bundle = BundlePacket::new(packets.clone());
+        let datagram = bundle.to_datagram();
+        assert_eq!(datagram.len(), BundlePacket::size_with(&packets));
+        let outer = GamePacket::deserialize(&datagram).unwrap();
+        assert_eq!(outer.msg_type, MessageType::Bundle);
+        let unpacked = BundlePacket::deserialize(&outer.payload).unwrap();
+        assert_eq!(unpacked, bundle);
+        assert_eq!(
+            GamePacket::deserialize(&unpacked.packets[1])
+                .unwrap()
+                .seq_num,
+            2
+        );
+        assert_eq!(BundlePacket::deserialize(&outer.payload[..5]), None);
+        assert_eq!(
+            BundlePacket::deserialize(&[]),
+            Some(BundlePacket::default())
+        );
+    }
+}
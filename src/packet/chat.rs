@@ -0,0 +1,115 @@
+use crate::game_state::PLAYER_ID_LEN;
+
+/// Default limit on a chat payload, sender id included.
+///
+/// Chat is fanned out to every other player, so this bounds the amplification of a single packet.
+pub const DEFAULT_MAX_CHAT_PAYLOAD: usize = 256;
+
+/// A chat line and the player that sent it.
+///
+/// Payload layout: 18 byte sender id followed by the UTF-8 message. Clients may leave
+/// the sender id zeroed, the server fills in the id registered for their address.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChatPacket {
+    pub sender_id: Vec<u8>,
+    pub message: String,
+}
+
+impl ChatPacket {
+    #[must_use]
+    pub fn new(sender_id: Vec<u8>, message: String) -> Self {
+        ChatPacket { sender_id, message }
+    }
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.sender_id.len().saturating_add(self.message.len()));
+        buf.extend_from_slice(&self.sender_id);
+        buf.extend_from_slice(self.message.as_bytes());
+        buf
+    }
+    /// Returns `None` if `data` is longer than `max_payload`, the sender id is truncated
+    /// or the message isn't valid UTF-8.
+    #[must_use]
+    pub fn deserialize(data: &[u8], max_payload: usize) -> Option<ChatPacket> {
+        if data.len() > max_payload {
+            return None;
+        }
+        let (sender_id, message) = data.split_at_checked(PLAYER_ID_LEN)?;
+        let message = String::from_utf8(message.to_vec()).ok()?;
+        Some(ChatPacket::new(sender_id.to_vec(), message))
+    }
+}
+
+/// A chat line for a single player.
+///
+/// Payload layout: 18 byte player id followed by the UTF-8 message. From a client the id
+/// is the recipient's, the server forwards the whisper to them with the sender's id in
+/// its place. Limited to [`DEFAULT_MAX_CHAT_PAYLOAD`] like chat.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WhisperPacket {
+    pub player_id: Vec<u8>,
+    pub message: String,
+}
+
+impl WhisperPacket {
+    #[must_use]
+    pub fn new(player_id: Vec<u8>, message: String) -> Self {
+        WhisperPacket { player_id, message }
+    }
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        ChatPacket::new(self.player_id.clone(), self.message.clone()).serialize()
+    }
+    /// Returns `None` in the same cases as [`ChatPacket::deserialize`].
+    #[must_use]
+    pub fn deserialize(data: &[u8], max_payload: usize) -> Option<WhisperPacket> {
+        let chat = ChatPacket::deserialize(data, max_payload)?;
+        Some(WhisperPacket::new(chat.sender_id, chat.message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_round_trip() {
+        let chat = ChatPacket::new(vec![3; PLAYER_ID_LEN], "hello".to_string());
+        let decoded = ChatPacket::deserialize(&chat.serialize(), DEFAULT_MAX_CHAT_PAYLOAD).unwrap();
+        assert_eq!(decoded.sender_id, vec![3; PLAYER_ID_LEN]);
+        assert_eq!(decoded.message, "hello");
+        assert!(
+            ChatPacket::deserialize(&[0; PLAYER_ID_LEN - 1], DEFAULT_MAX_CHAT_PAYLOAD).is_none()
+        );
+    }
+
+    #[test]
+    fn test_oversize_chat_is_rejected() {
+        let chat = ChatPacket::new(
+            vec![3; PLAYER_ID_LEN],
+            "a".repeat(DEFAULT_MAX_CHAT_PAYLOAD - PLAYER_ID_LEN),
+        );
+        assert!(ChatPacket::deserialize(&chat.serialize(), DEFAULT_MAX_CHAT_PAYLOAD).is_some());
+        let chat = ChatPacket::new(
+            vec![3; PLAYER_ID_LEN],
+            "a".repeat(DEFAULT_MAX_CHAT_PAYLOAD - PLAYER_ID_LEN + 1),
+        );
+        assert!(ChatPacket::deserialize(&chat.serialize(), DEFAULT_MAX_CHAT_PAYLOAD).is_none());
+    }
+
+    #[test]
+    fn test_whisper_round_trip() {
+        let whisper = WhisperPacket::new(vec![5; PLAYER_ID_LEN], "psst".to_string());
+        let decoded =
+            WhisperPacket::deserialize(&whisper.serialize(), DEFAULT_MAX_CHAT_PAYLOAD).unwrap();
+        assert_eq!(decoded.player_id, vec![5; PLAYER_ID_LEN]);
+        assert_eq!(decoded.message, "psst");
+        let oversize =
+            WhisperPacket::new(vec![5; PLAYER_ID_LEN], "a".repeat(DEFAULT_MAX_CHAT_PAYLOAD));
+        assert!(
+            WhisperPacket::deserialize(&oversize.serialize(), DEFAULT_MAX_CHAT_PAYLOAD).is_none()
+        );
+    }
+}
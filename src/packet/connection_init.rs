@@ -1,8 +1,217 @@
-use crate::game_state::{Player, Position};
+use crate::{
+    game_state::{
+        Player, PlayerId, Position, RoomId, MAX_ROOM_ID_LEN, PLAYER_ID_LEN, POSITION_F64_SIZE,
+    },
+    num::{f32_to_coord, record_capacity},
+};
+
+use super::{
+    crypto::{PublicKey, PUBLIC_KEY_LEN},
+    features::Features,
+    position::{POSITION_RECORD_F64_SIZE, POSITION_RECORD_SIZE},
+    sizes::MIN_PLAYER_JOIN_PAYLOAD,
+    world::{WorldInfo, WORLD_INFO_SIZE},
+    GamePacket, MessageType,
+};
+
+pub const RECONNECT_TOKEN_LEN: usize = 16;
+/// Random secret handed to a client on connect, used to reclaim its player after
+/// its address changes.
+pub type ReconnectToken = [u8; RECONNECT_TOKEN_LEN];
+pub const CHALLENGE_NONCE_LEN: usize = 8;
+/// Random value a client has to echo back in a second `ConnectionInit` before it
+/// gets a player, proving it can receive at the address it sends from.
+pub type ChallengeNonce = [u8; CHALLENGE_NONCE_LEN];
+
+/// Longest display name a client may pick, in bytes.
+pub const MAX_DISPLAY_NAME_LEN: usize = 24;
+/// Starts the optional display name section of a `ConnectionInit` payload.
+const NAME_MARKER: u8 = 0x00;
+/// Starts the optional feature flags section of a `ConnectionInit` payload.
+const FEATURES_MARKER: u8 = 0x01;
+/// Starts the optional requested player id section of a `ConnectionInit` payload.
+const PLAYER_ID_MARKER: u8 = 0x02;
+/// Starts the optional key exchange section of a `ConnectionInit` payload.
+const PUBLIC_KEY_MARKER: u8 = 0x03;
+
+/// Reads the room id a client's `ConnectionInit` payload carries after the challenge
+/// nonce, if any. Empty selects the default room. Returns `None` for ids that are too
+/// long, not UTF-8 or contain control characters.
+#[must_use]
+pub fn parse_room_id(data: &[u8]) -> Option<RoomId> {
+    if data.len() > MAX_ROOM_ID_LEN {
+        return None;
+    }
+    String::from_utf8(data.to_vec())
+        .ok()
+        .filter(|room| !room.chars().any(char::is_control))
+}
+
+/// Validates a client chosen display name. Surrounding whitespace is trimmed; names
+/// longer than [`MAX_DISPLAY_NAME_LEN`], empty or containing control characters are
+/// rejected.
+#[must_use]
+pub fn parse_display_name(data: &[u8]) -> Option<String> {
+    let name = std::str::from_utf8(data).ok()?.trim();
+    if name.is_empty() || name.len() > MAX_DISPLAY_NAME_LEN || name.chars().any(char::is_control) {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// Validates a player id a client asks to rejoin as: 18 bytes from the alphabet of the
+/// ids the server generates, ASCII letters, digits, `_` and `-`.
+#[must_use]
+pub fn parse_player_id(data: &[u8]) -> Option<PlayerId> {
+    if data.len() != PLAYER_ID_LEN
+        || !data
+            .iter()
+            .all(|&b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+    {
+        return None;
+    }
+    String::from_utf8(data.to_vec()).ok()
+}
+
+/// What a client asks for in its `ConnectionInit`, after the challenge nonce if any.
+///
+/// Payload layout: the room id, then optional sections each starting with a marker byte:
+/// a zero byte followed by the length prefixed display name, a one byte followed by
+/// the big endian [`Features`] the client supports, a two byte followed by the 18
+/// byte player id the client asks to rejoin as, and a three byte followed by the
+/// client's 32 byte X25519 public key for encryption. An empty payload joins the
+/// default room unnamed, without negotiating features.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionInitRequest {
+    pub room: RoomId,
+    pub name: Option<String>,
+    /// Features the client supports, `None` for clients predating negotiation.
+    pub features: Option<Features>,
+    /// Id the client persisted from an earlier session and asks to play as again.
+    pub requested_id: Option<PlayerId>,
+    /// Client's half of the key exchange, needed to negotiate [`Features::ENCRYPTION`].
+    pub public_key: Option<PublicKey>,
+}
+impl ConnectionInitRequest {
+    #[must_use]
+    pub fn new(room: RoomId, name: Option<String>) -> Self {
+        ConnectionInitRequest {
+            room,
+            name,
+            features: None,
+            requested_id: None,
+            public_key: None,
+        }
+    }
+    /// Advertises `features` to the server.
+    #[must_use]
+    pub fn with_features(mut self, features: Features) -> Self {
+        self.features = Some(features);
+        self
+    }
+    /// Asks to play as `id`, see [`ConnectionInitRequest::requested_id`].
+    #[must_use]
+    pub fn with_requested_id(mut self, id: PlayerId) -> Self {
+        self.requested_id = Some(id);
+        self
+    }
+    /// Starts a key exchange with the client's `public_key`.
+    #[must_use]
+    pub fn with_public_key(mut self, public_key: PublicKey) -> Self {
+        self.public_key = Some(public_key);
+        self
+    }
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = self.room.as_bytes().to_vec();
+        if let Some(name) = &self.name {
+            buf.push(NAME_MARKER);
+            buf.push(u8::try_from(name.len()).unwrap_or(u8::MAX));
+            buf.extend_from_slice(name.as_bytes());
+        }
+        if let Some(features) = self.features {
+            buf.push(FEATURES_MARKER);
+            buf.extend_from_slice(&features.to_be_bytes());
+        }
+        if let Some(id) = &self.requested_id {
+            buf.push(PLAYER_ID_MARKER);
+            buf.extend_from_slice(id.as_bytes());
+        }
+        if let Some(public_key) = &self.public_key {
+            buf.push(PUBLIC_KEY_MARKER);
+            buf.extend_from_slice(public_key);
+        }
+        buf
+    }
+    /// Returns `None` for an invalid room id, display name or player id, a truncated
+    /// section or an unknown marker.
+    #[must_use]
+    pub fn deserialize(data: &[u8]) -> Option<ConnectionInitRequest> {
+        // Room ids have no control characters, so the first marker ends the room
+        let end = data
+            .iter()
+            .position(|&b| {
+                matches!(
+                    b,
+                    NAME_MARKER | FEATURES_MARKER | PLAYER_ID_MARKER | PUBLIC_KEY_MARKER
+                )
+            })
+            .unwrap_or(data.len());
+        let mut request = ConnectionInitRequest::new(parse_room_id(&data[..end])?, None);
+        let mut rest = &data[end..];
+        while let Some((&marker, section)) = rest.split_first() {
+            match marker {
+                NAME_MARKER => {
+                    // A lone marker carries no name
+                    let Some((&len, name)) = section.split_first() else {
+                        break;
+                    };
+                    let (name, remaining) = name.split_at_checked(usize::from(len))?;
+                    request.name = Some(parse_display_name(name)?);
+                    rest = remaining;
+                }
+                FEATURES_MARKER => {
+                    let (features, remaining) = section.split_first_chunk::<4>()?;
+                    request.features = Some(Features::from_be_bytes(*features));
+                    rest = remaining;
+                }
+                PLAYER_ID_MARKER => {
+                    let (id, remaining) = section.split_at_checked(PLAYER_ID_LEN)?;
+                    request.requested_id = Some(parse_player_id(id)?);
+                    rest = remaining;
+                }
+                PUBLIC_KEY_MARKER => {
+                    let (public_key, remaining) = section.split_first_chunk::<PUBLIC_KEY_LEN>()?;
+                    request.public_key = Some(*public_key);
+                    rest = remaining;
+                }
+                _ => return None,
+            }
+        }
+        Some(request)
+    }
+}
+
+/// Appends `name` length prefixed, or a zero length for unnamed players.
+fn put_name(buf: &mut Vec<u8>, name: Option<&str>) {
+    let name = name.unwrap_or_default().as_bytes();
+    buf.push(u8::try_from(name.len()).unwrap_or(u8::MAX));
+    buf.extend_from_slice(name.get(..usize::from(u8::MAX)).unwrap_or(name));
+}
+
+/// Reads a name written by [`put_name`], `None` for a zero length.
+pub(crate) fn take_name(data: &[u8]) -> Option<String> {
+    let (&len, rest) = data.split_first()?;
+    let name = rest.get(..usize::from(len))?;
+    (!name.is_empty())
+        .then(|| String::from_utf8(name.to_vec()).ok())
+        .flatten()
+}
 
-use super::{GamePacket, MessageType};
 #[derive(Debug)]
 #[allow(clippy::module_name_repetitions)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConnectionInitPacketReceived {
     pub msg_type: MessageType,
     pub version: u8,
@@ -26,69 +235,478 @@ impl ConnectionInitPacketReceived {
     }
 }
 #[allow(clippy::module_name_repetitions)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConnectionInitPacketSent {
     pub msg_type: MessageType,
     pub version: u8,
     pub seq_num: u32,
     pub client_id: Vec<u8>,
+    pub reconnect_token: ReconnectToken,
+    pub world: WorldInfo,
     pub players: Vec<Player>,
+    /// Whether every player record is followed by the player's length prefixed name.
+    /// Only set for clients that sent a name themselves, older clients expect fixed
+    /// size records.
+    pub with_names: bool,
+    /// Features the server uses for the session, sent after the [`WorldInfo`]. Only set
+    /// for clients that advertised features themselves.
+    pub features: Option<Features>,
+    /// Whether the id the client asked for was honored, `false` if it got a fresh one
+    /// instead. Only set for clients that asked for an id.
+    pub requested_id_honored: Option<bool>,
+    /// Server's half of the key exchange, set when [`Features::ENCRYPTION`] was
+    /// negotiated.
+    pub public_key: Option<PublicKey>,
+    /// Whether player positions are in the 16 byte format of
+    /// [`Position::serialize_f64`], set when [`Features::F64_POSITIONS`] was negotiated.
+    pub f64_positions: bool,
 }
 
 impl ConnectionInitPacketSent {
+    /// Payload layout: the 16 byte reconnect token, the [`WorldInfo`], the negotiated
+    /// [`Features`] if [`ConnectionInitPacketSent::features`] is set, a one byte if
+    /// the requested id was honored or zero if not when
+    /// [`ConnectionInitPacketSent::requested_id_honored`] is set, the server's 32 byte
+    /// public key if [`ConnectionInitPacketSent::public_key`] is set, then an
+    /// `(id, position)` record for every other player, each followed by its name
+    /// when [`ConnectionInitPacketSent::with_names`] is set. Positions are 16 bytes
+    /// instead of 8 when [`ConnectionInitPacketSent::f64_positions`] is set.
     #[must_use]
     pub fn serialize(&self) -> GamePacket {
-        #[allow(clippy::arithmetic_side_effects)]
-        let mut buf = Vec::with_capacity(18 * self.players.len());
+        let record_size = if self.f64_positions {
+            POSITION_RECORD_F64_SIZE
+        } else {
+            POSITION_RECORD_SIZE
+        };
+        let mut buf = Vec::with_capacity(record_capacity(
+            RECONNECT_TOKEN_LEN.saturating_add(WORLD_INFO_SIZE),
+            record_size,
+            self.players.len(),
+        ));
+        buf.extend_from_slice(&self.reconnect_token);
+        buf.extend_from_slice(&self.world.serialize());
+        if let Some(features) = self.features {
+            buf.extend_from_slice(&features.to_be_bytes());
+        }
+        if let Some(honored) = self.requested_id_honored {
+            buf.push(u8::from(honored));
+        }
+        if let Some(public_key) = &self.public_key {
+            buf.extend_from_slice(public_key);
+        }
         for player in &self.players {
             buf.extend_from_slice(player.id.as_bytes());
-            buf.extend_from_slice(&player.position.serialize());
+            if self.f64_positions {
+                buf.extend_from_slice(&player.position.serialize_f64());
+            } else {
+                buf.extend_from_slice(&player.position.serialize());
+            }
+            if self.with_names {
+                put_name(&mut buf, player.name.as_deref());
+            }
         }
 
         GamePacket::new(self.msg_type, self.seq_num, buf, self.client_id.clone())
     }
     #[must_use]
-    pub fn new(seq_num: u32, client_id: Vec<u8>, players: Vec<Player>) -> Self {
+    pub fn new(
+        seq_num: u32,
+        client_id: Vec<u8>,
+        reconnect_token: ReconnectToken,
+        world: WorldInfo,
+        players: Vec<Player>,
+    ) -> Self {
         ConnectionInitPacketSent {
             msg_type: MessageType::ConnectionInit,
             version: 1,
             seq_num,
             client_id,
+            reconnect_token,
+            world,
             players,
+            with_names: false,
+            features: None,
+            requested_id_honored: None,
+            public_key: None,
+            f64_positions: false,
+        }
+    }
+    /// Includes the players' names in the player list.
+    #[must_use]
+    pub fn with_names(mut self) -> Self {
+        self.with_names = true;
+        self
+    }
+    /// Tells the client which features the session uses.
+    #[must_use]
+    pub fn with_features(mut self, features: Features) -> Self {
+        self.features = Some(features);
+        self
+    }
+    /// Sends player positions in the 16 byte `f64` format.
+    #[must_use]
+    pub fn with_f64_positions(mut self) -> Self {
+        self.f64_positions = true;
+        self
+    }
+    /// Tells the client whether it got the id it asked for.
+    #[must_use]
+    pub fn with_requested_id_honored(mut self, honored: bool) -> Self {
+        self.requested_id_honored = Some(honored);
+        self
+    }
+    /// Completes the client's key exchange with the server's `public_key`.
+    #[must_use]
+    pub fn with_public_key(mut self, public_key: PublicKey) -> Self {
+        self.public_key = Some(public_key);
+        self
+    }
+    /// Decodes the server's answer to `request`. Which optional sections the payload
+    /// holds follows from what the request asked for and the negotiated features, see
+    /// [`ConnectionInitPacketSent::serialize`]. Only the id, position and name of the
+    /// decoded players are set.
+    ///
+    /// Returns `None` for another message type, a payload cut short or a trailing
+    /// partial player record.
+    #[must_use]
+    pub fn deserialize(
+        packet: &GamePacket,
+        request: &ConnectionInitRequest,
+    ) -> Option<ConnectionInitPacketSent> {
+        if packet.msg_type != MessageType::ConnectionInit {
+            return None;
+        }
+        let (reconnect_token, rest) = packet.payload.split_first_chunk::<RECONNECT_TOKEN_LEN>()?;
+        let (world, mut rest) = rest.split_at_checked(WORLD_INFO_SIZE)?;
+        let mut response = ConnectionInitPacketSent::new(
+            packet.seq_num,
+            packet.client_id.clone(),
+            *reconnect_token,
+            WorldInfo::deserialize(world)?,
+            Vec::new(),
+        );
+        response.version = packet.version;
+        response.with_names = request.name.is_some();
+        if request.features.is_some() {
+            let (features, tail) = rest.split_first_chunk::<4>()?;
+            response.features = Some(Features::from_be_bytes(*features));
+            rest = tail;
+        }
+        if request.requested_id.is_some() {
+            let (honored, tail) = rest.split_first()?;
+            response.requested_id_honored = Some(*honored != 0);
+            rest = tail;
+        }
+        let negotiated = response.features.unwrap_or_default();
+        if negotiated.contains(Features::ENCRYPTION) {
+            let (public_key, tail) = rest.split_first_chunk::<PUBLIC_KEY_LEN>()?;
+            response.public_key = Some(*public_key);
+            rest = tail;
+        }
+        response.f64_positions = negotiated.contains(Features::F64_POSITIONS);
+        while !rest.is_empty() {
+            let (id, tail) = rest.split_at_checked(PLAYER_ID_LEN)?;
+            let (position, tail) = if response.f64_positions {
+                let (position, tail) = tail.split_at_checked(POSITION_F64_SIZE)?;
+                (Position::deserialize_f64(position)?, tail)
+            } else {
+                // The server writes positions big endian, see `Position::serialize`.
+                let (x, tail) = tail.split_first_chunk::<4>()?;
+                let (y, tail) = tail.split_first_chunk::<4>()?;
+                let x = f32_to_coord(f32::from_be_bytes(*x));
+                let y = f32_to_coord(f32::from_be_bytes(*y));
+                (Position::new(x, y), tail)
+            };
+            rest = tail;
+            let mut name = None;
+            if response.with_names {
+                let (&len, _) = rest.split_first()?;
+                name = take_name(rest);
+                rest = rest.get(usize::from(len).saturating_add(1)..)?;
+            }
+            response.players.push(Player {
+                id: String::from_utf8(id.to_vec()).ok()?,
+                position,
+                name,
+                ..Player::default()
+            });
         }
+        Some(response)
     }
 }
 
+/// Sent by a client whose address changed to reclaim its player.
 #[derive(Debug, Clone)]
-#[allow(clippy::module_name_repetitions)]
-pub struct ConnectionInitSync {
-    client_id: Vec<u8>,
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReconnectPacket {
+    pub token: ReconnectToken,
+}
+impl ReconnectPacket {
+    #[must_use]
+    pub fn new(token: ReconnectToken) -> Self {
+        ReconnectPacket { token }
+    }
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        self.token.to_vec()
+    }
+    #[must_use]
+    pub fn deserialize(data: &[u8]) -> Option<ReconnectPacket> {
+        let token = data.get(..RECONNECT_TOKEN_LEN)?.try_into().ok()?;
+        Some(ReconnectPacket { token })
+    }
+}
+
+/// Challenge sent in reply to a first `ConnectionInit` when handshakes are required,
+/// and echoed back by the client as the payload of its second `ConnectionInit`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChallengePacket {
+    pub nonce: ChallengeNonce,
+}
+impl ChallengePacket {
+    #[must_use]
+    pub fn new(nonce: ChallengeNonce) -> Self {
+        ChallengePacket { nonce }
+    }
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        self.nonce.to_vec()
+    }
+    #[must_use]
+    pub fn deserialize(data: &[u8]) -> Option<ChallengePacket> {
+        let nonce = data.get(..CHALLENGE_NONCE_LEN)?.try_into().ok()?;
+        Some(ChallengePacket { nonce })
+    }
+}
+
+/// Sent to every existing player when someone joins.
+///
+/// The header carries the recipient's id, the payload carries the joining player's
+/// 18 byte id followed by their spawn position and, if they picked one, their length
+/// prefixed display name.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PlayerJoinPacket {
+    pub msg_type: MessageType,
+    pub version: u8,
+    pub seq_num: u32,
+    pub client_id: Vec<u8>,
+    pub player_id: Vec<u8>,
     pub position: Position,
+    pub name: Option<String>,
 }
-impl ConnectionInitSync {
+impl PlayerJoinPacket {
     #[must_use]
-    pub fn new(client_id: Vec<u8>, position: Position) -> Self {
-        ConnectionInitSync {
+    pub fn new(seq_num: u32, client_id: Vec<u8>, player_id: Vec<u8>, position: Position) -> Self {
+        PlayerJoinPacket {
+            msg_type: MessageType::PlayerJoin,
+            version: 1,
+            seq_num,
             client_id,
+            player_id,
             position,
+            name: None,
         }
     }
+    /// Announces the joining player under `name`.
     #[must_use]
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(18 + 8);
-        buf.extend_from_slice(&self.client_id);
+    pub fn with_name(mut self, name: Option<String>) -> Self {
+        self.name = name;
+        self
+    }
+    #[must_use]
+    pub fn serialize(&self) -> GamePacket {
+        let mut buf = Vec::with_capacity(MIN_PLAYER_JOIN_PAYLOAD);
+        buf.extend_from_slice(&self.player_id);
         buf.extend_from_slice(&self.position.serialize());
-        buf
+        if self.name.is_some() {
+            put_name(&mut buf, self.name.as_deref());
+        }
+        GamePacket::new(self.msg_type, self.seq_num, buf, self.client_id.clone())
     }
+    /// Decodes a `PlayerJoin` packet as sent by the server.
     #[must_use]
-    pub fn deserialize(data: &[u8]) -> Option<ConnectionInitSync> {
-        if data.len() < 26 {
+    pub fn deserialize(packet: &GamePacket) -> Option<PlayerJoinPacket> {
+        if packet.msg_type != MessageType::PlayerJoin
+            || packet.payload.len() < MIN_PLAYER_JOIN_PAYLOAD
+        {
             return None;
         }
-        let client_id = data[..18].to_vec();
-        let position = Position::deserialize(&data[18..])?;
-        Some(ConnectionInitSync {
-            client_id,
-            position,
+        let data = &packet.payload;
+        let (player_id, position) = data.split_at(PLAYER_ID_LEN);
+        // The server writes positions big endian, see `Position::serialize`.
+        let (x, y) = position.split_first_chunk::<4>()?;
+        let x = f32::from_be_bytes(*x);
+        let y = f32::from_be_bytes(*y.first_chunk::<4>()?);
+        Some(PlayerJoinPacket {
+            msg_type: packet.msg_type,
+            version: packet.version,
+            seq_num: packet.seq_num,
+            client_id: packet.client_id.clone(),
+            player_id: player_id.to_vec(),
+            position: Position::new(f32_to_coord(x), f32_to_coord(y)),
+            name: take_name(&data[MIN_PLAYER_JOIN_PAYLOAD..]),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_round_trip() {
+        let request = ConnectionInitRequest::new("red".to_string(), Some("Alice".to_string()));
+        assert_eq!(
+            ConnectionInitRequest::deserialize(&request.serialize()),
+            Some(request)
+        );
+        assert_eq!(
+            ConnectionInitRequest::deserialize(b"red"),
+            Some(ConnectionInitRequest::new("red".to_string(), None))
+        );
+        assert_eq!(
+            ConnectionInitRequest::deserialize(&[]),
+            Some(ConnectionInitRequest::default())
+        );
+    }
+
+    #[test]
+    fn test_invalid_names_are_rejected() {
+        let too_long = "a".repeat(MAX_DISPLAY_NAME_LEN + 1);
+        for name in [too_long.as_str(), "bad\nname", "   "] {
+            let data =
+                ConnectionInitRequest::new(String::new(), Some(name.to_string())).serialize();
+            assert_eq!(ConnectionInitRequest::deserialize(&data), None, "{name:?}");
+        }
+        // Truncated name
+        assert_eq!(
+            ConnectionInitRequest::deserialize(&[NAME_MARKER, 5, b'a']),
+            None
+        );
+        assert_eq!(parse_display_name(b"  Bob "), Some("Bob".to_string()));
+    }
+
+    #[test]
+    fn test_request_carries_features() {
+        let named = ConnectionInitRequest::new("red".to_string(), Some("Alice".to_string()))
+            .with_features(Features::COMPRESSION | Features::CHECKSUM);
+        let unnamed =
+            ConnectionInitRequest::new(String::new(), None).with_features(Features::DELTA_ENCODING);
+        for request in [named, unnamed] {
+            assert_eq!(
+                ConnectionInitRequest::deserialize(&request.serialize()),
+                Some(request)
+            );
+        }
+        // Truncated features and unknown sections
+        assert_eq!(
+            ConnectionInitRequest::deserialize(&[FEATURES_MARKER, 0, 1]),
+            None
+        );
+        assert_eq!(ConnectionInitRequest::deserialize(&[0x04, 1]), None);
+    }
+
+    #[test]
+    fn test_request_carries_validated_player_id() {
+        let request = ConnectionInitRequest::new("red".to_string(), Some("Alice".to_string()))
+            .with_features(Features::CHECKSUM)
+            .with_requested_id("V1StGXR8_Z5jdHi6B-".to_string())
+            .with_public_key([9; PUBLIC_KEY_LEN]);
+        assert_eq!(
+            ConnectionInitRequest::deserialize(&request.serialize()),
+            Some(request)
+        );
+        for id in ["short", "V1StGXR8_Z5jdHi6B-x", "V1StGXR8 Z5jdHi6B-"] {
+            let mut data = vec![PLAYER_ID_MARKER];
+            data.extend_from_slice(id.as_bytes());
+            assert_eq!(ConnectionInitRequest::deserialize(&data), None, "{id:?}");
+        }
+    }
+
+    #[test]
+    fn test_player_join_carries_name() {
+        let join = PlayerJoinPacket::new(
+            1,
+            vec![0; PLAYER_ID_LEN],
+            vec![1; PLAYER_ID_LEN],
+            Position::new(1.0, 2.0),
+        )
+        .with_name(Some("Alice".to_string()));
+        let decoded = PlayerJoinPacket::deserialize(&join.serialize()).unwrap();
+        assert_eq!(decoded.name.as_deref(), Some("Alice"));
+
+        let unnamed = PlayerJoinPacket::new(
+            1,
+            vec![0; PLAYER_ID_LEN],
+            vec![1; PLAYER_ID_LEN],
+            Position::new(1.0, 2.0),
+        );
+        assert_eq!(unnamed.serialize().payload.len(), 26);
+        assert_eq!(
+            PlayerJoinPacket::deserialize(&unnamed.serialize())
+                .unwrap()
+                .name,
+            None
+        );
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let players = vec![
+            Player {
+                id: "a".repeat(PLAYER_ID_LEN),
+                position: Position::new(1.0, 2.0),
+                name: Some("Alice".to_string()),
+                ..Player::default()
+            },
+            Player {
+                id: "b".repeat(PLAYER_ID_LEN),
+                position: Position::new(3.0, -4.0),
+                ..Player::default()
+            },
+        ];
+        let world = WorldInfo::new(800, 600, Position::new(10.0, 20.0));
+        let request = ConnectionInitRequest::new(String::new(), Some("Carol".to_string()))
+            .with_features(Features::CHECKSUM)
+            .with_requested_id("c".repeat(PLAYER_ID_LEN));
+        let response =
+            ConnectionInitPacketSent::new(7, vec![b'c'; PLAYER_ID_LEN], [9; 16], world, players)
+                .with_names()
+                .with_features(Features::CHECKSUM)
+                .with_requested_id_honored(true);
+
+        let decoded =
+            ConnectionInitPacketSent::deserialize(&response.serialize(), &request).unwrap();
+        assert_eq!(decoded.reconnect_token, [9; 16]);
+        assert_eq!(decoded.world.spawn, Position::new(10.0, 20.0));
+        assert_eq!(decoded.features, Some(Features::CHECKSUM));
+        assert_eq!(decoded.requested_id_honored, Some(true));
+        let players = decoded
+            .players
+            .iter()
+            .map(|p| (p.id.as_str(), p.position.clone(), p.name.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            players,
+            vec![
+                (
+                    "a".repeat(PLAYER_ID_LEN).as_str(),
+                    Position::new(1.0, 2.0),
+                    Some("Alice")
+                ),
+                (
+                    "b".repeat(PLAYER_ID_LEN).as_str(),
+                    Position::new(3.0, -4.0),
+                    None
+                ),
+            ]
+        );
+
+        // A trailing partial record is rejected rather than dropped
+        let mut truncated = response.serialize();
+        truncated.payload.pop();
+        assert!(ConnectionInitPacketSent::deserialize(&truncated, &request).is_none());
+    }
+}
@@ -0,0 +1,271 @@
+//! Optional payload encryption, negotiated with [`Features::ENCRYPTION`].
+//!
+//! The client sends an X25519 public key in its `ConnectionInit`, the server answers with
+//! its own and both derive a per session ChaCha20-Poly1305 key. Every later datagram of
+//! the session has its payload sealed: a random nonce followed by the ciphertext and tag,
+//! with [`FLAG_ENCRYPTED`] set and the header authenticated as associated data. Needs the
+//! `crypto` feature, without it nothing is negotiated and every session stays plaintext.
+//!
+//! [`Features::ENCRYPTION`]: super::features::Features::ENCRYPTION
+
+use super::{FLAG_CHECKSUM, FLAG_ENCRYPTED, HEADER_SIZE};
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+/// X25519 public key exchanged in `ConnectionInit`.
+pub type PublicKey = [u8; PUBLIC_KEY_LEN];
+/// Random nonce in front of every sealed payload.
+pub const NONCE_LEN: usize = 12;
+/// Authentication tag after every sealed payload.
+pub const TAG_LEN: usize = 16;
+/// Bytes sealing adds to a datagram.
+pub const SEAL_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+const CHECKSUM_SIZE: usize = 4;
+
+/// Symmetric key of an encrypted session, stored on the `Player`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SessionKey(pub [u8; 32]);
+
+/// Keeps the key out of logs.
+impl std::fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SessionKey(..)")
+    }
+}
+
+/// `datagram` without its trailing checksum, `None` if the checksum doesn't match.
+fn body(datagram: &[u8]) -> Option<&[u8]> {
+    let version = *datagram.get(1)?;
+    if version & FLAG_CHECKSUM == 0 {
+        return Some(datagram);
+    }
+    let (body, checksum) = datagram.split_at_checked(datagram.len().checked_sub(CHECKSUM_SIZE)?)?;
+    (crc32fast::hash(body) == u32::from_be_bytes(checksum.try_into().ok()?)).then_some(body)
+}
+
+/// Appends a checksum to `datagram` if its header asks for one.
+fn finish(mut datagram: Vec<u8>) -> Vec<u8> {
+    if datagram
+        .get(1)
+        .is_some_and(|version| version & FLAG_CHECKSUM != 0)
+    {
+        let checksum = crc32fast::hash(&datagram);
+        datagram.extend_from_slice(&checksum.to_be_bytes());
+    }
+    datagram
+}
+
+#[cfg(feature = "crypto")]
+mod cipher {
+    use chacha20poly1305::{
+        aead::{Aead, KeyInit, Payload},
+        ChaCha20Poly1305, Nonce,
+    };
+    use hkdf::Hkdf;
+    use sha2::Sha256;
+    use x25519_dalek::EphemeralSecret;
+
+    use super::{PublicKey, SessionKey, NONCE_LEN};
+
+    /// One side of the key exchange.
+    pub struct KeyExchange {
+        secret: EphemeralSecret,
+        public: PublicKey,
+    }
+
+    impl KeyExchange {
+        #[must_use]
+        pub fn new() -> Self {
+            let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+            let public = x25519_dalek::PublicKey::from(&secret).to_bytes();
+            KeyExchange { secret, public }
+        }
+        /// Key to send to the other side.
+        #[must_use]
+        pub fn public_key(&self) -> PublicKey {
+            self.public
+        }
+        /// Derives the session key from the other side's public key. `client` is the
+        /// public key of the side that started the exchange, which may be this one.
+        /// Returns `None` for keys that would make the shared secret predictable.
+        #[must_use]
+        pub fn finish(self, theirs: &PublicKey, client: &PublicKey) -> Option<SessionKey> {
+            let server = if client == &self.public {
+                *theirs
+            } else {
+                self.public
+            };
+            let shared = self
+                .secret
+                .diffie_hellman(&x25519_dalek::PublicKey::from(*theirs));
+            if !shared.was_contributory() {
+                return None;
+            }
+            let salt = [client.as_slice(), server.as_slice()].concat();
+            let mut key = [0; 32];
+            Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+                .expand(b"server_dot session", &mut key)
+                .ok()?;
+            Some(SessionKey(key))
+        }
+    }
+
+    impl Default for KeyExchange {
+        fn default() -> Self {
+            KeyExchange::new()
+        }
+    }
+
+    pub(super) fn encrypt(key: &SessionKey, plaintext: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        let nonce = rand::random::<[u8; NONCE_LEN]>();
+        let ciphertext = ChaCha20Poly1305::new(&key.0.into())
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .ok()?;
+        Some([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    pub(super) fn decrypt(key: &SessionKey, sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        let (nonce, ciphertext) = sealed.split_at_checked(NONCE_LEN)?;
+        ChaCha20Poly1305::new(&key.0.into())
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+    }
+}
+
+#[cfg(feature = "crypto")]
+pub use cipher::KeyExchange;
+#[cfg(feature = "crypto")]
+use cipher::{decrypt, encrypt};
+#[cfg(not(feature = "crypto"))]
+fn encrypt(_key: &SessionKey, _plaintext: &[u8], _aad: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+#[cfg(not(feature = "crypto"))]
+fn decrypt(_key: &SessionKey, _sealed: &[u8], _aad: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+/// Answers a client's public key: the server's public key and the session key, `None`
+/// for an unusable client key or without the `crypto` feature.
+#[cfg(feature = "crypto")]
+#[must_use]
+pub fn respond(client: &PublicKey) -> Option<(PublicKey, SessionKey)> {
+    let exchange = KeyExchange::new();
+    let public = exchange.public_key();
+    Some((public, exchange.finish(client, client)?))
+}
+#[cfg(not(feature = "crypto"))]
+#[must_use]
+pub fn respond(_client: &PublicKey) -> Option<(PublicKey, SessionKey)> {
+    None
+}
+
+/// Encrypts the payload of a serialized `GamePacket`, keeping its checksum valid.
+/// Returns `None` for a truncated datagram or without the `crypto` feature.
+#[must_use]
+pub fn seal(key: &SessionKey, datagram: &[u8]) -> Option<Vec<u8>> {
+    let (header, payload) = body(datagram)?.split_at_checked(HEADER_SIZE)?;
+    let mut sealed = header.to_vec();
+    sealed[1] |= FLAG_ENCRYPTED;
+    let ciphertext = encrypt(key, payload, &sealed)?;
+    sealed.extend_from_slice(&ciphertext);
+    Some(finish(sealed))
+}
+
+/// Reverses [`seal`], returning the datagram with its plaintext payload. `None` if the
+/// datagram isn't sealed, was tampered with or sealed with another key, or without the
+/// `crypto` feature.
+#[must_use]
+pub fn open(key: &SessionKey, datagram: &[u8]) -> Option<Vec<u8>> {
+    let (header, sealed) = body(datagram)?.split_at_checked(HEADER_SIZE)?;
+    if header[1] & FLAG_ENCRYPTED == 0 {
+        return None;
+    }
+    let plaintext = decrypt(key, sealed, header)?;
+    let mut opened = header.to_vec();
+    opened[1] &= !FLAG_ENCRYPTED;
+    opened.extend_from_slice(&plaintext);
+    Some(finish(opened))
+}
+
+#[cfg(all(test, feature = "crypto"))]
+mod tests {
+    use super::*;
+    use crate::{
+        game_state::PLAYER_ID_LEN,
+        packet::{GamePacket, MessageType},
+    };
+
+    fn session() -> (SessionKey, SessionKey) {
+        let client = KeyExchange::new();
+        let client_public = client.public_key();
+        let (server_public, server_key) = respond(&client_public).unwrap();
+        let client_key = client.finish(&server_public, &client_public).unwrap();
+        (client_key, server_key)
+    }
+
+    #[test]
+    fn test_both_sides_derive_the_same_key() {
+        let (client_key, server_key) = session();
+        assert_eq!(client_key, server_key);
+        assert_ne!(session().0, client_key);
+        assert_eq!(format!("{client_key:?}"), "SessionKey(..)");
+        // An all zero key would make the shared secret known to everyone
+        assert!(respond(&[0; PUBLIC_KEY_LEN]).is_none());
+    }
+
+    #[test]
+    fn test_seal_and_open_round_trip() {
+        let (key, _) = session();
+        for packet in [
+            GamePacket::new(
+                MessageType::ChatMessage,
+                7,
+                b"hello".to_vec(),
+                vec![1; PLAYER_ID_LEN],
+            ),
+            GamePacket::new(MessageType::Heartbeat, 8, vec![], vec![1; PLAYER_ID_LEN])
+                .with_checksum(),
+        ] {
+            let datagram = packet.serialize();
+            let sealed = seal(&key, &datagram).unwrap();
+            assert_eq!(sealed.len(), datagram.len() + SEAL_OVERHEAD);
+            assert_ne!(sealed[1] & FLAG_ENCRYPTED, 0);
+            assert_eq!(open(&key, &sealed).unwrap(), datagram);
+            assert!(GamePacket::deserialize(&sealed).is_some());
+        }
+    }
+
+    #[test]
+    fn test_tampered_or_foreign_datagrams_do_not_open() {
+        let (key, _) = session();
+        let (other, _) = session();
+        let datagram = GamePacket::new(
+            MessageType::ChatMessage,
+            7,
+            b"hello".to_vec(),
+            vec![1; PLAYER_ID_LEN],
+        )
+        .serialize();
+        let sealed = seal(&key, &datagram).unwrap();
+        assert!(open(&other, &sealed).is_none());
+        assert!(open(&key, &datagram).is_none());
+        // The header is authenticated too
+        for index in [5, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 1;
+            assert!(open(&key, &tampered).is_none(), "{index}");
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{
+    game_state::{EntityId, EntityKind, PlayerId, Position, PLAYER_ID_LEN},
+    num::f32_to_coord,
+};
+
+use super::sizes::{MIN_ENTITY_DESPAWN_PAYLOAD, MIN_ENTITY_MOVE_PAYLOAD, MIN_ENTITY_SPAWN_PAYLOAD};
+
+/// Reads a big endian position, as the server writes them.
+fn position_be(data: &[u8]) -> Option<Position> {
+    let (x, y) = data.split_first_chunk::<4>()?;
+    let y = y.first_chunk::<4>()?;
+    Some(Position::new(
+        f32_to_coord(f32::from_be_bytes(*x)),
+        f32_to_coord(f32::from_be_bytes(*y)),
+    ))
+}
+
+/// A server tracked entity appeared, sent to every player and spectator.
+///
+/// Payload layout: big endian `u32` entity id, `u16` kind, `x` and `y`, then the 18 byte
+/// owner id, zeroed for entities without an owner.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EntitySpawnPacket {
+    pub entity_id: EntityId,
+    pub kind: EntityKind,
+    pub position: Position,
+    pub owner: Option<PlayerId>,
+}
+impl EntitySpawnPacket {
+    #[must_use]
+    pub fn new(
+        entity_id: EntityId,
+        kind: EntityKind,
+        position: Position,
+        owner: Option<PlayerId>,
+    ) -> Self {
+        EntitySpawnPacket {
+            entity_id,
+            kind,
+            position,
+            owner,
+        }
+    }
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MIN_ENTITY_SPAWN_PAYLOAD);
+        buf.extend_from_slice(&self.entity_id.to_be_bytes());
+        buf.extend_from_slice(&self.kind.to_be_bytes());
+        buf.extend_from_slice(&self.position.serialize());
+        let mut owner = [0; PLAYER_ID_LEN];
+        if let Some(id) = &self.owner {
+            for (byte, id_byte) in owner.iter_mut().zip(id.as_bytes()) {
+                *byte = *id_byte;
+            }
+        }
+        buf.extend_from_slice(&owner);
+        buf
+    }
+    #[must_use]
+    pub fn deserialize(data: &[u8]) -> Option<EntitySpawnPacket> {
+        let data = data.get(..MIN_ENTITY_SPAWN_PAYLOAD)?;
+        let (entity_id, rest) = data.split_first_chunk::<4>()?;
+        let (kind, rest) = rest.split_first_chunk::<2>()?;
+        let (position, owner) = rest.split_at_checked(8)?;
+        let owner = if owner.iter().all(|&byte| byte == 0) {
+            None
+        } else {
+            Some(String::from_utf8(owner.to_vec()).ok()?)
+        };
+        Some(EntitySpawnPacket::new(
+            EntityId::from_be_bytes(*entity_id),
+            EntityKind::from_be_bytes(*kind),
+            position_be(position)?,
+            owner,
+        ))
+    }
+}
+
+/// A server tracked entity moved.
+///
+/// Payload layout: big endian `u32` entity id, `x` and `y`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EntityMovePacket {
+    pub entity_id: EntityId,
+    pub position: Position,
+}
+impl EntityMovePacket {
+    #[must_use]
+    pub fn new(entity_id: EntityId, position: Position) -> Self {
+        EntityMovePacket {
+            entity_id,
+            position,
+        }
+    }
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MIN_ENTITY_MOVE_PAYLOAD);
+        buf.extend_from_slice(&self.entity_id.to_be_bytes());
+        buf.extend_from_slice(&self.position.serialize());
+        buf
+    }
+    #[must_use]
+    pub fn deserialize(data: &[u8]) -> Option<EntityMovePacket> {
+        let (entity_id, position) = data.split_first_chunk::<4>()?;
+        Some(EntityMovePacket::new(
+            EntityId::from_be_bytes(*entity_id),
+            position_be(position)?,
+        ))
+    }
+}
+
+/// A server tracked entity was removed. The payload is its big endian `u32` id.
+#[must_use]
+pub fn serialize_despawn(entity_id: EntityId) -> Vec<u8> {
+    entity_id.to_be_bytes().to_vec()
+}
+
+/// Reads the id of an `EntityDespawn` payload.
+#[must_use]
+pub fn deserialize_despawn(data: &[u8]) -> Option<EntityId> {
+    data.first_chunk::<MIN_ENTITY_DESPAWN_PAYLOAD>()
+        .map(|id| EntityId::from_be_bytes(*id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entity_packets_round_trip() {
+        let owned = EntitySpawnPacket::new(
+            7,
+            3,
+            Position::new(1.5, -2.0),
+            Some("a".repeat(PLAYER_ID_LEN)),
+        );
+        let data = owned.serialize();
+        assert_eq!(data.len(), MIN_ENTITY_SPAWN_PAYLOAD);
+        assert_eq!(EntitySpawnPacket::deserialize(&data), Some(owned));
+        let unowned = EntitySpawnPacket::new(8, 1, Position::new(0.0, 4.0), None);
+        assert_eq!(
+            EntitySpawnPacket::deserialize(&unowned.serialize()),
+            Some(unowned)
+        );
+        assert_eq!(EntitySpawnPacket::deserialize(&data[..31]), None);
+
+        let moved = EntityMovePacket::new(7, Position::new(10.0, 20.0));
+        let data = moved.serialize();
+        assert_eq!(data.len(), MIN_ENTITY_MOVE_PAYLOAD);
+        assert_eq!(EntityMovePacket::deserialize(&data), Some(moved));
+        assert_eq!(EntityMovePacket::deserialize(&data[..11]), None);
+
+        assert_eq!(deserialize_despawn(&serialize_despawn(7)), Some(7));
+        assert_eq!(deserialize_despawn(&[0; 3]), None);
+    }
+}
@@ -0,0 +1,136 @@
+/// Longest message an [`ErrorPacket`] carries, longer ones are cut at a character boundary.
+pub const MAX_ERROR_MESSAGE_LEN: usize = 64;
+
+/// Why the server rejected a request, see [`ErrorPacket`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ErrorCode {
+    /// The packet or its payload could not be parsed.
+    Malformed,
+    /// The message type is not handled by this server.
+    UnknownMessageType,
+    /// The sender has no player on the server.
+    NotConnected,
+    /// A position outside the world, the player was moved to the nearest valid position.
+    OutOfBounds,
+    /// The server already holds `ServerConfig::max_players` players.
+    ServerFull,
+    /// The request is well formed but not acceptable, e.g. an invalid room id.
+    InvalidRequest,
+    /// The sender is not allowed to do this, e.g. a spectator moving or a bad admin token.
+    Forbidden,
+    /// The payload is over a size limit.
+    TooLarge,
+    /// The request came too soon after the previous one.
+    Cooldown,
+    /// A code this build doesn't know, kept so newer servers don't break older clients.
+    Other(u8),
+}
+
+impl ErrorCode {
+    #[must_use]
+    pub fn from_byte(b: u8) -> ErrorCode {
+        match b {
+            0x01 => ErrorCode::Malformed,
+            0x02 => ErrorCode::UnknownMessageType,
+            0x03 => ErrorCode::NotConnected,
+            0x04 => ErrorCode::OutOfBounds,
+            0x05 => ErrorCode::ServerFull,
+            0x06 => ErrorCode::InvalidRequest,
+            0x07 => ErrorCode::Forbidden,
+            0x08 => ErrorCode::TooLarge,
+            0x09 => ErrorCode::Cooldown,
+            b => ErrorCode::Other(b),
+        }
+    }
+    #[must_use]
+    pub fn to_byte(self) -> u8 {
+        match self {
+            ErrorCode::Malformed => 0x01,
+            ErrorCode::UnknownMessageType => 0x02,
+            ErrorCode::NotConnected => 0x03,
+            ErrorCode::OutOfBounds => 0x04,
+            ErrorCode::ServerFull => 0x05,
+            ErrorCode::InvalidRequest => 0x06,
+            ErrorCode::Forbidden => 0x07,
+            ErrorCode::TooLarge => 0x08,
+            ErrorCode::Cooldown => 0x09,
+            ErrorCode::Other(b) => b,
+        }
+    }
+}
+
+/// Sent back to the originating address when the server rejects or fails to process
+/// a request, see `GameState::send_error`. The header carries the request's sequence number.
+///
+/// Payload layout: the code byte followed by an optional UTF-8 message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(clippy::module_name_repetitions)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ErrorPacket {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorPacket {
+    /// Cuts `message` to [`MAX_ERROR_MESSAGE_LEN`] bytes.
+    #[must_use]
+    pub fn new(code: ErrorCode, message: &str) -> Self {
+        let mut end = message.len().min(MAX_ERROR_MESSAGE_LEN);
+        while !message.is_char_boundary(end) {
+            end = end.saturating_sub(1);
+        }
+        ErrorPacket {
+            code,
+            message: message[..end].to_string(),
+        }
+    }
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.message.len().saturating_add(1));
+        buf.push(self.code.to_byte());
+        buf.extend_from_slice(self.message.as_bytes());
+        buf
+    }
+    #[must_use]
+    pub fn deserialize(data: &[u8]) -> Option<ErrorPacket> {
+        let (&code, message) = data.split_first()?;
+        Some(ErrorPacket {
+            code: ErrorCode::from_byte(code),
+            message: String::from_utf8(message.to_vec()).ok()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_packet_round_trip() {
+        let packet = ErrorPacket::new(ErrorCode::ServerFull, "server is full");
+        let data = packet.serialize();
+        assert_eq!(data[0], 0x05);
+        assert_eq!(ErrorPacket::deserialize(&data), Some(packet));
+        assert_eq!(
+            ErrorPacket::deserialize(&[0x04]),
+            Some(ErrorPacket::new(ErrorCode::OutOfBounds, ""))
+        );
+        assert_eq!(ErrorPacket::deserialize(&[]), None);
+    }
+
+    #[test]
+    fn test_long_message_is_cut_at_char_boundary() {
+        let packet = ErrorPacket::new(ErrorCode::Malformed, &"é".repeat(MAX_ERROR_MESSAGE_LEN));
+        assert_eq!(packet.message.len(), MAX_ERROR_MESSAGE_LEN);
+        let packet = ErrorPacket::new(ErrorCode::Malformed, &format!("a{}", "é".repeat(40)));
+        assert_eq!(packet.message.len(), MAX_ERROR_MESSAGE_LEN - 1);
+    }
+
+    #[test]
+    fn test_error_code_byte_round_trip() {
+        for b in 0..=u8::MAX {
+            assert_eq!(ErrorCode::from_byte(b).to_byte(), b);
+        }
+    }
+}
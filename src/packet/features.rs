@@ -0,0 +1,114 @@
+use std::ops::{BitAnd, BitOr};
+
+/// Optional protocol features, negotiated per session in `ConnectionInit`.
+///
+/// A client advertises what it supports, the server answers with the subset it will use
+/// and stores it on the `Player`; optional encoders only apply what was agreed on.
+/// Sent as a big endian `u32`, bits this build doesn't know are kept but never used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Features(pub u32);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    /// LZ4 compressed payloads, see `GamePacket::compress`.
+    pub const COMPRESSION: Features = Features(1 << 0);
+    /// Trailing CRC32 checksums, see `GamePacket::with_checksum`.
+    pub const CHECKSUM: Features = Features(1 << 1);
+    /// Positions sent as deltas from the previous tick. Reserved, not implemented yet.
+    pub const DELTA_ENCODING: Features = Features(1 << 2);
+    /// Payloads sealed with a per session key, see [`crate::packet::crypto`].
+    pub const ENCRYPTION: Features = Features(1 << 3);
+    /// Packets coalesced into `Bundle` datagrams, see `ServerConfig::coalesce_window`.
+    pub const BUNDLES: Features = Features(1 << 4);
+    /// Positions sent as 16 byte `f64` pairs, see `Position::serialize_f64`.
+    pub const F64_POSITIONS: Features = Features(1 << 5);
+
+    /// Features this build can use: compression only with the `compression` feature,
+    /// encryption only with the `crypto` feature and `f64` positions only with the
+    /// `f64-positions` feature.
+    #[must_use]
+    pub fn implemented() -> Features {
+        let mut features = Features::CHECKSUM | Features::BUNDLES;
+        if cfg!(feature = "compression") {
+            features = features | Features::COMPRESSION;
+        }
+        if cfg!(feature = "crypto") {
+            features = features | Features::ENCRYPTION;
+        }
+        if cfg!(feature = "f64-positions") {
+            features = features | Features::F64_POSITIONS;
+        }
+        features
+    }
+    /// Whether every feature of `other` is set.
+    #[must_use]
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+    /// `self` without the features of `other`.
+    #[must_use]
+    pub fn without(self, other: Features) -> Features {
+        Features(self.0 & !other.0)
+    }
+    #[must_use]
+    pub fn to_be_bytes(self) -> [u8; 4] {
+        self.0.to_be_bytes()
+    }
+    #[must_use]
+    pub fn from_be_bytes(bytes: [u8; 4]) -> Features {
+        Features(u32::from_be_bytes(bytes))
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+    fn bitor(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+/// The intersection, what both sides support.
+impl BitAnd for Features {
+    type Output = Features;
+    fn bitand(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intersection_and_contains() {
+        let client = Features::COMPRESSION | Features::DELTA_ENCODING | Features(1 << 31);
+        let server = Features::COMPRESSION | Features::CHECKSUM;
+        let agreed = client & server;
+        assert_eq!(agreed, Features::COMPRESSION);
+        assert!(agreed.contains(Features::COMPRESSION));
+        assert!(!agreed.contains(Features::DELTA_ENCODING));
+        assert!(agreed.contains(Features::NONE));
+        assert_eq!(agreed.without(Features::COMPRESSION), Features::NONE);
+        assert_eq!(Features::from_be_bytes(client.to_be_bytes()), client);
+    }
+
+    #[test]
+    fn test_implemented_matches_build() {
+        assert!(Features::implemented().contains(Features::CHECKSUM));
+        assert!(Features::implemented().contains(Features::BUNDLES));
+        assert_eq!(
+            Features::implemented().contains(Features::COMPRESSION),
+            cfg!(feature = "compression")
+        );
+        assert_eq!(
+            Features::implemented().contains(Features::ENCRYPTION),
+            cfg!(feature = "crypto")
+        );
+        assert_eq!(
+            Features::implemented().contains(Features::F64_POSITIONS),
+            cfg!(feature = "f64-positions")
+        );
+        assert!(!Features::implemented().contains(Features::DELTA_ENCODING));
+    }
+}
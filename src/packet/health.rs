@@ -0,0 +1,51 @@
+use super::sizes::MIN_HEALTH_OK_PAYLOAD;
+
+/// Answer to a `HealthProbe`, for load balancers and orchestrators checking the server
+/// is up without joining it.
+///
+/// Payload layout: big endian `u32` player count, then `u64` uptime in seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HealthOkPacket {
+    pub player_count: u32,
+    pub uptime_secs: u64,
+}
+impl HealthOkPacket {
+    #[must_use]
+    pub fn new(player_count: u32, uptime_secs: u64) -> Self {
+        HealthOkPacket {
+            player_count,
+            uptime_secs,
+        }
+    }
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(MIN_HEALTH_OK_PAYLOAD);
+        buf.extend_from_slice(&self.player_count.to_be_bytes());
+        buf.extend_from_slice(&self.uptime_secs.to_be_bytes());
+        buf
+    }
+    #[must_use]
+    pub fn deserialize(data: &[u8]) -> Option<HealthOkPacket> {
+        let (player_count, rest) = data.split_first_chunk::<4>()?;
+        let uptime_secs = rest.first_chunk::<8>()?;
+        Some(HealthOkPacket::new(
+            u32::from_be_bytes(*player_count),
+            u64::from_be_bytes(*uptime_secs),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_ok_round_trip() {
+        let health = HealthOkPacket::new(3, 86_400);
+        let data = health.serialize();
+        assert_eq!(data.len(), MIN_HEALTH_OK_PAYLOAD);
+        assert_eq!(HealthOkPacket::deserialize(&data), Some(health));
+        assert_eq!(HealthOkPacket::deserialize(&data[..11]), None);
+    }
+}
@@ -0,0 +1,543 @@
+use crate::game_state::PLAYER_ID_LEN;
+
+use super::{
+    connection_init::{CHALLENGE_NONCE_LEN, RECONNECT_TOKEN_LEN},
+    MessageType,
+};
+
+/// Byte order of a multi-byte field. Byte strings have no byte order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Endianness {
+    Big,
+    Little,
+    Bytes,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FieldLayout {
+    pub name: &'static str,
+    pub offset: usize,
+    pub size: usize,
+    pub endianness: Endianness,
+}
+
+/// Description of one wire structure, as sent inside a `GamePacket` payload
+/// unless it is the header itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PacketLayout {
+    pub name: &'static str,
+    /// Message type the layout is the payload of, `None` for shared structures.
+    pub msg_type: Option<MessageType>,
+    pub fields: Vec<FieldLayout>,
+    /// Name of a layout repeated after the fixed fields until the end of the payload.
+    pub repeated: Option<&'static str>,
+}
+
+impl PacketLayout {
+    /// Size of the fixed fields in bytes.
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.fields
+            .last()
+            .map_or(0, |field| field.offset.saturating_add(field.size))
+    }
+}
+
+/// Builds a layout from `(name, size, endianness)` triples, computing offsets.
+fn packet(
+    name: &'static str,
+    msg_type: Option<MessageType>,
+    fields: &[(&'static str, usize, Endianness)],
+    repeated: Option<&'static str>,
+) -> PacketLayout {
+    let mut offset = 0usize;
+    let fields = fields
+        .iter()
+        .map(|&(name, size, endianness)| {
+            let field = FieldLayout {
+                name,
+                offset,
+                size,
+                endianness,
+            };
+            offset = offset.saturating_add(size);
+            field
+        })
+        .collect();
+    PacketLayout {
+        name,
+        msg_type,
+        fields,
+        repeated,
+    }
+}
+
+/// Machine readable description of every structure on the wire.
+///
+/// Positions sent by clients are little endian while the server writes big endian,
+/// see `PositionGamePacket::new` and `Position::serialize`.
+#[must_use]
+#[allow(clippy::too_many_lines)]
+pub fn layout() -> Vec<PacketLayout> {
+    use Endianness::{Big, Bytes, Little};
+    vec![
+        packet(
+            "GamePacketHeader",
+            None,
+            &[
+                ("msg_type", 1, Bytes),
+                ("version", 1, Bytes),
+                ("client_id", PLAYER_ID_LEN, Bytes),
+                ("seq_num", 4, Big),
+            ],
+            None,
+        ),
+        packet(
+            "PlayerPosition",
+            None,
+            &[("id", PLAYER_ID_LEN, Bytes), ("x", 4, Big), ("y", 4, Big)],
+            None,
+        ),
+        packet(
+            "PositionUpdate",
+            Some(MessageType::PositionUpdate),
+            &[("x", 4, Little), ("y", 4, Little)],
+            None,
+        ),
+        packet(
+            "PositionBatch",
+            Some(MessageType::PositionBatch),
+            &[("count", 2, Big)],
+            Some("PlayerPosition"),
+        ),
+        // The 16 byte position formats of players that negotiated `Features::F64_POSITIONS`.
+        // Unlike their `f32` positions, the `f64` positions clients send are big endian,
+        // see `Position::deserialize` and `Position::deserialize_f64`.
+        packet(
+            "PositionUpdateF64",
+            Some(MessageType::PositionUpdate),
+            &[("x", 8, Big), ("y", 8, Big)],
+            None,
+        ),
+        packet(
+            "PlayerPositionF64",
+            None,
+            &[("id", PLAYER_ID_LEN, Bytes), ("x", 8, Big), ("y", 8, Big)],
+            None,
+        ),
+        packet(
+            "PositionBatchF64",
+            Some(MessageType::PositionBatch),
+            &[("count", 2, Big)],
+            Some("PlayerPositionF64"),
+        ),
+        // Sent by clients moving several players they control, see `GameState::add_avatar`.
+        packet(
+            "BulkPositionUpdate",
+            Some(MessageType::BulkPositionUpdate),
+            &[("count", 2, Big)],
+            Some("BulkPositionRecord"),
+        ),
+        packet(
+            "BulkPositionRecord",
+            None,
+            &[
+                ("id", PLAYER_ID_LEN, Bytes),
+                ("x", 4, Little),
+                ("y", 4, Little),
+            ],
+            None,
+        ),
+        // Sent by clients: the UTF-8 id of the room to join, up to 32 bytes and empty
+        // for the default room, preceded by the nonce when answering a `Challenge`.
+        // May be followed by a zero byte and a length prefixed display name, in which
+        // case the response's player records are each followed by a length prefixed name,
+        // by a one byte and the big endian `u32` features the client supports, in which
+        // case the response carries the negotiated features before its player records,
+        // by a two byte and an 18 byte player id to rejoin as, in which case the
+        // response carries a byte telling whether it was honored after the features,
+        // and by a three byte and a 32 byte X25519 public key, in which case the response
+        // carries the server's public key next if encryption was negotiated.
+        packet(
+            "ConnectionInitRequest",
+            Some(MessageType::ConnectionInit),
+            &[],
+            None,
+        ),
+        packet(
+            "ConnectionInitResponse",
+            Some(MessageType::ConnectionInit),
+            &[
+                ("reconnect_token", RECONNECT_TOKEN_LEN, Bytes),
+                ("width", 4, Big),
+                ("height", 4, Big),
+                ("spawn_x", 4, Big),
+                ("spawn_y", 4, Big),
+            ],
+            Some("PlayerPosition"),
+        ),
+        // `ConnectionInitResponse` with `f64` positions, once `Features::F64_POSITIONS`
+        // was negotiated.
+        packet(
+            "ConnectionInitResponseF64",
+            Some(MessageType::ConnectionInit),
+            &[
+                ("reconnect_token", RECONNECT_TOKEN_LEN, Bytes),
+                ("width", 4, Big),
+                ("height", 4, Big),
+                ("spawn_x", 4, Big),
+                ("spawn_y", 4, Big),
+            ],
+            Some("PlayerPositionF64"),
+        ),
+        // Sent instead of `ConnectionInitResponse` when handshakes are required; the client
+        // sends the nonce back as the payload of a second `ConnectionInit`.
+        packet(
+            "Challenge",
+            Some(MessageType::Challenge),
+            &[("nonce", CHALLENGE_NONCE_LEN, Bytes)],
+            None,
+        ),
+        // Rejects a `ConnectionInit` while the server is draining.
+        packet("Draining", Some(MessageType::Draining), &[], None),
+        // Followed by the length prefixed display name if the player picked one.
+        packet(
+            "PlayerJoin",
+            Some(MessageType::PlayerJoin),
+            &[
+                ("player_id", PLAYER_ID_LEN, Bytes),
+                ("x", 4, Big),
+                ("y", 4, Big),
+            ],
+            None,
+        ),
+        // Only sent when `ServerConfig::heartbeat_status` is set, otherwise empty
+        packet(
+            "HeartbeatStatus",
+            Some(MessageType::Heartbeat),
+            &[("player_count", 2, Big), ("tick", 4, Big)],
+            None,
+        ),
+        // Followed by a reason byte when confirming a departure to the leaver.
+        packet(
+            "PlayerLeft",
+            Some(MessageType::PlayerLeft),
+            &[("player_id", PLAYER_ID_LEN, Bytes)],
+            None,
+        ),
+        packet(
+            "Reconnect",
+            Some(MessageType::Reconnect),
+            &[("token", RECONNECT_TOKEN_LEN, Bytes)],
+            None,
+        ),
+        packet(
+            "InterestEnter",
+            Some(MessageType::InterestEnter),
+            &[
+                ("player_id", PLAYER_ID_LEN, Bytes),
+                ("x", 4, Big),
+                ("y", 4, Big),
+            ],
+            None,
+        ),
+        packet(
+            "InterestExit",
+            Some(MessageType::InterestExit),
+            &[("player_id", PLAYER_ID_LEN, Bytes)],
+            None,
+        ),
+        // Followed by the variable length UTF-8 message.
+        packet(
+            "ChatMessage",
+            Some(MessageType::ChatMessage),
+            &[("sender_id", PLAYER_ID_LEN, Bytes)],
+            None,
+        ),
+        // Followed by the variable length UTF-8 message.
+        packet(
+            "TeamChat",
+            Some(MessageType::TeamChat),
+            &[("sender_id", PLAYER_ID_LEN, Bytes)],
+            None,
+        ),
+        // Followed by the variable length UTF-8 message. From clients the id is the
+        // recipient's, forwarded whispers carry the sender's.
+        packet(
+            "Whisper",
+            Some(MessageType::Whisper),
+            &[("player_id", PLAYER_ID_LEN, Bytes)],
+            None,
+        ),
+        // Followed by the UTF-8 key and the value.
+        packet(
+            "SetMetadata",
+            Some(MessageType::SetMetadata),
+            &[("player_id", PLAYER_ID_LEN, Bytes), ("key_len", 1, Bytes)],
+            None,
+        ),
+        packet(
+            "MetadataUpdate",
+            Some(MessageType::MetadataUpdate),
+            &[("player_id", PLAYER_ID_LEN, Bytes), ("key_len", 1, Bytes)],
+            None,
+        ),
+        // From clients, optionally the UTF-8 id of the room to watch, the default room
+        // when empty.
+        packet("SpectateInit", Some(MessageType::SpectateInit), &[], None),
+        packet(
+            "WorldInfoRequest",
+            Some(MessageType::WorldInfoRequest),
+            &[],
+            None,
+        ),
+        packet(
+            "WorldInfo",
+            Some(MessageType::WorldInfo),
+            &[
+                ("width", 4, Big),
+                ("height", 4, Big),
+                ("spawn_x", 4, Big),
+                ("spawn_y", 4, Big),
+            ],
+            None,
+        ),
+        // Answered with a `PlayerPosition` payload under `PositionUpdate`, sent to everyone.
+        packet("Respawn", Some(MessageType::Respawn), &[], None),
+        // Same payload as `WorldInfo`, pushed to everyone when the world is resized.
+        packet(
+            "WorldResize",
+            Some(MessageType::WorldResize),
+            &[
+                ("width", 4, Big),
+                ("height", 4, Big),
+                ("spawn_x", 4, Big),
+                ("spawn_y", 4, Big),
+            ],
+            None,
+        ),
+        // Liveness probe, answered with a `Pong` carrying the `Ping`'s sequence number.
+        packet("Ping", Some(MessageType::Ping), &[], None),
+        packet("Pong", Some(MessageType::Pong), &[], None),
+        // Followed by the variable length admin token.
+        packet(
+            "Kick",
+            Some(MessageType::Kick),
+            &[("target_id", PLAYER_ID_LEN, Bytes)],
+            None,
+        ),
+        // Followed by the variable length admin token; answered like a respawn.
+        packet(
+            "Teleport",
+            Some(MessageType::Teleport),
+            &[
+                ("target_id", PLAYER_ID_LEN, Bytes),
+                ("x", 4, Little),
+                ("y", 4, Little),
+            ],
+            None,
+        ),
+        // Sent by a player leaving, confirmed with a `PlayerLeft` carrying its own id.
+        packet("Disconnect", Some(MessageType::Disconnect), &[], None),
+        // Server tracked entities, see `GameServer::spawn_entity`. The owner id is
+        // zeroed for entities without an owner.
+        packet(
+            "EntitySpawn",
+            Some(MessageType::EntitySpawn),
+            &[
+                ("entity_id", 4, Big),
+                ("kind", 2, Big),
+                ("x", 4, Big),
+                ("y", 4, Big),
+                ("owner_id", PLAYER_ID_LEN, Bytes),
+            ],
+            None,
+        ),
+        packet(
+            "EntityMove",
+            Some(MessageType::EntityMove),
+            &[("entity_id", 4, Big), ("x", 4, Big), ("y", 4, Big)],
+            None,
+        ),
+        packet(
+            "EntityDespawn",
+            Some(MessageType::EntityDespawn),
+            &[("entity_id", 4, Big)],
+            None,
+        ),
+        // Answered with a `HealthOk` without registering the sender, within a budget.
+        packet("HealthProbe", Some(MessageType::HealthProbe), &[], None),
+        packet(
+            "HealthOk",
+            Some(MessageType::HealthOk),
+            &[("player_count", 4, Big), ("uptime_secs", 8, Big)],
+            None,
+        ),
+        // Packets coalesced into one datagram, see `ServerConfig::coalesce_window`.
+        packet(
+            "Bundle",
+            Some(MessageType::Bundle),
+            &[],
+            Some("BundleEntry"),
+        ),
+        // Followed by `len` bytes of a serialized `GamePacket`, header included.
+        packet("BundleEntry", None, &[("len", 2, Big)], None),
+        // Sent to everyone as the server stops, followed by an optional UTF-8 reason.
+        packet(
+            "ServerShutdown",
+            Some(MessageType::ServerShutdown),
+            &[("eta_secs", 4, Big)],
+            None,
+        ),
+        // Sent back when a request is rejected, followed by an optional UTF-8 message.
+        packet(
+            "Error",
+            Some(MessageType::Error),
+            &[("code", 1, Bytes)],
+            None,
+        ),
+    ]
+}
+
+/// Looks up a layout by name.
+#[must_use]
+pub fn find(name: &str) -> Option<PacketLayout> {
+    layout().into_iter().find(|layout| layout.name == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        game_state::{Player, Position},
+        packet::{
+            connection_init::{ConnectionInitPacketSent, PlayerJoinPacket, ReconnectPacket},
+            ping::PlayerLeft,
+            position::{
+                PlayerPosition, PositionBatch, POSITION_RECORD_F64_SIZE, POSITION_RECORD_SIZE,
+            },
+            world::WorldInfo,
+            GamePacket, HEADER_SIZE,
+        },
+    };
+
+    fn size_of(name: &str) -> usize {
+        find(name).unwrap().size()
+    }
+
+    #[test]
+    fn test_header_matches_layout() {
+        let data =
+            GamePacket::new(MessageType::Heartbeat, 0, vec![], vec![0; PLAYER_ID_LEN]).serialize();
+        assert_eq!(data.len(), size_of("GamePacketHeader"));
+        assert_eq!(size_of("GamePacketHeader"), HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_payloads_match_layout() {
+        let position = PlayerPosition::new(vec![0; PLAYER_ID_LEN], Position::new(1.0, 2.0));
+        assert_eq!(position.serialize().len(), size_of("PlayerPosition"));
+        assert_eq!(size_of("PlayerPosition"), POSITION_RECORD_SIZE);
+        assert_eq!(position.serialize_f64().len(), size_of("PlayerPositionF64"));
+        assert_eq!(size_of("PlayerPositionF64"), POSITION_RECORD_F64_SIZE);
+        assert_eq!(
+            Position::new(1.0, 2.0).serialize_f64().len(),
+            size_of("PositionUpdateF64")
+        );
+
+        let join = PlayerJoinPacket::new(
+            0,
+            vec![0; PLAYER_ID_LEN],
+            vec![1; PLAYER_ID_LEN],
+            Position::new(1.0, 2.0),
+        );
+        assert_eq!(join.serialize().payload.len(), size_of("PlayerJoin"));
+
+        let left = PlayerLeft::new("a".repeat(PLAYER_ID_LEN));
+        assert_eq!(left.serialize().len(), size_of("PlayerLeft"));
+
+        let reconnect = ReconnectPacket::new([0; RECONNECT_TOKEN_LEN]);
+        assert_eq!(reconnect.serialize().len(), size_of("Reconnect"));
+    }
+
+    #[test]
+    fn test_repeated_payloads_match_layout() {
+        let batch = PositionBatch::new(vec![
+            PlayerPosition::new(
+                vec![0; PLAYER_ID_LEN],
+                Position::new(1.0, 2.0)
+            );
+            3
+        ]);
+        assert_eq!(
+            batch.serialize().len(),
+            size_of("PositionBatch") + 3 * size_of("PlayerPosition")
+        );
+        assert_eq!(
+            batch.serialize_f64().len(),
+            size_of("PositionBatchF64") + 3 * size_of("PlayerPositionF64")
+        );
+
+        let world = WorldInfo::new(1920, 1080, Position::new(600.0, 700.0));
+        assert_eq!(world.serialize().len(), size_of("WorldInfo"));
+
+        let init = ConnectionInitPacketSent::new(
+            0,
+            vec![0; PLAYER_ID_LEN],
+            [0; RECONNECT_TOKEN_LEN],
+            world,
+            vec![],
+        );
+        assert_eq!(
+            init.serialize().payload.len(),
+            size_of("ConnectionInitResponse")
+        );
+
+        let player = Player {
+            id: "a".repeat(PLAYER_ID_LEN),
+            ..Player::default()
+        };
+        let world = WorldInfo::new(1920, 1080, Position::new(600.0, 700.0));
+        let init = ConnectionInitPacketSent::new(
+            0,
+            vec![0; PLAYER_ID_LEN],
+            [0; RECONNECT_TOKEN_LEN],
+            world,
+            vec![player; 2],
+        )
+        .with_f64_positions();
+        assert_eq!(
+            init.serialize().payload.len(),
+            size_of("ConnectionInitResponseF64") + 2 * size_of("PlayerPositionF64")
+        );
+    }
+
+    #[test]
+    fn test_fields_are_contiguous() {
+        for layout in layout() {
+            let mut offset = 0;
+            for field in &layout.fields {
+                assert_eq!(field.offset, offset, "{}.{}", layout.name, field.name);
+                offset += field.size;
+            }
+            if let Some(repeated) = layout.repeated {
+                assert!(
+                    find(repeated).is_some(),
+                    "{} repeats {repeated}",
+                    layout.name
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_layout_dumps_as_json() {
+        let json = serde_json::to_value(layout()).unwrap();
+        assert_eq!(json[0]["name"], "GamePacketHeader");
+        assert_eq!(json[0]["fields"][3]["endianness"], "Big");
+    }
+}
@@ -0,0 +1,75 @@
+use crate::game_state::PLAYER_ID_LEN;
+
+/// A single metadata entry of a player.
+///
+/// Payload layout: 18 byte player id, key length (1 byte), UTF-8 key, then the value.
+/// Clients setting their own metadata may leave the player id zeroed, the server fills
+/// in the id registered for their address before broadcasting it.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MetadataPacket {
+    pub player_id: Vec<u8>,
+    pub key: String,
+    pub value: Vec<u8>,
+}
+
+impl MetadataPacket {
+    #[must_use]
+    pub fn new(player_id: Vec<u8>, key: String, value: Vec<u8>) -> Self {
+        MetadataPacket {
+            player_id,
+            key,
+            value,
+        }
+    }
+    /// Returns `None` if the key is longer than 255 bytes.
+    #[must_use]
+    pub fn serialize(&self) -> Option<Vec<u8>> {
+        let key_len = u8::try_from(self.key.len()).ok()?;
+        let mut buf = Vec::with_capacity(
+            self.player_id
+                .len()
+                .saturating_add(1)
+                .saturating_add(self.key.len())
+                .saturating_add(self.value.len()),
+        );
+        buf.extend_from_slice(&self.player_id);
+        buf.push(key_len);
+        buf.extend_from_slice(self.key.as_bytes());
+        buf.extend_from_slice(&self.value);
+        Some(buf)
+    }
+    #[must_use]
+    pub fn deserialize(data: &[u8]) -> Option<MetadataPacket> {
+        let (player_id, rest) = data.split_at_checked(PLAYER_ID_LEN)?;
+        let (&key_len, rest) = rest.split_first()?;
+        let (key, value) = rest.split_at_checked(usize::from(key_len))?;
+        let key = String::from_utf8(key.to_vec()).ok()?;
+        Some(MetadataPacket::new(player_id.to_vec(), key, value.to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let packet = MetadataPacket::new(
+            vec![1; PLAYER_ID_LEN],
+            "name".to_string(),
+            b"alice".to_vec(),
+        );
+        let decoded = MetadataPacket::deserialize(&packet.serialize().unwrap()).unwrap();
+        assert_eq!(decoded.player_id, vec![1; PLAYER_ID_LEN]);
+        assert_eq!(decoded.key, "name");
+        assert_eq!(decoded.value, b"alice");
+    }
+
+    #[test]
+    fn test_truncated_key_is_rejected() {
+        let mut data = vec![1; PLAYER_ID_LEN];
+        data.extend_from_slice(&[4, b'n', b'a']);
+        assert!(MetadataPacket::deserialize(&data).is_none());
+    }
+}
@@ -1,24 +1,122 @@
+pub mod admin;
+pub mod bundle;
+pub mod chat;
 pub mod connection_init;
+pub mod crypto;
+pub mod entity;
+pub mod error;
+pub mod features;
+pub mod health;
+pub mod layout;
+pub mod metadata;
 pub mod ping;
 pub mod position;
+pub mod seq;
+pub mod shutdown;
+pub mod sizes;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod world;
+pub use seq::{ReplayWindow, SeqNum};
+
 use bytes::{BufMut, BytesMut};
 
-use crate::game_state::Position;
+use crate::{
+    game_state::{Position, PLAYER_ID_LEN},
+    num::{f32_to_coord, record_capacity},
+};
+
+/// Size of the `GamePacket` header: type, version, 18 byte client id and sequence number.
+pub const HEADER_SIZE: usize = 1 + 1 + PLAYER_ID_LEN + 4;
+/// Largest datagram the server sends by default, small enough to avoid IP fragmentation
+/// on common links.
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+/// Bit in the version byte marking a packet that ends with a CRC32 of everything before it.
+pub const FLAG_CHECKSUM: u8 = 0x80;
+const CHECKSUM_SIZE: usize = 4;
+/// Bit in the version byte marking an LZ4 compressed payload, see [`GamePacket::compress`].
+/// Set on a client's `ConnectionInit` it advertises that the client can decompress.
+pub const FLAG_COMPRESSED: u8 = 0x40;
+/// Bit in the version byte marking a payload sealed with the session key, see
+/// [`crypto::seal`]. Such packets only make sense after [`crypto::open`].
+pub const FLAG_ENCRYPTED: u8 = 0x20;
+/// Largest size a compressed payload may claim to expand to, anything larger is rejected.
+pub const MAX_DECOMPRESSED_SIZE: usize = 65_536;
+/// Message type bytes from here on are never used by the protocol and left to embedders,
+/// see [`MessageType::Custom`].
+pub const CUSTOM_MESSAGE_TYPE_START: u8 = 0x80;
 
 // Define an enum for message types.
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MessageType {
-    PositionUpdate = 0x01,
-    ChatMessage = 0x02,
-    Heartbeat = 0x03,
-    ConnectionInit = 0x04,
-    PlayerJoin = 0x05,
-    ConfirmPlayerMovement = 0x06,
-    PlayerLeft = 0x07,
+    PositionUpdate,
+    ChatMessage,
+    Heartbeat,
+    ConnectionInit,
+    PlayerJoin,
+    ConfirmPlayerMovement,
+    PlayerLeft,
+    PositionBatch,
+    Reconnect,
+    InterestEnter,
+    InterestExit,
+    Kick,
+    SetMetadata,
+    MetadataUpdate,
+    SpectateInit,
+    WorldInfoRequest,
+    WorldInfo,
+    Respawn,
+    Challenge,
+    Draining,
+    WorldResize,
+    Ping,
+    Pong,
+    BulkPositionUpdate,
+    Error,
+    Teleport,
+    Disconnect,
+    EntitySpawn,
+    EntityMove,
+    EntityDespawn,
+    HealthProbe,
+    HealthOk,
+    Bundle,
+    ServerShutdown,
+    Whisper,
+    TeamChat,
+    /// Application defined message, at or above [`CUSTOM_MESSAGE_TYPE_START`].
+    Custom(u8),
 }
 
 impl MessageType {
+    /// Whether packets of this type are sent right away rather than held back for
+    /// coalescing: handshakes, probes and errors, which a client is waiting on, and the
+    /// shutdown notice, which nothing would be left to flush.
+    #[must_use]
+    pub fn is_latency_sensitive(self) -> bool {
+        matches!(
+            self,
+            MessageType::ConnectionInit
+                | MessageType::Challenge
+                | MessageType::Ping
+                | MessageType::Pong
+                | MessageType::Error
+                | MessageType::HealthOk
+                | MessageType::ServerShutdown
+        )
+    }
+    /// Whether clients send this type to become known to the server, so requests of it
+    /// are answered from addresses without a player.
+    #[must_use]
+    pub fn is_handshake(self) -> bool {
+        matches!(
+            self,
+            MessageType::ConnectionInit | MessageType::Reconnect | MessageType::SpectateInit
+        )
+    }
     #[must_use]
     pub fn from_byte(b: u8) -> Option<MessageType> {
         match b {
@@ -29,12 +127,105 @@ impl MessageType {
             0x05 => Some(MessageType::PlayerJoin),
             0x06 => Some(MessageType::ConfirmPlayerMovement),
             0x07 => Some(MessageType::PlayerLeft),
+            0x08 => Some(MessageType::PositionBatch),
+            0x09 => Some(MessageType::Reconnect),
+            0x0A => Some(MessageType::InterestEnter),
+            0x0B => Some(MessageType::InterestExit),
+            0x0C => Some(MessageType::Kick),
+            0x0D => Some(MessageType::SetMetadata),
+            0x0E => Some(MessageType::MetadataUpdate),
+            0x0F => Some(MessageType::SpectateInit),
+            0x10 => Some(MessageType::WorldInfoRequest),
+            0x11 => Some(MessageType::WorldInfo),
+            0x12 => Some(MessageType::Respawn),
+            0x13 => Some(MessageType::Challenge),
+            0x14 => Some(MessageType::Draining),
+            0x15 => Some(MessageType::WorldResize),
+            0x16 => Some(MessageType::Ping),
+            0x17 => Some(MessageType::Pong),
+            0x18 => Some(MessageType::BulkPositionUpdate),
+            0x19 => Some(MessageType::Error),
+            0x1A => Some(MessageType::Teleport),
+            0x1B => Some(MessageType::Disconnect),
+            0x1C => Some(MessageType::EntitySpawn),
+            0x1D => Some(MessageType::EntityMove),
+            0x1E => Some(MessageType::EntityDespawn),
+            0x1F => Some(MessageType::HealthProbe),
+            0x20 => Some(MessageType::HealthOk),
+            0x21 => Some(MessageType::Bundle),
+            0x22 => Some(MessageType::ServerShutdown),
+            0x23 => Some(MessageType::Whisper),
+            0x24 => Some(MessageType::TeamChat),
+            b if b >= CUSTOM_MESSAGE_TYPE_START => Some(MessageType::Custom(b)),
             _ => None,
         }
     }
+    #[must_use]
+    pub fn to_byte(self) -> u8 {
+        match self {
+            MessageType::PositionUpdate => 0x01,
+            MessageType::ChatMessage => 0x02,
+            MessageType::Heartbeat => 0x03,
+            MessageType::ConnectionInit => 0x04,
+            MessageType::PlayerJoin => 0x05,
+            MessageType::ConfirmPlayerMovement => 0x06,
+            MessageType::PlayerLeft => 0x07,
+            MessageType::PositionBatch => 0x08,
+            MessageType::Reconnect => 0x09,
+            MessageType::InterestEnter => 0x0A,
+            MessageType::InterestExit => 0x0B,
+            MessageType::Kick => 0x0C,
+            MessageType::SetMetadata => 0x0D,
+            MessageType::MetadataUpdate => 0x0E,
+            MessageType::SpectateInit => 0x0F,
+            MessageType::WorldInfoRequest => 0x10,
+            MessageType::WorldInfo => 0x11,
+            MessageType::Respawn => 0x12,
+            MessageType::Challenge => 0x13,
+            MessageType::Draining => 0x14,
+            MessageType::WorldResize => 0x15,
+            MessageType::Ping => 0x16,
+            MessageType::Pong => 0x17,
+            MessageType::BulkPositionUpdate => 0x18,
+            MessageType::Error => 0x19,
+            MessageType::Teleport => 0x1A,
+            MessageType::Disconnect => 0x1B,
+            MessageType::EntitySpawn => 0x1C,
+            MessageType::EntityMove => 0x1D,
+            MessageType::EntityDespawn => 0x1E,
+            MessageType::HealthProbe => 0x1F,
+            MessageType::HealthOk => 0x20,
+            MessageType::Bundle => 0x21,
+            MessageType::ServerShutdown => 0x22,
+            MessageType::Whisper => 0x23,
+            MessageType::TeamChat => 0x24,
+            MessageType::Custom(b) => b,
+        }
+    }
 }
-#[derive(Debug)]
+impl From<MessageType> for u8 {
+    fn from(msg_type: MessageType) -> u8 {
+        msg_type.to_byte()
+    }
+}
+/// A message type byte no [`MessageType`] is assigned to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownMessageType(pub u8);
+impl std::fmt::Display for UnknownMessageType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown message type byte {:#04x}", self.0)
+    }
+}
+impl std::error::Error for UnknownMessageType {}
+impl TryFrom<u8> for MessageType {
+    type Error = UnknownMessageType;
+    fn try_from(b: u8) -> Result<Self, UnknownMessageType> {
+        MessageType::from_byte(b).ok_or(UnknownMessageType(b))
+    }
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[allow(clippy::module_name_repetitions)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GamePacket {
     pub msg_type: MessageType,
     pub version: u8,
@@ -54,28 +245,90 @@ impl GamePacket {
             client_id,
         }
     }
+    /// Marks the packet to be sent with a trailing CRC32 checksum.
+    #[must_use]
+    pub fn with_checksum(mut self) -> Self {
+        self.version |= FLAG_CHECKSUM;
+        self
+    }
+    #[must_use]
+    pub fn has_checksum(&self) -> bool {
+        self.version & FLAG_CHECKSUM != 0
+    }
+    /// Compresses a payload larger than `threshold` bytes and flags the packet as such,
+    /// unless compressing doesn't make it smaller.
+    #[cfg(feature = "compression")]
+    #[must_use]
+    pub fn compress(mut self, threshold: usize) -> Self {
+        if !self.is_compressed() && self.payload.len() > threshold {
+            let compressed = lz4_flex::compress_prepend_size(&self.payload);
+            if compressed.len() < self.payload.len() {
+                self.payload = compressed;
+                self.version |= FLAG_COMPRESSED;
+            }
+        }
+        self
+    }
+    /// Leaves the packet as is, compression needs the `compression` feature.
+    #[cfg(not(feature = "compression"))]
+    #[must_use]
+    pub fn compress(self, _threshold: usize) -> Self {
+        self
+    }
+    #[must_use]
+    pub fn is_compressed(&self) -> bool {
+        self.version & FLAG_COMPRESSED != 0
+    }
+    #[must_use]
+    pub fn is_encrypted(&self) -> bool {
+        self.version & FLAG_ENCRYPTED != 0
+    }
     #[must_use]
     pub fn serialize(&self) -> Vec<u8> {
-        #[allow(clippy::arithmetic_side_effects)]
-        let mut buf = BytesMut::with_capacity(1 + 1 + 18 + 4 + self.payload.len());
-        #[allow(clippy::as_conversions)]
-        buf.put_u8(self.msg_type as u8);
+        let mut buf = BytesMut::with_capacity(record_capacity(
+            HEADER_SIZE.saturating_add(CHECKSUM_SIZE),
+            1,
+            self.payload.len(),
+        ));
+        buf.put_u8(self.msg_type.to_byte());
         buf.put_u8(self.version);
         buf.put_slice(&self.client_id);
         buf.put_u32(self.seq_num);
         buf.put_slice(&self.payload);
+        if self.has_checksum() {
+            let checksum = crc32fast::hash(&buf);
+            buf.put_u32(checksum);
+        }
         buf.to_vec()
     }
+    /// Returns `None` for a truncated header, an unknown message type or,
+    /// when the checksum flag is set, a checksum mismatch. A compressed payload is
+    /// decompressed, `None` if that fails or the `compression` feature is disabled.
     #[must_use]
     pub fn deserialize(data: &[u8]) -> Option<GamePacket> {
-        if data.len() < 6 {
+        if data.len() < HEADER_SIZE {
             return None; // Not enough for header
         }
         let msg_type = MessageType::from_byte(data[0])?;
         let version = data[1];
-        let client_id = &data[2..20];
-        let seq_num = u32::from_be_bytes([data[20], data[21], data[22], data[23]]);
-        let payload = data[24..].to_vec();
+        let data = if version & FLAG_CHECKSUM == 0 {
+            data
+        } else {
+            let (body, checksum) = data.split_at_checked(data.len().checked_sub(CHECKSUM_SIZE)?)?;
+            if body.len() < HEADER_SIZE
+                || crc32fast::hash(body) != u32::from_be_bytes(checksum.try_into().ok()?)
+            {
+                return None;
+            }
+            body
+        };
+        let (client_id, seq_num) = data[2..HEADER_SIZE].split_at(PLAYER_ID_LEN);
+        let seq_num = u32::from_be_bytes(seq_num.try_into().ok()?);
+        let mut payload = data[HEADER_SIZE..].to_vec();
+        // An empty payload has nothing to decompress, the flag only advertises support
+        if version & FLAG_COMPRESSED != 0 && !payload.is_empty() {
+            payload = decompress(&payload)?;
+        }
         Some(GamePacket {
             msg_type,
             seq_num,
@@ -85,8 +338,22 @@ impl GamePacket {
         })
     }
 }
-#[derive(Debug)]
+#[cfg(feature = "compression")]
+fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    let (size, _) = data.split_first_chunk::<4>()?;
+    if usize::try_from(u32::from_le_bytes(*size)).ok()? > MAX_DECOMPRESSED_SIZE {
+        return None;
+    }
+    lz4_flex::decompress_size_prepended(data).ok()
+}
+#[cfg(not(feature = "compression"))]
+fn decompress(_data: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[derive(Debug, Clone)]
 #[allow(clippy::module_name_repetitions)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PositionGamePacket {
     pub msg_type: MessageType,
     pub version: u8,
@@ -98,18 +365,18 @@ impl PositionGamePacket {
     #[must_use]
     pub fn new(game_packet: &GamePacket) -> Self {
         let position = Position {
-            x: f32::from_be_bytes([
+            x: f32_to_coord(f32::from_be_bytes([
                 game_packet.payload[3],
                 game_packet.payload[2],
                 game_packet.payload[1],
                 game_packet.payload[0],
-            ]),
-            y: f32::from_be_bytes([
+            ])),
+            y: f32_to_coord(f32::from_be_bytes([
                 game_packet.payload[7],
                 game_packet.payload[6],
                 game_packet.payload[5],
                 game_packet.payload[4],
-            ]),
+            ])),
         };
         PositionGamePacket {
             msg_type: game_packet.msg_type,
@@ -119,4 +386,185 @@ impl PositionGamePacket {
             position,
         }
     }
+    /// Reads a position update in the 16 byte format of `Position::serialize_f64`,
+    /// sent by players that negotiated `Features::F64_POSITIONS`. `None` if the
+    /// payload is shorter.
+    #[must_use]
+    pub fn new_f64(game_packet: &GamePacket) -> Option<Self> {
+        Some(PositionGamePacket {
+            msg_type: game_packet.msg_type,
+            version: game_packet.version,
+            client_id: game_packet.client_id.clone(),
+            seq_num: game_packet.seq_num,
+            position: Position::deserialize_f64(&game_packet.payload)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum_round_trip() {
+        let packet = GamePacket::new(
+            MessageType::PositionUpdate,
+            7,
+            vec![1, 2, 3, 4],
+            vec![9; PLAYER_ID_LEN],
+        )
+        .with_checksum();
+        let data = packet.serialize();
+        assert_eq!(data.len(), HEADER_SIZE + 4 + CHECKSUM_SIZE);
+
+        let decoded = GamePacket::deserialize(&data).unwrap();
+        assert!(decoded.has_checksum());
+        assert_eq!(decoded.seq_num, 7);
+        assert_eq!(decoded.client_id, vec![9; PLAYER_ID_LEN]);
+        assert_eq!(decoded.payload, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_checksum_detects_flipped_payload_byte() {
+        let packet = GamePacket::new(
+            MessageType::PositionUpdate,
+            7,
+            vec![1, 2, 3, 4],
+            vec![9; PLAYER_ID_LEN],
+        )
+        .with_checksum();
+        let mut data = packet.serialize();
+        data[HEADER_SIZE + 1] ^= 0xFF;
+        assert!(GamePacket::deserialize(&data).is_none());
+    }
+
+    #[test]
+    fn test_packet_without_checksum_is_unchanged() {
+        let packet = GamePacket::new(MessageType::Heartbeat, 1, vec![5], vec![1; PLAYER_ID_LEN]);
+        let data = packet.serialize();
+        assert_eq!(data.len(), HEADER_SIZE + 1);
+        let decoded = GamePacket::deserialize(&data).unwrap();
+        assert!(!decoded.has_checksum());
+        assert_eq!(decoded.payload, vec![5]);
+    }
+
+    #[test]
+    fn test_message_type_byte_round_trip() {
+        for b in 0..=u8::MAX {
+            if let Some(msg_type) = MessageType::from_byte(b) {
+                assert_eq!(msg_type.to_byte(), b);
+            }
+        }
+        assert_eq!(MessageType::from_byte(0x7F), None);
+        assert_eq!(MessageType::try_from(0x7F), Err(UnknownMessageType(0x7F)));
+        assert_eq!(
+            MessageType::from_byte(0x80),
+            Some(MessageType::Custom(0x80))
+        );
+    }
+
+    #[test]
+    fn test_message_type_converts_to_its_byte() {
+        let expected = [
+            (MessageType::PositionUpdate, 0x01),
+            (MessageType::ChatMessage, 0x02),
+            (MessageType::Heartbeat, 0x03),
+            (MessageType::ConnectionInit, 0x04),
+            (MessageType::PlayerJoin, 0x05),
+            (MessageType::ConfirmPlayerMovement, 0x06),
+            (MessageType::PlayerLeft, 0x07),
+            (MessageType::PositionBatch, 0x08),
+            (MessageType::Reconnect, 0x09),
+            (MessageType::InterestEnter, 0x0A),
+            (MessageType::InterestExit, 0x0B),
+            (MessageType::Kick, 0x0C),
+            (MessageType::SetMetadata, 0x0D),
+            (MessageType::MetadataUpdate, 0x0E),
+            (MessageType::SpectateInit, 0x0F),
+            (MessageType::WorldInfoRequest, 0x10),
+            (MessageType::WorldInfo, 0x11),
+            (MessageType::Respawn, 0x12),
+            (MessageType::Challenge, 0x13),
+            (MessageType::Draining, 0x14),
+            (MessageType::WorldResize, 0x15),
+            (MessageType::Ping, 0x16),
+            (MessageType::Pong, 0x17),
+            (MessageType::BulkPositionUpdate, 0x18),
+            (MessageType::Error, 0x19),
+            (MessageType::Teleport, 0x1A),
+            (MessageType::Disconnect, 0x1B),
+            (MessageType::EntitySpawn, 0x1C),
+            (MessageType::EntityMove, 0x1D),
+            (MessageType::EntityDespawn, 0x1E),
+            (MessageType::HealthProbe, 0x1F),
+            (MessageType::HealthOk, 0x20),
+            (MessageType::Bundle, 0x21),
+            (MessageType::ServerShutdown, 0x22),
+            (MessageType::Whisper, 0x23),
+            (MessageType::TeamChat, 0x24),
+            (MessageType::Custom(0xC0), 0xC0),
+        ];
+        for (msg_type, byte) in expected {
+            assert_eq!(u8::from(msg_type), byte, "{msg_type:?}");
+            assert_eq!(MessageType::try_from(byte), Ok(msg_type));
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_large_payload_round_trips_compressed() {
+        let payload = [[7u8; PLAYER_ID_LEN], [0u8; PLAYER_ID_LEN]]
+            .concat()
+            .repeat(20);
+        let packet = GamePacket::new(
+            MessageType::PositionBatch,
+            1,
+            payload.clone(),
+            vec![1; PLAYER_ID_LEN],
+        )
+        .compress(256)
+        .with_checksum();
+        assert!(packet.is_compressed());
+        let data = packet.serialize();
+        assert!(data.len() < HEADER_SIZE + payload.len());
+
+        let decoded = GamePacket::deserialize(&data).unwrap();
+        assert!(decoded.is_compressed());
+        assert_eq!(decoded.payload, payload);
+    }
+
+    #[test]
+    fn test_small_payload_stays_uncompressed() {
+        let packet = GamePacket::new(
+            MessageType::PositionBatch,
+            1,
+            vec![0; 100],
+            vec![1; PLAYER_ID_LEN],
+        )
+        .compress(256);
+        assert!(!packet.is_compressed());
+        assert_eq!(packet.serialize().len(), HEADER_SIZE + 100);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_decompression_bomb_is_rejected() {
+        let mut payload = lz4_flex::compress_prepend_size(&[0; 16]);
+        payload[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut packet = GamePacket::new(
+            MessageType::PositionBatch,
+            1,
+            payload,
+            vec![1; PLAYER_ID_LEN],
+        );
+        packet.version |= FLAG_COMPRESSED;
+        assert!(GamePacket::deserialize(&packet.serialize()).is_none());
+    }
+
+    #[test]
+    fn test_truncated_header_is_rejected() {
+        let data =
+            GamePacket::new(MessageType::Heartbeat, 1, vec![], vec![1; PLAYER_ID_LEN]).serialize();
+        assert!(GamePacket::deserialize(&data[..HEADER_SIZE - 1]).is_none());
+    }
 }
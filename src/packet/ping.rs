@@ -1,25 +1,112 @@
+use crate::num::wrap_u32;
+
+use super::sizes::MIN_PLAYER_LEFT_PAYLOAD;
+
+/// Why a player left, sent in the [`PlayerLeft`] confirming a departure to the leaver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LeaveReason {
+    /// The player sent a `Disconnect`.
+    Disconnected,
+    /// An operator kicked the player.
+    Kicked,
+    /// A reason this build doesn't know.
+    Other(u8),
+}
+
+impl LeaveReason {
+    #[must_use]
+    pub fn from_byte(b: u8) -> LeaveReason {
+        match b {
+            0x01 => LeaveReason::Disconnected,
+            0x02 => LeaveReason::Kicked,
+            b => LeaveReason::Other(b),
+        }
+    }
+    #[must_use]
+    pub fn to_byte(self) -> u8 {
+        match self {
+            LeaveReason::Disconnected => 0x01,
+            LeaveReason::Kicked => 0x02,
+            LeaveReason::Other(b) => b,
+        }
+    }
+}
+
+/// Payload layout: the 18 byte id of the player leaving, followed by a [`LeaveReason`]
+/// byte when confirming the departure to the leaver itself.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PlayerLeft {
     pub player_id: String,
+    pub reason: Option<LeaveReason>,
 }
 
 impl PlayerLeft {
     #[must_use]
     pub fn new(player_id: String) -> Self {
-        PlayerLeft { player_id }
+        PlayerLeft {
+            player_id,
+            reason: None,
+        }
+    }
+    #[must_use]
+    pub fn with_reason(mut self, reason: LeaveReason) -> Self {
+        self.reason = Some(reason);
+        self
     }
     #[must_use]
     pub fn serialize(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(18);
+        let mut buf = Vec::with_capacity(MIN_PLAYER_LEFT_PAYLOAD + 1);
         buf.extend_from_slice(self.player_id.as_bytes());
+        if let Some(reason) = self.reason {
+            buf.push(reason.to_byte());
+        }
         buf
     }
     #[must_use]
     pub fn deserialize(data: &[u8]) -> Option<PlayerLeft> {
-        if data.len() < 18 {
-            return None;
+        let (player_id, rest) = data.split_at_checked(MIN_PLAYER_LEFT_PAYLOAD)?;
+        Some(PlayerLeft {
+            player_id: String::from_utf8(player_id.to_vec()).ok()?,
+            reason: rest.first().copied().map(LeaveReason::from_byte),
+        })
+    }
+}
+
+/// Optional heartbeat payload describing the server, see `ServerConfig::heartbeat_status`.
+///
+/// Payload layout: big endian `u16` player count followed by the `u32` server tick.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HeartbeatStatus {
+    pub player_count: u16,
+    /// Simulation tick, wrapping at `u32::MAX`.
+    pub tick: u32,
+}
+
+impl HeartbeatStatus {
+    /// Saturates `player_count` at `u16::MAX` and wraps `tick`.
+    #[must_use]
+    pub fn new(player_count: usize, tick: u64) -> Self {
+        HeartbeatStatus {
+            player_count: u16::try_from(player_count).unwrap_or(u16::MAX),
+            tick: wrap_u32(tick),
         }
-        let player_id = String::from_utf8(data[..18].to_vec()).ok()?;
-        Some(PlayerLeft { player_id })
+    }
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(6);
+        buf.extend_from_slice(&self.player_count.to_be_bytes());
+        buf.extend_from_slice(&self.tick.to_be_bytes());
+        buf
+    }
+    #[must_use]
+    pub fn deserialize(data: &[u8]) -> Option<HeartbeatStatus> {
+        let data = data.get(..6)?;
+        Some(HeartbeatStatus {
+            player_count: u16::from_be_bytes([data[0], data[1]]),
+            tick: u32::from_be_bytes([data[2], data[3], data[4], data[5]]),
+        })
     }
 }
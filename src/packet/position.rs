@@ -1,7 +1,27 @@
-use crate::game_state::Position;
+use crate::{
+    game_state::{Position, PLAYER_ID_LEN, POSITION_F64_SIZE},
+    num::{coord_to_f32, record_capacity},
+};
+
+use super::sizes::MIN_POSITION_BATCH_PAYLOAD;
+
+/// Size of one `(id, position)` record in a [`PositionBatch`].
+pub const POSITION_RECORD_SIZE: usize = PLAYER_ID_LEN + 8;
+/// Maximum records per [`PositionBatch`] datagram.
+///
+/// Keeps the datagram (24 byte header, 2 byte count, records) under 1200 bytes,
+/// which fits the usual internet MTU without IP fragmentation.
+pub const MAX_POSITION_BATCH_RECORDS: usize = 45;
+/// Size of one `(id, position)` record with `f64` coordinates, for players that
+/// negotiated `Features::F64_POSITIONS`.
+pub const POSITION_RECORD_F64_SIZE: usize = PLAYER_ID_LEN + POSITION_F64_SIZE;
+/// Maximum records per [`PositionBatch`] datagram in the `f64` format, under the
+/// same 1200 bytes as [`MAX_POSITION_BATCH_RECORDS`].
+pub const MAX_POSITION_BATCH_F64_RECORDS: usize = 34;
 
 #[derive(Debug, Clone)]
 #[allow(clippy::module_name_repetitions)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PlayerPosition {
     pub id: Vec<u8>,
     pub position: Position,
@@ -13,18 +33,273 @@ impl PlayerPosition {
     }
     #[must_use]
     pub fn serialize(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(18 + 8);
+        let mut buf = Vec::with_capacity(POSITION_RECORD_SIZE);
         buf.extend_from_slice(&self.id);
         buf.extend_from_slice(&self.position.serialize());
         buf
     }
     #[must_use]
     pub fn deserialize(data: &[u8]) -> Option<PlayerPosition> {
-        if data.len() < 26 {
+        if data.len() < POSITION_RECORD_SIZE {
             return None;
         }
-        let id = data[..18].to_vec();
-        let position = Position::deserialize(&data[18..])?;
+        let (id, position) = data.split_at(PLAYER_ID_LEN);
+        let id = id.to_vec();
+        let position = Position::deserialize(position)?;
         Some(PlayerPosition { id, position })
     }
+    /// The record with the position in the 16 byte format of
+    /// [`Position::serialize_f64`].
+    #[must_use]
+    pub fn serialize_f64(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(POSITION_RECORD_F64_SIZE);
+        buf.extend_from_slice(&self.id);
+        buf.extend_from_slice(&self.position.serialize_f64());
+        buf
+    }
+    /// Reads the format of [`PlayerPosition::serialize_f64`].
+    #[must_use]
+    pub fn deserialize_f64(data: &[u8]) -> Option<PlayerPosition> {
+        if data.len() < POSITION_RECORD_F64_SIZE {
+            return None;
+        }
+        let (id, position) = data.split_at(PLAYER_ID_LEN);
+        let id = id.to_vec();
+        let position = Position::deserialize_f64(position)?;
+        Some(PlayerPosition { id, position })
+    }
+}
+
+/// All position changes a recipient needs for one tick, sent as a single datagram.
+///
+/// Payload layout: `u16` big endian record count followed by that many
+/// 26 byte [`PlayerPosition`] records.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PositionBatch {
+    pub positions: Vec<PlayerPosition>,
+}
+impl PositionBatch {
+    #[must_use]
+    pub fn new(positions: Vec<PlayerPosition>) -> Self {
+        PositionBatch { positions }
+    }
+    /// Splits `positions` into batches of at most [`MAX_POSITION_BATCH_RECORDS`] records.
+    #[must_use]
+    pub fn split(positions: &[PlayerPosition]) -> Vec<PositionBatch> {
+        positions
+            .chunks(MAX_POSITION_BATCH_RECORDS)
+            .map(|chunk| PositionBatch::new(chunk.to_vec()))
+            .collect()
+    }
+    /// Splits `positions` into batches of at most [`MAX_POSITION_BATCH_F64_RECORDS`]
+    /// records, for [`PositionBatch::serialize_f64`].
+    #[must_use]
+    pub fn split_f64(positions: &[PlayerPosition]) -> Vec<PositionBatch> {
+        positions
+            .chunks(MAX_POSITION_BATCH_F64_RECORDS)
+            .map(|chunk| PositionBatch::new(chunk.to_vec()))
+            .collect()
+    }
+    /// # Panics
+    ///
+    /// if the batch holds more than `u16::MAX` records, use [`PositionBatch::split`]
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let count = u16::try_from(self.positions.len()).expect("Too many records in batch");
+        let mut buf = Vec::with_capacity(record_capacity(
+            2,
+            POSITION_RECORD_SIZE,
+            self.positions.len(),
+        ));
+        buf.extend_from_slice(&count.to_be_bytes());
+        for position in &self.positions {
+            buf.extend_from_slice(&position.serialize());
+        }
+        buf
+    }
+    #[must_use]
+    pub fn deserialize(data: &[u8]) -> Option<PositionBatch> {
+        PositionBatch::decode(data, POSITION_RECORD_SIZE, PlayerPosition::deserialize)
+    }
+    /// Like [`PositionBatch::serialize`] with [`PlayerPosition::serialize_f64`] records.
+    ///
+    /// # Panics
+    ///
+    /// if the batch holds more than `u16::MAX` records, use [`PositionBatch::split_f64`]
+    #[must_use]
+    pub fn serialize_f64(&self) -> Vec<u8> {
+        let count = u16::try_from(self.positions.len()).expect("Too many records in batch");
+        let mut buf = Vec::with_capacity(record_capacity(
+            2,
+            POSITION_RECORD_F64_SIZE,
+            self.positions.len(),
+        ));
+        buf.extend_from_slice(&count.to_be_bytes());
+        for position in &self.positions {
+            buf.extend_from_slice(&position.serialize_f64());
+        }
+        buf
+    }
+    /// Reads the format of [`PositionBatch::serialize_f64`].
+    #[must_use]
+    pub fn deserialize_f64(data: &[u8]) -> Option<PositionBatch> {
+        PositionBatch::decode(
+            data,
+            POSITION_RECORD_F64_SIZE,
+            PlayerPosition::deserialize_f64,
+        )
+    }
+    fn decode(
+        data: &[u8],
+        record_size: usize,
+        record: fn(&[u8]) -> Option<PlayerPosition>,
+    ) -> Option<PositionBatch> {
+        if data.len() < MIN_POSITION_BATCH_PAYLOAD {
+            return None;
+        }
+        let count = usize::from(u16::from_be_bytes([data[0], data[1]]));
+        let records = &data[MIN_POSITION_BATCH_PAYLOAD..];
+        if records.len() < count.checked_mul(record_size)? {
+            return None;
+        }
+        let positions = records
+            .chunks_exact(record_size)
+            .take(count)
+            .map(record)
+            .collect::<Option<Vec<_>>>()?;
+        Some(PositionBatch { positions })
+    }
+}
+
+/// Moves several players controlled by the sending connection at once.
+///
+/// Payload layout: `u16` big endian record count followed by that many 26 byte
+/// `(id, position)` records. Like a `PositionUpdate`, positions are little endian.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BulkPositionUpdate {
+    pub positions: Vec<PlayerPosition>,
+}
+impl BulkPositionUpdate {
+    #[must_use]
+    pub fn new(positions: Vec<PlayerPosition>) -> Self {
+        BulkPositionUpdate { positions }
+    }
+    /// # Panics
+    ///
+    /// if the update holds more than `u16::MAX` records
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let count = u16::try_from(self.positions.len()).expect("Too many records in update");
+        let mut buf = Vec::with_capacity(record_capacity(
+            2,
+            POSITION_RECORD_SIZE,
+            self.positions.len(),
+        ));
+        buf.extend_from_slice(&count.to_be_bytes());
+        for record in &self.positions {
+            buf.extend_from_slice(&record.id);
+            buf.extend_from_slice(&coord_to_f32(record.position.x).to_le_bytes());
+            buf.extend_from_slice(&coord_to_f32(record.position.y).to_le_bytes());
+        }
+        buf
+    }
+    /// Returns `None` if the payload holds fewer records than its count.
+    #[must_use]
+    pub fn deserialize(data: &[u8]) -> Option<BulkPositionUpdate> {
+        // Same framing as a batch, and `PlayerPosition::deserialize` reads little endian
+        PositionBatch::deserialize(data).map(|batch| BulkPositionUpdate::new(batch.positions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_position_batch_round_trip() {
+        let batch = PositionBatch::new(vec![
+            PlayerPosition::new(vec![1; PLAYER_ID_LEN], Position::new(1.0, 2.0)),
+            PlayerPosition::new(vec![2; PLAYER_ID_LEN], Position::new(3.0, 4.0)),
+        ]);
+        let data = batch.serialize();
+        assert_eq!(data.len(), 2 + 2 * POSITION_RECORD_SIZE);
+
+        let decoded = PositionBatch::deserialize(&data).unwrap();
+        assert_eq!(decoded.positions.len(), 2);
+        assert_eq!(decoded.positions[0].id, vec![1; PLAYER_ID_LEN]);
+        assert_eq!(decoded.positions[1].id, vec![2; PLAYER_ID_LEN]);
+    }
+
+    #[test]
+    fn test_position_batch_rejects_truncated_payload() {
+        let batch = PositionBatch::new(vec![PlayerPosition::new(
+            vec![1; PLAYER_ID_LEN],
+            Position::new(1.0, 2.0),
+        )]);
+        let data = batch.serialize();
+        assert!(PositionBatch::deserialize(&data[..data.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn test_position_batch_split_respects_cap() {
+        let positions =
+            vec![PlayerPosition::new(vec![0; PLAYER_ID_LEN], Position::new(0.0, 0.0)); 100];
+        let batches = PositionBatch::split(&positions);
+        assert_eq!(batches.len(), 3);
+        assert!(batches
+            .iter()
+            .all(|b| b.positions.len() <= MAX_POSITION_BATCH_RECORDS));
+        assert!(batches[0].serialize().len() + 24 <= 1200);
+    }
+
+    #[test]
+    fn test_position_batch_f64_round_trip() {
+        let batch = PositionBatch::new(vec![
+            PlayerPosition::new(vec![1; PLAYER_ID_LEN], Position::new(1.5, -2.0)),
+            PlayerPosition::new(vec![2; PLAYER_ID_LEN], Position::new(3.0, 4.25)),
+        ]);
+        let data = batch.serialize_f64();
+        assert_eq!(data.len(), 2 + 2 * POSITION_RECORD_F64_SIZE);
+
+        let decoded = PositionBatch::deserialize_f64(&data).unwrap();
+        assert_eq!(decoded.positions[0].id, vec![1; PLAYER_ID_LEN]);
+        assert_eq!(decoded.positions[0].position, Position::new(1.5, -2.0));
+        assert_eq!(decoded.positions[1].position, Position::new(3.0, 4.25));
+        assert!(PositionBatch::deserialize_f64(&data[..data.len() - 1]).is_none());
+
+        let positions =
+            vec![PlayerPosition::new(vec![0; PLAYER_ID_LEN], Position::new(0.0, 0.0)); 100];
+        let batches = PositionBatch::split_f64(&positions);
+        assert_eq!(batches.len(), 3);
+        assert!(batches[0].serialize_f64().len() + 24 <= 1200);
+    }
+
+    #[test]
+    #[cfg(feature = "f64-positions")]
+    fn test_position_batch_f64_keeps_large_coordinates() {
+        let far = Position::new(100_000_000.125, -16_777_217.5);
+        let batch = PositionBatch::new(vec![PlayerPosition::new(
+            vec![1; PLAYER_ID_LEN],
+            far.clone(),
+        )]);
+        let decoded = PositionBatch::deserialize_f64(&batch.serialize_f64()).unwrap();
+        assert_eq!(decoded.positions[0].position, far);
+    }
+
+    #[test]
+    fn test_bulk_position_update_round_trip() {
+        let update = BulkPositionUpdate::new(vec![
+            PlayerPosition::new(vec![1; PLAYER_ID_LEN], Position::new(1.0, 2.0)),
+            PlayerPosition::new(vec![2; PLAYER_ID_LEN], Position::new(3.0, 4.0)),
+        ]);
+        let decoded = BulkPositionUpdate::deserialize(&update.serialize()).unwrap();
+        assert_eq!(decoded.positions.len(), 2);
+        assert_eq!(decoded.positions[1].id, vec![2; PLAYER_ID_LEN]);
+        assert_eq!(
+            decoded.positions[1].position.serialize(),
+            Position::new(3.0, 4.0).serialize()
+        );
+    }
 }
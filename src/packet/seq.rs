@@ -0,0 +1,159 @@
+/// Half the sequence number space, see [`SeqNum::is_newer_than`].
+const HALF_RANGE: u32 = 1 << 31;
+/// How many sequence numbers behind the newest one a [`ReplayWindow`] remembers.
+pub const REPLAY_WINDOW_SIZE: u32 = u64::BITS;
+
+/// A packet sequence number, wrapping from `u32::MAX` back to 0.
+///
+/// Compare them with [`SeqNum::is_newer_than`], never with `<`: after a wrap the newer
+/// number is the smaller one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SeqNum(pub u32);
+
+impl SeqNum {
+    /// The number following this one, wrapping on overflow.
+    #[must_use]
+    pub fn next(self) -> SeqNum {
+        SeqNum(self.0.wrapping_add(1))
+    }
+    /// Serial number comparison as in RFC 1982: `self` is newer if it is less than half
+    /// the number space ahead of `other`, counting across the wrap. Numbers exactly half
+    /// the space apart are newer than neither.
+    #[must_use]
+    pub fn is_newer_than(self, other: SeqNum) -> bool {
+        let ahead = self.0.wrapping_sub(other.0);
+        ahead != 0 && ahead < HALF_RANGE
+    }
+}
+
+impl From<u32> for SeqNum {
+    fn from(seq_num: u32) -> Self {
+        SeqNum(seq_num)
+    }
+}
+
+impl From<SeqNum> for u32 {
+    fn from(seq_num: SeqNum) -> u32 {
+        seq_num.0
+    }
+}
+
+/// Sliding window over the sequence numbers received in a session, rejecting any seen
+/// before. Numbers older than [`REPLAY_WINDOW_SIZE`] behind the newest are rejected too,
+/// as the window can no longer tell whether they were seen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReplayWindow {
+    newest: Option<SeqNum>,
+    /// Bit `n` is set when `newest - n` was received.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Records `seq_num`, returning `false` if it was already seen or is too old to tell.
+    pub fn accept(&mut self, seq_num: SeqNum) -> bool {
+        let Some(newest) = self.newest else {
+            self.newest = Some(seq_num);
+            self.seen = 1;
+            return true;
+        };
+        if seq_num.is_newer_than(newest) {
+            let ahead = seq_num.0.wrapping_sub(newest.0);
+            self.seen = self.seen.checked_shl(ahead).unwrap_or(0) | 1;
+            self.newest = Some(seq_num);
+            return true;
+        }
+        let Some(bit) = 1u64.checked_shl(newest.0.wrapping_sub(seq_num.0)) else {
+            return false;
+        };
+        let fresh = self.seen & bit == 0;
+        self.seen |= bit;
+        fresh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_wraps() {
+        assert_eq!(SeqNum(7).next(), SeqNum(8));
+        assert_eq!(SeqNum(u32::MAX).next(), SeqNum(0));
+    }
+
+    #[test]
+    fn test_newer_without_wrap() {
+        assert!(SeqNum(2).is_newer_than(SeqNum(1)));
+        assert!(!SeqNum(1).is_newer_than(SeqNum(2)));
+        assert!(!SeqNum(5).is_newer_than(SeqNum(5)));
+    }
+
+    #[test]
+    fn test_newer_across_wrap() {
+        assert!(SeqNum(0).is_newer_than(SeqNum(u32::MAX)));
+        assert!(!SeqNum(u32::MAX).is_newer_than(SeqNum(0)));
+        assert!(SeqNum(10).is_newer_than(SeqNum(u32::MAX - 10)));
+        assert!(!SeqNum(u32::MAX - 10).is_newer_than(SeqNum(10)));
+        for ahead in [1, 2, 1000, HALF_RANGE - 1] {
+            let old = SeqNum(u32::MAX - 3);
+            let new = SeqNum(old.0.wrapping_add(ahead));
+            assert!(new.is_newer_than(old), "{ahead}");
+            assert!(!old.is_newer_than(new), "{ahead}");
+        }
+    }
+
+    #[test]
+    fn test_half_range_apart_is_newer_than_neither() {
+        let a = SeqNum(3);
+        let b = SeqNum(3 + HALF_RANGE);
+        assert!(!a.is_newer_than(b));
+        assert!(!b.is_newer_than(a));
+        // One less than half the range is still newer, one more is older
+        assert!(SeqNum(2 + HALF_RANGE).is_newer_than(a));
+        assert!(!SeqNum(4 + HALF_RANGE).is_newer_than(a));
+        assert!(a.is_newer_than(SeqNum(4 + HALF_RANGE)));
+    }
+
+    #[test]
+    fn test_counting_past_the_wrap_stays_ordered() {
+        let mut seq = SeqNum(u32::MAX - 2);
+        for _ in 0..6 {
+            let next = seq.next();
+            assert!(next.is_newer_than(seq));
+            seq = next;
+        }
+        assert_eq!(seq, SeqNum(3));
+    }
+
+    #[test]
+    fn test_replay_window_rejects_repeats() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(SeqNum(5)));
+        assert!(!window.accept(SeqNum(5)));
+        assert!(window.accept(SeqNum(7)));
+        // Late but unseen is still fine, once
+        assert!(window.accept(SeqNum(6)));
+        assert!(!window.accept(SeqNum(6)));
+        assert!(!window.accept(SeqNum(7)));
+        assert!(window.accept(SeqNum(8)));
+    }
+
+    #[test]
+    fn test_replay_window_slides() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(SeqNum(u32::MAX - 1)));
+        // Across the wrap
+        assert!(window.accept(SeqNum(1)));
+        assert!(!window.accept(SeqNum(u32::MAX - 1)));
+        assert!(window.accept(SeqNum(u32::MAX)));
+        assert!(window.accept(SeqNum(1 + REPLAY_WINDOW_SIZE)));
+        // Fell out of the window
+        assert!(!window.accept(SeqNum(1)));
+        // The oldest still in it
+        assert!(window.accept(SeqNum(2)));
+        assert!(!window.accept(SeqNum(2)));
+        // Half the number space away is neither newer nor in the window
+        assert!(!window.accept(SeqNum(1 + REPLAY_WINDOW_SIZE + HALF_RANGE)));
+    }
+}
@@ -0,0 +1,72 @@
+use super::sizes::MIN_SERVER_SHUTDOWN_PAYLOAD;
+
+/// Longest reason a [`ServerShutdownPacket`] carries, longer ones are cut at a character
+/// boundary.
+pub const MAX_SHUTDOWN_REASON_LEN: usize = 64;
+
+/// Sent to every player and spectator as the server shuts down, so clients can tell
+/// their users and stop sending instead of waiting for the heartbeat timeout.
+///
+/// Payload layout: big endian `u32` seconds until the server stops, zero when it stops
+/// right away, followed by an optional UTF-8 reason.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ServerShutdownPacket {
+    pub eta_secs: u32,
+    pub reason: String,
+}
+
+impl ServerShutdownPacket {
+    /// Cuts `reason` to [`MAX_SHUTDOWN_REASON_LEN`] bytes.
+    #[must_use]
+    pub fn new(eta_secs: u32, reason: &str) -> Self {
+        let mut end = reason.len().min(MAX_SHUTDOWN_REASON_LEN);
+        while !reason.is_char_boundary(end) {
+            end = end.saturating_sub(1);
+        }
+        ServerShutdownPacket {
+            eta_secs,
+            reason: reason[..end].to_string(),
+        }
+    }
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf =
+            Vec::with_capacity(MIN_SERVER_SHUTDOWN_PAYLOAD.saturating_add(self.reason.len()));
+        buf.extend_from_slice(&self.eta_secs.to_be_bytes());
+        buf.extend_from_slice(self.reason.as_bytes());
+        buf
+    }
+    #[must_use]
+    pub fn deserialize(data: &[u8]) -> Option<ServerShutdownPacket> {
+        let (eta_secs, reason) = data.split_first_chunk::<4>()?;
+        Some(ServerShutdownPacket {
+            eta_secs: u32::from_be_bytes(*eta_secs),
+            reason: String::from_utf8(reason.to_vec()).ok()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_shutdown_round_trip() {
+        let shutdown = ServerShutdownPacket::new(30, "maintenance");
+        let data = shutdown.serialize();
+        assert_eq!(&data[..4], &[0, 0, 0, 30]);
+        assert_eq!(ServerShutdownPacket::deserialize(&data), Some(shutdown));
+        assert_eq!(
+            ServerShutdownPacket::deserialize(&[0, 0, 0, 0]),
+            Some(ServerShutdownPacket::default())
+        );
+        assert_eq!(ServerShutdownPacket::deserialize(&[0, 0, 0]), None);
+        assert_eq!(
+            ServerShutdownPacket::new(0, &"é".repeat(MAX_SHUTDOWN_REASON_LEN))
+                .reason
+                .len(),
+            MAX_SHUTDOWN_REASON_LEN
+        );
+    }
+}
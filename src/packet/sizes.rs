@@ -0,0 +1,177 @@
+use crate::game_state::PLAYER_ID_LEN;
+
+use super::{
+    connection_init::{CHALLENGE_NONCE_LEN, RECONNECT_TOKEN_LEN},
+    position::POSITION_RECORD_SIZE,
+    world::WORLD_INFO_SIZE,
+    MessageType,
+};
+
+/// Little endian `x` and `y` of a client's position update.
+pub const MIN_POSITION_UPDATE_PAYLOAD: usize = 8;
+/// The id of the player leaving, also the payload of an `InterestExit`.
+pub const MIN_PLAYER_LEFT_PAYLOAD: usize = PLAYER_ID_LEN;
+/// The joining player's id and position, also the payload of an `InterestEnter`.
+pub const MIN_PLAYER_JOIN_PAYLOAD: usize = POSITION_RECORD_SIZE;
+/// The record count of a `PositionBatch` or `BulkPositionUpdate`.
+pub const MIN_POSITION_BATCH_PAYLOAD: usize = 2;
+/// The sender id, or a whisper's recipient id, the message may be empty.
+pub const MIN_CHAT_PAYLOAD: usize = PLAYER_ID_LEN;
+/// The player id and key length, key and value may be empty.
+pub const MIN_METADATA_PAYLOAD: usize = PLAYER_ID_LEN + 1;
+/// The target id, the admin token may be empty.
+pub const MIN_KICK_PAYLOAD: usize = PLAYER_ID_LEN;
+/// The target id and position, the admin token may be empty.
+pub const MIN_TELEPORT_PAYLOAD: usize = PLAYER_ID_LEN + 8;
+/// The error code, the message may be empty.
+pub const MIN_ERROR_PAYLOAD: usize = 1;
+/// Entity id, kind, position and owner id.
+pub const MIN_ENTITY_SPAWN_PAYLOAD: usize = 4 + 2 + 8 + PLAYER_ID_LEN;
+/// Entity id and position.
+pub const MIN_ENTITY_MOVE_PAYLOAD: usize = 4 + 8;
+/// The entity id.
+pub const MIN_ENTITY_DESPAWN_PAYLOAD: usize = 4;
+/// Player count and uptime.
+pub const MIN_HEALTH_OK_PAYLOAD: usize = 4 + 8;
+/// Seconds until the server stops, the reason may be empty.
+pub const MIN_SERVER_SHUTDOWN_PAYLOAD: usize = 4;
+
+/// Smallest payload a packet of `msg_type` can carry, zero for types whose payload is
+/// optional or entirely variable, and for custom types.
+#[must_use]
+pub fn min_payload_len(msg_type: MessageType) -> usize {
+    match msg_type {
+        MessageType::PositionUpdate => MIN_POSITION_UPDATE_PAYLOAD,
+        MessageType::PlayerLeft | MessageType::InterestExit => MIN_PLAYER_LEFT_PAYLOAD,
+        MessageType::PlayerJoin | MessageType::InterestEnter => MIN_PLAYER_JOIN_PAYLOAD,
+        MessageType::PositionBatch | MessageType::BulkPositionUpdate => MIN_POSITION_BATCH_PAYLOAD,
+        MessageType::ChatMessage | MessageType::Whisper | MessageType::TeamChat => MIN_CHAT_PAYLOAD,
+        MessageType::SetMetadata | MessageType::MetadataUpdate => MIN_METADATA_PAYLOAD,
+        MessageType::Kick => MIN_KICK_PAYLOAD,
+        MessageType::Teleport => MIN_TELEPORT_PAYLOAD,
+        MessageType::Error => MIN_ERROR_PAYLOAD,
+        MessageType::EntitySpawn => MIN_ENTITY_SPAWN_PAYLOAD,
+        MessageType::EntityMove => MIN_ENTITY_MOVE_PAYLOAD,
+        MessageType::EntityDespawn => MIN_ENTITY_DESPAWN_PAYLOAD,
+        MessageType::HealthOk => MIN_HEALTH_OK_PAYLOAD,
+        MessageType::ServerShutdown => MIN_SERVER_SHUTDOWN_PAYLOAD,
+        MessageType::Reconnect => RECONNECT_TOKEN_LEN,
+        MessageType::Challenge => CHALLENGE_NONCE_LEN,
+        MessageType::WorldInfo | MessageType::WorldResize => WORLD_INFO_SIZE,
+        MessageType::Heartbeat
+        | MessageType::ConnectionInit
+        | MessageType::ConfirmPlayerMovement
+        | MessageType::SpectateInit
+        | MessageType::WorldInfoRequest
+        | MessageType::Respawn
+        | MessageType::Draining
+        | MessageType::Ping
+        | MessageType::Pong
+        | MessageType::Disconnect
+        | MessageType::HealthProbe
+        | MessageType::Bundle
+        | MessageType::Custom(_) => 0,
+    }
+}
+
+/// A payload shorter than its message type needs, see [`validate_payload_len`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadTooShort {
+    pub msg_type: MessageType,
+    pub len: usize,
+    pub min: usize,
+}
+impl std::fmt::Display for PayloadTooShort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} payload of {} bytes, at least {} expected",
+            self.msg_type, self.len, self.min
+        )
+    }
+}
+impl std::error::Error for PayloadTooShort {}
+
+/// Checks that a `len` byte payload is long enough for `msg_type`, so handlers never
+/// see a payload too short to parse.
+///
+/// # Errors
+/// Returns [`PayloadTooShort`] if `len` is below [`min_payload_len`].
+pub fn validate_payload_len(msg_type: MessageType, len: usize) -> Result<(), PayloadTooShort> {
+    let min = min_payload_len(msg_type);
+    if len < min {
+        return Err(PayloadTooShort { msg_type, len, min });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::layout::find;
+
+    #[test]
+    fn test_minimums_match_layout() {
+        // Inbound layouts whose fixed fields are all required
+        for name in [
+            "PositionUpdate",
+            "PositionBatch",
+            "BulkPositionUpdate",
+            "PlayerJoin",
+            "PlayerLeft",
+            "Reconnect",
+            "InterestEnter",
+            "InterestExit",
+            "ChatMessage",
+            "Whisper",
+            "TeamChat",
+            "SetMetadata",
+            "MetadataUpdate",
+            "WorldInfo",
+            "WorldResize",
+            "Challenge",
+            "Kick",
+            "Teleport",
+            "Error",
+            "EntitySpawn",
+            "EntityMove",
+            "EntityDespawn",
+            "HealthOk",
+            "ServerShutdown",
+        ] {
+            let layout = find(name).unwrap();
+            assert_eq!(
+                min_payload_len(layout.msg_type.unwrap()),
+                layout.size(),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_short_payloads_are_rejected() {
+        for byte in 0x01..=0x22 {
+            let msg_type = MessageType::from_byte(byte).unwrap();
+            let min = min_payload_len(msg_type);
+            assert_eq!(validate_payload_len(msg_type, min), Ok(()), "{msg_type:?}");
+            assert_eq!(
+                validate_payload_len(msg_type, min + 1),
+                Ok(()),
+                "{msg_type:?}"
+            );
+            if let Some(short) = min.checked_sub(1) {
+                assert_eq!(
+                    validate_payload_len(msg_type, short),
+                    Err(PayloadTooShort {
+                        msg_type,
+                        len: short,
+                        min
+                    }),
+                    "{msg_type:?}"
+                );
+                assert_eq!(validate_payload_len(msg_type, 0).unwrap_err().len, 0);
+            }
+        }
+        assert_eq!(validate_payload_len(MessageType::Custom(0x80), 0), Ok(()));
+    }
+}
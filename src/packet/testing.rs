@@ -0,0 +1,101 @@
+//! Builders for the packets clients send, so tests don't hand assemble payloads.
+
+use crate::{
+    game_state::{Position, PLAYER_ID_LEN},
+    num::coord_to_f32,
+};
+
+use super::{chat::ChatPacket, GamePacket, MessageType};
+
+/// Builds a well formed client packet, starting from sensible defaults: sequence
+/// number 1 and the zeroed client id of a connection without a player yet.
+#[derive(Debug, Clone)]
+pub(crate) struct PacketBuilder {
+    msg_type: MessageType,
+    seq_num: u32,
+    client_id: Vec<u8>,
+    payload: Vec<u8>,
+}
+
+impl PacketBuilder {
+    fn new(msg_type: MessageType, payload: Vec<u8>) -> Self {
+        PacketBuilder {
+            msg_type,
+            seq_num: 1,
+            client_id: vec![0; PLAYER_ID_LEN],
+            payload,
+        }
+    }
+    /// A `ConnectionInit` joining the default room.
+    pub(crate) fn connection_init() -> Self {
+        Self::new(MessageType::ConnectionInit, vec![])
+    }
+    /// A `PositionUpdate` to `position`, encoded little endian as clients do.
+    pub(crate) fn position_update(position: &Position) -> Self {
+        let mut payload = Vec::with_capacity(8);
+        payload.extend_from_slice(&coord_to_f32(position.x).to_le_bytes());
+        payload.extend_from_slice(&coord_to_f32(position.y).to_le_bytes());
+        Self::new(MessageType::PositionUpdate, payload)
+    }
+    pub(crate) fn heartbeat() -> Self {
+        Self::new(MessageType::Heartbeat, vec![])
+    }
+    /// A chat line with the sender id left zeroed for the server to fill in.
+    pub(crate) fn chat(message: &str) -> Self {
+        let chat = ChatPacket::new(vec![0; PLAYER_ID_LEN], message.to_string());
+        Self::new(MessageType::ChatMessage, chat.serialize())
+    }
+    pub(crate) fn seq(mut self, seq_num: u32) -> Self {
+        self.seq_num = seq_num;
+        self
+    }
+    /// # Panics
+    ///
+    /// if `client_id` isn't 18 bytes long, the header would be malformed
+    pub(crate) fn client_id(mut self, client_id: &[u8]) -> Self {
+        assert_eq!(
+            client_id.len(),
+            PLAYER_ID_LEN,
+            "client ids are {PLAYER_ID_LEN} bytes"
+        );
+        self.client_id = client_id.to_vec();
+        self
+    }
+    pub(crate) fn payload(mut self, payload: Vec<u8>) -> Self {
+        self.payload = payload;
+        self
+    }
+    pub(crate) fn build(self) -> GamePacket {
+        GamePacket::new(self.msg_type, self.seq_num, self.payload, self.client_id)
+    }
+    /// The packet as sent on the wire.
+    pub(crate) fn serialize(self) -> Vec<u8> {
+        self.build().serialize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{game_state::Coord, packet::PositionGamePacket};
+
+    #[test]
+    fn test_built_packets_decode() {
+        let packet = GamePacket::deserialize(
+            &PacketBuilder::position_update(&Position::new(100.0, 200.0))
+                .seq(7)
+                .client_id(&[b'a'; PLAYER_ID_LEN])
+                .serialize(),
+        )
+        .unwrap();
+        assert_eq!(packet.seq_num, 7);
+        assert_eq!(packet.client_id, vec![b'a'; PLAYER_ID_LEN]);
+        let update = PositionGamePacket::new(&packet);
+        assert!((update.position.x - 100.0).abs() < Coord::EPSILON);
+        assert!((update.position.y - 200.0).abs() < Coord::EPSILON);
+
+        let chat = PacketBuilder::chat("hi").build();
+        let chat = ChatPacket::deserialize(&chat.payload, 256).unwrap();
+        assert_eq!(chat.message, "hi");
+    }
+}
@@ -0,0 +1,63 @@
+use crate::{game_state::Position, num::f32_to_coord};
+
+/// Size of a serialized [`WorldInfo`].
+pub const WORLD_INFO_SIZE: usize = 4 + 4 + 8;
+
+/// Dimensions of the world and where players spawn, sent in reply to a
+/// `WorldInfoRequest` and as part of the `ConnectionInit` response.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WorldInfo {
+    pub width: u32,
+    pub height: u32,
+    pub spawn: Position,
+}
+
+impl WorldInfo {
+    #[must_use]
+    pub fn new(width: u32, height: u32, spawn: Position) -> Self {
+        WorldInfo {
+            width,
+            height,
+            spawn,
+        }
+    }
+    /// Width and height as big endian `u32`s followed by the spawn position.
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(WORLD_INFO_SIZE);
+        buf.extend_from_slice(&self.width.to_be_bytes());
+        buf.extend_from_slice(&self.height.to_be_bytes());
+        buf.extend_from_slice(&self.spawn.serialize());
+        buf
+    }
+    #[must_use]
+    pub fn deserialize(data: &[u8]) -> Option<WorldInfo> {
+        let data = data.get(..WORLD_INFO_SIZE)?;
+        let width = u32::from_be_bytes(data[0..4].try_into().ok()?);
+        let height = u32::from_be_bytes(data[4..8].try_into().ok()?);
+        let spawn = Position::new(
+            f32_to_coord(f32::from_be_bytes(data[8..12].try_into().ok()?)),
+            f32_to_coord(f32::from_be_bytes(data[12..16].try_into().ok()?)),
+        );
+        Some(WorldInfo::new(width, height, spawn))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_state::Coord;
+
+    #[test]
+    fn test_world_info_round_trip() {
+        let info = WorldInfo::new(1920, 1080, Position::new(600.0, 700.0));
+        let data = info.serialize();
+        assert_eq!(data.len(), WORLD_INFO_SIZE);
+        let decoded = WorldInfo::deserialize(&data).unwrap();
+        assert_eq!((decoded.width, decoded.height), (1920, 1080));
+        assert!((decoded.spawn.x - 600.0).abs() < Coord::EPSILON);
+        assert!((decoded.spawn.y - 700.0).abs() < Coord::EPSILON);
+        assert!(WorldInfo::deserialize(&data[..WORLD_INFO_SIZE - 1]).is_none());
+    }
+}
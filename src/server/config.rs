@@ -0,0 +1,253 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    game_state::{
+        IdGenerator, NanoidGenerator, DEFAULT_LOCK_WAIT_THRESHOLD, DEFAULT_PENDING_ENTRY_TTL,
+        DEFAULT_POSITION_HISTORY_RETENTION, DEFAULT_UNKNOWN_HEARTBEAT_WINDOW,
+    },
+    packet::{chat::DEFAULT_MAX_CHAT_PAYLOAD, features::Features, MAX_DATAGRAM_SIZE},
+};
+
+/// IP version of a socket address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    /// Whether `addr` belongs to this family.
+    #[must_use]
+    pub fn matches(self, addr: &std::net::SocketAddr) -> bool {
+        match self {
+            AddressFamily::Ipv4 => addr.is_ipv4(),
+            AddressFamily::Ipv6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// What the server does about heartbeats from addresses without a player or spectator,
+/// e.g. a client the server forgot after a timeout or spoofed traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownHeartbeatPolicy {
+    /// Logs a warning, at most once per `ServerConfig::unknown_heartbeat_window` per
+    /// address.
+    #[default]
+    Warn,
+    /// Drops them without a trace.
+    Ignore,
+    /// Warns and answers with a `NotConnected` error so a client that lost its player
+    /// knows to handshake again, both at most once per window per address.
+    Hint,
+}
+
+/// Tunables for a [`GameServer`](super::GameServer).
+///
+/// `ServerConfig::default()` matches the behavior of `GameServer::new`.
+#[derive(Debug, Clone)]
+#[allow(clippy::module_name_repetitions, clippy::struct_excessive_bools)]
+pub struct ServerConfig {
+    /// How many times per second the simulation loop runs.
+    pub tick_rate_hz: u32,
+    /// Highest tick rate the simulation loop runs at, whatever `tick_rate_hz` asks for.
+    pub max_tick_rate_hz: u32,
+    /// Consecutive failed sends after which a player is considered unreachable and removed.
+    pub max_send_failures: u32,
+    /// When set, players only receive position updates for players within this distance,
+    /// plus enter/exit events as others cross it. `None` broadcasts to everyone.
+    pub interest_radius: Option<f32>,
+    /// Radius of the circle each player occupies. When set, a move that would overlap
+    /// another player stops at the contact point. `None` disables collisions.
+    pub collision_radius: Option<f32>,
+    /// Whether heartbeats carry a [`HeartbeatStatus`](crate::packet::ping::HeartbeatStatus)
+    /// payload. Off by default, older clients expect an empty heartbeat.
+    pub heartbeat_status: bool,
+    /// Whether a `ConnectionInit` must first be answered with a challenge the client echoes
+    /// back before it gets a player. Filters out spoofed and stray packets, off by default
+    /// since older clients send a single `ConnectionInit`.
+    pub require_challenge: bool,
+    /// Whether a `ConnectionInit` asking to rejoin as a given player id gets it when no
+    /// connected player has it. Clients can claim any free id, so only enable this for
+    /// trusted clients, e.g. on a LAN. Off by default: every player gets a fresh id.
+    pub honor_requested_ids: bool,
+    /// How often players are sent a `Ping` they must answer with a `Pong`. `None`, the
+    /// default, disables probing and leaves dead clients to the heartbeat timeout.
+    pub liveness_probe_interval: Option<Duration>,
+    /// Consecutive unanswered probes after which a player is removed.
+    pub max_missed_probes: u32,
+    /// Shared secret required by admin commands such as `Kick`. `None` disables them.
+    pub admin_token: Option<String>,
+    /// Whether players leaving with a `Disconnect` or kicked are sent a last `PlayerLeft`
+    /// with their own id and the reason. Players timing out are assumed gone and never are.
+    pub confirm_departures: bool,
+    /// Largest datagram the server sends. Larger packets are dropped and counted in
+    /// [`ServerMetrics`](super::ServerMetrics) instead of being sent.
+    pub max_datagram_size: usize,
+    /// Largest datagram accepted from clients. Larger ones would be cut short by the
+    /// receive buffer, so they are dropped and counted in
+    /// [`ServerMetrics`](super::ServerMetrics) instead of being parsed.
+    pub max_receive_size: usize,
+    /// Datagrams buffered between the receive task and the handler workers. When full,
+    /// further datagrams are dropped and counted in [`ServerMetrics`](super::ServerMetrics).
+    pub receive_queue_capacity: usize,
+    /// Most datagrams each receive task reads per wakeup: after waiting for one, up to
+    /// this many already waiting are read without waiting again before being queued for
+    /// the handlers. Handler workers take as many off the queue at once, applying the
+    /// position updates among them under one lock, see
+    /// `GameState::apply_position_batch`. One, the default, handles a single datagram
+    /// per wakeup.
+    pub receive_batch_size: usize,
+    /// When set, sends go through a queue per client holding up to this many datagrams,
+    /// drained by a dedicated sender task so handlers never wait on the socket. When a
+    /// client's queue is full its oldest datagram is dropped. `None` sends directly.
+    pub outbound_queue_capacity: Option<usize>,
+    /// Payloads of player lists and position batches larger than this many bytes are
+    /// LZ4 compressed for clients that negotiated compression on their `ConnectionInit`.
+    /// Needs the `compression` feature. `None` never compresses.
+    pub compression_threshold: Option<usize>,
+    /// Optional features offered to clients on connect. Features this build doesn't
+    /// implement are never negotiated.
+    pub supported_features: Features,
+    /// Whether to send from a second socket bound to the same address with
+    /// `SO_REUSEPORT`, instead of the socket datagrams are received on. Unix only.
+    pub separate_send_socket: bool,
+    /// Largest accepted chat payload in bytes, sender id included. Larger chats are dropped.
+    pub max_chat_payload: usize,
+    /// Chat messages kept and replayed to players as they join.
+    pub chat_history_len: usize,
+    /// Minimum time between two respawns of the same player.
+    pub respawn_cooldown: Duration,
+    /// Largest random offset on each axis from the spawn point when respawning.
+    pub respawn_jitter: f32,
+    /// Times binding the socket is tried before giving up, useful when a restarted server's
+    /// port hasn't been released yet. Zero is treated as one.
+    pub bind_attempts: u32,
+    /// Delay before the first bind retry, doubled after every further failure.
+    pub bind_retry_delay: Duration,
+    /// `SO_RCVBUF` requested for the server's sockets, for bursts the OS default buffer
+    /// would drop. The OS may grant a different size, which is logged. `None` keeps
+    /// the OS default.
+    pub recv_buffer_size: Option<usize>,
+    /// `SO_SNDBUF` requested for the server's sockets, see `recv_buffer_size`.
+    pub send_buffer_size: Option<usize>,
+    /// Address family bound when the bind address is a hostname resolving to both.
+    pub preferred_address_family: AddressFamily,
+    /// Handlers still running after this long are logged as slow, with their message type
+    /// and sender, and then left to complete.
+    pub slow_handler_threshold: Duration,
+    /// Waits for the game state lock longer than this are logged with the waiting handler.
+    pub lock_wait_threshold: Duration,
+    /// Unanswered handshake challenges older than this are dropped by the cleanup task.
+    /// Anything below `CHALLENGE_TIMEOUT_SECS` cuts the time clients have to answer.
+    pub pending_entry_ttl: Duration,
+    /// Positions kept per player for lag compensation, see
+    /// [`GameState::position_at`](crate::game_state::GameState::position_at). Zero, the
+    /// default, keeps none.
+    pub position_history_len: usize,
+    /// Age after which a player's kept positions are dropped.
+    pub position_history_retention: Duration,
+    /// How heartbeats from unknown addresses are answered.
+    pub unknown_heartbeats: UnknownHeartbeatPolicy,
+    /// Shortest time between two warnings or hints about heartbeats from the same
+    /// unknown address.
+    pub unknown_heartbeat_window: Duration,
+    /// When set, players that send nothing after their `ConnectionInit` for this long
+    /// are removed at the next cleanup instead of after the heartbeat timeout, freeing
+    /// slots held by half-open connections. `None`, the default, waits for the timeout.
+    pub connect_grace_period: Option<Duration>,
+    /// Mints the ids of new players, random nanoids by default. Ids that aren't
+    /// `PLAYER_ID_LEN` valid bytes are logged and replaced by a random one.
+    pub id_generator: Arc<dyn IdGenerator>,
+    /// Most concurrent players, over every room. When set, the default room's player maps
+    /// are allocated for this many players up front and further `ConnectionInit`s are
+    /// answered with a `ServerFull` error.
+    pub max_players: Option<usize>,
+    /// Most concurrent players connected from the same IP, whatever their port. Further
+    /// `ConnectionInit`s from that IP are answered with a `ServerFull` error. `None`
+    /// doesn't limit players per IP.
+    pub max_players_per_ip: Option<usize>,
+    /// Most rooms open at once, the default room included. A `ConnectionInit` or
+    /// `SpectateInit` asking for another room is answered with a `ServerFull` error.
+    /// `None` doesn't limit rooms.
+    pub max_rooms: Option<usize>,
+    /// Number of tasks dispatching queued datagrams to handlers. Zero is treated as one.
+    pub worker_count: usize,
+    /// `HealthProbe`s answered per second, further ones are dropped unanswered. Zero
+    /// disables health probes.
+    pub health_probes_per_second: u32,
+    /// When set, packets to players that negotiated `Features::BUNDLES` are held for up
+    /// to this long and sent together as one `Bundle` datagram, except latency sensitive
+    /// ones. `None`, the default, sends every packet on its own.
+    pub coalesce_window: Option<Duration>,
+    /// Longest [`GameServer::shutdown`](super::GameServer::shutdown) spends sending the
+    /// `ServerShutdown` notice to players and spectators before stopping regardless.
+    pub shutdown_notice_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            tick_rate_hz: 20,
+            max_tick_rate_hz: 120,
+            max_send_failures: 3,
+            interest_radius: None,
+            collision_radius: None,
+            heartbeat_status: false,
+            require_challenge: false,
+            honor_requested_ids: false,
+            liveness_probe_interval: None,
+            max_missed_probes: 3,
+            admin_token: None,
+            confirm_departures: true,
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+            max_receive_size: 1024,
+            receive_queue_capacity: 1024,
+            receive_batch_size: 1,
+            outbound_queue_capacity: None,
+            separate_send_socket: false,
+            compression_threshold: Some(512),
+            supported_features: Features::implemented(),
+            max_chat_payload: DEFAULT_MAX_CHAT_PAYLOAD,
+            chat_history_len: 20,
+            respawn_cooldown: Duration::from_secs(3),
+            respawn_jitter: 0.0,
+            bind_attempts: 5,
+            bind_retry_delay: Duration::from_millis(100),
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            preferred_address_family: AddressFamily::Ipv4,
+            slow_handler_threshold: Duration::from_millis(100),
+            lock_wait_threshold: DEFAULT_LOCK_WAIT_THRESHOLD,
+            pending_entry_ttl: DEFAULT_PENDING_ENTRY_TTL,
+            position_history_len: 0,
+            position_history_retention: DEFAULT_POSITION_HISTORY_RETENTION,
+            unknown_heartbeats: UnknownHeartbeatPolicy::Warn,
+            unknown_heartbeat_window: DEFAULT_UNKNOWN_HEARTBEAT_WINDOW,
+            connect_grace_period: None,
+            id_generator: Arc::new(NanoidGenerator),
+            max_players: None,
+            max_players_per_ip: None,
+            max_rooms: None,
+            worker_count: 4,
+            health_probes_per_second: 20,
+            coalesce_window: None,
+            shutdown_notice_timeout: Duration::from_millis(250),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Tick rate the simulation loop targets: `tick_rate_hz` capped at `max_tick_rate_hz`,
+    /// and at least one tick per second.
+    #[must_use]
+    pub fn effective_tick_rate_hz(&self) -> u32 {
+        self.tick_rate_hz.min(self.max_tick_rate_hz).max(1)
+    }
+    /// Time between two simulation ticks, see [`ServerConfig::effective_tick_rate_hz`].
+    #[must_use]
+    pub fn tick_interval(&self) -> Duration {
+        Duration::from_secs(1)
+            .checked_div(self.effective_tick_rate_hz())
+            .unwrap_or(Duration::from_secs(1))
+    }
+}
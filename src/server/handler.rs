@@ -0,0 +1,269 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use tokio::{net::UdpSocket, sync::Mutex};
+
+use super::{GameServer, HealthProbes, Rooms, ServerConfig, ServerMetrics};
+use crate::{
+    game_state::GameState,
+    packet::{GamePacket, MessageType},
+};
+
+/// Everything a [`PacketHandler`] may need to act on a packet.
+pub struct HandlerContext {
+    pub socket: Arc<UdpSocket>,
+    /// State of the room the packet's sender is in, see [`Rooms::route`].
+    pub game_state: Arc<Mutex<GameState>>,
+    /// Every room of the server, for handlers acting across rooms.
+    pub rooms: Arc<Rooms>,
+    pub metrics: Arc<ServerMetrics>,
+    pub config: Arc<ServerConfig>,
+    /// Set while the server turns away new players, see [`GameServer::drain`].
+    pub draining: Arc<AtomicBool>,
+    /// Uptime and reply budget reported to `HealthProbe`s.
+    pub health: Arc<HealthProbes>,
+}
+
+impl HandlerContext {
+    /// Context for a packet from `addr`, with the state of the room it is routed to.
+    pub(crate) fn for_sender(&self, addr: SocketAddr) -> HandlerContext {
+        HandlerContext {
+            socket: Arc::clone(&self.socket),
+            game_state: self.rooms.route(&addr.to_string()),
+            rooms: Arc::clone(&self.rooms),
+            metrics: Arc::clone(&self.metrics),
+            config: Arc::clone(&self.config),
+            draining: Arc::clone(&self.draining),
+            health: Arc::clone(&self.health),
+        }
+    }
+}
+
+/// Handles packets of the message types it is registered for.
+///
+/// Register one with [`GameServer::register_handler`] to serve custom message types
+/// or to replace a built-in handler.
+#[async_trait::async_trait]
+#[allow(clippy::module_name_repetitions)]
+pub trait PacketHandler: Send + Sync {
+    async fn handle(&self, ctx: &HandlerContext, packet: &GamePacket, addr: SocketAddr);
+    /// Handles packets of one message type received back to back, in order. The server
+    /// hands over position updates received together this way, see
+    /// `ServerConfig::receive_batch_size`. Handles each packet on its own by default.
+    async fn handle_batch(&self, ctx: &HandlerContext, packets: &[(GamePacket, SocketAddr)]) {
+        for (packet, addr) in packets {
+            self.handle(ctx, packet, *addr).await;
+        }
+    }
+}
+
+/// Handlers keyed by message type byte.
+pub(crate) type HandlerRegistry = HashMap<u8, Arc<dyn PacketHandler>>;
+
+/// Dispatches the message types the server understands out of the box.
+struct BuiltinHandler;
+
+#[async_trait::async_trait]
+impl PacketHandler for BuiltinHandler {
+    #[allow(clippy::too_many_lines)]
+    async fn handle(&self, ctx: &HandlerContext, packet: &GamePacket, addr: SocketAddr) {
+        match packet.msg_type {
+            MessageType::PositionUpdate => {
+                GameServer::handle_position_updates(
+                    &[(packet, addr)],
+                    &ctx.socket,
+                    &ctx.game_state,
+                    ctx.config.collision_radius,
+                )
+                .await;
+            }
+            MessageType::BulkPositionUpdate => {
+                GameServer::handle_bulk_position_update(
+                    packet,
+                    &ctx.socket,
+                    &ctx.game_state,
+                    addr,
+                    ctx.config.collision_radius,
+                )
+                .await;
+            }
+            MessageType::Heartbeat => {
+                GameServer::handle_heartbeat(
+                    packet,
+                    &ctx.socket,
+                    &ctx.game_state,
+                    addr,
+                    ctx.config.unknown_heartbeats,
+                )
+                .await;
+            }
+            MessageType::Pong => {
+                GameServer::handle_pong(packet, &ctx.game_state, addr).await;
+            }
+            MessageType::ConnectionInit => {
+                GameServer::handle_connection_init(
+                    packet,
+                    &ctx.socket,
+                    &ctx.game_state,
+                    &ctx.rooms,
+                    addr,
+                    &ctx.config,
+                    ctx.draining.load(Ordering::Relaxed),
+                )
+                .await;
+            }
+            MessageType::WorldInfoRequest => {
+                GameServer::handle_world_info_request(packet, &ctx.socket, &ctx.game_state, addr)
+                    .await;
+            }
+            MessageType::HealthProbe => {
+                GameServer::handle_health_probe(packet, ctx, addr).await;
+            }
+            MessageType::SpectateInit => {
+                GameServer::handle_spectate_init(
+                    packet,
+                    &ctx.socket,
+                    &ctx.game_state,
+                    &ctx.rooms,
+                    addr,
+                )
+                .await;
+            }
+            MessageType::ChatMessage | MessageType::TeamChat => {
+                GameServer::handle_chat_message(
+                    packet,
+                    &ctx.socket,
+                    &ctx.game_state,
+                    addr,
+                    &ctx.metrics,
+                    &ctx.config,
+                )
+                .await;
+            }
+            MessageType::Whisper => {
+                GameServer::handle_whisper(
+                    packet,
+                    &ctx.socket,
+                    &ctx.game_state,
+                    addr,
+                    &ctx.metrics,
+                    &ctx.config,
+                )
+                .await;
+            }
+            MessageType::Reconnect => {
+                GameServer::handle_reconnect(
+                    packet,
+                    &ctx.socket,
+                    &ctx.game_state,
+                    &ctx.rooms,
+                    addr,
+                )
+                .await;
+            }
+            MessageType::SetMetadata => {
+                GameServer::handle_set_metadata(packet, &ctx.socket, &ctx.game_state, addr).await;
+            }
+            MessageType::Respawn => {
+                GameServer::handle_respawn(packet, &ctx.socket, &ctx.game_state, addr, &ctx.config)
+                    .await;
+            }
+            MessageType::Kick => {
+                GameServer::handle_kick(
+                    packet,
+                    &ctx.socket,
+                    &ctx.game_state,
+                    &ctx.rooms,
+                    addr,
+                    ctx.config.admin_token.as_deref(),
+                    ctx.config.confirm_departures,
+                )
+                .await;
+            }
+            MessageType::Disconnect => {
+                GameServer::handle_disconnect(
+                    packet,
+                    &ctx.socket,
+                    &ctx.game_state,
+                    addr,
+                    ctx.config.confirm_departures,
+                )
+                .await;
+            }
+            MessageType::Teleport => {
+                GameServer::handle_teleport(
+                    packet,
+                    &ctx.socket,
+                    &ctx.game_state,
+                    &ctx.rooms,
+                    addr,
+                    ctx.config.admin_token.as_deref(),
+                )
+                .await;
+            }
+            // Only reachable if the built-in handler is registered for a type it doesn't serve
+            _ => {
+                tracing::warn!(
+                    "No built-in handling for message type {:?}",
+                    packet.msg_type
+                );
+            }
+        }
+    }
+    async fn handle_batch(&self, ctx: &HandlerContext, packets: &[(GamePacket, SocketAddr)]) {
+        // Position updates received together are applied under one lock
+        if packets
+            .iter()
+            .all(|(packet, _)| packet.msg_type == MessageType::PositionUpdate)
+        {
+            let packets = packets
+                .iter()
+                .map(|(packet, addr)| (packet, *addr))
+                .collect::<Vec<_>>();
+            GameServer::handle_position_updates(
+                &packets,
+                &ctx.socket,
+                &ctx.game_state,
+                ctx.config.collision_radius,
+            )
+            .await;
+            return;
+        }
+        for (packet, addr) in packets {
+            self.handle(ctx, packet, *addr).await;
+        }
+    }
+}
+
+/// Registry with the built-in handlers for every message type clients send.
+pub(crate) fn default_handlers() -> HandlerRegistry {
+    let builtin: Arc<dyn PacketHandler> = Arc::new(BuiltinHandler);
+    [
+        MessageType::PositionUpdate,
+        MessageType::BulkPositionUpdate,
+        MessageType::Heartbeat,
+        MessageType::Pong,
+        MessageType::ConnectionInit,
+        MessageType::SpectateInit,
+        MessageType::WorldInfoRequest,
+        MessageType::HealthProbe,
+        MessageType::ChatMessage,
+        MessageType::TeamChat,
+        MessageType::Whisper,
+        MessageType::Reconnect,
+        MessageType::SetMetadata,
+        MessageType::Respawn,
+        MessageType::Kick,
+        MessageType::Teleport,
+        MessageType::Disconnect,
+    ]
+    .into_iter()
+    .map(|msg_type| (msg_type.to_byte(), Arc::clone(&builtin)))
+    .collect()
+}
@@ -0,0 +1,70 @@
+use std::{
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// Uptime and reply budget for `HealthProbe`s, shared by every worker.
+///
+/// Probes never touch the players, but they are answered unauthenticated, so replies
+/// are capped per second to keep the server from being used to reflect traffic.
+#[derive(Debug)]
+pub struct HealthProbes {
+    started: Instant,
+    /// Start of the current one second window and the replies sent in it.
+    window: Mutex<(Instant, u32)>,
+}
+
+impl HealthProbes {
+    #[must_use]
+    pub fn new() -> Self {
+        let now = Instant::now();
+        HealthProbes {
+            started: now,
+            window: Mutex::new((now, 0)),
+        }
+    }
+    /// Time since the server started serving.
+    #[must_use]
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+    /// Takes one reply out of a budget of `per_second`, returning `false` once this
+    /// second's budget is spent.
+    pub fn try_reply(&self, per_second: u32) -> bool {
+        let now = Instant::now();
+        let mut window = self.window.lock().unwrap_or_else(PoisonError::into_inner);
+        if now.saturating_duration_since(window.0) >= Duration::from_secs(1) {
+            *window = (now, 0);
+        }
+        if window.1 >= per_second {
+            return false;
+        }
+        window.1 = window.1.saturating_add(1);
+        true
+    }
+}
+
+impl Default for HealthProbes {
+    fn default() -> Self {
+        HealthProbes::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_budget_refills_every_second() {
+        let probes = HealthProbes::new();
+        assert!(probes.try_reply(2));
+        assert!(probes.try_reply(2));
+        assert!(!probes.try_reply(2));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(probes.try_reply(2));
+        assert_eq!(probes.uptime(), Duration::from_secs(1));
+        assert!(!HealthProbes::new().try_reply(0));
+    }
+}
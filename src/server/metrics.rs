@@ -0,0 +1,199 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Upper bounds of the [`ServerMetrics::lock_waits`] buckets, a last bucket counts
+/// everything slower.
+pub const LOCK_WAIT_BUCKETS: [Duration; 5] = [
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+];
+
+/// Counters describing traffic the server dropped or otherwise flagged.
+#[derive(Debug, Default)]
+#[allow(clippy::module_name_repetitions)]
+pub struct ServerMetrics {
+    /// Datagrams that could not be parsed, including checksum mismatches.
+    pub invalid_packets: AtomicU64,
+    /// Packets whose payload is too short for their message type, see
+    /// [`validate_payload_len`](crate::packet::sizes::validate_payload_len).
+    pub short_payloads: AtomicU64,
+    /// Packets whose message type byte is undefined or has no handler registered.
+    pub unknown_message_types: AtomicU64,
+    /// Outbound datagrams dropped for exceeding `ServerConfig::max_datagram_size`.
+    pub oversize_datagrams: AtomicU64,
+    /// Datagrams dropped because the handler queue was full.
+    pub queue_full_drops: AtomicU64,
+    /// Chat messages dropped for being oversize, malformed or sent from an unknown address.
+    pub rejected_chats: AtomicU64,
+    /// Datagrams dropped for being larger than `ServerConfig::max_receive_size`.
+    pub truncated_datagrams: AtomicU64,
+    /// Outbound datagrams dropped because their destination's send queue was full.
+    pub outbound_queue_drops: AtomicU64,
+    /// Datagrams waiting in the outbound send queues, when enabled.
+    pub outbound_queue_depth: AtomicU64,
+    /// Histogram of waits for the game state lock, see [`LOCK_WAIT_BUCKETS`].
+    pub lock_waits: [AtomicU64; LOCK_WAIT_BUCKETS.len() + 1],
+    /// Waits for the game state lock longer than `ServerConfig::lock_wait_threshold`.
+    pub long_lock_waits: AtomicU64,
+    /// Tick rate the simulation loop aims for, see `ServerConfig::effective_tick_rate_hz`.
+    pub target_tick_rate_hz: AtomicU64,
+    /// Time between the starts of the last two simulation ticks, in microseconds.
+    pub tick_period_micros: AtomicU64,
+    /// Simulation ticks that took longer than the tick interval.
+    pub tick_overruns: AtomicU64,
+    /// Stale handshake challenges and reconnect tokens dropped by the cleanup task.
+    pub expired_entries: AtomicU64,
+    /// Encrypted datagrams dropped for repeating a sequence number, see
+    /// [`ReplayWindow`](crate::packet::ReplayWindow).
+    pub replayed_packets: AtomicU64,
+    /// `HealthProbe`s dropped unanswered for exceeding
+    /// `ServerConfig::health_probes_per_second`.
+    pub dropped_health_probes: AtomicU64,
+}
+
+impl ServerMetrics {
+    #[must_use]
+    pub fn invalid_packets(&self) -> u64 {
+        self.invalid_packets.load(Ordering::Relaxed)
+    }
+    #[must_use]
+    pub fn short_payloads(&self) -> u64 {
+        self.short_payloads.load(Ordering::Relaxed)
+    }
+    #[must_use]
+    pub fn unknown_message_types(&self) -> u64 {
+        self.unknown_message_types.load(Ordering::Relaxed)
+    }
+    #[must_use]
+    pub fn oversize_datagrams(&self) -> u64 {
+        self.oversize_datagrams.load(Ordering::Relaxed)
+    }
+    #[must_use]
+    pub fn queue_full_drops(&self) -> u64 {
+        self.queue_full_drops.load(Ordering::Relaxed)
+    }
+    #[must_use]
+    pub fn rejected_chats(&self) -> u64 {
+        self.rejected_chats.load(Ordering::Relaxed)
+    }
+    #[must_use]
+    pub fn truncated_datagrams(&self) -> u64 {
+        self.truncated_datagrams.load(Ordering::Relaxed)
+    }
+    #[must_use]
+    pub fn outbound_queue_drops(&self) -> u64 {
+        self.outbound_queue_drops.load(Ordering::Relaxed)
+    }
+    #[must_use]
+    pub fn outbound_queue_depth(&self) -> u64 {
+        self.outbound_queue_depth.load(Ordering::Relaxed)
+    }
+    /// Number of lock waits per [`LOCK_WAIT_BUCKETS`] bucket, slowest last.
+    #[must_use]
+    pub fn lock_wait_histogram(&self) -> Vec<u64> {
+        self.lock_waits
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect()
+    }
+    #[must_use]
+    pub fn long_lock_waits(&self) -> u64 {
+        self.long_lock_waits.load(Ordering::Relaxed)
+    }
+    #[must_use]
+    pub fn target_tick_rate(&self) -> u64 {
+        self.target_tick_rate_hz.load(Ordering::Relaxed)
+    }
+    /// Ticks per second going by the last two ticks, zero before the second tick.
+    #[must_use]
+    pub fn actual_tick_rate(&self) -> f64 {
+        match self.tick_period_micros.load(Ordering::Relaxed) {
+            0 => 0.0,
+            micros => Duration::from_micros(micros).as_secs_f64().recip(),
+        }
+    }
+    #[must_use]
+    pub fn tick_overruns(&self) -> u64 {
+        self.tick_overruns.load(Ordering::Relaxed)
+    }
+    #[must_use]
+    pub fn expired_entries(&self) -> u64 {
+        self.expired_entries.load(Ordering::Relaxed)
+    }
+    #[must_use]
+    pub fn replayed_packets(&self) -> u64 {
+        self.replayed_packets.load(Ordering::Relaxed)
+    }
+    #[must_use]
+    pub fn dropped_health_probes(&self) -> u64 {
+        self.dropped_health_probes.load(Ordering::Relaxed)
+    }
+    pub(crate) fn record_invalid_packet(&self) {
+        self.invalid_packets.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn record_short_payload(&self) {
+        self.short_payloads.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn record_unknown_message_type(&self) {
+        self.unknown_message_types.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn record_oversize_datagram(&self) {
+        self.oversize_datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn record_queue_full_drop(&self) {
+        self.queue_full_drops.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn record_rejected_chat(&self) {
+        self.rejected_chats.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn record_truncated_datagram(&self) {
+        self.truncated_datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn record_outbound_queue_drop(&self) {
+        self.outbound_queue_drops.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn record_lock_wait(&self, waited: Duration, long: bool) {
+        let bucket = LOCK_WAIT_BUCKETS
+            .iter()
+            .position(|bound| waited <= *bound)
+            .unwrap_or(LOCK_WAIT_BUCKETS.len());
+        if let Some(bucket) = self.lock_waits.get(bucket) {
+            bucket.fetch_add(1, Ordering::Relaxed);
+        }
+        if long {
+            self.long_lock_waits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    pub(crate) fn set_target_tick_rate(&self, hz: u32) {
+        self.target_tick_rate_hz
+            .store(u64::from(hz), Ordering::Relaxed);
+    }
+    pub(crate) fn record_tick_period(&self, period: Duration) {
+        self.tick_period_micros.store(
+            u64::try_from(period.as_micros()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+    pub(crate) fn record_expired_entries(&self, count: usize) {
+        self.expired_entries
+            .fetch_add(u64::try_from(count).unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+    pub(crate) fn record_replayed_packet(&self) {
+        self.replayed_packets.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn record_dropped_health_probe(&self) {
+        self.dropped_health_probes.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn record_tick_overrun(&self) {
+        self.tick_overruns.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(crate) fn set_outbound_queue_depth(&self, depth: usize) {
+        self.outbound_queue_depth
+            .store(u64::try_from(depth).unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+}
@@ -1,49 +1,533 @@
-use std::{sync::Arc, time::Instant};
+pub mod config;
+pub mod handler;
+pub mod health;
+pub mod metrics;
+pub mod receive;
+pub mod rooms;
 
-use tokio::{net::UdpSocket, sync::Mutex, task};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError,
+    },
+};
+
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, Mutex, Notify},
+    task::{self, JoinHandle},
+};
 
 use crate::{
-    game_state::{self, GameState, Player},
+    game_state::{
+        self, lock_timed, AddPlayerOutcome, BroadcastScope, Entity, EntityId, EntityKind,
+        GameState, Player, PlayerId, Position, PositionHistory, PLAYER_ID_LEN,
+    },
     packet::{
-        connection_init::{ConnectionInitPacketSent, ConnectionInitSync},
-        position::PlayerPosition,
-        GamePacket, MessageType,
+        admin::{KickPacket, TeleportPacket},
+        chat::{ChatPacket, WhisperPacket},
+        connection_init::{
+            parse_player_id, parse_room_id, ChallengePacket, ConnectionInitPacketSent,
+            ConnectionInitRequest, PlayerJoinPacket, ReconnectPacket, CHALLENGE_NONCE_LEN,
+        },
+        crypto,
+        entity::{self, EntityMovePacket, EntitySpawnPacket},
+        error::ErrorCode,
+        features::Features,
+        health::HealthOkPacket,
+        metadata::MetadataPacket,
+        ping::LeaveReason,
+        position::BulkPositionUpdate,
+        shutdown::ServerShutdownPacket,
+        sizes::validate_payload_len,
+        GamePacket, MessageType, ReplayWindow, SeqNum, FLAG_ENCRYPTED, HEADER_SIZE,
     },
-    tasks::{handle_cleanup_task, HeartbeatManager},
+    tasks::OutboundSender,
 };
 
+pub use config::{AddressFamily, ServerConfig, UnknownHeartbeatPolicy};
+pub use handler::{HandlerContext, PacketHandler};
+pub use health::HealthProbes;
+pub use metrics::ServerMetrics;
+pub use receive::recv_batch;
+pub use rooms::Rooms;
+
+use handler::HandlerRegistry;
+
+/// Players the maps are sized for by [`GameServer::warmup`] without `max_players`.
+const WARMUP_CAPACITY: usize = 64;
+
+/// Notifies its `Notify` when dropped.
+struct NotifyOnDrop(Arc<Notify>);
+
+impl Drop for NotifyOnDrop {
+    fn drop(&mut self) {
+        self.0.notify_one();
+    }
+}
+
+/// `EntitySpawn` payload of entity `id`, empty if there is no such entity.
+fn entity_spawn_payload(game_state: &GameState, id: EntityId) -> Vec<u8> {
+    game_state
+        .get_entity(id)
+        .map(|entity| {
+            EntitySpawnPacket::new(
+                id,
+                entity.kind,
+                entity.position.clone(),
+                entity.owner.clone(),
+            )
+            .serialize()
+        })
+        .unwrap_or_default()
+}
+
 #[allow(clippy::module_name_repetitions)]
 pub struct GameServer {
     socket: Arc<UdpSocket>,
+    /// Socket everything is sent from, the receive socket itself unless
+    /// [`ServerConfig::separate_send_socket`] is set.
+    send_socket: Arc<UdpSocket>,
+    /// State of the default room, see [`Rooms`].
     game_state: Arc<Mutex<GameState>>,
+    rooms: Arc<Rooms>,
+    config: ServerConfig,
+    metrics: Arc<ServerMetrics>,
+    handlers: HandlerRegistry,
+    draining: Arc<AtomicBool>,
+    /// Handles of the tasks spawned by `run`, stopped by `shutdown`.
+    tasks: std::sync::Mutex<Vec<JoinHandle<()>>>,
+    shutdown: Notify,
+    /// Notified when a receive task exits, whatever the reason. See [`GameServer::run`].
+    receive_stopped: Arc<Notify>,
+    /// Set once `run` has warmed up and serves packets, see [`GameServer::ready`].
+    ready: AtomicBool,
+    ready_notify: Notify,
 }
 
 impl GameServer {
     #[tracing::instrument(name = "GameServer New", skip(addr))]
     pub async fn new(addr: Option<&str>) -> Result<Self, anyhow::Error> {
+        Self::with_config(addr, ServerConfig::default()).await
+    }
+    #[tracing::instrument(name = "GameServer With Config", skip(addr))]
+    pub async fn with_config(
+        addr: Option<&str>,
+        config: ServerConfig,
+    ) -> Result<Self, anyhow::Error> {
         match addr {
             Some(addr) => {
-                tracing::info!("Binding to address: {}", addr);
-                let socket = Arc::new(UdpSocket::bind(addr).await?);
-                tracing::info!("Socket bound to address: {}", addr);
-                let game_state = Arc::new(Mutex::new(game_state::GameState::default()));
+                let socket = Arc::new(Self::bind(addr, &config).await?);
+                let send_socket = Self::send_socket_for(&socket, &config)?;
+                let metrics = Arc::new(ServerMetrics::default());
+                let rooms = Arc::new(Rooms::new(
+                    Arc::clone(&send_socket),
+                    config.clone(),
+                    Arc::clone(&metrics),
+                ));
                 tracing::info!("Game state initialized");
-                Ok(Self { socket, game_state })
+                Ok(Self {
+                    socket,
+                    send_socket,
+                    game_state: rooms.default_room(),
+                    rooms,
+                    config,
+                    metrics,
+                    handlers: handler::default_handlers(),
+                    draining: Arc::default(),
+                    tasks: std::sync::Mutex::default(),
+                    shutdown: Notify::new(),
+                    receive_stopped: Arc::default(),
+                    ready: AtomicBool::new(false),
+                    ready_notify: Notify::new(),
+                })
             }
-            None => Self::default().await,
+            None => Self::default(config).await,
         }
     }
-    async fn default() -> Result<Self, anyhow::Error> {
+    async fn default(config: ServerConfig) -> Result<Self, anyhow::Error> {
         let server_addr = "0.0.0.0:5000";
-        tracing::info!("Binding to address: {}", server_addr);
-        let socket = Arc::new(UdpSocket::bind(server_addr).await?);
-        tracing::info!("Socket bound to address: {}", server_addr);
+        let socket = Arc::new(Self::bind(server_addr, &config).await?);
+        let send_socket = Self::send_socket_for(&socket, &config)?;
 
-        let game_state = Arc::new(Mutex::new(game_state::GameState::default()));
+        let metrics = Arc::new(ServerMetrics::default());
+        let rooms = Arc::new(Rooms::new(
+            Arc::clone(&send_socket),
+            config.clone(),
+            Arc::clone(&metrics),
+        ));
         tracing::info!("Game state initialized");
 
-        Ok(Self { socket, game_state })
+        Ok(Self {
+            socket,
+            send_socket,
+            game_state: rooms.default_room(),
+            rooms,
+            config,
+            metrics,
+            handlers: handler::default_handlers(),
+            draining: Arc::default(),
+            tasks: std::sync::Mutex::default(),
+            shutdown: Notify::new(),
+            receive_stopped: Arc::default(),
+            ready: AtomicBool::new(false),
+            ready_notify: Notify::new(),
+        })
+    }
+    /// Binds `addr`, retrying with exponential backoff as configured.
+    /// Returns the last bind error once every attempt has failed.
+    async fn bind(addr: &str, config: &ServerConfig) -> std::io::Result<UdpSocket> {
+        let attempts = config.bind_attempts.max(1);
+        let mut delay = config.bind_retry_delay;
+        for attempt in 1..attempts {
+            tracing::info!(
+                "Binding to address: {} (attempt {}/{})",
+                addr,
+                attempt,
+                attempts
+            );
+            match Self::bind_once(addr, config).await {
+                Ok(socket) => {
+                    tracing::info!("Socket bound to address: {}", addr);
+                    return Ok(socket);
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to bind {}: {}, retrying in {:?}", addr, e, delay);
+                    tokio::time::sleep(delay).await;
+                    delay = delay.saturating_mul(2);
+                }
+            }
+        }
+        tracing::info!(
+            "Binding to address: {} (attempt {}/{})",
+            addr,
+            attempts,
+            attempts
+        );
+        let socket = Self::bind_once(addr, config).await.inspect_err(|e| {
+            tracing::error!("Failed to bind {} after {} attempts: {}", addr, attempts, e);
+        })?;
+        tracing::info!("Socket bound to address: {}", addr);
+        Ok(socket)
+    }
+    /// Resolves and binds `addr`, with `SO_REUSEPORT` set if a separate send socket
+    /// has to share the address later.
+    async fn bind_once(addr: &str, config: &ServerConfig) -> std::io::Result<UdpSocket> {
+        let addr = Self::resolve_bind_addr(addr, config.preferred_address_family).await?;
+        let socket = if config.separate_send_socket {
+            Self::bind_reuse_port(addr)?
+        } else {
+            UdpSocket::bind(addr).await?
+        };
+        Self::set_buffer_sizes(&socket, config)?;
+        Ok(socket)
+    }
+    /// Applies the configured `SO_RCVBUF` and `SO_SNDBUF` to `socket` and logs the sizes
+    /// the OS actually granted, which may be clamped or, on Linux, doubled.
+    fn set_buffer_sizes(socket: &UdpSocket, config: &ServerConfig) -> std::io::Result<()> {
+        let socket = socket2::SockRef::from(socket);
+        if let Some(size) = config.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+            tracing::info!(
+                "Requested a {} byte receive buffer, got {}",
+                size,
+                socket.recv_buffer_size()?
+            );
+        }
+        if let Some(size) = config.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+            tracing::info!(
+                "Requested a {} byte send buffer, got {}",
+                size,
+                socket.send_buffer_size()?
+            );
+        }
+        Ok(())
+    }
+    /// Parses `addr` as a socket address, or else resolves it as `host:port` and picks
+    /// the first result of the `preferred` family, falling back to the first result.
+    async fn resolve_bind_addr(
+        addr: &str,
+        preferred: AddressFamily,
+    ) -> std::io::Result<SocketAddr> {
+        if let Ok(literal) = addr.parse() {
+            return Ok(literal);
+        }
+        let candidates = tokio::net::lookup_host(addr).await?.collect::<Vec<_>>();
+        let resolved = candidates
+            .iter()
+            .find(|candidate| preferred.matches(candidate))
+            .or_else(|| candidates.first())
+            .copied()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("bind address {addr} resolved to no addresses"),
+                )
+            })?;
+        tracing::info!("Resolved bind address {} to {}", addr, resolved);
+        Ok(resolved)
+    }
+    #[cfg(unix)]
+    fn bind_reuse_port(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::for_address(addr),
+            socket2::Type::DGRAM,
+            Some(socket2::Protocol::UDP),
+        )?;
+        socket.set_reuse_port(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        UdpSocket::from_std(socket.into())
+    }
+    #[cfg(not(unix))]
+    fn bind_reuse_port(_addr: SocketAddr) -> std::io::Result<UdpSocket> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "separate send sockets need SO_REUSEPORT",
+        ))
+    }
+    /// The socket to send from: a second socket bound to the receive socket's address
+    /// when configured, so replies still come from the port clients talk to.
+    fn send_socket_for(
+        socket: &Arc<UdpSocket>,
+        config: &ServerConfig,
+    ) -> std::io::Result<Arc<UdpSocket>> {
+        if !config.separate_send_socket {
+            return Ok(Arc::clone(socket));
+        }
+        let send_socket = Self::bind_reuse_port(socket.local_addr()?)?;
+        Self::set_buffer_sizes(&send_socket, config)?;
+        tracing::info!(
+            "Bound separate send socket to {:?}",
+            send_socket.local_addr()?
+        );
+        Ok(Arc::new(send_socket))
+    }
+    /// Address the server receives on, with the actual port when bound to port 0.
+    ///
+    /// # Errors
+    /// Returns the socket's error.
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+    /// Counters for dropped and invalid traffic.
+    #[must_use]
+    pub fn metrics(&self) -> Arc<ServerMetrics> {
+        Arc::clone(&self.metrics)
+    }
+    /// Routes packets with message type byte `msg_type` to `handler`, replacing any
+    /// handler already registered for it. Must be called before [`GameServer::run`].
+    pub fn register_handler(&mut self, msg_type: u8, handler: Arc<dyn PacketHandler>) {
+        self.handlers.insert(msg_type, handler);
+    }
+    /// The rooms of the server, each with a game state of its own.
+    #[must_use]
+    pub fn rooms(&self) -> Arc<Rooms> {
+        Arc::clone(&self.rooms)
+    }
+    /// Number of rooms with at least one player.
+    pub async fn room_count(&self) -> usize {
+        let mut count = 0usize;
+        for (_, state) in self.rooms.states() {
+            if state.lock().await.get_player_count() > 0 {
+                count = count.saturating_add(1);
+            }
+        }
+        count
+    }
+    /// Number of players in `room`, zero if it isn't open.
+    pub async fn player_count(&self, room: &str) -> usize {
+        match self.rooms.get(room) {
+            Some(state) => state.lock().await.get_player_count(),
+            None => 0,
+        }
+    }
+    /// Stops accepting new players, answering their `ConnectionInit` with `Draining`,
+    /// while players already connected keep being served.
+    pub fn drain(&self) {
+        tracing::info!("Draining, new players are turned away");
+        self.draining.store(true, Ordering::Relaxed);
+    }
+    /// Accepts new players again after [`GameServer::drain`].
+    pub fn resume(&self) {
+        tracing::info!("Resuming, accepting new players");
+        self.draining.store(false, Ordering::Relaxed);
+    }
+    #[must_use]
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+    /// Resizes the world of `room`, see [`GameState::resize`], and sends the new
+    /// dimensions to its players and spectators as a `WorldResize`. Returns `false` if
+    /// the room isn't open.
+    pub async fn resize_world(&self, room: &str, width: u32, height: u32) -> bool {
+        let Some(state) = self.rooms.get(room) else {
+            return false;
+        };
+        let mut game_state = state.lock().await;
+        let payload = game_state.resize(width, height).serialize();
+        tracing::info!("World of room {:?} resized to {}x{}", room, width, height);
+        game_state
+            .broadcast(
+                &self.send_socket,
+                &BroadcastScope::everyone(),
+                MessageType::WorldResize,
+                |_, _| vec![payload.clone()],
+            )
+            .await;
+        true
+    }
+    /// Current position of `player_id`, `None` if no such player is connected.
+    pub async fn player_position(&self, player_id: &PlayerId) -> Option<Position> {
+        let state = self.rooms.find_player(player_id).await?;
+        let game_state = state.lock().await;
+        Some(game_state.get_player_by_id(player_id)?.position.clone())
+    }
+    /// Moves `player_id` to `position` clamped to the world bounds, for game logic such
+    /// as knockback or teleporters, and sends the new position right away to everyone in
+    /// its room, the player included, and to spectators. A position update the player
+    /// sent earlier in the tick is discarded so it can't undo the move. Returns where
+    /// the player ended up, `None` if no such player is connected.
+    pub async fn set_player_position(
+        &self,
+        player_id: &PlayerId,
+        position: Position,
+    ) -> Option<Position> {
+        let state = self.rooms.find_player(player_id).await?;
+        let mut game_state = state.lock().await;
+        let position = game_state.clamp_position(&position);
+        game_state.get_player_by_id_mut(player_id)?.position = position.clone();
+        game_state
+            .pending_position_updates
+            .remove(player_id.as_bytes());
+        game_state
+            .broadcast_position(player_id, &self.send_socket)
+            .await;
+        Some(position)
+    }
+    /// Adds an entity to `room`, see [`GameState::spawn_entity`], and sends an
+    /// `EntitySpawn` to the players and spectators in that room. Players joining the room
+    /// later are sent every entity in it. Returns `None` if the room isn't open.
+    pub async fn spawn_entity(
+        &self,
+        room: &str,
+        kind: EntityKind,
+        position: &Position,
+        owner: Option<PlayerId>,
+    ) -> Option<EntityId> {
+        let state = self.rooms.get(room)?;
+        let mut game_state = state.lock().await;
+        let id = game_state.spawn_entity(room, kind, position, owner);
+        let payload = entity_spawn_payload(&game_state, id);
+        game_state
+            .broadcast_entity_event(MessageType::EntitySpawn, &payload, &self.send_socket)
+            .await;
+        Some(id)
+    }
+    /// Moves entity `id` of `room`, see [`GameState::move_entity`], and sends an
+    /// `EntityMove` to the players and spectators in that room. Returns `false` if there
+    /// is no such entity.
+    pub async fn move_entity(&self, room: &str, id: EntityId, position: &Position) -> bool {
+        let Some(state) = self.rooms.get(room) else {
+            return false;
+        };
+        let mut game_state = state.lock().await;
+        if !game_state.move_entity(id, position) {
+            return false;
+        }
+        let Some(entity) = game_state.get_entity(id) else {
+            return false;
+        };
+        let payload = EntityMovePacket::new(id, entity.position.clone()).serialize();
+        game_state
+            .broadcast_entity_event(MessageType::EntityMove, &payload, &self.send_socket)
+            .await;
+        true
+    }
+    /// Removes entity `id` of `room` and sends an `EntityDespawn` to the players and
+    /// spectators in that room. Returns the entity, `None` if there was no such entity.
+    pub async fn despawn_entity(&self, room: &str, id: EntityId) -> Option<Entity> {
+        let state = self.rooms.get(room)?;
+        let mut game_state = state.lock().await;
+        let entity = game_state.despawn_entity(id)?;
+        game_state
+            .broadcast_entity_event(
+                MessageType::EntityDespawn,
+                &entity::serialize_despawn(id),
+                &self.send_socket,
+            )
+            .await;
+        Some(entity)
+    }
+    /// Sends the type and payload of `packet` to every player in every room and returns
+    /// how many sends succeeded.
+    ///
+    /// Every recipient gets its own sequence number and its id as client id, like the
+    /// built-in broadcasts. Locks each room's game state in turn while sending, so it is
+    /// safe to call from any task but must not be called while holding one of those
+    /// locks, e.g. from a [`PacketHandler`] that has locked [`HandlerContext::game_state`].
+    pub async fn broadcast(&self, packet: GamePacket) -> usize {
+        self.broadcast_in_every_room(&BroadcastScope::All, &packet)
+            .await
+    }
+    /// Like [`GameServer::broadcast`], limited to the players in `ids`.
+    /// Unknown ids are skipped.
+    pub async fn broadcast_to(&self, ids: &[PlayerId], packet: GamePacket) -> usize {
+        self.broadcast_in_every_room(&BroadcastScope::Only(ids.to_vec()), &packet)
+            .await
+    }
+    async fn broadcast_in_every_room(&self, scope: &BroadcastScope, packet: &GamePacket) -> usize {
+        let mut sent = 0usize;
+        for (_, state) in self.rooms.states() {
+            let room_sent = state
+                .lock()
+                .await
+                .broadcast(&self.send_socket, scope, packet.msg_type, |_, _| {
+                    vec![packet.payload.clone()]
+                })
+                .await;
+            sent = sent.saturating_add(room_sent);
+        }
+        sent
+    }
+    /// Sends `message` as a chat line to `target` alone, from the server: the sender id
+    /// is zeroed and the line isn't kept in the chat history. Returns `false` if the
+    /// target is unknown or the message couldn't be sent.
+    pub async fn send_private_chat(&self, target: &PlayerId, message: &str) -> bool {
+        let Some(state) = self.rooms.find_player(target).await else {
+            return false;
+        };
+        let mut game_state = state.lock().await;
+        let Some(addr) = game_state.addr_for_id(target) else {
+            return false;
+        };
+        let chat = ChatPacket::new(vec![0; PLAYER_ID_LEN], message.to_string());
+        let packet = GamePacket::new(
+            MessageType::ChatMessage,
+            game_state.next_outbound_seq(target),
+            chat.serialize(),
+            target.as_bytes().to_vec(),
+        );
+        match game_state
+            .send_datagram(&self.send_socket, &packet.serialize(), addr)
+            .await
+        {
+            Ok(sent) => sent > 0,
+            Err(e) => {
+                tracing::error!("Error sending private chat to {}: {:?}", target, e);
+                game_state.record_send_failure(target);
+                false
+            }
+        }
     }
+    /// Spawns the server tasks and serves until [`GameServer::shutdown`] is called.
+    ///
+    /// # Errors
+    /// Returns an error if the socket address can't be read, warmup fails, or a receive
+    /// task stops on its own, in which case every other task is stopped first.
     #[tracing::instrument(name = "GameServer Run", skip(self))]
     pub async fn run(&self) -> Result<(), anyhow::Error> {
         tracing::info!("Starting game server");
@@ -53,206 +537,1759 @@ impl GameServer {
         self.spawn_maintenance_tasks();
         tracing::info!("Spawning message receiving task");
         self.spawn_handle_receiving_messages_task();
-        loop {
-            tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+        if let Err(e) = self.warmup().await {
+            self.shutdown().await;
+            return Err(e);
+        }
+        self.ready.store(true, Ordering::Release);
+        self.ready_notify.notify_waiters();
+        tracing::info!("Game server ready");
+        tokio::select! {
+            () = self.shutdown.notified() => {}
+            () = self.receive_stopped.notified() => {
+                // `shutdown` clears the ready flag before stopping the receive tasks
+                if self.is_ready() {
+                    tracing::error!("Receive task stopped unexpectedly, shutting down");
+                    self.shutdown().await;
+                    anyhow::bail!("the receive task stopped unexpectedly");
+                }
+                self.shutdown.notified().await;
+            }
+        }
+        tracing::info!("Game server stopped");
+        Ok(())
+    }
+    /// Gets the server ready for its first player: sizes the player maps, touches the
+    /// state lock and checks that no task spawned so far has already exited.
+    /// Called by [`GameServer::run`] before it reports ready.
+    ///
+    /// # Errors
+    /// Returns an error if a server task has stopped.
+    pub async fn warmup(&self) -> Result<(), anyhow::Error> {
+        self.game_state
+            .lock()
+            .await
+            .reserve(self.config.max_players.unwrap_or(WARMUP_CAPACITY));
+        // Gives the freshly spawned tasks a chance to run up to their first await
+        task::yield_now().await;
+        let stopped = self
+            .tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|handle| handle.is_finished())
+            .count();
+        if stopped > 0 {
+            anyhow::bail!("{stopped} server tasks stopped during warmup");
         }
-        // Ok(())
+        Ok(())
+    }
+    /// Whether [`GameServer::run`] has warmed up and serves packets.
+    #[must_use]
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+    /// Waits until [`GameServer::run`] has warmed up and serves packets.
+    pub async fn ready(&self) {
+        let notified = self.ready_notify.notified();
+        tokio::pin!(notified);
+        // Registered before the check so a `run` finishing in between still wakes us
+        notified.as_mut().enable();
+        if self.is_ready() {
+            return;
+        }
+        notified.await;
     }
     #[tracing::instrument(name = "GameServer Spawn Maintenance Tasks", skip(self))]
     fn spawn_maintenance_tasks(&self) {
-        // Spawn cleanup task
-        let cleanup_state = Arc::clone(&self.game_state);
-        let cleanup_socket = Arc::clone(&self.socket);
-
-        tokio::spawn(handle_cleanup_task(cleanup_state, cleanup_socket));
-        tracing::info!("Spawned cleanup task");
-        // Spawn heartbeat manager
-        let heartbeat_manager =
-            HeartbeatManager::new(Arc::clone(&self.socket), Arc::clone(&self.game_state));
-        task::spawn(async move { heartbeat_manager.run().await });
-        tracing::info!("Spawned heartbeat manager");
+        // Spawn the cleanup task, heartbeat manager, simulation loop and the rest of
+        // every room's tasks
+        self.rooms.start();
+        tracing::info!("Spawned room maintenance tasks");
+        let rooms = Arc::clone(&self.rooms);
+        self.track(task::spawn(async move { rooms.run_sweeper().await }));
+        tracing::info!("Spawned room sweeper");
+        if let Some(queue) = self.rooms.outbound() {
+            let socket = Arc::clone(&self.send_socket);
+            self.track(task::spawn(async move {
+                OutboundSender::new(socket, queue).run().await;
+            }));
+            tracing::info!("Spawned outbound sender");
+        }
     }
+    /// Spawns the receive task and the workers handling what it queues.
+    ///
+    /// The receive task only reads datagrams and hands them to a bounded queue, so a
+    /// slow handler never stalls reception; when the queue is full the datagram is dropped.
     #[tracing::instrument(name = "GameServer Spawn Handle Receiving Messages Task", skip(self))]
     fn spawn_handle_receiving_messages_task(&self) {
-        let socket_for_task = Arc::clone(&self.socket);
-        let state_for_task = Arc::clone(&self.game_state);
-        tokio::spawn(async move {
-            loop {
-                let mut buf = vec![0; 1024];
-                let (len, addr) = match socket_for_task.recv_from(&mut buf).await {
-                    Ok((len, addr)) => (len, addr),
-                    Err(e) => {
-                        tracing::error!("Error receiving from socket: {:?}", e);
-                        continue;
+        let (sender, receiver) =
+            mpsc::channel::<(Vec<u8>, SocketAddr)>(self.config.receive_queue_capacity.max(1));
+        let receiver = Arc::new(Mutex::new(receiver));
+        let ctx = Arc::new(HandlerContext {
+            socket: Arc::clone(&self.send_socket),
+            game_state: Arc::clone(&self.game_state),
+            rooms: Arc::clone(&self.rooms),
+            metrics: Arc::clone(&self.metrics),
+            config: Arc::new(self.config.clone()),
+            draining: Arc::clone(&self.draining),
+            health: Arc::default(),
+        });
+        let handlers = Arc::new(self.handlers.clone());
+        let batch_size = self.config.receive_batch_size.max(1);
+        for _ in 0..self.config.worker_count.max(1) {
+            let receiver = Arc::clone(&receiver);
+            let ctx = Arc::clone(&ctx);
+            let handlers = Arc::clone(&handlers);
+            self.track(tokio::spawn(async move {
+                let mut batch = Vec::with_capacity(batch_size);
+                loop {
+                    if receiver
+                        .lock()
+                        .await
+                        .recv_many(&mut batch, batch_size)
+                        .await
+                        == 0
+                    {
+                        break;
                     }
-                };
-                let Some(package) = GamePacket::deserialize(&buf[..len]) else {
-                    tracing::error!("Error deserializing packet");
-                    continue;
-                };
+                    Self::handle_datagrams(&batch, &ctx, &handlers).await;
+                    batch.clear();
+                }
+            }));
+        }
 
-                match package.msg_type {
-                    MessageType::PositionUpdate => {
-                        Self::handle_position_update(
-                            &package,
-                            &socket_for_task,
-                            &state_for_task,
+        // The kernel spreads datagrams over every socket sharing the port, so a separate
+        // send socket has to be read as well
+        let mut receive_sockets = vec![Arc::clone(&self.socket)];
+        if !Arc::ptr_eq(&self.socket, &self.send_socket) {
+            receive_sockets.push(Arc::clone(&self.send_socket));
+        }
+        for socket_for_task in receive_sockets {
+            self.spawn_receive_task(socket_for_task, sender.clone());
+        }
+    }
+    /// Reads datagrams from `socket_for_task` into the handler queue.
+    fn spawn_receive_task(
+        &self,
+        socket_for_task: Arc<UdpSocket>,
+        sender: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    ) {
+        let metrics = Arc::clone(&self.metrics);
+        let max_receive_size = self.config.max_receive_size;
+        let batch_size = self.config.receive_batch_size.max(1);
+        let stopped = NotifyOnDrop(Arc::clone(&self.receive_stopped));
+        self.track(tokio::spawn(async move {
+            // Dropped however the task ends, returning, panicking or aborted
+            let _stopped = stopped;
+            // One spare byte: filling it means the datagram didn't fit and was cut short
+            let mut buf = vec![0; max_receive_size.saturating_add(1)];
+            let mut batch = Vec::with_capacity(batch_size);
+            loop {
+                if let Err(e) =
+                    receive::recv_batch(&socket_for_task, &mut buf, batch_size, &mut batch).await
+                {
+                    tracing::error!("Error receiving from socket: {:?}", e);
+                    continue;
+                }
+                for (data, addr) in batch.drain(..) {
+                    if data.len() > max_receive_size {
+                        tracing::warn!(
+                            "Dropping datagram from {:?} larger than {} bytes",
                             addr,
-                        )
-                        .await;
+                            max_receive_size
+                        );
+                        metrics.record_truncated_datagram();
+                        continue;
                     }
-                    MessageType::Heartbeat => {
-                        Self::handle_heartbeat(&state_for_task, addr).await;
+                    match sender.try_send((data, addr)) {
+                        Ok(()) => {}
+                        Err(mpsc::error::TrySendError::Full((_, addr))) => {
+                            tracing::warn!("Handler queue full, dropping packet from {:?}", addr);
+                            metrics.record_queue_full_drop();
+                        }
+                        Err(mpsc::error::TrySendError::Closed(_)) => return,
                     }
-                    MessageType::ConnectionInit => {
-                        Self::handle_connection_init(
-                            &package,
-                            &socket_for_task,
-                            &state_for_task,
-                            addr,
-                        )
-                        .await;
+                }
+            }
+        }));
+    }
+    /// Keeps the handle of a spawned task so [`GameServer::shutdown`] can stop it.
+    fn track(&self, handle: JoinHandle<()>) {
+        self.tasks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(handle);
+    }
+    /// Stops every task spawned by [`GameServer::run`] and waits for them to finish,
+    /// then makes `run` return. Returns how many tasks were stopped.
+    ///
+    /// Players and spectators are first sent a `ServerShutdown` without a reason, see
+    /// [`GameServer::shutdown_with_notice`].
+    pub async fn shutdown(&self) -> usize {
+        self.shutdown_with_notice(ServerShutdownPacket::default())
+            .await
+    }
+    /// Like [`GameServer::shutdown`], sending `notice` to every player and spectator
+    /// first. Sending is best effort, given up after
+    /// [`ServerConfig::shutdown_notice_timeout`].
+    pub async fn shutdown_with_notice(&self, notice: ServerShutdownPacket) -> usize {
+        if tokio::time::timeout(
+            self.config.shutdown_notice_timeout,
+            self.announce_shutdown(&notice),
+        )
+        .await
+        .is_err()
+        {
+            tracing::warn!("Gave up sending the shutdown notice, stopping anyway");
+        }
+        self.ready.store(false, Ordering::Release);
+        let mut tasks =
+            std::mem::take(&mut *self.tasks.lock().unwrap_or_else(PoisonError::into_inner));
+        for handle in &tasks {
+            handle.abort();
+        }
+        tasks.extend(self.rooms.stop());
+        let count = tasks.len();
+        for handle in tasks {
+            if let Err(e) = handle.await {
+                if !e.is_cancelled() {
+                    tracing::error!("Server task failed during shutdown: {e}");
+                }
+            }
+        }
+        self.shutdown.notify_one();
+        tracing::info!("Stopped {count} server tasks");
+        count
+    }
+    /// Sends `notice` to every player and spectator of every room as a `ServerShutdown`.
+    async fn announce_shutdown(&self, notice: &ServerShutdownPacket) {
+        let payload = notice.serialize();
+        for (_, state) in self.rooms.states() {
+            state
+                .lock()
+                .await
+                .broadcast(
+                    &self.send_socket,
+                    &BroadcastScope::everyone(),
+                    MessageType::ServerShutdown,
+                    |_, _| vec![payload.clone()],
+                )
+                .await;
+        }
+        // The sender task is about to be stopped, so whatever is queued is sent here
+        if let Some(outbound) = self.rooms.outbound() {
+            for (addr, data) in outbound.take_all() {
+                if let Err(e) = self.send_socket.send_to(&data, addr.as_str()).await {
+                    tracing::error!("Failed to send queued datagram: {addr}: {e}");
+                }
+            }
+        }
+    }
+    /// Decrypts a datagram flagged as encrypted, rejecting it if it doesn't decrypt with
+    /// the session key of `addr`.
+    async fn open_datagram(data: &[u8], addr: SocketAddr, ctx: &HandlerContext) -> Option<Vec<u8>> {
+        let opened = lock_timed(&ctx.game_state, "handle_datagram")
+            .await
+            .open_datagram(&addr.to_string(), data);
+        if opened.is_none() {
+            tracing::warn!("Dropping packet from {:?} that doesn't decrypt", addr);
+            ctx.metrics.record_invalid_packet();
+            Self::reject_datagram(
+                data,
+                addr,
+                ctx,
+                ErrorCode::Malformed,
+                "undecryptable packet",
+            )
+            .await;
+        }
+        opened
+    }
+    /// Enforces the rules of an encrypted session: sealed packets repeating a sequence
+    /// number are dropped, and plaintext is refused except for a new `ConnectionInit`
+    /// replacing the session. Returns whether the packet was refused.
+    async fn refuse_for_session(
+        game_state: &mut GameState,
+        package: &GamePacket,
+        addr: SocketAddr,
+        ctx: &HandlerContext,
+        encrypted: bool,
+    ) -> bool {
+        if encrypted {
+            // The header is authenticated, so the sequence number can't be forged
+            if game_state.accept_sealed_seq(&addr.to_string(), SeqNum(package.seq_num)) {
+                return false;
+            }
+            tracing::warn!(
+                "Dropping replayed packet {} from {:?}",
+                package.seq_num,
+                addr
+            );
+            ctx.metrics.record_replayed_packet();
+            return true;
+        }
+        if package.msg_type == MessageType::ConnectionInit
+            || game_state.session_key_of(&addr.to_string()).is_none()
+        {
+            return false;
+        }
+        tracing::warn!(
+            "Dropping plaintext packet from encrypted session {:?}",
+            addr
+        );
+        ctx.metrics.record_invalid_packet();
+        game_state
+            .send_error(
+                &ctx.socket,
+                addr,
+                package.seq_num,
+                ErrorCode::Forbidden,
+                "encryption required",
+            )
+            .await;
+        true
+    }
+    /// Handles datagrams received together, in order, each in the room its sender is
+    /// routed to. Position updates received back to back for one room go to their
+    /// handler as one run, see [`PacketHandler::handle_batch`].
+    async fn handle_datagrams(
+        batch: &[(Vec<u8>, SocketAddr)],
+        ctx: &HandlerContext,
+        handlers: &HandlerRegistry,
+    ) {
+        let mut positions: Option<(HandlerContext, Vec<_>)> = None;
+        for (data, addr) in batch {
+            let routed = ctx.for_sender(*addr);
+            let Some(package) = Self::accept_datagram(data, *addr, &routed).await else {
+                continue;
+            };
+            if package.msg_type == MessageType::PositionUpdate {
+                match &mut positions {
+                    Some((run_ctx, run))
+                        if Arc::ptr_eq(&run_ctx.game_state, &routed.game_state) =>
+                    {
+                        run.push((package, *addr));
                     }
                     _ => {
-                        tracing::warn!("Received unknown message type: {:?}", package.msg_type);
+                        if let Some((run_ctx, run)) = positions.take() {
+                            Self::dispatch(&run, &run_ctx, handlers).await;
+                        }
+                        positions = Some((routed, vec![(package, *addr)]));
                     }
                 }
+                continue;
             }
-        });
+            if let Some((run_ctx, run)) = positions.take() {
+                Self::dispatch(&run, &run_ctx, handlers).await;
+            }
+            Self::dispatch(&[(package, *addr)], &routed, handlers).await;
+        }
+        if let Some((run_ctx, run)) = positions {
+            Self::dispatch(&run, &run_ctx, handlers).await;
+        }
     }
-    #[tracing::instrument(name = "GameServer Handle Heartbeat", skip(state_for_task))]
-    async fn handle_heartbeat(state_for_task: &Arc<Mutex<GameState>>, addr: std::net::SocketAddr) {
-        let mut state = state_for_task.lock().await;
-
-        if let Some(player) = state.get_player_mut(&addr.to_string()) {
-            player.heartbeat = Instant::now();
+    /// Decodes and checks a datagram, answering it if it is rejected. Returns the packet
+    /// for its handler, or `None` if it was rejected.
+    async fn accept_datagram(
+        data: &[u8],
+        addr: SocketAddr,
+        ctx: &HandlerContext,
+    ) -> Option<GamePacket> {
+        if let Some(&type_byte) = data.first() {
+            if MessageType::from_byte(type_byte).is_none() {
+                tracing::warn!(
+                    "Received unknown message type byte {:#04x} from {:?}",
+                    type_byte,
+                    addr
+                );
+                ctx.metrics.record_unknown_message_type();
+                Self::reject_datagram(
+                    data,
+                    addr,
+                    ctx,
+                    ErrorCode::UnknownMessageType,
+                    "unknown message type",
+                )
+                .await;
+                return None;
+            }
+        }
+        let encrypted = data
+            .get(1)
+            .is_some_and(|version| version & FLAG_ENCRYPTED != 0);
+        let opened;
+        let data = if encrypted {
+            opened = Self::open_datagram(data, addr, ctx).await?;
+            &opened
         } else {
-            tracing::warn!("Received heartbeat from unknown player: {:?}", addr);
+            data
+        };
+        let Some(package) = GamePacket::deserialize(data) else {
+            tracing::error!("Error deserializing packet");
+            ctx.metrics.record_invalid_packet();
+            Self::reject_datagram(data, addr, ctx, ErrorCode::Malformed, "malformed packet").await;
+            return None;
+        };
+        // Ids are handed out as ASCII nanoids, anything else is forged and would break
+        // every later conversion of the id to a string.
+        if std::str::from_utf8(&package.client_id).is_err() {
+            tracing::warn!("Dropping packet with a non UTF-8 client id from {:?}", addr);
+            ctx.metrics.record_invalid_packet();
+            Self::reject_datagram(data, addr, ctx, ErrorCode::Malformed, "invalid client id").await;
+            return None;
+        }
+        if let Err(e) = validate_payload_len(package.msg_type, package.payload.len()) {
+            tracing::warn!("Dropping packet from {:?}: {}", addr, e);
+            ctx.metrics.record_short_payload();
+            let game_state = lock_timed(&ctx.game_state, "handle_datagram").await;
+            if package.msg_type.is_handshake() {
+                game_state
+                    .send_error_to_unknown(
+                        &ctx.socket,
+                        addr,
+                        package.seq_num,
+                        ErrorCode::Malformed,
+                        "payload too short",
+                    )
+                    .await;
+            } else {
+                game_state
+                    .send_error(
+                        &ctx.socket,
+                        addr,
+                        package.seq_num,
+                        ErrorCode::Malformed,
+                        "payload too short",
+                    )
+                    .await;
+            }
+            return None;
+        }
+        {
+            let mut game_state = lock_timed(&ctx.game_state, "handle_datagram").await;
+            if Self::refuse_for_session(&mut game_state, &package, addr, ctx, encrypted).await {
+                return None;
+            }
+            game_state.record_receive(&addr.to_string());
+        }
+        Some(package)
+    }
+    /// Hands `packets`, all of one message type, to the handler registered for it.
+    async fn dispatch(
+        packets: &[(GamePacket, SocketAddr)],
+        ctx: &HandlerContext,
+        handlers: &HandlerRegistry,
+    ) {
+        let Some((first, first_addr)) = packets.first() else {
+            return;
+        };
+        let Some(handler) = handlers.get(&first.msg_type.to_byte()) else {
+            tracing::warn!(
+                "No handler registered for message type {:?} from {:?}",
+                first.msg_type,
+                first_addr
+            );
+            let game_state = lock_timed(&ctx.game_state, "handle_datagram").await;
+            for (package, addr) in packets {
+                ctx.metrics.record_unknown_message_type();
+                game_state
+                    .send_error(
+                        &ctx.socket,
+                        *addr,
+                        package.seq_num,
+                        ErrorCode::UnknownMessageType,
+                        "unhandled message type",
+                    )
+                    .await;
+            }
+            return;
+        };
+        let mut handling = handler.handle_batch(ctx, packets);
+        if tokio::time::timeout(ctx.config.slow_handler_threshold, &mut handling)
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "Slow handler for message type {:?} from {:?}, still running after {:?}",
+                first.msg_type,
+                first_addr,
+                ctx.config.slow_handler_threshold
+            );
+            handling.await;
         }
     }
+    /// Sends an error for a datagram that didn't parse. Only datagrams with a full header
+    /// are answered, and only to addresses [`GameState::send_error`] answers.
+    async fn reject_datagram(
+        data: &[u8],
+        addr: SocketAddr,
+        ctx: &HandlerContext,
+        code: ErrorCode,
+        message: &str,
+    ) {
+        let Some(seq) = data.get(HEADER_SIZE.saturating_sub(4)..HEADER_SIZE) else {
+            return;
+        };
+        let seq_num = u32::from_be_bytes([seq[0], seq[1], seq[2], seq[3]]);
+        lock_timed(&ctx.game_state, "reject_datagram")
+            .await
+            .send_error(&ctx.socket, addr, seq_num, code, message)
+            .await;
+    }
     #[tracing::instrument(
-        name = "GameServer Handle Position Update",
-        skip(socket_for_task, state_for_task)
+        name = "GameServer Handle Heartbeat",
+        skip(package, state_for_task),
+        fields(addr = %addr, seq = package.seq_num, player_id = tracing::field::Empty)
     )]
-    async fn handle_position_update(
+    async fn handle_heartbeat(
         package: &GamePacket,
         socket_for_task: &Arc<UdpSocket>,
         state_for_task: &Arc<Mutex<GameState>>,
         addr: std::net::SocketAddr,
+        unknown: UnknownHeartbeatPolicy,
     ) {
-        let package = crate::packet::PositionGamePacket::new(package);
-
-        let mut game_state = state_for_task.lock().await;
-        game_state.update_player_position(addr.to_string().as_str(), package.position.clone());
-        let position_payload = PlayerPosition::new(package.client_id.clone(), package.position);
+        let mut state = lock_timed(state_for_task, "handle_heartbeat").await;
 
-        for (player_id, player) in &game_state.players {
-            if player.id != String::from_utf8(package.client_id.clone()).unwrap() {
-                let position_packet = GamePacket::new(
-                    MessageType::PositionUpdate,
-                    package.seq_num,
-                    position_payload.serialize(),
-                    player.id.as_bytes().to_vec(),
-                );
-
-                match socket_for_task
-                    .send_to(&position_packet.serialize(), player_id)
-                    .await
-                {
-                    Ok(_) => {
-                        // println!("Position packet sent");
-                    }
-                    Err(e) => tracing::error!("Error sending position packet: {:?}", e),
-                }
+        let now = state.now();
+        // Avatars are kept alive by their connection's heartbeats
+        for avatar in state.avatars_of(&addr.to_string()) {
+            if let Some(avatar) = state.get_player_by_id_mut(&avatar) {
+                avatar.heartbeat = now;
+            }
+        }
+        if let Some(player) = state.get_player_by_addr_mut(&addr.to_string()) {
+            tracing::Span::current().record("player_id", player.id.as_str());
+            player.heartbeat = now;
+        } else if !state.touch_spectator(&addr.to_string())
+            && unknown != UnknownHeartbeatPolicy::Ignore
+            && state.report_unknown_heartbeat(&addr.to_string())
+        {
+            tracing::warn!("Received heartbeat from unknown player: {:?}", addr);
+            if unknown == UnknownHeartbeatPolicy::Hint {
+                state
+                    .send_error_to_unknown(
+                        socket_for_task,
+                        addr,
+                        package.seq_num,
+                        ErrorCode::NotConnected,
+                        "unknown player, connect again",
+                    )
+                    .await;
             }
         }
     }
     #[tracing::instrument(
-        name = "GameServer Handle Connection Init",
-        skip(socket_for_task, state_for_task)
+        name = "GameServer Handle Pong",
+        skip(package, state_for_task),
+        fields(addr = %addr, seq = package.seq_num)
     )]
-    async fn handle_connection_init(
+    async fn handle_pong(
+        package: &GamePacket,
+        state_for_task: &Arc<Mutex<GameState>>,
+        addr: std::net::SocketAddr,
+    ) {
+        let mut game_state = lock_timed(state_for_task, "handle_pong").await;
+        if !game_state.answer_probe(&addr.to_string(), package.seq_num) {
+            tracing::debug!("Pong from {:?} answers no outstanding ping", addr);
+        }
+    }
+    #[tracing::instrument(
+        name = "GameServer Handle Position Updates",
+        skip_all,
+        fields(count = packets.len())
+    )]
+    async fn handle_position_updates(
+        packets: &[(&GamePacket, SocketAddr)],
+        socket_for_task: &Arc<UdpSocket>,
+        state_for_task: &Arc<Mutex<GameState>>,
+        collision_radius: Option<f32>,
+    ) {
+        let mut game_state = lock_timed(state_for_task, "handle_position_updates").await;
+        let mut updates = Vec::with_capacity(packets.len());
+        // Who sent each update, to answer the ones applied clamped
+        let mut senders = Vec::with_capacity(packets.len());
+        for &(package, addr) in packets {
+            // Players that negotiated f64 positions send them in the 16 byte format
+            let f64_positions = game_state
+                .get_player_by_addr(&addr.to_string())
+                .is_some_and(|player| player.features.contains(Features::F64_POSITIONS));
+            let parsed = if f64_positions {
+                crate::packet::PositionGamePacket::new_f64(package)
+            } else {
+                (package.payload.len() >= 8)
+                    .then(|| crate::packet::PositionGamePacket::new(package))
+            };
+            let Some(mut update) = parsed else {
+                tracing::warn!("Malformed position update from {:?}", addr);
+                let message = if f64_positions {
+                    "position update needs 16 bytes"
+                } else {
+                    "position update needs 8 bytes"
+                };
+                game_state
+                    .send_error(
+                        socket_for_task,
+                        addr,
+                        package.seq_num,
+                        ErrorCode::Malformed,
+                        message,
+                    )
+                    .await;
+                continue;
+            };
+            if game_state.is_spectator(&addr.to_string()) {
+                tracing::warn!("Ignoring position update from spectator {:?}", addr);
+                game_state
+                    .send_error(
+                        socket_for_task,
+                        addr,
+                        package.seq_num,
+                        ErrorCode::Forbidden,
+                        "spectators can't move",
+                    )
+                    .await;
+                continue;
+            }
+            let Some(player) = game_state.get_player_by_addr(&addr.to_string()) else {
+                tracing::warn!("Received position update from unknown player: {:?}", addr);
+                game_state
+                    .send_error(
+                        socket_for_task,
+                        addr,
+                        package.seq_num,
+                        ErrorCode::NotConnected,
+                        "not connected",
+                    )
+                    .await;
+                continue;
+            };
+            // Applied to the sender's player, whatever id the header claims
+            update.client_id = player.id.as_bytes().to_vec();
+            updates.push(update);
+            senders.push((addr, package.seq_num));
+        }
+        let applied = game_state.apply_position_batch(&updates, collision_radius);
+        for index in applied.clamped {
+            let Some(&(addr, seq_num)) = senders.get(index) else {
+                continue;
+            };
+            // Still applied, clamped, the error tells the client to correct its position
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    seq_num,
+                    ErrorCode::OutOfBounds,
+                    "position outside the world",
+                )
+                .await;
+        }
+    }
+    #[tracing::instrument(
+        name = "GameServer Handle Bulk Position Update",
+        skip(package, state_for_task),
+        fields(addr = %addr, seq = package.seq_num)
+    )]
+    async fn handle_bulk_position_update(
         package: &GamePacket,
         socket_for_task: &Arc<UdpSocket>,
         state_for_task: &Arc<Mutex<GameState>>,
         addr: std::net::SocketAddr,
+        collision_radius: Option<f32>,
     ) {
-        let mut game_state = state_for_task.lock().await;
-        let player = game_state::Player {
-            id: nanoid::nanoid!(18),
-            position: game_state::Position { x: 600.0, y: 700.0 },
-            heartbeat: std::time::Instant::now(),
-            seq_num: package.seq_num,
-        };
-        let player_id = player.id.clone();
-        game_state.add_player(player, addr.to_string());
-        let players = game_state
-            .get_players()
-            .iter()
-            .filter(|(__, player)| player.id != player_id)
-            .map(|(_, p)| p.clone())
-            .collect::<Vec<Player>>();
-        match socket_for_task
-            .send_to(
-                &ConnectionInitPacketSent::new(
+        let mut game_state = lock_timed(state_for_task, "handle_bulk_position_update").await;
+        let Some(update) = BulkPositionUpdate::deserialize(&package.payload) else {
+            tracing::warn!("Malformed bulk position update from {:?}", addr);
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
                     package.seq_num,
-                    player_id.as_bytes().to_vec(),
-                    players,
+                    ErrorCode::Malformed,
+                    "malformed bulk position update",
                 )
-                .serialize()
-                .serialize(),
-                addr,
-            )
-            .await
-        {
-            Ok(_) => {
-                // println!("Position packet sent");
+                .await;
+            return;
+        };
+        let now = game_state.now();
+        // One error per packet at most, not one per record
+        let mut rejection = None;
+        let mut updates = Vec::with_capacity(update.positions.len());
+        for record in update.positions {
+            let Some(player_id) = std::str::from_utf8(&record.id)
+                .ok()
+                .filter(|id| game_state.controls(&addr.to_string(), id))
+                .map(str::to_string)
+            else {
+                tracing::warn!("{:?} moved a player it doesn't control", addr);
+                rejection = Some((ErrorCode::Forbidden, "moved a player it doesn't control"));
+                continue;
+            };
+            // Avatars are only ever heard from through their controller
+            if let Some(player) = game_state.get_player_by_id_mut(&player_id) {
+                player.heartbeat = now;
             }
-            Err(e) => tracing::error!("Error sending position packet: {:?}", e),
+            updates.push(crate::packet::PositionGamePacket {
+                msg_type: MessageType::PositionUpdate,
+                version: package.version,
+                client_id: record.id,
+                seq_num: package.seq_num,
+                position: record.position,
+            });
         }
-        for (send_addr, player) in &game_state.players {
-            let connection_payload =
-                ConnectionInitSync::new(player_id.as_bytes().to_vec(), player.position.clone());
-
-            if player_id != player.id {
-                // println!("Position {:?}", connection_payload.serialize());
-
-                let connection_packet = GamePacket::new(
-                    MessageType::PlayerJoin,
+        let applied = game_state.apply_position_batch(&updates, collision_radius);
+        if !applied.clamped.is_empty() {
+            rejection.get_or_insert((ErrorCode::OutOfBounds, "position outside the world"));
+        }
+        if let Some((code, message)) = rejection {
+            game_state
+                .send_error(socket_for_task, addr, package.seq_num, code, message)
+                .await;
+        }
+    }
+    #[tracing::instrument(
+        name = "GameServer Handle Connection Init",
+        skip(package, socket_for_task, state_for_task, rooms, config),
+        fields(addr = %addr, seq = package.seq_num, player_id = tracing::field::Empty)
+    )]
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_connection_init(
+        package: &GamePacket,
+        socket_for_task: &Arc<UdpSocket>,
+        state_for_task: &Arc<Mutex<GameState>>,
+        rooms: &Rooms,
+        addr: std::net::SocketAddr,
+        config: &ServerConfig,
+        draining: bool,
+    ) {
+        // Checked against the room the address is in so far, before joining the one asked for
+        let (request, rejoining) = {
+            let mut game_state = lock_timed(state_for_task, "handle_connection_init").await;
+            if draining && game_state.get_player_by_addr(&addr.to_string()).is_none() {
+                tracing::info!("Turning away {:?} while draining", addr);
+                let rejection = GamePacket::new(
+                    MessageType::Draining,
                     package.seq_num,
-                    connection_payload.serialize(),
-                    player.id.as_bytes().to_vec(),
+                    vec![],
+                    vec![0; PLAYER_ID_LEN],
                 );
-                match socket_for_task
-                    .send_to(&connection_packet.serialize(), send_addr)
+                if let Err(e) = game_state
+                    .send_datagram(socket_for_task, &rejection.serialize(), addr)
                     .await
                 {
-                    Ok(_) => {
-                        // println!(
-                        //     "Player join packet sent to {:?} player_id {}",
-                        //     addr, player.id
-                        // );
+                    tracing::error!("Error sending draining rejection: {:?}", e);
+                }
+                return;
+            }
+            if config.require_challenge {
+                let answered =
+                    ChallengePacket::deserialize(&package.payload).is_some_and(|answer| {
+                        game_state.answer_challenge(&addr.to_string(), &answer.nonce)
+                    });
+                if !answered {
+                    let nonce = game_state.issue_challenge(addr.to_string());
+                    let challenge = GamePacket::new(
+                        MessageType::Challenge,
+                        package.seq_num,
+                        ChallengePacket::new(nonce).serialize(),
+                        vec![0; PLAYER_ID_LEN],
+                    );
+                    if let Err(e) = game_state
+                        .send_datagram(socket_for_task, &challenge.serialize(), addr)
+                        .await
+                    {
+                        tracing::error!("Error sending challenge: {:?}", e);
                     }
-                    Err(e) => tracing::error!(
-                        "Error sending player join packet: {:?} send_addr {:?}",
-                        e,
-                        send_addr
-                    ),
+                    return;
                 }
             }
+            let request = if config.require_challenge {
+                package
+                    .payload
+                    .get(CHALLENGE_NONCE_LEN..)
+                    .unwrap_or_default()
+            } else {
+                &package.payload
+            };
+            let Some(request) = ConnectionInitRequest::deserialize(request) else {
+                tracing::warn!(
+                    "Connection init from {:?} with an invalid room id, name or player id",
+                    addr
+                );
+                game_state
+                    .send_error_to_unknown(
+                        socket_for_task,
+                        addr,
+                        package.seq_num,
+                        ErrorCode::InvalidRequest,
+                        "invalid room id, name or player id",
+                    )
+                    .await;
+                return;
+            };
+            // A player reconnecting from the same address replaces itself and takes no new slot
+            let rejoining = game_state.get_player_by_addr(&addr.to_string()).is_some();
+            (request, rejoining)
+        };
+        let ConnectionInitRequest {
+            room,
+            name,
+            features,
+            requested_id,
+            public_key,
+        } = request;
+        let full = match config.max_players {
+            Some(max) if !rejoining => rooms.player_count().await >= max,
+            _ => false,
+        };
+        let ip_full = match config.max_players_per_ip {
+            Some(max) if !rejoining => rooms.players_from_ip(addr.ip()).await >= max,
+            _ => false,
+        };
+        let refusal = if full {
+            tracing::info!("Turning away {:?}, the server is full", addr);
+            Some("server is full")
+        } else if ip_full {
+            tracing::info!("Turning away {:?}, too many players from its IP", addr);
+            Some("too many players from this address")
+        } else {
+            None
+        };
+        // Rooms are opened last, a refused player must not leave an empty one behind
+        let target = match refusal {
+            Some(_) => None,
+            None => rooms.open(&room),
+        };
+        let Some(target) = target else {
+            let message = refusal.unwrap_or_else(|| {
+                tracing::info!("Turning away {:?}, too many rooms are open", addr);
+                "too many rooms"
+            });
+            lock_timed(state_for_task, "handle_connection_init")
+                .await
+                .send_error_to_unknown(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::ServerFull,
+                    message,
+                )
+                .await;
+            return;
+        };
+        // A requested id held in another room is only free when this address holds it
+        let requested_free = match &requested_id {
+            Some(id) => match rooms.find_player(id).await {
+                None => true,
+                Some(holder) => {
+                    Arc::ptr_eq(&holder, state_for_task)
+                        && lock_timed(&holder, "handle_connection_init")
+                            .await
+                            .get_player_by_addr(&addr.to_string())
+                            .is_some_and(|player| &player.id == id)
+                }
+            },
+            None => false,
+        };
+        if !Arc::ptr_eq(&target, state_for_task) {
+            Self::leave_room(state_for_task, addr, socket_for_task).await;
         }
+        let mut game_state = lock_timed(&target, "handle_connection_init").await;
+        game_state.remove_spectator(&addr.to_string());
+        // Clients predating negotiation could only ask for compression with the header flag
+        let mut advertised = features.unwrap_or_default();
+        if package.is_compressed() {
+            advertised = advertised | Features::COMPRESSION;
+        }
+        if public_key.is_none() {
+            advertised = advertised.without(Features::ENCRYPTION);
+        }
+        let mut negotiated = game_state.negotiate_features(advertised);
+        let handshake = public_key
+            .filter(|_| negotiated.contains(Features::ENCRYPTION))
+            .and_then(|public_key| crypto::respond(&public_key));
+        if handshake.is_none() {
+            negotiated = negotiated.without(Features::ENCRYPTION);
+        }
+        // A requested id is free unless another connection plays as it
+        let honored_id = requested_id.clone().filter(|id| {
+            config.honor_requested_ids
+                && requested_free
+                && (game_state.get_player_by_id(id).is_none()
+                    || game_state
+                        .get_player_by_addr(&addr.to_string())
+                        .is_some_and(|player| &player.id == id))
+        });
+        if let Some(id) = &requested_id {
+            if honored_id.is_some() {
+                tracing::info!("{:?} rejoins as requested player id {}", addr, id);
+                // Tokens from the id's earlier sessions must not reclaim the new one
+                game_state.reconnect_tokens.retain(|_, owner| owner != id);
+            } else {
+                tracing::info!("{:?} asked for player id {}, minting a fresh one", addr, id);
+            }
+        }
+        let id = honored_id.clone().unwrap_or_else(|| {
+            game_state::ids::generate_checked(config.id_generator.as_ref(), |id| {
+                game_state.players.contains_key(id)
+            })
+        });
+        let player = game_state::Player {
+            id,
+            position: game_state.spawn.clone(),
+            heartbeat: game_state.now(),
+            seq_num: package.seq_num,
+            send_failures: 0,
+            outbound_seq: 0,
+            metadata: HashMap::new(),
+            last_respawn: None,
+            pending_probe: None,
+            missed_probes: 0,
+            room: room.clone(),
+            name: name.clone(),
+            features: negotiated,
+            session_key: None,
+            replay_window: ReplayWindow::default(),
+            position_history: PositionHistory::default(),
+            last_activity: None,
+            team: None,
+            connected_at: game_state.now(),
+        };
+        let player_id = player.id.clone();
+        tracing::Span::current().record("player_id", player_id.as_str());
+        let spawn_position = player.position.clone();
+        // Whether the id was already playing, when a connection rejoins as itself
+        let rejoined = game_state.get_player_by_id(&player_id).is_some();
+        if let AddPlayerOutcome::Replaced(previous) =
+            game_state.add_player(player, addr.to_string())
+        {
+            tracing::warn!(
+                "Connection init from {:?} replaces its player {}",
+                addr,
+                previous.id
+            );
+            // The joiner may have kept its id, it mustn't hear of itself leaving
+            let scope = BroadcastScope::WithSpectators(Box::new(BroadcastScope::AllExcept(addr)));
+            game_state
+                .broadcast_player_left(&previous.id, &scope, socket_for_task)
+                .await;
+        }
+        let reconnect_token = game_state.issue_reconnect_token(&player_id);
+        let players = game_state
+            .players_by_join_time()
+            .into_iter()
+            .filter(|player| player.id != player_id && player.room == room)
+            .cloned()
+            .collect::<Vec<Player>>();
+        let mut response = ConnectionInitPacketSent::new(
+            package.seq_num,
+            player_id.as_bytes().to_vec(),
+            reconnect_token,
+            game_state.world_info(),
+            players,
+        );
+        // Clients that send a name understand named player records
+        if name.is_some() {
+            response = response.with_names();
+        }
+        if features.is_some() {
+            response = response.with_features(negotiated);
+        }
+        if negotiated.contains(Features::F64_POSITIONS) {
+            response = response.with_f64_positions();
+        }
+        if requested_id.is_some() {
+            response = response.with_requested_id_honored(honored_id.is_some());
+        }
+        if let Some((server_key, _)) = &handshake {
+            response = response.with_public_key(*server_key);
+        }
+        let response = game_state.encode_for(&player_id, response.serialize());
+        if let Err(e) = game_state
+            .send_datagram(socket_for_task, &response, addr)
+            .await
+        {
+            // Without its id the client can't play, so nobody else should see it join
+            tracing::error!(
+                "Error sending connection init response to {:?}, rolling back player {}: {:?}",
+                addr,
+                player_id,
+                e
+            );
+            game_state.remove_player(&player_id);
+            if rejoined {
+                game_state
+                    .broadcast_player_left(&player_id, &BroadcastScope::everyone(), socket_for_task)
+                    .await;
+            }
+            return;
+        }
+        rooms.set_route(addr.to_string(), &room);
+        // The response itself is sent in the clear, the client can't derive the key before
+        if let Some((_, session_key)) = handshake {
+            if let Some(player) = game_state.get_player_by_id_mut(&player_id) {
+                player.session_key = Some(session_key);
+            }
+        }
+        // Replay the recent chat so the joiner has context
+        let history = game_state
+            .chat_history_of(&room)
+            .cloned()
+            .collect::<Vec<_>>();
+        for chat in history {
+            let packet = GamePacket::new(
+                MessageType::ChatMessage,
+                game_state.next_outbound_seq(&player_id),
+                chat.serialize(),
+                player_id.as_bytes().to_vec(),
+            );
+            if let Err(e) = game_state
+                .send_datagram(socket_for_task, &packet.serialize(), addr)
+                .await
+            {
+                tracing::error!("Error sending chat history: {:?}", e);
+            }
+        }
+        // Entities spawned in the room before the player joined
+        let mut entity_ids = game_state
+            .entities
+            .iter()
+            .filter(|(_, entity)| entity.room == room)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        entity_ids.sort_unstable();
+        for entity_id in entity_ids {
+            let packet = GamePacket::new(
+                MessageType::EntitySpawn,
+                game_state.next_outbound_seq(&player_id),
+                entity_spawn_payload(&game_state, entity_id),
+                player_id.as_bytes().to_vec(),
+            );
+            if let Err(e) = game_state
+                .send_datagram(socket_for_task, &packet.serialize(), addr)
+                .await
+            {
+                tracing::error!("Error sending entity: {:?}", e);
+            }
+        }
+        let join_payload = PlayerJoinPacket::new(
+            0,
+            vec![0; PLAYER_ID_LEN],
+            player_id.as_bytes().to_vec(),
+            spawn_position,
+        )
+        .with_name(name)
+        .serialize()
+        .payload;
+        let scope = BroadcastScope::WithSpectators(Box::new(BroadcastScope::AllExcept(addr)));
+        game_state
+            .broadcast(socket_for_task, &scope, MessageType::PlayerJoin, |_, _| {
+                vec![join_payload.clone()]
+            })
+            .await;
+    }
+    /// Removes the player at `addr` from the room of `state`, telling the others in it,
+    /// and the spectator at `addr`, for an address moving to another room.
+    async fn leave_room(state: &Arc<Mutex<GameState>>, addr: SocketAddr, socket: &Arc<UdpSocket>) {
+        let mut game_state = lock_timed(state, "leave_room").await;
+        game_state.remove_spectator(&addr.to_string());
+        let Some(player_id) = game_state
+            .get_player_by_addr(&addr.to_string())
+            .map(|player| player.id.clone())
+        else {
+            return;
+        };
+        tracing::info!("Player {} at {:?} leaves its room", player_id, addr);
+        game_state
+            .remove_player_and_notify(&player_id, socket)
+            .await;
+    }
+    #[tracing::instrument(
+        name = "GameServer Handle Spectate Init",
+        skip(socket_for_task, state_for_task, rooms)
+    )]
+    async fn handle_spectate_init(
+        package: &GamePacket,
+        socket_for_task: &Arc<UdpSocket>,
+        state_for_task: &Arc<Mutex<GameState>>,
+        rooms: &Rooms,
+        addr: std::net::SocketAddr,
+    ) {
+        let is_player = lock_timed(state_for_task, "handle_spectate_init")
+            .await
+            .get_player_by_addr(&addr.to_string())
+            .is_some();
+        let room = parse_room_id(&package.payload);
+        let refusal = if is_player {
+            tracing::warn!("Player at {:?} asked to spectate, ignoring", addr);
+            Some((ErrorCode::InvalidRequest, "players can't spectate"))
+        } else if room.is_none() {
+            tracing::warn!("Spectate init from {:?} with an invalid room id", addr);
+            Some((ErrorCode::Malformed, "invalid room id"))
+        } else {
+            None
+        };
+        let target = match (&refusal, &room) {
+            (None, Some(room)) => rooms.open(room),
+            _ => None,
+        };
+        let (Some(target), Some(room)) = (target, room) else {
+            let (code, message) = refusal.unwrap_or_else(|| {
+                tracing::info!("Turning away spectator {:?}, too many rooms are open", addr);
+                (ErrorCode::ServerFull, "too many rooms")
+            });
+            lock_timed(state_for_task, "handle_spectate_init")
+                .await
+                .send_error_to_unknown(socket_for_task, addr, package.seq_num, code, message)
+                .await;
+            return;
+        };
+        if !Arc::ptr_eq(&target, state_for_task) {
+            Self::leave_room(state_for_task, addr, socket_for_task).await;
+        }
+        let mut game_state = lock_timed(&target, "handle_spectate_init").await;
+        game_state.add_spectator(addr.to_string());
+        rooms.set_route(addr.to_string(), &room);
+        tracing::info!("Spectator joined from {:?}", addr);
+        let reply = GamePacket::new(
+            MessageType::SpectateInit,
+            package.seq_num,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        if let Err(e) = game_state
+            .send_datagram(socket_for_task, &reply.serialize(), addr)
+            .await
+        {
+            tracing::error!("Error sending spectate reply: {:?}", e);
+        }
+    }
+    #[tracing::instrument(
+        name = "GameServer Handle World Info Request",
+        skip(socket_for_task, state_for_task)
+    )]
+    async fn handle_world_info_request(
+        package: &GamePacket,
+        socket_for_task: &Arc<UdpSocket>,
+        state_for_task: &Arc<Mutex<GameState>>,
+        addr: std::net::SocketAddr,
+    ) {
+        let game_state = lock_timed(state_for_task, "handle_world_info_request").await;
+        let world = game_state.world_info();
+        let reply = GamePacket::new(
+            MessageType::WorldInfo,
+            package.seq_num,
+            world.serialize(),
+            package.client_id.clone(),
+        );
+        if let Err(e) = game_state
+            .send_datagram(socket_for_task, &reply.serialize(), addr)
+            .await
+        {
+            tracing::error!("Error sending world info: {:?}", e);
+        }
+    }
+    /// Answers a `HealthProbe` with the player count over every room and the uptime,
+    /// leaving the players alone. Probes beyond `ServerConfig::health_probes_per_second` are dropped.
+    async fn handle_health_probe(package: &GamePacket, ctx: &HandlerContext, addr: SocketAddr) {
+        if !ctx.health.try_reply(ctx.config.health_probes_per_second) {
+            tracing::debug!("Dropping health probe from {:?} over budget", addr);
+            ctx.metrics.record_dropped_health_probe();
+            return;
+        }
+        let player_count = ctx.rooms.player_count().await;
+        let game_state = lock_timed(&ctx.game_state, "handle_health_probe").await;
+        let health = HealthOkPacket::new(
+            u32::try_from(player_count).unwrap_or(u32::MAX),
+            ctx.health.uptime().as_secs(),
+        );
+        let reply = GamePacket::new(
+            MessageType::HealthOk,
+            package.seq_num,
+            health.serialize(),
+            package.client_id.clone(),
+        );
+        if let Err(e) = game_state
+            .send_datagram(&ctx.socket, &reply.serialize(), addr)
+            .await
+        {
+            tracing::error!("Error answering health probe: {:?}", e);
+        }
+    }
+    #[tracing::instrument(
+        name = "GameServer Handle Chat Message",
+        skip(socket_for_task, state_for_task, metrics, config)
+    )]
+    async fn handle_chat_message(
+        package: &GamePacket,
+        socket_for_task: &Arc<UdpSocket>,
+        state_for_task: &Arc<Mutex<GameState>>,
+        addr: std::net::SocketAddr,
+        metrics: &ServerMetrics,
+        config: &ServerConfig,
+    ) {
+        // Rejected before the fan-out, which would amplify it once per player
+        let Some(mut chat) = ChatPacket::deserialize(&package.payload, config.max_chat_payload)
+        else {
+            tracing::warn!("Dropping malformed or oversize chat packet from {:?}", addr);
+            metrics.record_rejected_chat();
+            let code = if package.payload.len() > config.max_chat_payload {
+                ErrorCode::TooLarge
+            } else {
+                ErrorCode::Malformed
+            };
+            lock_timed(state_for_task, "handle_chat_message")
+                .await
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    code,
+                    "chat message rejected",
+                )
+                .await;
+            return;
+        };
+        let mut game_state = lock_timed(state_for_task, "handle_chat_message").await;
+        let Some(sender) = game_state.get_player_by_addr(&addr.to_string()) else {
+            tracing::warn!("Received chat from unknown player: {:?}", addr);
+            metrics.record_rejected_chat();
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::NotConnected,
+                    "not connected",
+                )
+                .await;
+            return;
+        };
+        let sender_id = sender.id.clone();
+        let room = sender.room.clone();
+        chat.sender_id = sender_id.as_bytes().to_vec();
+        // Team chat only reaches teammates and isn't replayed to joiners of other teams
+        let team_chat = package.msg_type == MessageType::TeamChat;
+        let scope = if team_chat {
+            let teammates = game_state
+                .scope_recipients(&BroadcastScope::Team(sender.team))
+                .into_iter()
+                .map(|(_, id)| id)
+                .filter(|id| *id != sender_id)
+                .collect();
+            BroadcastScope::Only(teammates)
+        } else {
+            BroadcastScope::AllExcept(addr)
+        };
+        let payload = chat.serialize();
+        game_state
+            .broadcast(socket_for_task, &scope, package.msg_type, |_, _| {
+                vec![payload.clone()]
+            })
+            .await;
+        if !team_chat {
+            game_state.record_chat(&room, chat, config.chat_history_len);
+        }
+    }
+    #[tracing::instrument(
+        name = "GameServer Handle Whisper",
+        skip(socket_for_task, state_for_task, metrics, config)
+    )]
+    async fn handle_whisper(
+        package: &GamePacket,
+        socket_for_task: &Arc<UdpSocket>,
+        state_for_task: &Arc<Mutex<GameState>>,
+        addr: std::net::SocketAddr,
+        metrics: &ServerMetrics,
+        config: &ServerConfig,
+    ) {
+        let mut game_state = lock_timed(state_for_task, "handle_whisper").await;
+        let Some(mut whisper) =
+            WhisperPacket::deserialize(&package.payload, config.max_chat_payload)
+        else {
+            tracing::warn!("Dropping malformed or oversize whisper from {:?}", addr);
+            metrics.record_rejected_chat();
+            let code = if package.payload.len() > config.max_chat_payload {
+                ErrorCode::TooLarge
+            } else {
+                ErrorCode::Malformed
+            };
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    code,
+                    "whisper rejected",
+                )
+                .await;
+            return;
+        };
+        let Some(sender) = game_state.get_player_by_addr(&addr.to_string()) else {
+            tracing::warn!("Received whisper from unknown player: {:?}", addr);
+            metrics.record_rejected_chat();
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::NotConnected,
+                    "not connected",
+                )
+                .await;
+            return;
+        };
+        let sender_id = sender.id.clone();
+        let room = sender.room.clone();
+        let Some(target) = parse_player_id(&whisper.player_id) else {
+            tracing::warn!("Whisper with a malformed target id from {:?}", addr);
+            metrics.record_rejected_chat();
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::Malformed,
+                    "malformed player id",
+                )
+                .await;
+            return;
+        };
+        // Players in other rooms are as good as unknown, their ids mustn't be confirmed
+        if game_state.room_of(&target) != Some(&room) {
+            tracing::warn!("Whisper for unknown player {} from {:?}", target, addr);
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::InvalidRequest,
+                    "unknown player",
+                )
+                .await;
+            return;
+        }
+        whisper.player_id = sender_id.into_bytes();
+        let payload = whisper.serialize();
+        game_state
+            .broadcast(
+                socket_for_task,
+                &BroadcastScope::Only(vec![target]),
+                MessageType::Whisper,
+                |_, _| vec![payload.clone()],
+            )
+            .await;
+    }
+    #[tracing::instrument(
+        name = "GameServer Handle Set Metadata",
+        skip(socket_for_task, state_for_task)
+    )]
+    async fn handle_set_metadata(
+        package: &GamePacket,
+        socket_for_task: &Arc<UdpSocket>,
+        state_for_task: &Arc<Mutex<GameState>>,
+        addr: std::net::SocketAddr,
+    ) {
+        let mut game_state = lock_timed(state_for_task, "handle_set_metadata").await;
+        let Some(mut update) = MetadataPacket::deserialize(&package.payload) else {
+            tracing::warn!("Malformed metadata packet from {:?}", addr);
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::Malformed,
+                    "malformed metadata",
+                )
+                .await;
+            return;
+        };
+        let Some(player) = game_state.get_player_by_addr(&addr.to_string()) else {
+            tracing::warn!("Received metadata from unknown player: {:?}", addr);
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::NotConnected,
+                    "not connected",
+                )
+                .await;
+            return;
+        };
+        let player_id = player.id.clone();
+        if !game_state.set_metadata(&player_id, update.key.clone(), update.value.clone()) {
+            tracing::warn!("Rejected metadata for {} over the size limit", player_id);
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::TooLarge,
+                    "metadata over the size limit",
+                )
+                .await;
+            return;
+        }
+        update.player_id = player_id.as_bytes().to_vec();
+        let Some(payload) = update.serialize() else {
+            return;
+        };
+
+        game_state
+            .broadcast(
+                socket_for_task,
+                &BroadcastScope::AllExcept(addr),
+                MessageType::MetadataUpdate,
+                |_, _| vec![payload.clone()],
+            )
+            .await;
+    }
+    #[tracing::instrument(
+        name = "GameServer Handle Respawn",
+        skip(package, socket_for_task, state_for_task, config),
+        fields(addr = %addr, seq = package.seq_num, player_id = tracing::field::Empty)
+    )]
+    async fn handle_respawn(
+        package: &GamePacket,
+        socket_for_task: &Arc<UdpSocket>,
+        state_for_task: &Arc<Mutex<GameState>>,
+        addr: std::net::SocketAddr,
+        config: &ServerConfig,
+    ) {
+        let mut game_state = lock_timed(state_for_task, "handle_respawn").await;
+        let Some(player) = game_state.get_player_by_addr(&addr.to_string()) else {
+            tracing::warn!("Received respawn from unknown player: {:?}", addr);
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::NotConnected,
+                    "not connected",
+                )
+                .await;
+            return;
+        };
+        let player_id = player.id.clone();
+        tracing::Span::current().record("player_id", player_id.as_str());
+        if game_state
+            .respawn(&player_id, config.respawn_cooldown, config.respawn_jitter)
+            .is_none()
+        {
+            tracing::debug!("Ignoring respawn during cooldown");
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::Cooldown,
+                    "respawn on cooldown",
+                )
+                .await;
+            return;
+        }
+        // The respawning player is included, it has to learn where it ended up.
+        game_state
+            .broadcast_position(&player_id, socket_for_task)
+            .await;
+    }
+    #[tracing::instrument(
+        name = "GameServer Handle Reconnect",
+        skip(socket_for_task, state_for_task, rooms)
+    )]
+    async fn handle_reconnect(
+        package: &GamePacket,
+        socket_for_task: &Arc<UdpSocket>,
+        state_for_task: &Arc<Mutex<GameState>>,
+        rooms: &Rooms,
+        addr: std::net::SocketAddr,
+    ) {
+        let Some(reconnect) = ReconnectPacket::deserialize(&package.payload) else {
+            tracing::warn!("Malformed reconnect packet from {:?}", addr);
+            lock_timed(state_for_task, "handle_reconnect")
+                .await
+                .send_error_to_unknown(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::Malformed,
+                    "malformed reconnect token",
+                )
+                .await;
+            return;
+        };
+        // The token is redeemed in the room that handed it out
+        let (room, target) = match rooms.find_reconnect_token(&reconnect.token).await {
+            Some((room, target)) => (Some(room), target),
+            None => (None, Arc::clone(state_for_task)),
+        };
+        if !Arc::ptr_eq(&target, state_for_task) {
+            Self::leave_room(state_for_task, addr, socket_for_task).await;
+        }
+        let mut game_state = lock_timed(&target, "handle_reconnect").await;
+        let Some(player) = game_state
+            .reconnect(&reconnect.token, addr.to_string(), socket_for_task)
+            .await
+        else {
+            tracing::warn!("Reconnect with unknown token from {:?}", addr);
+            // The client has to join afresh with a ConnectionInit
+            game_state
+                .send_error_to_unknown(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::NotConnected,
+                    "unknown reconnect token",
+                )
+                .await;
+            return;
+        };
+        if let Some(room) = room {
+            rooms.set_route(addr.to_string(), &room);
+        }
+        tracing::info!("Player {} reconnected from {:?}", player.id, addr);
+        let reply = GamePacket::new(
+            MessageType::Reconnect,
+            package.seq_num,
+            vec![],
+            player.id.as_bytes().to_vec(),
+        );
+        if let Err(e) = game_state
+            .send_datagram(socket_for_task, &reply.serialize(), addr)
+            .await
+        {
+            tracing::error!("Error sending reconnect reply: {:?}", e);
+        }
+    }
+    #[tracing::instrument(
+        name = "GameServer Handle Disconnect",
+        skip(package, socket_for_task, state_for_task),
+        fields(addr = %addr, player_id = tracing::field::Empty)
+    )]
+    async fn handle_disconnect(
+        package: &GamePacket,
+        socket_for_task: &Arc<UdpSocket>,
+        state_for_task: &Arc<Mutex<GameState>>,
+        addr: std::net::SocketAddr,
+        confirm_departures: bool,
+    ) {
+        let mut game_state = lock_timed(state_for_task, "handle_disconnect").await;
+        let Some(player) = game_state.get_player_by_addr(&addr.to_string()) else {
+            tracing::warn!("Received disconnect from unknown player: {:?}", addr);
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::NotConnected,
+                    "not connected",
+                )
+                .await;
+            return;
+        };
+        let player_id = player.id.clone();
+        tracing::Span::current().record("player_id", player_id.as_str());
+        if confirm_departures {
+            if let Err(e) = game_state
+                .confirm_departure(&player_id, LeaveReason::Disconnected, socket_for_task)
+                .await
+            {
+                tracing::error!("Error confirming disconnect: {:?}", e);
+            }
+        }
+        game_state
+            .remove_player_and_notify(&player_id, socket_for_task)
+            .await;
+        tracing::info!("Player {} disconnected", player_id);
+    }
+    #[tracing::instrument(
+        name = "GameServer Handle Kick",
+        skip(socket_for_task, state_for_task, rooms, admin_token)
+    )]
+    async fn handle_kick(
+        package: &GamePacket,
+        socket_for_task: &Arc<UdpSocket>,
+        state_for_task: &Arc<Mutex<GameState>>,
+        rooms: &Rooms,
+        addr: std::net::SocketAddr,
+        admin_token: Option<&str>,
+        confirm_departures: bool,
+    ) {
+        let game_state = lock_timed(state_for_task, "handle_kick").await;
+        let Some(kick) = KickPacket::deserialize(&package.payload) else {
+            tracing::warn!("Malformed kick packet from {:?}", addr);
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::Malformed,
+                    "malformed kick",
+                )
+                .await;
+            return;
+        };
+        if !admin_token.is_some_and(|token| kick.is_authorized(token)) {
+            tracing::warn!("Rejected unauthorized kick from {:?}", addr);
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::Forbidden,
+                    "unauthorized",
+                )
+                .await;
+            return;
+        }
+        let Ok(target_id) = String::from_utf8(kick.target_id) else {
+            tracing::warn!("Kick with invalid target id from {:?}", addr);
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::Malformed,
+                    "invalid target id",
+                )
+                .await;
+            return;
+        };
+        drop(game_state);
+        // The target may play in another room than the admin's
+        let target = rooms
+            .find_player(&target_id)
+            .await
+            .unwrap_or_else(|| Arc::clone(state_for_task));
+        let mut game_state = lock_timed(&target, "handle_kick").await;
+        if confirm_departures {
+            if let Err(e) = game_state
+                .confirm_departure(&target_id, LeaveReason::Kicked, socket_for_task)
+                .await
+            {
+                tracing::error!("Error confirming kick to {}: {:?}", target_id, e);
+            }
+        }
+        if game_state
+            .remove_player_and_notify(&target_id, socket_for_task)
+            .await
+        {
+            tracing::info!("Kicked player {} on request from {:?}", target_id, addr);
+        } else {
+            tracing::warn!("Kick for unknown player {} from {:?}", target_id, addr);
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::InvalidRequest,
+                    "unknown player",
+                )
+                .await;
+        }
+    }
+    #[tracing::instrument(
+        name = "GameServer Handle Teleport",
+        skip(socket_for_task, state_for_task, rooms, admin_token)
+    )]
+    async fn handle_teleport(
+        package: &GamePacket,
+        socket_for_task: &Arc<UdpSocket>,
+        state_for_task: &Arc<Mutex<GameState>>,
+        rooms: &Rooms,
+        addr: std::net::SocketAddr,
+        admin_token: Option<&str>,
+    ) {
+        let game_state = lock_timed(state_for_task, "handle_teleport").await;
+        let Some(teleport) = TeleportPacket::deserialize(&package.payload) else {
+            tracing::warn!("Malformed teleport packet from {:?}", addr);
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::Malformed,
+                    "malformed teleport",
+                )
+                .await;
+            return;
+        };
+        if !admin_token.is_some_and(|token| teleport.is_authorized(token)) {
+            tracing::warn!("Rejected unauthorized teleport from {:?}", addr);
+            game_state
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::Forbidden,
+                    "unauthorized",
+                )
+                .await;
+            return;
+        }
+        drop(game_state);
+        // The target may play in another room than the admin's, clamped to that world
+        let target = match std::str::from_utf8(&teleport.target_id) {
+            Ok(id) => rooms.find_player(id).await,
+            Err(_) => None,
+        };
+        let Some(target) = target else {
+            tracing::warn!("Teleport for unknown player from {:?}", addr);
+            lock_timed(state_for_task, "handle_teleport")
+                .await
+                .send_error(
+                    socket_for_task,
+                    addr,
+                    package.seq_num,
+                    ErrorCode::InvalidRequest,
+                    "unknown player",
+                )
+                .await;
+            return;
+        };
+        let mut game_state = lock_timed(&target, "handle_teleport").await;
+        let position = game_state.clamp_position(&teleport.position);
+        let Some(player) = std::str::from_utf8(&teleport.target_id)
+            .ok()
+            .and_then(|id| game_state.get_player_by_id_mut(id))
+        else {
+            // Left between the lookup and the lock
+            return;
+        };
+        player.position = position;
+        let target_id = player.id.clone();
+        // A position update staged earlier in the tick would undo the teleport
+        game_state
+            .pending_position_updates
+            .remove(target_id.as_bytes());
+        tracing::info!("Teleported player {} on request from {:?}", target_id, addr);
+        // The target is included, it has to learn where it ended up.
+        game_state
+            .broadcast_position(&target_id, socket_for_task)
+            .await;
     }
 }
+
 #[cfg(test)]
 mod tests {
-    use std::{
-        collections::HashSet,
-        time::{Duration, Instant},
-    };
+    use std::{collections::HashSet, time::Duration};
 
-    use game_state::{Player, Position};
+    use game_state::{Coord, Player, Position, TEAM_METADATA_KEY};
     use rand::Rng;
 
+    use crate::packet::{
+        bundle::BundlePacket,
+        connection_init::RECONNECT_TOKEN_LEN,
+        error::ErrorPacket,
+        ping::PlayerLeft,
+        position::{PlayerPosition, PositionBatch, POSITION_RECORD_SIZE},
+        testing::PacketBuilder,
+        world::{WorldInfo, WORLD_INFO_SIZE},
+        PositionGamePacket, FLAG_CHECKSUM, FLAG_COMPRESSED,
+    };
+
     use super::*;
 
     #[tokio::test]
@@ -260,7 +2297,7 @@ mod tests {
         let mut rng = rand::thread_rng();
         // Generate a port in the ephemeral range 49152..65535 (inclusive of 65535)
         let random_port = rng.gen_range(49152..=65535);
-        let addr = format!("0.0.0.0:{}", random_port);
+        let addr = format!("0.0.0.0:{random_port}");
         let server = GameServer::new(Some(&addr)).await;
         assert!(server.is_ok());
     }
@@ -270,7 +2307,7 @@ mod tests {
         let mut rng = rand::thread_rng();
         // Generate a port in the ephemeral range 49152..65535 (inclusive of 65535)
         let random_port = rng.gen_range(49152..=65535);
-        let addr = format!("0.0.0.0:{}", random_port);
+        let addr = format!("0.0.0.0:{random_port}");
         let server = GameServer::new(Some(&addr)).await.unwrap();
         assert!(server.socket.local_addr().is_ok());
     }
@@ -280,7 +2317,7 @@ mod tests {
         let mut rng = rand::thread_rng();
         // Generate a port in the ephemeral range 49152..65535 (inclusive of 65535)
         let random_port = rng.gen_range(49152..=65535);
-        let addr = format!("0.0.0.0:{}", random_port);
+        let addr = format!("0.0.0.0:{random_port}");
         let server = GameServer::new(Some(&addr)).await.unwrap();
         let state = server.game_state.lock().await;
         assert_eq!(state.players.len(), 0);
@@ -291,7 +2328,7 @@ mod tests {
         let mut rng = rand::thread_rng();
         // Generate a port in the ephemeral range 49152..65535 (inclusive of 65535)
         let random_port = rng.gen_range(49152..=65535);
-        let addr = format!("0.0.0.0:{}", random_port);
+        let addr = format!("0.0.0.0:{random_port}");
         let server = GameServer::new(Some(&addr)).await.unwrap();
         server.spawn_maintenance_tasks();
         // Verify tasks are spawned by checking they don't panic
@@ -302,7 +2339,7 @@ mod tests {
         let mut rng = rand::thread_rng();
         // Generate a port in the ephemeral range 49152..65535 (inclusive of 65535)
         let random_port = rng.gen_range(49152..=65535);
-        let addr = format!("0.0.0.0:{}", random_port);
+        let addr = format!("0.0.0.0:{random_port}");
         let server = GameServer::new(Some(&addr)).await.unwrap();
         server.spawn_handle_receiving_messages_task();
         // Verify tasks are spawned by checking they don't panic
@@ -315,34 +2352,28 @@ mod tests {
         let mut rng = rand::thread_rng();
         // Generate a port in the ephemeral range 49152..65535 (inclusive of 65535)
         let random_port = rng.gen_range(49152..=65535);
-        let addr = format!("0.0.0.0:{}", random_port);
+        let addr = format!("0.0.0.0:{random_port}");
         let server2 = GameServer::new(Some(&addr)).await.unwrap();
         // Add a player to the game state
         let mut game_state = server2.game_state.lock().await;
         let player = game_state::Player {
-            id: nanoid::nanoid!(18),
+            id: game_state::generate_player_id(),
             position: Position { x: 700.0, y: 700.0 },
-            heartbeat: std::time::Instant::now(),
-            seq_num: 0,
+            heartbeat: game_state.now(),
+            connected_at: game_state.now(),
+            ..game_state::Player::default()
         };
-        game_state.add_player(player, addr.to_string());
+        game_state.add_player(player, addr.clone());
         drop(game_state);
         let game_state = Arc::clone(&server2.game_state);
-        let socket = Arc::clone(&server2.socket);
-        let package = GamePacket {
-            msg_type: MessageType::PositionUpdate,
-            version: 1,
-            client_id: vec![
-                1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18,
-            ],
-            seq_num: 0,
-            payload: vec![0, 0, 0, 0, 0, 0, 0, 0],
-        };
-        GameServer::handle_position_update(
-            &package,
-            &socket,
+        let package = PacketBuilder::position_update(&Position::new(0.0, 0.0))
+            .seq(0)
+            .build();
+        GameServer::handle_position_updates(
+            &[(&package, server2.socket.local_addr().unwrap())],
+            &server2.socket,
             &game_state,
-            server2.socket.local_addr().unwrap(),
+            None,
         )
         .await;
         // Verify tasks are spawned by checking they don't panic
@@ -352,7 +2383,7 @@ mod tests {
     #[tokio::test]
     async fn test_position_update_broadcast() {
         // Server setup
-        let server = Arc::new(GameServer::new(Some("127.0.0.1:5002")).await.unwrap());
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
         let server_addr = server.socket.local_addr().unwrap();
 
         // Spawn server task
@@ -361,8 +2392,8 @@ mod tests {
             tokio::spawn(async move { server.run().await.unwrap() })
         };
 
-        // Allow server startup
-        tokio::time::sleep(Duration::from_millis(100)).await;
+        // Wait for server startup
+        server.ready().await;
 
         // Create test clients
         let clients: Vec<Arc<UdpSocket>> = vec![
@@ -374,12 +2405,13 @@ mod tests {
         // Register players
         {
             let mut state = server.game_state.lock().await;
-            for (_, client) in clients.iter().enumerate() {
+            for client in &clients {
                 let player = Player {
-                    id: nanoid::nanoid!(18),
+                    id: game_state::generate_player_id(),
                     position: Position { x: 0.0, y: 0.0 },
-                    heartbeat: Instant::now(),
-                    seq_num: 0,
+                    heartbeat: state.now(),
+                    connected_at: state.now(),
+                    ..Player::default()
                 };
                 state.add_player(player, client.local_addr().unwrap().to_string());
             }
@@ -387,14 +2419,9 @@ mod tests {
 
         // Send position update
         let new_pos = Position { x: 100.0, y: 200.0 };
-        let player_id = nanoid::nanoid!(18).as_bytes().to_vec();
+        let player_id = game_state::generate_player_id().as_bytes().to_vec();
 
-        let update = GamePacket::new(
-            MessageType::PositionUpdate,
-            1,
-            new_pos.serialize(),
-            player_id.clone(),
-        );
+        let update = PacketBuilder::position_update(&new_pos).client_id(&player_id);
 
         clients[0]
             .send_to(&update.serialize(), server_addr)
@@ -408,14 +2435,15 @@ mod tests {
                 Ok(Ok((len, _))) => {
                     let packet = GamePacket::deserialize(&buf[..len]).unwrap();
 
-                    assert_eq!(packet.msg_type, MessageType::PositionUpdate);
-
-                    let position_packet = PlayerPosition::deserialize(&packet.payload).unwrap();
-                    // Assert f32 equality with small epsilon for floating-point comparison
-                    let epsilon = 0.0001;
+                    assert_eq!(packet.msg_type, MessageType::PositionBatch);
 
-                    assert!((position_packet.position.x - 100.0).abs() < epsilon);
-                    assert!((position_packet.position.y - 200.0).abs() < epsilon);
+                    let batch = PositionBatch::deserialize(&packet.payload).unwrap();
+                    assert_eq!(batch.positions.len(), 1);
+                    // The server writes big endian, compare the raw record
+                    assert_eq!(
+                        packet.payload[2 + PLAYER_ID_LEN..2 + POSITION_RECORD_SIZE],
+                        new_pos.serialize()
+                    );
                 }
                 _ => panic!("Failed to receive broadcast"),
             }
@@ -430,28 +2458,237 @@ mod tests {
         let mut rng = rand::thread_rng();
         // Generate a port in the ephemeral range 49152..65535 (inclusive of 65535)
         let random_port = rng.gen_range(49152..=65535);
-        let addr = format!("0.0.0.0:{}", random_port);
+        let addr = format!("0.0.0.0:{random_port}");
         let server2 = GameServer::new(Some(&addr)).await.unwrap();
         // Add a player to the game state
         let mut game_state = server2.game_state.lock().await;
         let player = game_state::Player {
-            id: nanoid::nanoid!(18),
+            id: game_state::generate_player_id(),
             position: Position { x: 700.0, y: 700.0 },
-            heartbeat: std::time::Instant::now(),
-            seq_num: 0,
+            heartbeat: game_state.now(),
+            connected_at: game_state.now(),
+            ..game_state::Player::default()
         };
-        game_state.add_player(player, addr.to_string());
+        game_state.add_player(player, addr.clone());
         drop(game_state);
         let game_state = Arc::clone(&server2.game_state);
         let addr = std::net::SocketAddr::from(([127, 0, 0, 1], random_port));
-        GameServer::handle_heartbeat(&game_state, addr).await;
+        let heartbeat = PacketBuilder::heartbeat().seq(0).build();
+        GameServer::handle_heartbeat(
+            &heartbeat,
+            &server2.socket,
+            &game_state,
+            addr,
+            UnknownHeartbeatPolicy::Warn,
+        )
+        .await;
         // Verify tasks are spawned by checking they don't panic
         tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
     }
+    /// Collects every field recorded on any span or event.
+    #[derive(Clone, Default)]
+    struct SpanFields(Arc<std::sync::Mutex<Vec<(String, String)>>>);
+
+    impl tracing::field::Visit for SpanFields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((field.name().to_string(), format!("{value:?}")));
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanFields {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            attrs.record(&mut self.clone());
+        }
+        fn on_record(
+            &self,
+            _id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            values.record(&mut self.clone());
+        }
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            event.record(&mut self.clone());
+        }
+    }
+
+    struct SlowHandler;
+
+    #[async_trait::async_trait]
+    impl PacketHandler for SlowHandler {
+        async fn handle(&self, _ctx: &HandlerContext, _packet: &GamePacket, _addr: SocketAddr) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Context of a server without tasks, serving the default room of fresh rooms.
+    fn test_context(socket: Arc<UdpSocket>, config: ServerConfig) -> HandlerContext {
+        let rooms = Arc::new(Rooms::new(
+            Arc::clone(&socket),
+            config.clone(),
+            Arc::default(),
+        ));
+        HandlerContext {
+            socket,
+            game_state: rooms.default_room(),
+            rooms,
+            metrics: Arc::default(),
+            config: Arc::new(config),
+            draining: Arc::default(),
+            health: Arc::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_handler_is_logged() {
+        use tracing::instrument::WithSubscriber;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let ctx = test_context(
+            Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+            ServerConfig {
+                slow_handler_threshold: Duration::from_millis(10),
+                ..ServerConfig::default()
+            },
+        );
+        let slow: Arc<dyn PacketHandler> = Arc::new(SlowHandler);
+        let handlers: HandlerRegistry = HashMap::from([(0x90, slow)]);
+        let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 5555));
+        let data = GamePacket::new(MessageType::Custom(0x90), 1, vec![], vec![0; PLAYER_ID_LEN])
+            .serialize();
+
+        let fields = SpanFields::default();
+        let subscriber = tracing_subscriber::registry().with(fields.clone());
+        GameServer::handle_datagrams(&[(data, addr)], &ctx, &handlers)
+            .with_subscriber(subscriber)
+            .await;
+
+        let recorded = fields.0.lock().unwrap().clone();
+        assert!(recorded.iter().any(|(name, value)| name == "message"
+            && value.starts_with("Slow handler for message type Custom(144)")));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_heartbeats_are_reported_once_per_window() {
+        use crate::{
+            game_state::{MockClock, DEFAULT_UNKNOWN_HEARTBEAT_WINDOW},
+            testing::CapturedLogs,
+        };
+
+        async fn nothing_arrives(client: &UdpSocket) -> bool {
+            let mut buf = vec![0; 1024];
+            tokio::time::timeout(Duration::from_millis(100), client.recv_from(&mut buf))
+                .await
+                .is_err()
+        }
+
+        let logs = CapturedLogs::default();
+        let _guard = logs.install();
+        let clock = Arc::new(MockClock::new(game_state::Timestamp::from_millis(1_000)));
+        let game_state = Arc::new(Mutex::new(GameState::with_clock(800, 600, clock.clone())));
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let warned = || {
+            logs.contents()
+                .matches("heartbeat from unknown player")
+                .count()
+        };
+        let heartbeat = PacketBuilder::heartbeat().seq(3).build();
+        let beat = |client: &UdpSocket, policy| {
+            GameServer::handle_heartbeat(
+                &heartbeat,
+                &socket,
+                &game_state,
+                client.local_addr().unwrap(),
+                policy,
+            )
+        };
+
+        let warn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for _ in 0..3 {
+            beat(&warn, UnknownHeartbeatPolicy::Warn).await;
+        }
+        assert_eq!(warned(), 1);
+        assert!(nothing_arrives(&warn).await);
+        clock.advance(DEFAULT_UNKNOWN_HEARTBEAT_WINDOW);
+        beat(&warn, UnknownHeartbeatPolicy::Warn).await;
+        assert_eq!(warned(), 2);
+
+        let ignore = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        beat(&ignore, UnknownHeartbeatPolicy::Ignore).await;
+        assert_eq!(warned(), 2);
+        assert!(nothing_arrives(&ignore).await);
+
+        let hint = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        beat(&hint, UnknownHeartbeatPolicy::Hint).await;
+        beat(&hint, UnknownHeartbeatPolicy::Hint).await;
+        let (packet, error) = next_error(&hint).await;
+        assert_eq!(packet.seq_num, 3);
+        assert_eq!(error.code, ErrorCode::NotConnected);
+        assert!(nothing_arrives(&hint).await);
+        assert_eq!(warned(), 3);
+
+        // Reports are forgotten with the other stale per-address state
+        assert!(!game_state.lock().await.is_idle());
+        clock.advance(DEFAULT_UNKNOWN_HEARTBEAT_WINDOW);
+        game_state.lock().await.expire_stale_entries();
+        assert!(game_state.lock().await.is_idle());
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_span_records_addr_and_player() {
+        use tracing::instrument::WithSubscriber;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let game_state = Arc::new(Mutex::new(GameState::default()));
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 5555));
+        let player_id = game_state::generate_player_id();
+        {
+            let mut state = game_state.lock().await;
+            let player = Player {
+                id: player_id.clone(),
+                heartbeat: state.now(),
+                connected_at: state.now(),
+                ..Player::default()
+            };
+            state.add_player(player, addr.to_string());
+        }
+
+        let fields = SpanFields::default();
+        let subscriber = tracing_subscriber::registry().with(fields.clone());
+        let heartbeat = GamePacket::new(MessageType::Heartbeat, 7, vec![], vec![0; PLAYER_ID_LEN]);
+        GameServer::handle_heartbeat(
+            &heartbeat,
+            &socket,
+            &game_state,
+            addr,
+            UnknownHeartbeatPolicy::Warn,
+        )
+        .with_subscriber(subscriber)
+        .await;
+
+        let recorded = fields.0.lock().unwrap().clone();
+        assert!(recorded.contains(&("addr".to_string(), "127.0.0.1:5555".to_string())));
+        assert!(recorded.contains(&("seq".to_string(), "7".to_string())));
+        assert!(recorded.contains(&("player_id".to_string(), format!("{player_id:?}"))));
+    }
+
     #[tokio::test]
     async fn test_handle_connection_init() {
         // Server setup
-        let server = Arc::new(GameServer::new(Some("127.0.0.1:5003")).await.unwrap());
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
         let server_addr = server.socket.local_addr().unwrap();
 
         // Spawn server task
@@ -460,19 +2697,14 @@ mod tests {
             tokio::spawn(async move { server.run().await.unwrap() })
         };
 
-        // Allow server startup
-        tokio::time::sleep(Duration::from_millis(100)).await;
+        // Wait for server startup
+        server.ready().await;
 
         // Create test client
         let client = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
 
         // Create and send connection init packet
-        let init_packet = GamePacket::new(
-            MessageType::ConnectionInit,
-            1,
-            vec![], // Empty payload for connection init
-            vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], // Empty client ID for new connection
-        );
+        let init_packet = PacketBuilder::connection_init().build();
         client
             .send_to(&init_packet.serialize(), server_addr)
             .await
@@ -492,14 +2724,13 @@ mod tests {
                 let (_, player) = state.players.iter().next().unwrap();
 
                 // Verify player position
-                assert_eq!(player.position.x, 600.0);
-                assert_eq!(player.position.y, 700.0);
+                assert!((player.position.x - 600.0).abs() < Coord::EPSILON);
+                assert!((player.position.y - 700.0).abs() < Coord::EPSILON);
 
                 // Verify sequence number matches
                 assert_eq!(player.seq_num, init_packet.seq_num);
 
-                // Verify player ID length (nanoid generates 18 character IDs)
-                assert_eq!(player.id.len(), 18);
+                assert_eq!(player.id.len(), PLAYER_ID_LEN);
             }
             _ => panic!("Failed to receive connection init response"),
         }
@@ -509,7 +2740,7 @@ mod tests {
     #[tokio::test]
     async fn test_multiple_connection_init_responses() {
         // Server setup
-        let server = Arc::new(GameServer::new(Some("127.0.0.1:5004")).await.unwrap());
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
         let server_addr = server.socket.local_addr().unwrap();
 
         // Spawn server task
@@ -518,15 +2749,15 @@ mod tests {
             tokio::spawn(async move { server.run().await.unwrap() })
         };
 
-        // Allow server startup
-        tokio::time::sleep(Duration::from_millis(100)).await;
+        // Wait for server startup
+        server.ready().await;
 
         // Create test clients
         let client1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
         let client2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
 
         // Empty client ID for new connections
-        let empty_client_id = vec![0; 18];
+        let empty_client_id = vec![0; PLAYER_ID_LEN];
 
         // Send connection init packets from both clients
         for client in [&client1, &client2] {
@@ -571,4 +2802,4132 @@ mod tests {
         // Cleanup
         server_handle.abort();
     }
+
+    #[tokio::test]
+    async fn test_player_join_carries_joiner_id_and_spawn_position() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let existing = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let joiner = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let init_packet = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+
+        // Connect the existing player and read its id from the response header
+        existing
+            .send_to(&init_packet.serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), existing.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let existing_id = GamePacket::deserialize(&buf[..len]).unwrap().client_id;
+
+        // Connect the joiner
+        joiner
+            .send_to(&init_packet.serialize(), server_addr)
+            .await
+            .unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), joiner.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let joiner_id = GamePacket::deserialize(&buf[..len]).unwrap().client_id;
+
+        // The existing player is told about the joiner
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), existing.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+        let join = PlayerJoinPacket::deserialize(&packet).unwrap();
+
+        assert_eq!(join.client_id, existing_id);
+        assert_eq!(join.player_id, joiner_id);
+        assert!((join.position.x - 600.0).abs() < Coord::EPSILON);
+        assert!((join.position.y - 700.0).abs() < Coord::EPSILON);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_joiner_receives_chat_history() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let talker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let init_packet = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        talker
+            .send_to(&init_packet.serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), talker.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let talker_id = GamePacket::deserialize(&buf[..len]).unwrap().client_id;
+
+        let messages = ["hello", "is anyone", "there?"];
+        for message in messages {
+            let chat = ChatPacket::new(vec![0; PLAYER_ID_LEN], message.to_string());
+            let packet = GamePacket::new(
+                MessageType::ChatMessage,
+                2,
+                chat.serialize(),
+                talker_id.clone(),
+            );
+            talker
+                .send_to(&packet.serialize(), server_addr)
+                .await
+                .unwrap();
+            // Keep the messages in order across handler workers
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let joiner = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        joiner
+            .send_to(&init_packet.serialize(), server_addr)
+            .await
+            .unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), joiner.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(response.msg_type, MessageType::ConnectionInit);
+
+        for message in messages {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), joiner.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            assert_eq!(packet.msg_type, MessageType::ChatMessage);
+            let chat = ChatPacket::deserialize(&packet.payload, 1024).unwrap();
+            assert_eq!(chat.sender_id, talker_id);
+            assert_eq!(chat.message, message);
+        }
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_oversize_chat_is_dropped_before_broadcast() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let talker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let init_packet = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        let mut buf = vec![0; 1024];
+        for client in [&talker, &listener] {
+            client
+                .send_to(&init_packet.serialize(), server_addr)
+                .await
+                .unwrap();
+            tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        let packet = PacketBuilder::chat(&"a".repeat(500)).seq(2);
+        talker
+            .send_to(&packet.serialize(), server_addr)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(server.metrics().rejected_chats(), 1);
+        assert!(server.game_state.lock().await.chat_history.is_empty());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), listener.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_chat_from_unknown_address_is_dropped() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let chat = ChatPacket::new(vec![0; PLAYER_ID_LEN], "hi".to_string());
+        let packet = GamePacket::new(
+            MessageType::ChatMessage,
+            1,
+            chat.serialize(),
+            vec![0; PLAYER_ID_LEN],
+        );
+        stranger
+            .send_to(&packet.serialize(), server_addr)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(server.metrics().rejected_chats(), 1);
+        assert!(server.game_state.lock().await.chat_history.is_empty());
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_whisper_reaches_only_its_recipient() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let mut clients = Vec::new();
+        let mut ids = Vec::new();
+        let mut buf = vec![0; 1024];
+        for _ in 0..3 {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client
+                .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+                .await
+                .unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            ids.push(GamePacket::deserialize(&buf[..len]).unwrap().client_id);
+            clients.push(client);
+        }
+        let [sender, recipient, bystander] = &clients[..] else {
+            unreachable!()
+        };
+
+        let whisper = WhisperPacket::new(ids[1].clone(), "psst".to_string());
+        let packet = GamePacket::new(MessageType::Whisper, 2, whisper.serialize(), ids[0].clone());
+        sender
+            .send_to(&packet.serialize(), server_addr)
+            .await
+            .unwrap();
+
+        let whisper = loop {
+            let (len, _) =
+                tokio::time::timeout(Duration::from_secs(5), recipient.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            if packet.msg_type == MessageType::Whisper {
+                break WhisperPacket::deserialize(&packet.payload, 1024).unwrap();
+            }
+        };
+        assert_eq!(whisper.player_id, ids[0]);
+        assert_eq!(whisper.message, "psst");
+        for other in [sender, bystander] {
+            while let Ok(received) =
+                tokio::time::timeout(Duration::from_millis(100), other.recv_from(&mut buf)).await
+            {
+                let (len, _) = received.unwrap();
+                let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+                assert_ne!(packet.msg_type, MessageType::Whisper);
+            }
+        }
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_whisper_does_not_cross_rooms() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let elsewhere = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_id = game_state::generate_player_id();
+        let player = Player {
+            id: target_id.clone(),
+            room: "elsewhere".to_string(),
+            ..Player::default()
+        };
+        server
+            .game_state
+            .lock()
+            .await
+            .add_player(player, elsewhere.local_addr().unwrap().to_string());
+
+        let whisper = WhisperPacket::new(target_id.into_bytes(), "psst".to_string());
+        let packet = GamePacket::new(
+            MessageType::Whisper,
+            7,
+            whisper.serialize(),
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&packet.serialize(), server_addr)
+            .await
+            .unwrap();
+
+        let (packet, error) = next_error(&client).await;
+        assert_eq!(packet.seq_num, 7);
+        assert_eq!(error.code, ErrorCode::InvalidRequest);
+        while let Ok(received) =
+            tokio::time::timeout(Duration::from_millis(100), elsewhere.recv_from(&mut buf)).await
+        {
+            let (len, _) = received.unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            assert_ne!(packet.msg_type, MessageType::Whisper);
+        }
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_whisper_to_unknown_player_is_an_error() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let whisper = WhisperPacket::new(vec![b'z'; PLAYER_ID_LEN], "anyone?".to_string());
+        let packet = GamePacket::new(
+            MessageType::Whisper,
+            7,
+            whisper.serialize(),
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&packet.serialize(), server_addr)
+            .await
+            .unwrap();
+
+        let (packet, error) = next_error(&client).await;
+        assert_eq!(packet.seq_num, 7);
+        assert_eq!(error.code, ErrorCode::InvalidRequest);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_whisper_to_malformed_id_is_rejected() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let whisper = WhisperPacket::new(vec![0xff; PLAYER_ID_LEN], "anyone?".to_string());
+        let packet = GamePacket::new(
+            MessageType::Whisper,
+            8,
+            whisper.serialize(),
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&packet.serialize(), server_addr)
+            .await
+            .unwrap();
+
+        let (packet, error) = next_error(&client).await;
+        assert_eq!(packet.seq_num, 8);
+        assert_eq!(error.code, ErrorCode::Malformed);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_team_chat_reaches_only_teammates() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let mut clients = Vec::new();
+        let mut ids = Vec::new();
+        let mut buf = vec![0; 1024];
+        for team in [1, 1, 2] {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client
+                .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+                .await
+                .unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let id = GamePacket::deserialize(&buf[..len]).unwrap().client_id;
+            let update = MetadataPacket::new(id.clone(), TEAM_METADATA_KEY.to_string(), vec![team]);
+            let packet = GamePacket::new(
+                MessageType::SetMetadata,
+                2,
+                update.serialize().unwrap(),
+                id.clone(),
+            );
+            client
+                .send_to(&packet.serialize(), server_addr)
+                .await
+                .unwrap();
+            ids.push(id);
+            clients.push(client);
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        {
+            let game_state = server.game_state.lock().await;
+            let teams = ids
+                .iter()
+                .map(|id| {
+                    let id = String::from_utf8(id.clone()).unwrap();
+                    game_state.get_player_by_id(&id).unwrap().team
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(teams, vec![Some(1), Some(1), Some(2)]);
+        }
+        let [talker, teammate, opponent] = &clients[..] else {
+            unreachable!()
+        };
+        // Drop the join notices and metadata updates sent so far
+        for client in [teammate, opponent] {
+            while tokio::time::timeout(Duration::from_millis(50), client.recv_from(&mut buf))
+                .await
+                .is_ok()
+            {}
+        }
+
+        let chat = ChatPacket::new(vec![0; PLAYER_ID_LEN], "flank left".to_string());
+        let packet = GamePacket::new(MessageType::TeamChat, 3, chat.serialize(), ids[0].clone());
+        talker
+            .send_to(&packet.serialize(), server_addr)
+            .await
+            .unwrap();
+
+        let chat = loop {
+            let (len, _) =
+                tokio::time::timeout(Duration::from_secs(5), teammate.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            if packet.msg_type == MessageType::TeamChat {
+                break ChatPacket::deserialize(&packet.payload, 1024).unwrap();
+            }
+        };
+        assert_eq!(chat.sender_id, ids[0]);
+        assert_eq!(chat.message, "flank left");
+        // Neither the other team nor the talker itself
+        for other in [opponent, talker] {
+            while let Ok(received) =
+                tokio::time::timeout(Duration::from_millis(100), other.recv_from(&mut buf)).await
+            {
+                let (len, _) = received.unwrap();
+                let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+                assert_ne!(packet.msg_type, MessageType::TeamChat);
+            }
+        }
+        assert!(server.game_state.lock().await.chat_history.is_empty());
+
+        server_handle.abort();
+    }
+
+    struct RecordingHandler(mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>);
+
+    #[async_trait::async_trait]
+    impl PacketHandler for RecordingHandler {
+        async fn handle(&self, _ctx: &HandlerContext, packet: &GamePacket, addr: SocketAddr) {
+            self.0.send((packet.payload.clone(), addr)).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_handler_is_invoked() {
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let mut server = GameServer::new(Some("127.0.0.1:0")).await.unwrap();
+        server.register_handler(0x90, Arc::new(RecordingHandler(sender)));
+        let server = Arc::new(server);
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let packet = GamePacket::new(
+            MessageType::Custom(0x90),
+            1,
+            vec![1, 2, 3],
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&packet.serialize(), server_addr)
+            .await
+            .unwrap();
+
+        let (payload, addr) = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(payload, vec![1, 2, 3]);
+        assert_eq!(addr, client.local_addr().unwrap());
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_batched_receive_processes_a_whole_burst() {
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let config = ServerConfig {
+            receive_batch_size: 16,
+            ..ServerConfig::default()
+        };
+        let mut server = GameServer::with_config(Some("127.0.0.1:0"), config)
+            .await
+            .unwrap();
+        server.register_handler(0x90, Arc::new(RecordingHandler(sender)));
+        let server = Arc::new(server);
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for i in 0..100u8 {
+            let packet = GamePacket::new(
+                MessageType::Custom(0x90),
+                1,
+                vec![i],
+                vec![0; PLAYER_ID_LEN],
+            );
+            client
+                .send_to(&packet.serialize(), server_addr)
+                .await
+                .unwrap();
+        }
+
+        let mut payloads = HashSet::new();
+        while payloads.len() < 100 {
+            let (payload, _) = tokio::time::timeout(Duration::from_secs(5), received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            payloads.insert(payload);
+        }
+        assert_eq!(payloads, (0..100u8).map(|i| vec![i]).collect());
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_oversize_datagram_is_dropped_not_parsed() {
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let mut server = GameServer::new(Some("127.0.0.1:0")).await.unwrap();
+        server.register_handler(0x90, Arc::new(RecordingHandler(sender)));
+        let server = Arc::new(server);
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let oversize = GamePacket::new(
+            MessageType::Custom(0x90),
+            1,
+            vec![1; 2000 - HEADER_SIZE],
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&oversize.serialize(), server_addr)
+            .await
+            .unwrap();
+        // Exactly at the limit is still handled
+        let largest = GamePacket::new(
+            MessageType::Custom(0x90),
+            2,
+            vec![2; 1024 - HEADER_SIZE],
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&largest.serialize(), server_addr)
+            .await
+            .unwrap();
+
+        let (payload, _) = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(payload, vec![2; 1024 - HEADER_SIZE]);
+        assert_eq!(server.metrics().truncated_datagrams(), 1);
+        assert_eq!(server.metrics().invalid_packets(), 0);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_metadata_update_is_broadcast_to_others() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let named = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let init_packet = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        let mut buf = vec![0; 1024];
+        let mut ids = Vec::new();
+        for client in [&named, &other] {
+            client
+                .send_to(&init_packet.serialize(), server_addr)
+                .await
+                .unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            ids.push(GamePacket::deserialize(&buf[..len]).unwrap().client_id);
+        }
+
+        let update = MetadataPacket::new(
+            vec![0; PLAYER_ID_LEN],
+            "name".to_string(),
+            b"alice".to_vec(),
+        );
+        let packet = GamePacket::new(
+            MessageType::SetMetadata,
+            2,
+            update.serialize().unwrap(),
+            ids[0].clone(),
+        );
+        named
+            .send_to(&packet.serialize(), server_addr)
+            .await
+            .unwrap();
+
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), other.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(packet.msg_type, MessageType::MetadataUpdate);
+        let update = MetadataPacket::deserialize(&packet.payload).unwrap();
+        assert_eq!(update.player_id, ids[0]);
+        assert_eq!(update.key, "name");
+        assert_eq!(update.value, b"alice");
+
+        let named_id = String::from_utf8(ids[0].clone()).unwrap();
+        assert_eq!(
+            server
+                .game_state
+                .lock()
+                .await
+                .get_metadata(&named_id, "name"),
+            Some(&b"alice"[..])
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_bind_gives_up_after_configured_attempts() {
+        let occupied = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = occupied.local_addr().unwrap().to_string();
+        let config = ServerConfig {
+            bind_attempts: 3,
+            bind_retry_delay: Duration::from_millis(20),
+            ..ServerConfig::default()
+        };
+
+        let started = tokio::time::Instant::now();
+        let result = GameServer::with_config(Some(&addr), config).await;
+        assert!(result.is_err());
+        // Two retries, waiting 20ms then 40ms
+        assert!(started.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[tokio::test]
+    async fn test_binds_a_hostname() {
+        let server = GameServer::new(Some("localhost:0")).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        assert!(addr.ip().is_loopback());
+        assert!(addr.is_ipv4());
+        assert_ne!(addr.port(), 0);
+
+        let literal = GameServer::resolve_bind_addr("[::1]:5000", AddressFamily::Ipv4)
+            .await
+            .unwrap();
+        assert_eq!(literal, "[::1]:5000".parse().unwrap());
+        assert!(
+            GameServer::resolve_bind_addr("localhost", AddressFamily::Ipv4)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_configured_buffer_sizes_still_serve() {
+        let config = ServerConfig {
+            recv_buffer_size: Some(256 * 1024),
+            send_buffer_size: Some(256 * 1024),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(
+            GameServer::with_config(Some("127.0.0.1:0"), config)
+                .await
+                .unwrap(),
+        );
+        // The granted size is up to the OS, only that the socket has one is portable
+        assert!(
+            socket2::SockRef::from(server.socket.as_ref())
+                .recv_buffer_size()
+                .unwrap()
+                > 0
+        );
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            GamePacket::deserialize(&buf[..len]).unwrap().msg_type,
+            MessageType::ConnectionInit
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_reaches_every_player() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let mut clients = Vec::new();
+        let mut ids = Vec::new();
+        {
+            let mut state = server.game_state.lock().await;
+            for i in 0..3 {
+                let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                let id = format!("{i}").repeat(PLAYER_ID_LEN);
+                let player = Player {
+                    id: id.clone(),
+                    heartbeat: state.now(),
+                    connected_at: state.now(),
+                    ..Player::default()
+                };
+                state.add_player(player, client.local_addr().unwrap().to_string());
+                clients.push(client);
+                ids.push(id);
+            }
+        }
+
+        let packet = GamePacket::new(
+            MessageType::Custom(0x90),
+            0,
+            vec![7, 7],
+            vec![0; PLAYER_ID_LEN],
+        );
+        assert_eq!(server.broadcast(packet).await, 3);
+        let mut buf = vec![0; 64];
+        for client in &clients {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            assert_eq!(packet.msg_type, MessageType::Custom(0x90));
+            assert_eq!(packet.payload, vec![7, 7]);
+        }
+
+        let packet = GamePacket::new(MessageType::Custom(0x91), 0, vec![], vec![0; PLAYER_ID_LEN]);
+        assert_eq!(server.broadcast_to(&ids[1..2], packet).await, 1);
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), clients[1].recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            GamePacket::deserialize(&buf[..len]).unwrap().msg_type,
+            MessageType::Custom(0x91)
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), clients[0].recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+    }
+
+    async fn start_challenge_server() -> (Arc<GameServer>, SocketAddr, task::JoinHandle<()>) {
+        let config = ServerConfig {
+            require_challenge: true,
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(
+            GameServer::with_config(Some("127.0.0.1:0"), config)
+                .await
+                .unwrap(),
+        );
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+        (server, server_addr, server_handle)
+    }
+
+    #[tokio::test]
+    async fn test_challenge_handshake_registers_player() {
+        let (server, server_addr, server_handle) = start_challenge_server().await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let init_packet = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&init_packet.serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let challenge = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(challenge.msg_type, MessageType::Challenge);
+        assert_eq!(server.game_state.lock().await.get_player_count(), 0);
+
+        let answer = GamePacket::new(
+            MessageType::ConnectionInit,
+            2,
+            challenge.payload.clone(),
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&answer.serialize(), server_addr)
+            .await
+            .unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(response.msg_type, MessageType::ConnectionInit);
+        let player_id = String::from_utf8(response.client_id).unwrap();
+        let state = server.game_state.lock().await;
+        assert!(state.get_player_by_id(&player_id).is_some());
+        assert!(state.pending_challenges.is_empty());
+        drop(state);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_challenge_creates_no_player() {
+        let (server, server_addr, server_handle) = start_challenge_server().await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let init_packet = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&init_packet.serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            GamePacket::deserialize(&buf[..len]).unwrap().msg_type,
+            MessageType::Challenge
+        );
+
+        // A wrong answer is challenged again instead of admitted
+        let wrong = PacketBuilder::connection_init()
+            .seq(2)
+            .payload(vec![0; CHALLENGE_NONCE_LEN]);
+        client
+            .send_to(&wrong.serialize(), server_addr)
+            .await
+            .unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            GamePacket::deserialize(&buf[..len]).unwrap().msg_type,
+            MessageType::Challenge
+        );
+
+        let state = server.game_state.lock().await;
+        assert_eq!(state.get_player_count(), 0);
+        assert_eq!(state.pending_challenges.len(), 1);
+        drop(state);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_draining_turns_away_new_players_only() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let mover = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let watcher = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let init_packet = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        let mut buf = vec![0; 1024];
+        let mut ids = Vec::new();
+        for client in [&mover, &watcher] {
+            client
+                .send_to(&init_packet.serialize(), server_addr)
+                .await
+                .unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            ids.push(GamePacket::deserialize(&buf[..len]).unwrap().client_id);
+        }
+
+        server.drain();
+        assert!(server.is_draining());
+        let newcomer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        newcomer
+            .send_to(&init_packet.serialize(), server_addr)
+            .await
+            .unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), newcomer.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            GamePacket::deserialize(&buf[..len]).unwrap().msg_type,
+            MessageType::Draining
+        );
+        assert_eq!(server.game_state.lock().await.get_player_count(), 2);
+
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&100.0f32.to_le_bytes());
+        payload.extend_from_slice(&200.0f32.to_le_bytes());
+        let update = GamePacket::new(MessageType::PositionUpdate, 2, payload, ids[0].clone());
+        mover
+            .send_to(&update.serialize(), server_addr)
+            .await
+            .unwrap();
+        let batch = loop {
+            let (len, _) =
+                tokio::time::timeout(Duration::from_secs(5), watcher.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            if packet.msg_type == MessageType::PositionBatch {
+                break PositionBatch::deserialize(&packet.payload).unwrap();
+            }
+        };
+        assert_eq!(batch.positions[0].id, ids[0]);
+
+        server.resume();
+        assert!(!server.is_draining());
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_client_id_is_dropped() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let forged = GamePacket::new(
+            MessageType::PositionUpdate,
+            1,
+            vec![0; 8],
+            vec![0xFF; PLAYER_ID_LEN],
+        );
+        // A stranger is only counted, it could be a spoofed address
+        client
+            .send_to(&forged.serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), client.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+        assert_eq!(server.metrics().invalid_packets(), 1);
+
+        // The server still serves new players
+        let init_packet = GamePacket::new(
+            MessageType::ConnectionInit,
+            2,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&init_packet.serialize(), server_addr)
+            .await
+            .unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            GamePacket::deserialize(&buf[..len]).unwrap().msg_type,
+            MessageType::ConnectionInit
+        );
+
+        // A connected player is told what was wrong
+        let forged = GamePacket::new(
+            MessageType::PositionUpdate,
+            3,
+            vec![0; 8],
+            vec![0xFF; PLAYER_ID_LEN],
+        );
+        let short = GamePacket::new(
+            MessageType::PositionUpdate,
+            4,
+            vec![0; 3],
+            vec![0; PLAYER_ID_LEN],
+        );
+        for packet in [forged, short] {
+            client
+                .send_to(&packet.serialize(), server_addr)
+                .await
+                .unwrap();
+        }
+        // Both are answered with an error and go no further
+        for seq in [3, 4] {
+            let (packet, error) = next_error(&client).await;
+            assert_eq!(packet.seq_num, seq);
+            assert_eq!(error.code, ErrorCode::Malformed);
+        }
+        assert_eq!(server.metrics().invalid_packets(), 2);
+        assert!(server
+            .game_state
+            .lock()
+            .await
+            .pending_position_updates
+            .is_empty());
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_shrinking_world_clamps_players_and_notifies() {
+        let server = GameServer::new(Some("127.0.0.1:0")).await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let player_id = "a".repeat(PLAYER_ID_LEN);
+        {
+            let mut state = server.game_state.lock().await;
+            let player = Player {
+                id: player_id.clone(),
+                position: Position::new(1500.0, 900.0),
+                heartbeat: state.now(),
+                connected_at: state.now(),
+                ..Player::default()
+            };
+            state.add_player(player, client.local_addr().unwrap().to_string());
+        }
+
+        assert!(server.resize_world("", 800, 600).await);
+
+        let mut buf = vec![0; 64];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(packet.msg_type, MessageType::WorldResize);
+        let world = WorldInfo::deserialize(&packet.payload).unwrap();
+        assert_eq!((world.width, world.height), (800, 600));
+        assert!(world.spawn.distance(&Position::new(600.0, 600.0)) < f32::EPSILON);
+
+        let state = server.game_state.lock().await;
+        let position = state.get_player_position(&player_id).unwrap();
+        assert!(position.distance(&Position::new(800.0, 600.0)) < f32::EPSILON);
+    }
+
+    #[tokio::test]
+    async fn test_players_not_answering_pings_are_removed() {
+        let config = ServerConfig {
+            liveness_probe_interval: Some(Duration::from_millis(100)),
+            max_missed_probes: 2,
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(
+            GameServer::with_config(Some("127.0.0.1:0"), config)
+                .await
+                .unwrap(),
+        );
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let responsive = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let init_packet = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        let mut buf = vec![0; 1024];
+        let mut ids = Vec::new();
+        for client in [&responsive, &silent] {
+            client
+                .send_to(&init_packet.serialize(), server_addr)
+                .await
+                .unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            ids.push(
+                String::from_utf8(GamePacket::deserialize(&buf[..len]).unwrap().client_id).unwrap(),
+            );
+        }
+        let responder = tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            loop {
+                let (len, _) = responsive.recv_from(&mut buf).await.unwrap();
+                let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+                if packet.msg_type == MessageType::Ping {
+                    let pong = GamePacket::new(
+                        MessageType::Pong,
+                        packet.seq_num,
+                        vec![],
+                        packet.client_id,
+                    );
+                    responsive
+                        .send_to(&pong.serialize(), server_addr)
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(700)).await;
+        let state = server.game_state.lock().await;
+        assert!(state.get_player_by_id(&ids[0]).is_some());
+        assert!(state.get_player_by_id(&ids[1]).is_none());
+        drop(state);
+
+        responder.abort();
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connection_init_response_omits_the_joining_player() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let request = ConnectionInitRequest::default();
+        let mut clients = Vec::new();
+        let mut ids = Vec::new();
+        let mut buf = vec![0; 1024];
+        for _ in 0..2 {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client
+                .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+                .await
+                .unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            let response = ConnectionInitPacketSent::deserialize(&packet, &request).unwrap();
+            let own_id = String::from_utf8(response.client_id.clone()).unwrap();
+            let listed = response
+                .players
+                .into_iter()
+                .map(|player| player.id)
+                .collect::<Vec<_>>();
+            assert!(!listed.contains(&own_id));
+            // Everyone who joined before, and only them
+            assert_eq!(listed, ids);
+            ids.push(own_id);
+            clients.push(client);
+        }
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connection_init_lists_players_by_join_time() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let request = ConnectionInitRequest::default();
+        let mut clients = Vec::new();
+        let mut ids = Vec::new();
+        let mut buf = vec![0; 1024];
+        for _ in 0..4 {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client
+                .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+                .await
+                .unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            let response = ConnectionInitPacketSent::deserialize(&packet, &request).unwrap();
+            let listed = response
+                .players
+                .into_iter()
+                .map(|player| player.id)
+                .collect::<Vec<_>>();
+            // Oldest first, whatever the ids sort as
+            assert_eq!(listed, ids);
+            ids.push(String::from_utf8(response.client_id).unwrap());
+            clients.push(client);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        server_handle.abort();
+    }
+
+    #[cfg(feature = "uuid")]
+    #[tokio::test]
+    async fn test_uuid_generator_mints_well_formed_unique_ids() {
+        let config = ServerConfig {
+            id_generator: Arc::new(game_state::UuidGenerator),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(
+            GameServer::with_config(Some("127.0.0.1:0"), config)
+                .await
+                .unwrap(),
+        );
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let mut ids = HashSet::new();
+        let mut buf = vec![0; 1024];
+        for _ in 0..32 {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client
+                .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+                .await
+                .unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            let response =
+                ConnectionInitPacketSent::deserialize(&packet, &ConnectionInitRequest::default())
+                    .unwrap();
+            let id = String::from_utf8(response.client_id).unwrap();
+            assert_eq!(id.len(), PLAYER_ID_LEN);
+            assert!(id.bytes().all(|b| b.is_ascii_hexdigit()), "{id:?}");
+            assert!(ids.insert(id));
+        }
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_generated_ids_skip_held_ones() {
+        let config = ServerConfig {
+            id_generator: Arc::new(game_state::SequentialGenerator::starting_at(1)),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(
+            GameServer::with_config(Some("127.0.0.1:0"), config)
+                .await
+                .unwrap(),
+        );
+        let server_addr = server.socket.local_addr().unwrap();
+        let held = format!("{:0>PLAYER_ID_LEN$}", 1);
+        {
+            let mut game_state = server.game_state.lock().await;
+            let heartbeat = game_state.now();
+            game_state.add_player(
+                Player {
+                    id: held.clone(),
+                    heartbeat,
+                    ..Player::default()
+                },
+                "127.0.0.1:1".to_string(),
+            );
+        }
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+        let response =
+            ConnectionInitPacketSent::deserialize(&packet, &ConnectionInitRequest::default())
+                .unwrap();
+        assert_eq!(
+            String::from_utf8(response.client_id).unwrap(),
+            format!("{:0>PLAYER_ID_LEN$}", 2)
+        );
+        assert!(server.game_state.lock().await.players.contains_key(&held));
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_rooms_do_not_see_each_other() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let mut clients = Vec::new();
+        let mut ids = Vec::new();
+        let mut buf = vec![0; 1024];
+        for room in ["red", "blue", "red"] {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let init_packet = GamePacket::new(
+                MessageType::ConnectionInit,
+                1,
+                room.as_bytes().to_vec(),
+                vec![0; PLAYER_ID_LEN],
+            );
+            client
+                .send_to(&init_packet.serialize(), server_addr)
+                .await
+                .unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let response = GamePacket::deserialize(&buf[..len]).unwrap();
+            // Only the earlier player of the same room is listed
+            let expected_players = usize::from(clients.len() == 2);
+            assert_eq!(
+                response.payload.len(),
+                RECONNECT_TOKEN_LEN + 16 + expected_players * POSITION_RECORD_SIZE
+            );
+            ids.push(response.client_id);
+            clients.push(client);
+        }
+        assert_eq!(server.room_count().await, 2);
+        assert_eq!(server.player_count("red").await, 2);
+        assert_eq!(server.player_count("blue").await, 1);
+
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&100.0f32.to_le_bytes());
+        payload.extend_from_slice(&200.0f32.to_le_bytes());
+        let update = GamePacket::new(MessageType::PositionUpdate, 2, payload, ids[0].clone());
+        clients[0]
+            .send_to(&update.serialize(), server_addr)
+            .await
+            .unwrap();
+
+        let batch = loop {
+            let (len, _) =
+                tokio::time::timeout(Duration::from_secs(5), clients[2].recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            if packet.msg_type == MessageType::PositionBatch {
+                break PositionBatch::deserialize(&packet.payload).unwrap();
+            }
+        };
+        assert_eq!(batch.positions[0].id, ids[0]);
+
+        // The other room hears nothing about the move
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(300);
+        while let Ok(received) =
+            tokio::time::timeout_at(deadline, clients[1].recv_from(&mut buf)).await
+        {
+            let (len, _) = received.unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            assert_ne!(packet.msg_type, MessageType::PositionBatch);
+            assert_ne!(packet.msg_type, MessageType::PlayerJoin);
+        }
+
+        server_handle.abort();
+    }
+
+    /// Joins `room` from `client` and returns the connection init response.
+    async fn join_room(client: &UdpSocket, server_addr: SocketAddr, room: &str) -> GamePacket {
+        let request = ConnectionInitRequest::new(room.to_string(), None);
+        client
+            .send_to(
+                &PacketBuilder::connection_init()
+                    .payload(request.serialize())
+                    .serialize(),
+                server_addr,
+            )
+            .await
+            .unwrap();
+        next_message(client, MessageType::ConnectionInit).await
+    }
+
+    /// Next packet of `msg_type` `client` receives, skipping any other.
+    async fn next_message(client: &UdpSocket, msg_type: MessageType) -> GamePacket {
+        let mut buf = vec![0; 1024];
+        loop {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            if packet.msg_type == msg_type {
+                return packet;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_each_room_has_its_own_world() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let red = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        join_room(&red, server_addr, "red").await;
+        let lobby = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        join_room(&lobby, server_addr, "").await;
+
+        assert!(server.resize_world("red", 800, 600).await);
+        let resize = next_message(&red, MessageType::WorldResize).await;
+        assert_eq!(
+            WorldInfo::deserialize(&resize.payload).map(|world| (world.width, world.height)),
+            Some((800, 600))
+        );
+        let red_state = server.rooms().get("red").unwrap();
+        assert_eq!(red_state.lock().await.get_width(), 800);
+        assert_eq!(
+            server.game_state.lock().await.get_width(),
+            game_state::DEFAULT_WORLD_WIDTH
+        );
+        let mut buf = vec![0; 1024];
+        while let Ok(received) =
+            tokio::time::timeout(Duration::from_millis(300), lobby.recv_from(&mut buf)).await
+        {
+            let (len, _) = received.unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            assert_ne!(packet.msg_type, MessageType::WorldResize);
+        }
+        assert!(!server.resize_world("green", 800, 600).await);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_joining_another_room_leaves_the_first() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let mover = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let first_id = join_room(&mover, server_addr, "red").await.client_id;
+        let stayer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        join_room(&stayer, server_addr, "red").await;
+
+        join_room(&mover, server_addr, "blue").await;
+        let left = next_message(&stayer, MessageType::PlayerLeft).await;
+        assert_eq!(
+            PlayerLeft::deserialize(&left.payload).map(|left| left.player_id),
+            Some(String::from_utf8(first_id).unwrap())
+        );
+        assert_eq!(server.player_count("red").await, 1);
+        assert_eq!(server.player_count("blue").await, 1);
+        assert_eq!(server.rooms().player_count().await, 2);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_full_outbound_queue_drops_oldest_and_server_stays_responsive() {
+        let server = GameServer::with_config(
+            Some("127.0.0.1:0"),
+            ServerConfig {
+                outbound_queue_capacity: Some(4),
+                ..ServerConfig::default()
+            },
+        )
+        .await
+        .unwrap();
+        let server_addr = server.socket.local_addr().unwrap();
+        let metrics = server.metrics();
+        let socket = Arc::clone(&server.socket);
+        let game_state = Arc::clone(&server.game_state);
+        let server = Arc::new(server);
+        let server_handle = tokio::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        {
+            // Nothing drains the queue while the state is held on this single threaded runtime
+            let state = game_state.lock().await;
+            for seq in 0..10 {
+                let packet = GamePacket::new(
+                    MessageType::Custom(0x90),
+                    seq,
+                    vec![],
+                    vec![0; PLAYER_ID_LEN],
+                );
+                let sent = state
+                    .send_datagram(&socket, &packet.serialize(), client_addr)
+                    .await
+                    .unwrap();
+                assert_eq!(sent, HEADER_SIZE);
+            }
+        }
+        assert_eq!(metrics.outbound_queue_drops(), 6);
+
+        let mut buf = [0u8; 1024];
+        for expected in 6..10 {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                GamePacket::deserialize(&buf[..len]).unwrap().seq_num,
+                expected
+            );
+        }
+
+        let init = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&init.serialize(), server_addr)
+            .await
+            .unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(response.msg_type, MessageType::ConnectionInit);
+        assert_eq!(metrics.outbound_queue_depth(), 0);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_every_task_and_ends_run() {
+        let server = GameServer::with_config(
+            Some("127.0.0.1:0"),
+            ServerConfig {
+                worker_count: 2,
+                ..ServerConfig::default()
+            },
+        )
+        .await
+        .unwrap();
+        let server = Arc::new(server);
+        let server_handle = tokio::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        server.ready().await;
+
+        // The default room's cleanup, heartbeat and simulation tasks, the room sweeper,
+        // two workers and the receive task
+        assert_eq!(server.shutdown().await, 7);
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(server.tasks.lock().unwrap().is_empty());
+        assert_eq!(server.shutdown().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_notifies_connected_players() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = tokio::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        server
+            .shutdown_with_notice(ServerShutdownPacket::new(0, "maintenance"))
+            .await;
+        let packet = loop {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            if packet.msg_type == MessageType::ServerShutdown {
+                break packet;
+            }
+        };
+        assert_eq!(
+            ServerShutdownPacket::deserialize(&packet.payload),
+            Some(ServerShutdownPacket::new(0, "maintenance"))
+        );
+        tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_fails_when_receive_task_dies() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_handle = tokio::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await }
+        });
+        server.ready().await;
+
+        // The receive task is spawned last
+        server.tasks.lock().unwrap().last().unwrap().abort();
+        let result = tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(result.is_err());
+        assert!(!server.is_ready());
+        assert!(server.tasks.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_player_join_carries_display_name() {
+        let server = GameServer::new(Some("127.0.0.1:0")).await.unwrap();
+        let server_addr = server.socket.local_addr().unwrap();
+        let server = Arc::new(server);
+        let server_handle = tokio::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await.unwrap() }
+        });
+        server.ready().await;
+
+        let mut buf = [0u8; 1024];
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let init = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        first.send_to(&init.serialize(), server_addr).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), first.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = ConnectionInitRequest::new(String::new(), Some("Alice".to_string()));
+        let init = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            request.serialize(),
+            vec![0; PLAYER_ID_LEN],
+        );
+        second
+            .send_to(&init.serialize(), server_addr)
+            .await
+            .unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), second.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = GamePacket::deserialize(&buf[..len]).unwrap();
+        // The first player is listed with an empty name
+        assert_eq!(
+            response.payload.len(),
+            RECONNECT_TOKEN_LEN + 16 + POSITION_RECORD_SIZE + 1
+        );
+
+        let join = loop {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), first.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            if packet.msg_type == MessageType::PlayerJoin {
+                break PlayerJoinPacket::deserialize(&packet).unwrap();
+            }
+        };
+        assert_eq!(join.player_id, response.client_id);
+        assert_eq!(join.name.as_deref(), Some("Alice"));
+        let state = server.game_state.lock().await;
+        let player = state
+            .players
+            .get(std::str::from_utf8(&join.player_id).unwrap());
+        assert_eq!(player.unwrap().name.as_deref(), Some("Alice"));
+        drop(state);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_reordered_position_updates_are_skipped() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let ctx = test_context(Arc::clone(&socket), ServerConfig::default());
+        let handlers = handler::default_handlers();
+        let client = std::net::SocketAddr::from(([127, 0, 0, 1], 5555));
+        let bot = std::net::SocketAddr::from(([127, 0, 0, 1], 5556));
+        let (player_id, avatar_id) = ("p".repeat(PLAYER_ID_LEN), "a".repeat(PLAYER_ID_LEN));
+        {
+            let mut state = ctx.game_state.lock().await;
+            let heartbeat = state.now();
+            state.add_player(
+                Player {
+                    id: player_id.clone(),
+                    heartbeat,
+                    ..Player::default()
+                },
+                client.to_string(),
+            );
+            state.add_avatar(
+                Player {
+                    id: avatar_id.clone(),
+                    heartbeat,
+                    ..Player::default()
+                },
+                bot.to_string(),
+            );
+        }
+
+        // One receive batch, the second update overtaken by the third
+        let update = |seq, x| {
+            PacketBuilder::position_update(&Position::new(x, 10.0))
+                .seq(seq)
+                .serialize()
+        };
+        let batch = [(update(3, 30.0), client), (update(2, 20.0), client)];
+        GameServer::handle_datagrams(&batch, &ctx, &handlers).await;
+
+        // Bulk updates go through the same checks
+        for (seq, x) in [(5, 50.0), (4, 40.0)] {
+            let records = BulkPositionUpdate::new(vec![PlayerPosition::new(
+                avatar_id.as_bytes().to_vec(),
+                Position::new(x, 10.0),
+            )]);
+            let packet = GamePacket::new(
+                MessageType::BulkPositionUpdate,
+                seq,
+                records.serialize(),
+                vec![0; PLAYER_ID_LEN],
+            );
+            GameServer::handle_bulk_position_update(&packet, &socket, &ctx.game_state, bot, None)
+                .await;
+        }
+
+        let state = ctx.game_state.lock().await;
+        assert_eq!(
+            state.get_player_position(&player_id),
+            Some(&Position::new(30.0, 10.0))
+        );
+        assert_eq!(
+            state.get_player_position(&avatar_id),
+            Some(&Position::new(50.0, 10.0))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bulk_position_update_moves_every_avatar() {
+        let server = GameServer::new(Some("127.0.0.1:0")).await.unwrap();
+        let server_addr = server.socket.local_addr().unwrap();
+        let server = Arc::new(server);
+        let server_handle = tokio::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await.unwrap() }
+        });
+        server.ready().await;
+
+        let bot = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let bot_addr = bot.local_addr().unwrap().to_string();
+        let avatars = ["a".repeat(PLAYER_ID_LEN), "b".repeat(PLAYER_ID_LEN)];
+        {
+            let mut state = server.game_state.lock().await;
+            for id in &avatars {
+                let avatar = Player {
+                    id: id.clone(),
+                    heartbeat: state.now(),
+                    connected_at: state.now(),
+                    ..Player::default()
+                };
+                state.add_avatar(avatar, bot_addr.clone());
+            }
+        }
+        let observer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let init = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        observer
+            .send_to(&init.serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1024];
+        tokio::time::timeout(Duration::from_secs(5), observer.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let update = BulkPositionUpdate::new(vec![
+            PlayerPosition::new(avatars[0].as_bytes().to_vec(), Position::new(100.0, 200.0)),
+            PlayerPosition::new(avatars[1].as_bytes().to_vec(), Position::new(300.0, 400.0)),
+            // Not controlled by the bot, ignored
+            PlayerPosition::new(vec![b'c'; PLAYER_ID_LEN], Position::new(1.0, 1.0)),
+        ]);
+        let packet = GamePacket::new(
+            MessageType::BulkPositionUpdate,
+            1,
+            update.serialize(),
+            avatars[0].as_bytes().to_vec(),
+        );
+        bot.send_to(&packet.serialize(), server_addr).await.unwrap();
+
+        let mut moved = HashMap::new();
+        while moved.len() < 2 {
+            let (len, _) =
+                tokio::time::timeout(Duration::from_secs(5), observer.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            if packet.msg_type == MessageType::PositionBatch {
+                // Compare raw records, `PlayerPosition::deserialize` reads client byte order
+                for record in packet.payload[2..].chunks_exact(POSITION_RECORD_SIZE) {
+                    moved.insert(
+                        record[..PLAYER_ID_LEN].to_vec(),
+                        record[PLAYER_ID_LEN..].to_vec(),
+                    );
+                }
+            }
+        }
+        assert_eq!(
+            moved[avatars[0].as_bytes()],
+            Position::new(100.0, 200.0).serialize()
+        );
+        assert_eq!(
+            moved[avatars[1].as_bytes()],
+            Position::new(300.0, 400.0).serialize()
+        );
+        let state = server.game_state.lock().await;
+        assert!(state.get_player_by_id(&"c".repeat(PLAYER_ID_LEN)).is_none());
+        assert_eq!(
+            state.get_player_position(&avatars[1]).unwrap().serialize(),
+            Position::new(300.0, 400.0).serialize()
+        );
+        drop(state);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_private_chat_reaches_only_its_target() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let clients = [
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let ids = ["a".repeat(PLAYER_ID_LEN), "b".repeat(PLAYER_ID_LEN)];
+        {
+            let mut state = server.game_state.lock().await;
+            for (client, id) in clients.iter().zip(&ids) {
+                let player = Player {
+                    id: id.clone(),
+                    heartbeat: state.now(),
+                    connected_at: state.now(),
+                    ..Player::default()
+                };
+                state.add_player(player, client.local_addr().unwrap().to_string());
+            }
+        }
+
+        assert!(server.send_private_chat(&ids[1], "psst").await);
+        assert!(
+            !server
+                .send_private_chat(&"c".repeat(PLAYER_ID_LEN), "psst")
+                .await
+        );
+
+        let mut buf = [0u8; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), clients[1].recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(packet.msg_type, MessageType::ChatMessage);
+        let chat = ChatPacket::deserialize(&packet.payload, usize::MAX).unwrap();
+        assert_eq!(chat.message, "psst");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), clients[0].recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+        assert_eq!(server.game_state.lock().await.chat_history.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_broadcasts_arrive_with_separate_send_socket() {
+        let server = GameServer::with_config(
+            Some("127.0.0.1:0"),
+            ServerConfig {
+                separate_send_socket: true,
+                ..ServerConfig::default()
+            },
+        )
+        .await
+        .unwrap();
+        let server_addr = server.socket.local_addr().unwrap();
+        assert!(!Arc::ptr_eq(&server.socket, &server.send_socket));
+        assert_eq!(server.send_socket.local_addr().unwrap(), server_addr);
+        let server = Arc::new(server);
+        let server_handle = tokio::spawn({
+            let server = Arc::clone(&server);
+            async move { server.run().await.unwrap() }
+        });
+        server.ready().await;
+
+        let mut buf = [0u8; 1024];
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client
+                .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+                .await
+                .unwrap();
+            let (len, from) =
+                tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            // Replies come from the port the client sent to
+            assert_eq!(from, server_addr);
+            assert_eq!(
+                GamePacket::deserialize(&buf[..len]).unwrap().msg_type,
+                MessageType::ConnectionInit
+            );
+            clients.push(client);
+        }
+
+        let (len, from) =
+            tokio::time::timeout(Duration::from_secs(5), clients[0].recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(from, server_addr);
+        assert_eq!(
+            GamePacket::deserialize(&buf[..len]).unwrap().msg_type,
+            MessageType::PlayerJoin
+        );
+
+        server_handle.abort();
+    }
+
+    async fn next_error(client: &UdpSocket) -> (GamePacket, ErrorPacket) {
+        let mut buf = vec![0; 1024];
+        loop {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            if packet.msg_type == MessageType::Error {
+                let error = ErrorPacket::deserialize(&packet.payload).unwrap();
+                return (packet, error);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_short_payloads_are_dropped_before_dispatch() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // Only known addresses are answered
+        {
+            let mut state = server.game_state.lock().await;
+            let player = Player {
+                id: "p".repeat(PLAYER_ID_LEN),
+                heartbeat: state.now(),
+                ..Player::default()
+            };
+            state.add_player(player, client.local_addr().unwrap().to_string());
+        }
+        let handled = [
+            MessageType::PositionUpdate,
+            MessageType::BulkPositionUpdate,
+            MessageType::ChatMessage,
+            MessageType::Reconnect,
+            MessageType::SetMetadata,
+            MessageType::Kick,
+            MessageType::Teleport,
+        ];
+        for (seq_num, msg_type) in (1..).zip(handled) {
+            let short = vec![0; crate::packet::sizes::min_payload_len(msg_type) - 1];
+            let packet = GamePacket::new(msg_type, seq_num, short, vec![0; PLAYER_ID_LEN]);
+            client
+                .send_to(&packet.serialize(), server_addr)
+                .await
+                .unwrap();
+            let (response, error) = next_error(&client).await;
+            assert_eq!(response.seq_num, seq_num, "{msg_type:?}");
+            assert_eq!(error.code, ErrorCode::Malformed, "{msg_type:?}");
+            assert_eq!(error.message, "payload too short");
+        }
+        assert_eq!(server.metrics.short_payloads(), 7);
+        // Nothing reached the handlers
+        assert_eq!(server.metrics.rejected_chats(), 0);
+        assert_eq!(server.game_state.lock().await.get_player_count(), 1);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_out_of_bounds_move_yields_error() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let id = GamePacket::deserialize(&buf[..len]).unwrap().client_id;
+
+        let moved = PacketBuilder::position_update(&Position::new(-50.0, 1e9))
+            .seq(9)
+            .client_id(&id)
+            .serialize();
+        client.send_to(&moved, server_addr).await.unwrap();
+        let (packet, error) = next_error(&client).await;
+        assert_eq!(packet.seq_num, 9);
+        assert_eq!(error.code, ErrorCode::OutOfBounds);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_full_server_yields_error() {
+        let server = Arc::new(
+            GameServer::with_config(
+                Some("127.0.0.1:0"),
+                ServerConfig {
+                    max_players: Some(1),
+                    ..ServerConfig::default()
+                },
+            )
+            .await
+            .unwrap(),
+        );
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let init = PacketBuilder::connection_init().seq(3).serialize();
+        first.send_to(&init, server_addr).await.unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), first.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            GamePacket::deserialize(&buf[..len]).unwrap().msg_type,
+            MessageType::ConnectionInit
+        );
+
+        second.send_to(&init, server_addr).await.unwrap();
+        let (packet, error) = next_error(&second).await;
+        assert_eq!(packet.seq_num, 3);
+        assert_eq!(error.code, ErrorCode::ServerFull);
+        assert_eq!(server.game_state.lock().await.get_player_count(), 1);
+
+        // The player already connected may reconnect from its address, which also
+        // tells it that its previous player left
+        first.send_to(&init, server_addr).await.unwrap();
+        loop {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), first.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            match GamePacket::deserialize(&buf[..len]).unwrap().msg_type {
+                MessageType::ConnectionInit => break,
+                msg_type => assert_eq!(msg_type, MessageType::PlayerLeft),
+            }
+        }
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_players_beyond_the_per_ip_cap_are_rejected() {
+        let server = Arc::new(
+            GameServer::with_config(
+                Some("127.0.0.1:0"),
+                ServerConfig {
+                    max_players_per_ip: Some(2),
+                    ..ServerConfig::default()
+                },
+            )
+            .await
+            .unwrap(),
+        );
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let init = PacketBuilder::connection_init().seq(3).serialize();
+        let mut buf = vec![0; 1024];
+        for _ in 0..2 {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.send_to(&init, server_addr).await.unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                GamePacket::deserialize(&buf[..len]).unwrap().msg_type,
+                MessageType::ConnectionInit
+            );
+        }
+
+        let third = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        third.send_to(&init, server_addr).await.unwrap();
+        let (packet, error) = next_error(&third).await;
+        assert_eq!(packet.seq_num, 3);
+        assert_eq!(error.code, ErrorCode::ServerFull);
+        assert_eq!(server.game_state.lock().await.get_player_count(), 2);
+
+        // The whole 127.0.0.0/8 block is loopback, so this is another IP on the same host
+        let other_ip = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        other_ip.send_to(&init, server_addr).await.unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), other_ip.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            GamePacket::deserialize(&buf[..len]).unwrap().msg_type,
+            MessageType::ConnectionInit
+        );
+        assert_eq!(server.game_state.lock().await.get_player_count(), 3);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connect_right_after_ready() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        assert!(!server.is_ready());
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        tokio::time::timeout(Duration::from_secs(5), server.ready())
+            .await
+            .unwrap();
+        assert!(server.is_ready());
+        assert!(server.game_state.lock().await.players.capacity() >= WARMUP_CAPACITY);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            GamePacket::deserialize(&buf[..len]).unwrap().msg_type,
+            MessageType::ConnectionInit
+        );
+        // Waiting again returns straight away
+        server.ready().await;
+
+        server.shutdown().await;
+        assert!(!server.is_ready());
+        server_handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_entity_events_stay_in_their_room() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let mut buf = vec![0; 1024];
+        let mut clients = Vec::new();
+        for room in ["red", "blue"] {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let request = ConnectionInitRequest::new(room.to_string(), None);
+            client
+                .send_to(
+                    &PacketBuilder::connection_init()
+                        .payload(request.serialize())
+                        .serialize(),
+                    server_addr,
+                )
+                .await
+                .unwrap();
+            tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            clients.push(client);
+        }
+        let [red, blue] = &clients[..] else {
+            unreachable!()
+        };
+
+        let id = server
+            .spawn_entity("red", 1, &Position::new(1.0, 2.0), None)
+            .await
+            .unwrap();
+        assert!(
+            server
+                .move_entity("red", id, &Position::new(3.0, 4.0))
+                .await
+        );
+        assert!(server.despawn_entity("red", id).await.is_some());
+        let mut received = Vec::new();
+        while let Ok(Ok((len, _))) =
+            tokio::time::timeout(Duration::from_millis(200), red.recv_from(&mut buf)).await
+        {
+            received.push(GamePacket::deserialize(&buf[..len]).unwrap().msg_type);
+        }
+        for msg_type in [
+            MessageType::EntitySpawn,
+            MessageType::EntityMove,
+            MessageType::EntityDespawn,
+        ] {
+            assert!(received.contains(&msg_type), "{msg_type:?}");
+        }
+        while let Ok(Ok((len, _))) =
+            tokio::time::timeout(Duration::from_millis(200), blue.recv_from(&mut buf)).await
+        {
+            let msg_type = GamePacket::deserialize(&buf[..len]).unwrap().msg_type;
+            assert!(
+                !matches!(
+                    msg_type,
+                    MessageType::EntitySpawn | MessageType::EntityMove | MessageType::EntityDespawn
+                ),
+                "{msg_type:?}"
+            );
+        }
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_entity_spawn_move_and_despawn_are_broadcast() {
+        async fn next_of(client: &UdpSocket, msg_type: MessageType) -> GamePacket {
+            let mut buf = vec![0; 1024];
+            loop {
+                let (len, _) =
+                    tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                        .await
+                        .unwrap()
+                        .unwrap();
+                let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+                if packet.msg_type == msg_type {
+                    return packet;
+                }
+            }
+        }
+
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let early = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        early
+            .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+            .await
+            .unwrap();
+        let owner = next_of(&early, MessageType::ConnectionInit).await.client_id;
+        let owner = String::from_utf8(owner).unwrap();
+
+        let id = server
+            .spawn_entity("", 3, &Position::new(10.0, 20.0), Some(owner.clone()))
+            .await
+            .unwrap();
+        let expected = EntitySpawnPacket::new(id, 3, Position::new(10.0, 20.0), Some(owner));
+        let spawn = next_of(&early, MessageType::EntitySpawn).await;
+        assert_eq!(
+            EntitySpawnPacket::deserialize(&spawn.payload),
+            Some(expected.clone())
+        );
+
+        // A player joining later learns about the entity on connect
+        let late = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        late.send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+            .await
+            .unwrap();
+        let spawn = next_of(&late, MessageType::EntitySpawn).await;
+        assert_eq!(
+            EntitySpawnPacket::deserialize(&spawn.payload),
+            Some(expected)
+        );
+
+        assert!(server.move_entity("", id, &Position::new(30.0, 40.0)).await);
+        for client in [&early, &late] {
+            let moved = next_of(client, MessageType::EntityMove).await;
+            assert_eq!(
+                EntityMovePacket::deserialize(&moved.payload),
+                Some(EntityMovePacket::new(id, Position::new(30.0, 40.0)))
+            );
+        }
+
+        assert_eq!(
+            server
+                .despawn_entity("", id)
+                .await
+                .map(|entity| entity.kind),
+            Some(3)
+        );
+        for client in [&early, &late] {
+            let despawned = next_of(client, MessageType::EntityDespawn).await;
+            assert_eq!(entity::deserialize_despawn(&despawned.payload), Some(id));
+        }
+        assert!(!server.move_entity("", id, &Position::new(0.0, 0.0)).await);
+        assert!(server.despawn_entity("", id).await.is_none());
+        // Players are untouched
+        assert_eq!(server.game_state.lock().await.get_player_count(), 2);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_is_confirmed_to_leaver_and_announced_to_others() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let leaver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stayer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = vec![0; 1024];
+        let mut ids = Vec::new();
+        for client in [&leaver, &stayer] {
+            client
+                .send_to(&PacketBuilder::connection_init().serialize(), server_addr)
+                .await
+                .unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            ids.push(GamePacket::deserialize(&buf[..len]).unwrap().client_id);
+        }
+        let leaver_id = String::from_utf8(ids[0].clone()).unwrap();
+
+        let disconnect = GamePacket::new(MessageType::Disconnect, 2, vec![], ids[0].clone());
+        leaver
+            .send_to(&disconnect.serialize(), server_addr)
+            .await
+            .unwrap();
+
+        for (client, reason) in [(&leaver, Some(LeaveReason::Disconnected)), (&stayer, None)] {
+            let left = loop {
+                let (len, _) =
+                    tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                        .await
+                        .unwrap()
+                        .unwrap();
+                let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+                if packet.msg_type == MessageType::PlayerLeft {
+                    break PlayerLeft::deserialize(&packet.payload).unwrap();
+                }
+            };
+            assert_eq!(left.player_id, leaver_id);
+            assert_eq!(left.reason, reason);
+        }
+        let game_state = server.game_state.lock().await;
+        assert!(game_state.get_player_by_id(&leaver_id).is_none());
+        assert_eq!(game_state.get_player_count(), 1);
+        drop(game_state);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_respawn_returns_player_to_spawn_and_notifies_others() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let mover = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let watcher = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let init_packet = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        let mut buf = vec![0; 1024];
+        let mut ids = Vec::new();
+        for client in [&mover, &watcher] {
+            client
+                .send_to(&init_packet.serialize(), server_addr)
+                .await
+                .unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            ids.push(GamePacket::deserialize(&buf[..len]).unwrap().client_id);
+        }
+        let mover_id = String::from_utf8(ids[0].clone()).unwrap();
+        let spawn = server.game_state.lock().await.spawn.clone();
+        server
+            .game_state
+            .lock()
+            .await
+            .update_player_position(&mover_id, Position::new(10.0, 20.0));
+
+        let respawn = GamePacket::new(MessageType::Respawn, 2, vec![], ids[0].clone());
+        mover
+            .send_to(&respawn.serialize(), server_addr)
+            .await
+            .unwrap();
+
+        let payload = loop {
+            let (len, _) =
+                tokio::time::timeout(Duration::from_secs(5), watcher.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            if packet.msg_type == MessageType::PositionUpdate {
+                break packet.payload;
+            }
+        };
+        assert_eq!(
+            payload,
+            PlayerPosition::new(ids[0].clone(), spawn.clone()).serialize()
+        );
+        let position = server
+            .game_state
+            .lock()
+            .await
+            .get_player_position(&mover_id)
+            .unwrap()
+            .clone();
+        assert!(position.distance(&spawn) < f32::EPSILON);
+
+        // A second respawn within the cooldown leaves the player where it is.
+        server
+            .game_state
+            .lock()
+            .await
+            .update_player_position(&mover_id, Position::new(10.0, 20.0));
+        mover
+            .send_to(&respawn.serialize(), server_addr)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let position = server
+            .game_state
+            .lock()
+            .await
+            .get_player_position(&mover_id)
+            .unwrap()
+            .clone();
+        assert!(position.distance(&Position::new(10.0, 20.0)) < f32::EPSILON);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_spectator_receives_positions_without_being_a_player() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let spectator = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let spectate =
+            GamePacket::new(MessageType::SpectateInit, 1, vec![], vec![0; PLAYER_ID_LEN]);
+        spectator
+            .send_to(&spectate.serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), spectator.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let reply = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(reply.msg_type, MessageType::SpectateInit);
+
+        let player = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let init_packet = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        player
+            .send_to(&init_packet.serialize(), server_addr)
+            .await
+            .unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), player.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let player_id = GamePacket::deserialize(&buf[..len]).unwrap().client_id;
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&10.0f32.to_le_bytes());
+        payload.extend_from_slice(&20.0f32.to_le_bytes());
+        let update = GamePacket::new(MessageType::PositionUpdate, 2, payload, player_id.clone());
+        player
+            .send_to(&update.serialize(), server_addr)
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        while !received.contains(&MessageType::PositionBatch) {
+            let (len, _) =
+                tokio::time::timeout(Duration::from_secs(5), spectator.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            if packet.msg_type == MessageType::PositionBatch {
+                let batch = PositionBatch::deserialize(&packet.payload).unwrap();
+                assert_eq!(batch.positions[0].id, player_id);
+            }
+            received.push(packet.msg_type);
+        }
+        assert_eq!(
+            received,
+            vec![MessageType::PlayerJoin, MessageType::PositionBatch]
+        );
+
+        let state = server.game_state.lock().await;
+        assert_eq!(state.get_player_count(), 1);
+        assert!(state.is_spectator(&spectator.local_addr().unwrap().to_string()));
+        drop(state);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_world_info_carries_dimensions() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = GamePacket::new(
+            MessageType::WorldInfoRequest,
+            3,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&request.serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let reply = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(reply.msg_type, MessageType::WorldInfo);
+        assert_eq!(reply.seq_num, 3);
+        let world = WorldInfo::deserialize(&reply.payload).unwrap();
+        assert_eq!((world.width, world.height), (1920, 1080));
+
+        // The ConnectionInit response carries it right after the reconnect token
+        let init_packet = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&init_packet.serialize(), server_addr)
+            .await
+            .unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = GamePacket::deserialize(&buf[..len]).unwrap();
+        let world = WorldInfo::deserialize(&response.payload[RECONNECT_TOKEN_LEN..]).unwrap();
+        assert_eq!((world.width, world.height), (1920, 1080));
+        assert!((world.spawn.x - 600.0).abs() < Coord::EPSILON);
+        assert!((world.spawn.y - 700.0).abs() < Coord::EPSILON);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_health_probe_is_answered_without_joining() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+        let (_player, _) = join_requesting_id(server_addr, "V1StGXR8_Z5jdHi6B-").await;
+
+        let probe = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request = GamePacket::new(MessageType::HealthProbe, 9, vec![], vec![0; PLAYER_ID_LEN]);
+        probe
+            .send_to(&request.serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), probe.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let reply = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(reply.msg_type, MessageType::HealthOk);
+        assert_eq!(reply.seq_num, 9);
+        let health = HealthOkPacket::deserialize(&reply.payload).unwrap();
+        assert_eq!(health.player_count, 1);
+        assert!(health.uptime_secs < 60, "{health:?}");
+
+        let game_state = server.game_state.lock().await;
+        assert_eq!(game_state.get_player_count(), 1);
+        assert!(game_state
+            .get_player_by_addr(&probe.local_addr().unwrap().to_string())
+            .is_none());
+        drop(game_state);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_keeps_identity_across_address_change() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let old_client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let init_packet = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        old_client
+            .send_to(&init_packet.serialize(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), old_client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = GamePacket::deserialize(&buf[..len]).unwrap();
+        let player_id = response.client_id.clone();
+        let token = ReconnectPacket::deserialize(&response.payload)
+            .unwrap()
+            .token;
+
+        // Move the player somewhere other than the spawn point
+        {
+            let mut state = server.game_state.lock().await;
+            state
+                .get_player_by_addr_mut(&old_client.local_addr().unwrap().to_string())
+                .unwrap()
+                .position = Position::new(42.0, 24.0);
+        }
+
+        // The client roams to a new address
+        let new_client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let reconnect = GamePacket::new(
+            MessageType::Reconnect,
+            2,
+            ReconnectPacket::new(token).serialize(),
+            player_id.clone(),
+        );
+        new_client
+            .send_to(&reconnect.serialize(), server_addr)
+            .await
+            .unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), new_client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let reply = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(reply.msg_type, MessageType::Reconnect);
+        assert_eq!(reply.client_id, player_id);
+
+        let state = server.game_state.lock().await;
+        assert_eq!(state.players.len(), 1);
+        assert!(state
+            .get_player_by_addr(&old_client.local_addr().unwrap().to_string())
+            .is_none());
+        let player = state
+            .get_player_by_addr(&new_client.local_addr().unwrap().to_string())
+            .unwrap();
+        assert_eq!(player.id.as_bytes(), player_id.as_slice());
+        assert!((player.position.x - 42.0).abs() < Coord::EPSILON);
+        assert!((player.position.y - 24.0).abs() < Coord::EPSILON);
+        drop(state);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_with_unknown_token_is_an_error() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let reconnect = GamePacket::new(
+            MessageType::Reconnect,
+            3,
+            ReconnectPacket::new([7; RECONNECT_TOKEN_LEN]).serialize(),
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&reconnect.serialize(), server_addr)
+            .await
+            .unwrap();
+
+        let (packet, error) = next_error(&client).await;
+        assert_eq!(packet.seq_num, 3);
+        assert_eq!(error.code, ErrorCode::NotConnected);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_malformed_reconnect_is_an_error() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let reconnect = GamePacket::new(
+            MessageType::Reconnect,
+            4,
+            vec![7; RECONNECT_TOKEN_LEN.saturating_sub(1)],
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&reconnect.serialize(), server_addr)
+            .await
+            .unwrap();
+
+        let (packet, error) = next_error(&client).await;
+        assert_eq!(packet.seq_num, 4);
+        assert_eq!(error.code, ErrorCode::Malformed);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_player_asking_to_spectate_is_an_error() {
+        let server = GameServer::new(Some("127.0.0.1:0")).await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = client.local_addr().unwrap();
+        server.game_state.lock().await.add_player(
+            Player {
+                id: "p".repeat(PLAYER_ID_LEN),
+                ..Player::default()
+            },
+            addr.to_string(),
+        );
+
+        let spectate =
+            GamePacket::new(MessageType::SpectateInit, 5, vec![], vec![0; PLAYER_ID_LEN]);
+        GameServer::handle_spectate_init(
+            &spectate,
+            &server.socket,
+            &server.game_state,
+            &server.rooms,
+            addr,
+        )
+        .await;
+
+        let (packet, error) = next_error(&client).await;
+        assert_eq!(packet.seq_num, 5);
+        assert_eq!(error.code, ErrorCode::InvalidRequest);
+        assert!(!server
+            .game_state
+            .lock()
+            .await
+            .is_spectator(&addr.to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_chat_from_unknown_address_is_not_answered() {
+        let server = GameServer::new(Some("127.0.0.1:0")).await.unwrap();
+        let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let chat = GamePacket::new(
+            MessageType::ChatMessage,
+            6,
+            ChatPacket::new(vec![0; PLAYER_ID_LEN], "hello".to_string()).serialize(),
+            vec![0; PLAYER_ID_LEN],
+        );
+        GameServer::handle_chat_message(
+            &chat,
+            &server.socket,
+            &server.game_state,
+            stranger.local_addr().unwrap(),
+            &server.metrics,
+            &server.config,
+        )
+        .await;
+
+        let mut buf = vec![0; 1024];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), stranger.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_packet_is_dropped_and_counted() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut data = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![0],
+            vec![0; PLAYER_ID_LEN],
+        )
+        .with_checksum()
+        .serialize();
+        data[24] ^= 0xFF;
+        client.send_to(&data, server_addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(server.metrics().invalid_packets(), 1);
+        assert_eq!(server.game_state.lock().await.get_player_count(), 0);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_flood_is_dropped_when_handler_queue_is_full() {
+        let config = ServerConfig {
+            receive_queue_capacity: 1,
+            worker_count: 1,
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(
+            GameServer::with_config(Some("127.0.0.1:0"), config)
+                .await
+                .unwrap(),
+        );
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let heartbeat =
+            GamePacket::new(MessageType::Heartbeat, 1, vec![], vec![0; PLAYER_ID_LEN]).serialize();
+        {
+            // Stall the worker so the queue fills up
+            let _state = server.game_state.lock().await;
+            for _ in 0..50 {
+                client.send_to(&heartbeat, server_addr).await.unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert!(server.metrics().queue_full_drops() > 0);
+        }
+
+        // Once the worker is free again, new packets are handled
+        let init_packet = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![0],
+            vec![0; PLAYER_ID_LEN],
+        )
+        .serialize();
+        client.send_to(&init_packet, server_addr).await.unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(packet.msg_type, MessageType::ConnectionInit);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_message_type_is_counted_separately() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // 0xFF is in the custom range but has no handler, 0x7F is not defined at all
+        for type_byte in [0xFF, 0x7F] {
+            let mut data =
+                GamePacket::new(MessageType::Heartbeat, 1, vec![], vec![0; PLAYER_ID_LEN])
+                    .serialize();
+            data[0] = type_byte;
+            client.send_to(&data, server_addr).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(server.metrics().unknown_message_types(), 2);
+        assert_eq!(server.metrics().invalid_packets(), 0);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_oversize_connection_init_response_is_not_sent() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        // Enough players that their records alone exceed the datagram limit
+        let player_count = crate::packet::MAX_DATAGRAM_SIZE.div_ceil(POSITION_RECORD_SIZE);
+        {
+            let mut state = server.game_state.lock().await;
+            for i in 0..player_count {
+                let player = Player {
+                    id: game_state::generate_player_id(),
+                    heartbeat: state.now(),
+                    connected_at: state.now(),
+                    ..Player::default()
+                };
+                state.add_player(player, format!("10.255.255.1:{}", 20000 + i));
+            }
+        }
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let init_packet = GamePacket::new(
+            MessageType::ConnectionInit,
+            1,
+            vec![],
+            vec![0; PLAYER_ID_LEN],
+        );
+        client
+            .send_to(&init_packet.serialize(), server_addr)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(server.metrics().oversize_datagrams(), 1);
+        let mut buf = vec![0; 2048];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), client.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            server.game_state.lock().await.get_player_count(),
+            player_count + 1
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_position_updates_are_staged_for_the_sending_player() {
+        let server = GameServer::new(Some("127.0.0.1:0")).await.unwrap();
+        let imposter = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        {
+            let mut state = server.game_state.lock().await;
+            for (id, addr) in [
+                ("victim", "127.0.0.1:1".to_string()),
+                ("imposter", imposter.local_addr().unwrap().to_string()),
+            ] {
+                let player = Player {
+                    id: id.repeat(PLAYER_ID_LEN)[..PLAYER_ID_LEN].to_string(),
+                    position: Position::new(10.0, 10.0),
+                    ..Player::default()
+                };
+                state.add_player(player, addr);
+            }
+        }
+        let victim_id = "victim".repeat(PLAYER_ID_LEN)[..PLAYER_ID_LEN].to_string();
+        let imposter_id = "imposter".repeat(PLAYER_ID_LEN)[..PLAYER_ID_LEN].to_string();
+
+        // Both claim to be the victim in the header
+        for (seq, client) in [(1, &imposter), (2, &stranger)] {
+            let packet = PacketBuilder::position_update(&Position::new(50.0, 50.0))
+                .seq(seq)
+                .client_id(victim_id.as_bytes())
+                .build();
+            GameServer::handle_position_updates(
+                &[(&packet, client.local_addr().unwrap())],
+                &server.socket,
+                &server.game_state,
+                None,
+            )
+            .await;
+        }
+
+        // The stranger has no player, it could be a spoofed victim and isn't answered
+        let mut buf = vec![0; 1024];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), stranger.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+        let state = server.game_state.lock().await;
+        let staged = state
+            .pending_position_updates
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(staged, vec![imposter_id.as_bytes().to_vec()]);
+        assert_eq!(
+            state.get_player_position(&victim_id),
+            Some(&Position::new(10.0, 10.0))
+        );
+        assert_eq!(
+            state.get_player_position(&imposter_id),
+            Some(&Position::new(50.0, 50.0))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_connection_init_response_rolls_back_the_join() {
+        let server = GameServer::new(Some("127.0.0.1:0")).await.unwrap();
+        let bystander = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        {
+            let mut state = server.game_state.lock().await;
+            let player = Player {
+                id: game_state::generate_player_id(),
+                heartbeat: state.now(),
+                connected_at: state.now(),
+                ..Player::default()
+            };
+            state.add_player(player, bystander.local_addr().unwrap().to_string());
+        }
+
+        // An IPv4 socket can't send to an IPv6 address, so the id response fails
+        let joiner: std::net::SocketAddr = "[::1]:40000".parse().unwrap();
+        let init_packet = PacketBuilder::connection_init().build();
+        GameServer::handle_connection_init(
+            &init_packet,
+            &server.send_socket,
+            &server.game_state,
+            &server.rooms,
+            joiner,
+            &server.config,
+            false,
+        )
+        .await;
+
+        let state = server.game_state.lock().await;
+        assert_eq!(state.get_player_count(), 1);
+        assert!(state.get_player_by_addr(&joiner.to_string()).is_none());
+        assert!(state.reconnect_tokens.is_empty());
+        drop(state);
+        let mut buf = vec![0; 1024];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), bystander.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+    }
+
+    /// Starts a server requiring `admin_token` for admin commands and registers
+    /// a target and a bystander player.
+    async fn kick_fixture() -> (
+        Arc<GameServer>,
+        UdpSocket,
+        String,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let config = ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..ServerConfig::default()
+        };
+        let server = Arc::new(
+            GameServer::with_config(Some("127.0.0.1:0"), config)
+                .await
+                .unwrap(),
+        );
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let bystander = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_id = game_state::generate_player_id();
+        {
+            let mut state = server.game_state.lock().await;
+            for (id, addr) in [
+                (target_id.clone(), "127.0.0.1:9".to_string()),
+                (
+                    game_state::generate_player_id(),
+                    bystander.local_addr().unwrap().to_string(),
+                ),
+            ] {
+                let player = Player {
+                    id,
+                    heartbeat: state.now(),
+                    connected_at: state.now(),
+                    ..Player::default()
+                };
+                state.add_player(player, addr);
+            }
+        }
+        (server, bystander, target_id, server_handle)
+    }
+
+    #[tokio::test]
+    async fn test_kick_with_admin_token() {
+        let (server, bystander, target_id, server_handle) = kick_fixture().await;
+        let admin = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let kick = GamePacket::new(
+            MessageType::Kick,
+            1,
+            KickPacket::new(target_id.as_bytes().to_vec(), b"secret".to_vec()).serialize(),
+            vec![0; PLAYER_ID_LEN],
+        );
+        admin
+            .send_to(&kick.serialize(), server.socket.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), bystander.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(packet.msg_type, MessageType::PlayerLeft);
+        let left = PlayerLeft::deserialize(&packet.payload).unwrap();
+        assert_eq!(left.player_id, target_id);
+        assert!(server
+            .game_state
+            .lock()
+            .await
+            .get_player_by_id(&target_id)
+            .is_none());
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_kick_with_wrong_token_is_rejected() {
+        let (server, _bystander, target_id, server_handle) = kick_fixture().await;
+        let admin = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let kick = GamePacket::new(
+            MessageType::Kick,
+            1,
+            KickPacket::new(target_id.as_bytes().to_vec(), b"guess".to_vec()).serialize(),
+            vec![0; PLAYER_ID_LEN],
+        );
+        admin
+            .send_to(&kick.serialize(), server.socket.local_addr().unwrap())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let state = server.game_state.lock().await;
+        assert!(state.get_player_by_id(&target_id).is_some());
+        assert_eq!(state.get_player_count(), 2);
+        drop(state);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_teleport_with_admin_token_moves_and_broadcasts() {
+        let (server, bystander, target_id, server_handle) = kick_fixture().await;
+        let (width, seq_before) = {
+            let state = server.game_state.lock().await;
+            (
+                state.width,
+                state.get_player_by_id(&target_id).unwrap().outbound_seq,
+            )
+        };
+        let admin = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let teleport = GamePacket::new(
+            MessageType::Teleport,
+            1,
+            TeleportPacket::new(
+                target_id.as_bytes().to_vec(),
+                Position::new(1e6, 50.0),
+                b"secret".to_vec(),
+            )
+            .serialize(),
+            vec![0; PLAYER_ID_LEN],
+        );
+        admin
+            .send_to(&teleport.serialize(), server.socket.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let mut buf = vec![0; 1024];
+        let payload = loop {
+            let (len, _) =
+                tokio::time::timeout(Duration::from_secs(5), bystander.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            if packet.msg_type == MessageType::PositionUpdate {
+                break packet.payload;
+            }
+        };
+        // Clamped onto the world's edge
+        let destination = Position::new(crate::num::u32_to_coord(width), 50.0);
+        assert_eq!(
+            payload,
+            PlayerPosition::new(target_id.as_bytes().to_vec(), destination.clone()).serialize()
+        );
+        let state = server.game_state.lock().await;
+        let target = state.get_player_by_id(&target_id).unwrap();
+        assert_eq!(target.position, destination);
+        assert!(SeqNum(target.outbound_seq).is_newer_than(SeqNum(seq_before)));
+        drop(state);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_teleport_discards_staged_position_update() {
+        let server = GameServer::new(Some("127.0.0.1:0")).await.unwrap();
+        let admin = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_id = game_state::generate_player_id();
+        {
+            let mut state = server.game_state.lock().await;
+            let player = Player {
+                id: target_id.clone(),
+                ..Player::default()
+            };
+            state.add_player(player, "127.0.0.1:9".to_string());
+            state.stage_position_update(PositionGamePacket {
+                msg_type: MessageType::PositionUpdate,
+                version: 1,
+                client_id: target_id.as_bytes().to_vec(),
+                seq_num: 1,
+                position: Position::new(5.0, 5.0),
+            });
+        }
+
+        let destination = Position::new(40.0, 50.0);
+        let teleport = GamePacket::new(
+            MessageType::Teleport,
+            1,
+            TeleportPacket::new(
+                target_id.as_bytes().to_vec(),
+                destination.clone(),
+                b"secret".to_vec(),
+            )
+            .serialize(),
+            vec![0; PLAYER_ID_LEN],
+        );
+        GameServer::handle_teleport(
+            &teleport,
+            &server.socket,
+            &server.game_state,
+            &server.rooms,
+            admin.local_addr().unwrap(),
+            Some("secret"),
+        )
+        .await;
+
+        let state = server.game_state.lock().await;
+        assert_eq!(
+            state.get_player_by_id(&target_id).unwrap().position,
+            destination
+        );
+        // The next tick won't move the player back
+        assert!(state.pending_position_updates.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_set_player_position_clamps_and_broadcasts() {
+        let (server, bystander, target_id, server_handle) = kick_fixture().await;
+        let height = server.game_state.lock().await.height;
+        assert_eq!(
+            server.player_position(&target_id).await,
+            server
+                .game_state
+                .lock()
+                .await
+                .get_player_by_id(&target_id)
+                .map(|player| player.position.clone())
+        );
+
+        let knocked_back = server
+            .set_player_position(&target_id, Position::new(40.0, 1e6))
+            .await;
+        let destination = Position::new(40.0, crate::num::u32_to_coord(height));
+        assert_eq!(knocked_back, Some(destination.clone()));
+        assert_eq!(
+            server.player_position(&target_id).await,
+            Some(destination.clone())
+        );
+
+        let mut buf = vec![0; 1024];
+        let payload = loop {
+            let (len, _) =
+                tokio::time::timeout(Duration::from_secs(5), bystander.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            if packet.msg_type == MessageType::PositionUpdate {
+                break packet.payload;
+            }
+        };
+        assert_eq!(
+            payload,
+            PlayerPosition::new(target_id.as_bytes().to_vec(), destination).serialize()
+        );
+
+        let missing = game_state::generate_player_id();
+        assert_eq!(server.player_position(&missing).await, None);
+        assert_eq!(
+            server
+                .set_player_position(&missing, Position::new(1.0, 1.0))
+                .await,
+            None
+        );
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_teleport_with_wrong_token_is_ignored() {
+        let (server, _bystander, target_id, server_handle) = kick_fixture().await;
+        let before = server
+            .game_state
+            .lock()
+            .await
+            .get_player_position(&target_id)
+            .cloned();
+        let admin = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let teleport = GamePacket::new(
+            MessageType::Teleport,
+            4,
+            TeleportPacket::new(
+                target_id.as_bytes().to_vec(),
+                Position::new(10.0, 10.0),
+                b"guess".to_vec(),
+            )
+            .serialize(),
+            vec![0; PLAYER_ID_LEN],
+        );
+        admin
+            .send_to(&teleport.serialize(), server.socket.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        // The admin has no player, so the refusal isn't answered
+        let mut buf = vec![0; 1024];
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), admin.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+        let after = server
+            .game_state
+            .lock()
+            .await
+            .get_player_position(&target_id)
+            .cloned();
+        assert_eq!(after, before);
+
+        server_handle.abort();
+    }
+
+    async fn join_requesting_id(server_addr: SocketAddr, id: &str) -> (UdpSocket, GamePacket) {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request =
+            ConnectionInitRequest::new(String::new(), None).with_requested_id(id.to_string());
+        client
+            .send_to(
+                &PacketBuilder::connection_init()
+                    .payload(request.serialize())
+                    .serialize(),
+                server_addr,
+            )
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(response.msg_type, MessageType::ConnectionInit);
+        (client, response)
+    }
+
+    #[tokio::test]
+    async fn test_requested_id_is_honored_when_free() {
+        let server = Arc::new(
+            GameServer::with_config(
+                Some("127.0.0.1:0"),
+                ServerConfig {
+                    honor_requested_ids: true,
+                    ..ServerConfig::default()
+                },
+            )
+            .await
+            .unwrap(),
+        );
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let id = "V1StGXR8_Z5jdHi6B-";
+        let (client, response) = join_requesting_id(server_addr, id).await;
+        assert_eq!(response.client_id, id.as_bytes());
+        // Honored flag right after the world info
+        assert_eq!(response.payload[RECONNECT_TOKEN_LEN + WORLD_INFO_SIZE], 1);
+        let game_state = server.game_state.lock().await;
+        let player = game_state
+            .get_player_by_addr(&client.local_addr().unwrap().to_string())
+            .unwrap();
+        assert_eq!(player.id, id);
+        drop(game_state);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_requested_id_collision_mints_a_fresh_id() {
+        let server = Arc::new(
+            GameServer::with_config(
+                Some("127.0.0.1:0"),
+                ServerConfig {
+                    honor_requested_ids: true,
+                    ..ServerConfig::default()
+                },
+            )
+            .await
+            .unwrap(),
+        );
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let id = "V1StGXR8_Z5jdHi6B-";
+        let (first, _) = join_requesting_id(server_addr, id).await;
+        let (second, response) = join_requesting_id(server_addr, id).await;
+        assert_ne!(response.client_id, id.as_bytes());
+        assert_eq!(response.client_id.len(), PLAYER_ID_LEN);
+        assert_eq!(response.payload[RECONNECT_TOKEN_LEN + WORLD_INFO_SIZE], 0);
+        let game_state = server.game_state.lock().await;
+        assert_eq!(game_state.get_player_count(), 2);
+        let first_addr = first.local_addr().unwrap().to_string();
+        let second_addr = second.local_addr().unwrap().to_string();
+        assert_eq!(game_state.get_player_by_addr(&first_addr).unwrap().id, id);
+        assert_eq!(
+            game_state
+                .get_player_by_addr(&second_addr)
+                .unwrap()
+                .id
+                .as_bytes(),
+            response.client_id
+        );
+        drop(game_state);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_broadcasts_within_the_window_arrive_bundled() {
+        let server = Arc::new(
+            GameServer::with_config(
+                Some("127.0.0.1:0"),
+                ServerConfig {
+                    coalesce_window: Some(Duration::from_millis(100)),
+                    ..ServerConfig::default()
+                },
+            )
+            .await
+            .unwrap(),
+        );
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request =
+            ConnectionInitRequest::new(String::new(), None).with_features(Features::BUNDLES);
+        client
+            .send_to(
+                &PacketBuilder::connection_init()
+                    .payload(request.serialize())
+                    .serialize(),
+                server_addr,
+            )
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        // The handshake answer isn't held back
+        let (len, _) = tokio::time::timeout(Duration::from_millis(50), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(response.msg_type, MessageType::ConnectionInit);
+        let features = RECONNECT_TOKEN_LEN + WORLD_INFO_SIZE;
+        assert_eq!(
+            Features::from_be_bytes(response.payload[features..features + 4].try_into().unwrap()),
+            Features::BUNDLES
+        );
+        // Let whatever followed the join be flushed
+        while tokio::time::timeout(Duration::from_millis(300), client.recv_from(&mut buf))
+            .await
+            .is_ok()
+        {}
+
+        let first = server
+            .spawn_entity("", 1, &Position::new(1.0, 2.0), None)
+            .await
+            .unwrap();
+        let second = server
+            .spawn_entity("", 2, &Position::new(3.0, 4.0), None)
+            .await
+            .unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let outer = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(outer.msg_type, MessageType::Bundle);
+        let spawned: Vec<EntityId> = BundlePacket::deserialize(&outer.payload)
+            .unwrap()
+            .packets
+            .iter()
+            .map(|packet| {
+                let packet = GamePacket::deserialize(packet).unwrap();
+                assert_eq!(packet.msg_type, MessageType::EntitySpawn);
+                EntitySpawnPacket::deserialize(&packet.payload)
+                    .unwrap()
+                    .entity_id
+            })
+            .collect();
+        assert_eq!(spawned, vec![first, second]);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "f64-positions")]
+    async fn test_f64_sessions_keep_large_coordinates_exact() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+        server.game_state.lock().await.resize(u32::MAX, u32::MAX);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request =
+            ConnectionInitRequest::new(String::new(), None).with_features(Features::F64_POSITIONS);
+        client
+            .send_to(
+                &PacketBuilder::connection_init()
+                    .payload(request.serialize())
+                    .serialize(),
+                server_addr,
+            )
+            .await
+            .unwrap();
+        let mut buf = vec![0; 2048];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(response.msg_type, MessageType::ConnectionInit);
+        let offset = RECONNECT_TOKEN_LEN + WORLD_INFO_SIZE;
+        assert_eq!(
+            Features::from_be_bytes(response.payload[offset..offset + 4].try_into().unwrap()),
+            Features::F64_POSITIONS
+        );
+        let player_id = String::from_utf8(response.client_id.clone()).unwrap();
+
+        // Past 2^24, where an f32 would round to a whole unit
+        let far = Position::new(16_777_217.5, 33_554_433.25);
+        client
+            .send_to(
+                &PacketBuilder::position_update(&far)
+                    .payload(far.serialize_f64())
+                    .client_id(&response.client_id)
+                    .seq(2)
+                    .serialize(),
+                server_addr,
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(
+            server.game_state.lock().await.players[&player_id].position,
+            far
+        );
+
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        {
+            let mut game_state = server.game_state.lock().await;
+            let player = Player {
+                id: "o".repeat(PLAYER_ID_LEN),
+                ..Player::default()
+            };
+            game_state.add_player(player, other.local_addr().unwrap().to_string());
+            game_state.stage_position_update(PositionGamePacket {
+                msg_type: MessageType::PositionUpdate,
+                version: 1,
+                client_id: vec![b'o'; PLAYER_ID_LEN],
+                seq_num: 1,
+                position: far.clone(),
+            });
+        }
+        let batch = loop {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            if packet.msg_type == MessageType::PositionBatch {
+                break PositionBatch::deserialize_f64(&packet.payload).unwrap();
+            }
+        };
+        assert_eq!(batch.positions[0].position, far);
+
+        server_handle.abort();
+    }
+
+    #[tokio::test]
+    async fn test_negotiates_only_advertised_features() {
+        let server = Arc::new(
+            GameServer::with_config(
+                Some("127.0.0.1:0"),
+                ServerConfig {
+                    compression_threshold: Some(64),
+                    ..ServerConfig::default()
+                },
+            )
+            .await
+            .unwrap(),
+        );
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let request =
+            ConnectionInitRequest::new(String::new(), None).with_features(Features::COMPRESSION);
+        client
+            .send_to(
+                &PacketBuilder::connection_init()
+                    .payload(request.serialize())
+                    .serialize(),
+                server_addr,
+            )
+            .await
+            .unwrap();
+        let mut buf = vec![0; 2048];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(response.msg_type, MessageType::ConnectionInit);
+        let offset = RECONNECT_TOKEN_LEN + WORLD_INFO_SIZE;
+        let features =
+            Features::from_be_bytes(response.payload[offset..offset + 4].try_into().unwrap());
+        let expected = if cfg!(feature = "compression") {
+            Features::COMPRESSION
+        } else {
+            Features::NONE
+        };
+        assert_eq!(features, expected);
+        assert!(!features.contains(Features::DELTA_ENCODING));
+        let player_id = String::from_utf8(response.client_id).unwrap();
+        let mut others = Vec::new();
+        for _ in 0..20 {
+            others.push(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        }
+        {
+            let mut game_state = server.game_state.lock().await;
+            assert_eq!(game_state.players[&player_id].features, expected);
+            // Enough nearly identical records to be worth compressing
+            for (i, other) in others.iter().enumerate() {
+                let player = Player {
+                    id: format!("{i:0>PLAYER_ID_LEN$}"),
+                    ..Player::default()
+                };
+                game_state.add_player(player, other.local_addr().unwrap().to_string());
+                game_state.stage_position_update(PositionGamePacket {
+                    msg_type: MessageType::PositionUpdate,
+                    version: 1,
+                    client_id: format!("{i:0>PLAYER_ID_LEN$}").into_bytes(),
+                    seq_num: 1,
+                    position: Position::new(10.0, 10.0),
+                });
+            }
+        }
+
+        let version = loop {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+            if packet.msg_type == MessageType::PositionBatch {
+                let batch = PositionBatch::deserialize(&packet.payload).unwrap();
+                assert_eq!(batch.positions.len(), 20);
+                break buf[1];
+            }
+        };
+        assert_eq!(
+            version & FLAG_COMPRESSED != 0,
+            cfg!(feature = "compression")
+        );
+        // Checksums weren't advertised
+        assert_eq!(version & FLAG_CHECKSUM, 0);
+
+        server_handle.abort();
+    }
+
+    #[cfg(feature = "crypto")]
+    async fn join_encrypted(server_addr: SocketAddr) -> (UdpSocket, Vec<u8>, crypto::SessionKey) {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let exchange = crypto::KeyExchange::new();
+        let client_public = exchange.public_key();
+        let request = ConnectionInitRequest::new(String::new(), None)
+            .with_features(Features::ENCRYPTION)
+            .with_public_key(client_public);
+        client
+            .send_to(
+                &PacketBuilder::connection_init()
+                    .payload(request.serialize())
+                    .serialize(),
+                server_addr,
+            )
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        // The response itself goes out in the clear
+        let response = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(response.msg_type, MessageType::ConnectionInit);
+        let features = RECONNECT_TOKEN_LEN + WORLD_INFO_SIZE;
+        assert_eq!(
+            Features::from_be_bytes(response.payload[features..features + 4].try_into().unwrap()),
+            Features::ENCRYPTION
+        );
+        let server_public: crypto::PublicKey = response.payload[features + 4..]
+            [..crypto::PUBLIC_KEY_LEN]
+            .try_into()
+            .unwrap();
+        let key = exchange.finish(&server_public, &client_public).unwrap();
+        (client, response.client_id, key)
+    }
+
+    #[cfg(feature = "crypto")]
+    #[tokio::test]
+    async fn test_encrypted_session_round_trips_position_update() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let (mover, mover_id, mover_key) = join_encrypted(server_addr).await;
+        let (watcher, _, watcher_key) = join_encrypted(server_addr).await;
+        assert_ne!(mover_key, watcher_key);
+
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&100.0f32.to_le_bytes());
+        payload.extend_from_slice(&200.0f32.to_le_bytes());
+        let update = GamePacket::new(MessageType::PositionUpdate, 2, payload, mover_id.clone());
+        let sealed = crypto::seal(&mover_key, &update.serialize()).unwrap();
+        mover.send_to(&sealed, server_addr).await.unwrap();
+
+        let mut buf = vec![0; 1024];
+        let batch = loop {
+            let (len, _) =
+                tokio::time::timeout(Duration::from_secs(5), watcher.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_ne!(buf[1] & FLAG_ENCRYPTED, 0);
+            let opened = crypto::open(&watcher_key, &buf[..len]).unwrap();
+            let packet = GamePacket::deserialize(&opened).unwrap();
+            if packet.msg_type == MessageType::PositionBatch {
+                break PositionBatch::deserialize(&packet.payload).unwrap();
+            }
+        };
+        assert_eq!(batch.positions[0].id, mover_id);
+        assert_eq!(
+            server
+                .game_state
+                .lock()
+                .await
+                .get_player_by_addr(&mover.local_addr().unwrap().to_string())
+                .unwrap()
+                .position,
+            Position::new(100.0, 200.0)
+        );
+
+        // Plaintext is refused once the session is encrypted
+        let heartbeat = GamePacket::new(MessageType::Heartbeat, 3, vec![], mover_id);
+        mover
+            .send_to(&heartbeat.serialize(), server_addr)
+            .await
+            .unwrap();
+        let error = loop {
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), mover.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let packet =
+                GamePacket::deserialize(&crypto::open(&mover_key, &buf[..len]).unwrap()).unwrap();
+            if packet.msg_type == MessageType::Error {
+                break ErrorPacket::deserialize(&packet.payload).unwrap();
+            }
+        };
+        assert_eq!(error.code, ErrorCode::Forbidden);
+
+        server_handle.abort();
+    }
+
+    #[cfg(feature = "crypto")]
+    #[tokio::test]
+    async fn test_replayed_sealed_packet_is_dropped() {
+        let server = Arc::new(GameServer::new(Some("127.0.0.1:0")).await.unwrap());
+        let server_addr = server.socket.local_addr().unwrap();
+        let server_handle = {
+            let server = server.clone();
+            tokio::spawn(async move { server.run().await.unwrap() })
+        };
+        server.ready().await;
+
+        let (mover, mover_id, mover_key) = join_encrypted(server_addr).await;
+        let (watcher, _, watcher_key) = join_encrypted(server_addr).await;
+        let sealed_update = |seq_num: u32, x: f32, y: f32| {
+            let mut payload = Vec::new();
+            payload.extend_from_slice(&x.to_le_bytes());
+            payload.extend_from_slice(&y.to_le_bytes());
+            let update = GamePacket::new(
+                MessageType::PositionUpdate,
+                seq_num,
+                payload,
+                mover_id.clone(),
+            );
+            crypto::seal(&mover_key, &update.serialize()).unwrap()
+        };
+        let mut buf = vec![0; 1024];
+        let mut next_batch = async || loop {
+            let (len, _) =
+                tokio::time::timeout(Duration::from_secs(5), watcher.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let opened = crypto::open(&watcher_key, &buf[..len]).unwrap();
+            let packet = GamePacket::deserialize(&opened).unwrap();
+            if packet.msg_type == MessageType::PositionBatch {
+                break packet.payload;
+            }
+        };
+
+        let first = sealed_update(2, 100.0, 200.0);
+        mover.send_to(&first, server_addr).await.unwrap();
+        next_batch().await;
+        // Replaying the captured datagram is dropped, a newer one still goes through
+        mover.send_to(&first, server_addr).await.unwrap();
+        mover
+            .send_to(&sealed_update(3, 300.0, 400.0), server_addr)
+            .await
+            .unwrap();
+        let batch = next_batch().await;
+        assert_eq!(
+            batch[2 + PLAYER_ID_LEN..2 + POSITION_RECORD_SIZE],
+            Position::new(300.0, 400.0).serialize()
+        );
+        assert_eq!(server.metrics().replayed_packets(), 1);
+
+        server_handle.abort();
+    }
 }
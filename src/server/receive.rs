@@ -0,0 +1,70 @@
+use std::{io, net::SocketAddr};
+
+use tokio::net::UdpSocket;
+
+/// Waits for a datagram on `socket`, then reads up to `max_batch - 1` more that are
+/// already waiting without waiting again, appending each to `batch`. Amortizes the
+/// wakeup over every datagram queued at high packet rates; a `max_batch` of one or
+/// zero reads a single datagram per call. `buf` should be one byte larger than the
+/// largest accepted datagram, so datagrams cut short are longer than that limit.
+/// Returns how many datagrams were read.
+///
+/// # Errors
+/// Returns the error of the first read. Errors after it end the batch and are logged.
+pub async fn recv_batch(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    max_batch: usize,
+    batch: &mut Vec<(Vec<u8>, SocketAddr)>,
+) -> io::Result<usize> {
+    let (len, addr) = socket.recv_from(buf).await?;
+    batch.push((buf[..len].to_vec(), addr));
+    let mut read = 1;
+    while read < max_batch {
+        match socket.try_recv_from(buf) {
+            Ok((len, addr)) => {
+                batch.push((buf[..len].to_vec(), addr));
+                read = read.saturating_add(1);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => {
+                tracing::error!("Error receiving from socket: {:?}", e);
+                break;
+            }
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drains_waiting_datagrams_up_to_the_batch_size() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for i in 0..5u8 {
+            client
+                .send_to(&[i], socket.local_addr().unwrap())
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut buf = vec![0; 16];
+        let mut batch = Vec::new();
+        assert_eq!(
+            recv_batch(&socket, &mut buf, 3, &mut batch).await.unwrap(),
+            3
+        );
+        assert_eq!(
+            recv_batch(&socket, &mut buf, 8, &mut batch).await.unwrap(),
+            2
+        );
+        let payloads = batch.into_iter().map(|(data, _)| data).collect::<Vec<_>>();
+        assert_eq!(payloads, vec![vec![0], vec![1], vec![2], vec![3], vec![4]]);
+    }
+}
@@ -0,0 +1,466 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    net::UdpSocket,
+    sync::Mutex,
+    task::{self, JoinHandle},
+    time,
+};
+
+use super::{ServerConfig, ServerMetrics};
+use crate::{
+    game_state::{self, Coalescer, GameState, OutboundQueue, RoomId, CLEANUP_INTERVAL_SECS},
+    packet::{connection_init::ReconnectToken, crypto},
+    tasks::{
+        handle_cleanup_task, CoalesceFlusher, HeartbeatManager, LivenessProbe, SimulationLoop,
+    },
+};
+
+/// One open room: its state and the maintenance tasks running on it.
+struct Room {
+    state: Arc<Mutex<GameState>>,
+    tasks: Vec<JoinHandle<()>>,
+    /// Whether the room was idle at the previous [`Rooms::close_idle`] sweep.
+    idle: bool,
+}
+
+/// The rooms of a server. Each room has a [`GameState`] of its own, with its own world,
+/// players, spectators and entities, and its own maintenance tasks, so players in
+/// different rooms never see each other.
+///
+/// The default room, the empty string, is always open. Other rooms are opened by the
+/// first player or spectator asking for them and closed once nobody is left in them.
+pub struct Rooms {
+    by_id: RwLock<HashMap<RoomId, Room>>,
+    /// Room each address last joined, unless that is the default room. An address its
+    /// room has dropped since stays routed there, as unknown, until the next sweep.
+    routes: RwLock<HashMap<String, RoomId>>,
+    default: Arc<Mutex<GameState>>,
+    /// Socket the maintenance tasks send from.
+    socket: Arc<UdpSocket>,
+    config: ServerConfig,
+    metrics: Arc<ServerMetrics>,
+    /// Shared by every room, sent by the server's one `OutboundSender`.
+    outbound: Option<Arc<OutboundQueue>>,
+    /// Set by [`Rooms::start`]; rooms opened from then on get their tasks right away.
+    running: AtomicBool,
+}
+
+impl Rooms {
+    pub(crate) fn new(
+        socket: Arc<UdpSocket>,
+        config: ServerConfig,
+        metrics: Arc<ServerMetrics>,
+    ) -> Self {
+        let outbound = config
+            .outbound_queue_capacity
+            .map(|capacity| Arc::new(OutboundQueue::new(capacity, Arc::clone(&metrics))));
+        let default = Arc::new(Mutex::new(new_game_state(
+            &config,
+            &metrics,
+            outbound.clone(),
+            config.max_players.unwrap_or(0),
+        )));
+        let rooms = HashMap::from([(
+            RoomId::new(),
+            Room {
+                state: Arc::clone(&default),
+                tasks: Vec::new(),
+                idle: false,
+            },
+        )]);
+        Rooms {
+            by_id: RwLock::new(rooms),
+            routes: RwLock::default(),
+            default,
+            socket,
+            config,
+            metrics,
+            outbound,
+            running: AtomicBool::new(false),
+        }
+    }
+    /// State of the default room.
+    #[must_use]
+    pub fn default_room(&self) -> Arc<Mutex<GameState>> {
+        Arc::clone(&self.default)
+    }
+    /// Queue every room sends through, when the server has one.
+    #[must_use]
+    pub fn outbound(&self) -> Option<Arc<OutboundQueue>> {
+        self.outbound.clone()
+    }
+    /// State of `room`, `None` if it isn't open.
+    #[must_use]
+    pub fn get(&self, room: &str) -> Option<Arc<Mutex<GameState>>> {
+        self.by_id
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(room)
+            .map(|room| Arc::clone(&room.state))
+    }
+    /// State of `room`, opening it if needed. Returns `None` when opening it would go
+    /// over [`ServerConfig::max_rooms`].
+    pub fn open(&self, room: &str) -> Option<Arc<Mutex<GameState>>> {
+        if let Some(state) = self.get(room) {
+            return Some(state);
+        }
+        let mut rooms = self.by_id.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(open) = rooms.get(room) {
+            return Some(Arc::clone(&open.state));
+        }
+        if self.config.max_rooms.is_some_and(|max| rooms.len() >= max) {
+            tracing::warn!(
+                "Not opening room {:?}, {} rooms are open",
+                room,
+                rooms.len()
+            );
+            return None;
+        }
+        // Most players stay in the default room, the others grow as needed
+        let state = Arc::new(Mutex::new(new_game_state(
+            &self.config,
+            &self.metrics,
+            self.outbound.clone(),
+            0,
+        )));
+        let tasks = if self.running.load(Ordering::Acquire) {
+            self.spawn_tasks(&state)
+        } else {
+            Vec::new()
+        };
+        tracing::info!("Opened room {:?}", room);
+        rooms.insert(
+            room.to_string(),
+            Room {
+                state: Arc::clone(&state),
+                tasks,
+                idle: false,
+            },
+        );
+        Some(state)
+    }
+    /// Every open room with its state, ordered by room id.
+    #[must_use]
+    pub fn states(&self) -> Vec<(RoomId, Arc<Mutex<GameState>>)> {
+        let mut states = self
+            .by_id
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(id, room)| (id.clone(), Arc::clone(&room.state)))
+            .collect::<Vec<_>>();
+        states.sort_by(|a, b| a.0.cmp(&b.0));
+        states
+    }
+    /// State of the room packets from `address` go to: the room it last joined, or the
+    /// default room for addresses that never joined one.
+    #[must_use]
+    pub fn route(&self, address: &str) -> Arc<Mutex<GameState>> {
+        self.routes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(address)
+            .and_then(|room| self.get(room))
+            .unwrap_or_else(|| Arc::clone(&self.default))
+    }
+    /// Routes the packets from `address` to `room` from now on.
+    pub fn set_route(&self, address: String, room: &str) {
+        let mut routes = self.routes.write().unwrap_or_else(PoisonError::into_inner);
+        if room.is_empty() {
+            routes.remove(&address);
+        } else {
+            routes.insert(address, room.to_string());
+        }
+    }
+    /// Number of players in every room.
+    pub async fn player_count(&self) -> usize {
+        let mut count = 0usize;
+        for (_, state) in self.states() {
+            count = count.saturating_add(state.lock().await.get_player_count());
+        }
+        count
+    }
+    /// Number of players connected from `ip` in every room.
+    pub async fn players_from_ip(&self, ip: IpAddr) -> usize {
+        let mut count = 0usize;
+        for (_, state) in self.states() {
+            count = count.saturating_add(state.lock().await.players_from_ip(ip));
+        }
+        count
+    }
+    /// State of the room player `player_id` is in, `None` if no room has it.
+    pub async fn find_player(&self, player_id: &str) -> Option<Arc<Mutex<GameState>>> {
+        for (_, state) in self.states() {
+            if state.lock().await.get_player_by_id(player_id).is_some() {
+                return Some(state);
+            }
+        }
+        None
+    }
+    /// Room that handed out `token` and its state, `None` if no room knows it.
+    pub async fn find_reconnect_token(
+        &self,
+        token: &ReconnectToken,
+    ) -> Option<(RoomId, Arc<Mutex<GameState>>)> {
+        for (room, state) in self.states() {
+            if state.lock().await.reconnect_tokens.contains_key(token) {
+                return Some((room, state));
+            }
+        }
+        None
+    }
+    /// Spawns the maintenance tasks of every open room, and of every room opened later.
+    pub(crate) fn start(&self) {
+        let mut rooms = self.by_id.write().unwrap_or_else(PoisonError::into_inner);
+        self.running.store(true, Ordering::Release);
+        for room in rooms.values_mut() {
+            if room.tasks.is_empty() {
+                room.tasks = self.spawn_tasks(&room.state);
+            }
+        }
+    }
+    /// Stops spawning tasks for new rooms and aborts those of every room, returning
+    /// their handles to wait on.
+    pub(crate) fn stop(&self) -> Vec<JoinHandle<()>> {
+        let mut rooms = self.by_id.write().unwrap_or_else(PoisonError::into_inner);
+        self.running.store(false, Ordering::Release);
+        let tasks = rooms
+            .values_mut()
+            .flat_map(|room| std::mem::take(&mut room.tasks))
+            .collect::<Vec<_>>();
+        for handle in &tasks {
+            handle.abort();
+        }
+        tasks
+    }
+    /// Closes the rooms, other than the default one, that had no players, spectators or
+    /// entities left at this sweep and the previous one, stopping their tasks. Waiting
+    /// for a second sweep leaves a room opened for a player that is still joining
+    /// alone. Returns how many rooms were closed.
+    pub async fn close_idle(&self) -> usize {
+        let mut idle = Vec::new();
+        let mut stale_routes = Vec::new();
+        for (room, state) in self.states() {
+            if room.is_empty() {
+                continue;
+            }
+            let routed = self
+                .routes
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .filter(|(_, routed)| **routed == room)
+                .map(|(address, _)| address.clone())
+                .collect::<Vec<_>>();
+            let state = state.lock().await;
+            if state.is_idle() && state.entities.is_empty() {
+                idle.push(room.clone());
+            }
+            stale_routes.extend(
+                routed
+                    .into_iter()
+                    .filter(|address| !state.is_known_address(address))
+                    .map(|address| (address, room.clone())),
+            );
+        }
+        {
+            let mut routes = self.routes.write().unwrap_or_else(PoisonError::into_inner);
+            for (address, room) in stale_routes {
+                if routes.get(&address) == Some(&room) {
+                    routes.remove(&address);
+                }
+            }
+        }
+        let mut rooms = self.by_id.write().unwrap_or_else(PoisonError::into_inner);
+        let mut closed = 0usize;
+        rooms.retain(|id, room| {
+            let now_idle = idle.contains(id);
+            let close = now_idle && room.idle;
+            room.idle = now_idle;
+            if close {
+                tracing::info!("Closing idle room {:?}", id);
+                for handle in &room.tasks {
+                    handle.abort();
+                }
+                closed = closed.saturating_add(1);
+            }
+            !close
+        });
+        closed
+    }
+    /// Closes idle rooms every cleanup interval, see [`Rooms::close_idle`].
+    pub(crate) async fn run_sweeper(&self) {
+        let mut interval = time::interval(Duration::from_secs(CLEANUP_INTERVAL_SECS));
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.close_idle().await;
+        }
+    }
+    /// Spawns the cleanup task, heartbeat manager, simulation loop and, as configured,
+    /// liveness probe and coalesce flusher of a room.
+    fn spawn_tasks(&self, state: &Arc<Mutex<GameState>>) -> Vec<JoinHandle<()>> {
+        let mut tasks = vec![tokio::spawn(handle_cleanup_task(
+            Arc::clone(state),
+            Arc::clone(&self.socket),
+        ))];
+        let heartbeat_manager = HeartbeatManager::new(
+            Arc::clone(&self.socket),
+            Arc::clone(state),
+            self.config.clone(),
+        );
+        tasks.push(task::spawn(async move { heartbeat_manager.run().await }));
+        let simulation_loop = SimulationLoop::new(
+            Arc::clone(&self.socket),
+            Arc::clone(state),
+            self.config.clone(),
+        );
+        tasks.push(task::spawn(async move { simulation_loop.run().await }));
+        if let Some(interval) = self.config.liveness_probe_interval {
+            let liveness_probe = LivenessProbe::new(
+                Arc::clone(&self.socket),
+                Arc::clone(state),
+                interval,
+                self.config.max_missed_probes,
+            );
+            tasks.push(task::spawn(async move { liveness_probe.run().await }));
+        }
+        if self.config.coalesce_window.is_some() {
+            let socket = Arc::clone(&self.socket);
+            let game_state = Arc::clone(state);
+            tasks.push(task::spawn(async move {
+                let Some(coalescer) = game_state.lock().await.coalescer.clone() else {
+                    return;
+                };
+                CoalesceFlusher::new(socket, game_state, coalescer)
+                    .run()
+                    .await;
+            }));
+        }
+        tracing::debug!("Spawned {} room maintenance tasks", tasks.len());
+        tasks
+    }
+}
+
+/// State of a newly opened room, set up as `config` asks, with the player maps sized
+/// for `capacity` players.
+fn new_game_state(
+    config: &ServerConfig,
+    metrics: &Arc<ServerMetrics>,
+    outbound: Option<Arc<OutboundQueue>>,
+    capacity: usize,
+) -> GameState {
+    GameState {
+        max_datagram_size: config.max_datagram_size,
+        compression_threshold: config.compression_threshold,
+        supported_features: config.supported_features,
+        lock_wait_threshold: config.lock_wait_threshold,
+        pending_entry_ttl: config.pending_entry_ttl,
+        position_history_len: config.position_history_len,
+        position_history_retention: config.position_history_retention,
+        unknown_heartbeat_window: config.unknown_heartbeat_window,
+        connect_grace_period: config.connect_grace_period,
+        outbound,
+        // Bundles are sealed as a whole, leave room for it
+        coalescer: config.coalesce_window.map(|window| {
+            Arc::new(Coalescer::new(
+                window,
+                config
+                    .max_datagram_size
+                    .saturating_sub(crypto::SEAL_OVERHEAD),
+            ))
+        }),
+        metrics: Arc::clone(metrics),
+        ..GameState::with_capacity(
+            game_state::DEFAULT_WORLD_WIDTH,
+            game_state::DEFAULT_WORLD_HEIGHT,
+            capacity,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_state::Player;
+
+    async fn rooms(config: ServerConfig) -> Rooms {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        Rooms::new(socket, config, Arc::default())
+    }
+
+    #[tokio::test]
+    async fn test_addresses_are_routed_to_the_room_they_joined() {
+        let rooms = rooms(ServerConfig::default()).await;
+        let red = rooms.open("red").unwrap();
+        assert!(Arc::ptr_eq(&rooms.open("red").unwrap(), &red));
+        assert!(Arc::ptr_eq(
+            &rooms.route("127.0.0.1:1"),
+            &rooms.default_room()
+        ));
+
+        rooms.set_route("127.0.0.1:1".to_string(), "red");
+        assert!(Arc::ptr_eq(&rooms.route("127.0.0.1:1"), &red));
+        rooms.set_route("127.0.0.1:1".to_string(), "");
+        assert!(Arc::ptr_eq(
+            &rooms.route("127.0.0.1:1"),
+            &rooms.default_room()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_room_count_is_capped() {
+        let rooms = rooms(ServerConfig {
+            max_rooms: Some(2),
+            ..ServerConfig::default()
+        })
+        .await;
+        assert!(rooms.open("red").is_some());
+        assert!(rooms.open("blue").is_none());
+        // Open rooms are still found
+        assert!(rooms.open("red").is_some());
+        assert!(rooms.open("").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rooms_close_after_two_idle_sweeps() {
+        let rooms = rooms(ServerConfig::default()).await;
+        let red = rooms.open("red").unwrap();
+        rooms.open("blue").unwrap();
+        {
+            let mut red = red.lock().await;
+            let player = Player {
+                id: "p".to_string(),
+                heartbeat: red.now(),
+                room: "red".to_string(),
+                ..Player::default()
+            };
+            red.add_player(player, "127.0.0.1:1".to_string());
+        }
+        rooms.set_route("127.0.0.1:1".to_string(), "red");
+        rooms.set_route("127.0.0.1:2".to_string(), "blue");
+
+        assert_eq!(rooms.close_idle().await, 0);
+        assert_eq!(rooms.close_idle().await, 1);
+        assert!(rooms.get("blue").is_none());
+        assert!(rooms.get("red").is_some());
+        assert!(rooms.get("").is_some());
+        // The route to the closed room was dropped, the player's is kept
+        assert!(Arc::ptr_eq(
+            &rooms.route("127.0.0.1:2"),
+            &rooms.default_room()
+        ));
+        assert!(Arc::ptr_eq(&rooms.route("127.0.0.1:1"), &red));
+        assert_eq!(rooms.player_count().await, 1);
+        assert!(rooms.find_player("p").await.is_some());
+    }
+}
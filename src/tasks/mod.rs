@@ -1,88 +1,1007 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use tokio::{net::UdpSocket, sync::Mutex, time};
 
 use crate::{
-    game_state::{GameState, CLEANUP_INTERVAL_SECS},
-    packet::{GamePacket, MessageType},
+    game_state::{
+        lock_timed, BroadcastScope, Coalescer, GameState, InterestEvent, OutboundQueue, PlayerId,
+        CLEANUP_INTERVAL_SECS,
+    },
+    packet::{
+        ping::{HeartbeatStatus, PlayerLeft},
+        position::{PlayerPosition, PositionBatch},
+        GamePacket, MessageType,
+    },
+    server::ServerConfig,
 };
 
+/// How often tasks paused while nobody is connected check the state anyway, in case
+/// players were inserted without [`GameState::add_player`].
+pub const IDLE_POLL_INTERVAL: Duration = Duration::from_mins(1);
+
+/// Returns once `game_state` has a player or spectator, waiting for one to join if
+/// it is idle. Returns whether it had to wait.
+pub async fn wait_until_active(game_state: &Mutex<GameState>) -> bool {
+    let mut waited = false;
+    loop {
+        let state = game_state.lock().await;
+        if !state.is_idle() {
+            return waited;
+        }
+        let joined = Arc::clone(&state.joined);
+        let notified = joined.notified();
+        tokio::pin!(notified);
+        // Registered before unlocking so a join right after can't be missed
+        notified.as_mut().enable();
+        drop(state);
+        waited = true;
+        let _ = time::timeout(IDLE_POLL_INTERVAL, notified).await;
+    }
+}
+
 pub async fn handle_cleanup_task(
     cleanup_state: Arc<Mutex<GameState>>,
     cleanup_socket: Arc<UdpSocket>,
 ) {
-    let interval = time::interval(Duration::from_secs(CLEANUP_INTERVAL_SECS));
-    tokio::pin!(interval);
+    let mut interval = time::interval(Duration::from_secs(CLEANUP_INTERVAL_SECS));
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
 
     loop {
+        if wait_until_active(&cleanup_state).await {
+            tracing::debug!("Cleanup task resumed");
+            interval.reset();
+        }
         interval.tick().await;
         let mut state = cleanup_state.lock().await;
-        if let Err(e) = state.cleanup_inactive_players(&cleanup_socket).await {
-            tracing::error!("Failed to cleanup inactive players: {e}");
-        }
+        state.cleanup_inactive_players(&cleanup_socket).await;
+        state.expire_stale_entries();
     }
 }
 
-pub async fn handle_heartbeat_task(ping_state: Arc<Mutex<GameState>>, ping_socket: Arc<UdpSocket>) {
-    let interval = time::interval(Duration::from_secs(3));
-    tokio::pin!(interval);
+/// Periodically sends every player a `Heartbeat` packet.
+pub struct HeartbeatManager {
+    socket: Arc<UdpSocket>,
+    game_state: Arc<Mutex<GameState>>,
+    config: ServerConfig,
+}
+
+impl HeartbeatManager {
+    pub fn new(
+        socket: Arc<UdpSocket>,
+        game_state: Arc<Mutex<GameState>>,
+        config: ServerConfig,
+    ) -> Self {
+        Self {
+            socket,
+            game_state,
+            config,
+        }
+    }
 
-    loop {
-        interval.tick().await;
-        let state = ping_state.lock().await;
-        for (addr, player) in &state.players {
-            let reply = GamePacket::new(
+    pub async fn run(&self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(3));
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+        loop {
+            if wait_until_active(&self.game_state).await {
+                tracing::debug!("Heartbeat manager resumed");
+                interval.reset();
+            }
+            interval.tick().await;
+            let sent = self.send_heartbeats().await;
+            tracing::debug!("Sent {sent} heartbeats");
+        }
+    }
+
+    /// Sends one heartbeat to every player and returns how many were sent successfully.
+    pub async fn send_heartbeats(&self) -> usize {
+        let mut state = self.game_state.lock().await;
+        let payload = if self.config.heartbeat_status {
+            HeartbeatStatus::new(state.get_player_count(), state.tick).serialize()
+        } else {
+            vec![]
+        };
+        state
+            .broadcast(
+                &self.socket,
+                &BroadcastScope::All,
                 MessageType::Heartbeat,
-                0,
+                |_, _| vec![payload.clone()],
+            )
+            .await
+    }
+}
+
+/// Actively probes players with `Ping`s and removes those that stop answering,
+/// noticing crashed clients sooner than the heartbeat timeout.
+pub struct LivenessProbe {
+    socket: Arc<UdpSocket>,
+    game_state: Arc<Mutex<GameState>>,
+    interval: Duration,
+    max_missed: u32,
+}
+
+impl LivenessProbe {
+    pub fn new(
+        socket: Arc<UdpSocket>,
+        game_state: Arc<Mutex<GameState>>,
+        interval: Duration,
+        max_missed: u32,
+    ) -> Self {
+        Self {
+            socket,
+            game_state,
+            interval,
+            max_missed,
+        }
+    }
+
+    pub async fn run(&self) {
+        let interval = time::interval(self.interval);
+        tokio::pin!(interval);
+
+        loop {
+            interval.tick().await;
+            let removed = self.probe().await;
+            if removed > 0 {
+                tracing::info!("Removed {removed} players not answering liveness probes");
+            }
+        }
+    }
+
+    /// Counts unanswered probes, removes players that missed too many and pings
+    /// everyone else. Returns how many players were removed.
+    pub async fn probe(&self) -> usize {
+        let mut state = self.game_state.lock().await;
+        let unresponsive = state.expire_probes(self.max_missed.max(1));
+        for player_id in &unresponsive {
+            tracing::info!("Player {player_id} stopped answering liveness probes");
+            state
+                .remove_player_and_notify(player_id, &self.socket)
+                .await;
+        }
+        let mut failed = Vec::new();
+        for (addr, player_id) in state.recipients() {
+            let Some(seq) = state.start_probe(&player_id) else {
+                continue;
+            };
+            let ping = GamePacket::new(
+                MessageType::Ping,
+                seq,
                 vec![],
-                player.id.as_bytes().to_vec(),
+                player_id.as_bytes().to_vec(),
             );
-            let data = reply.serialize();
-            if let Ok(addr) = addr.parse::<std::net::SocketAddr>() {
-                if let Err(e) = ping_socket.send_to(&data, addr).await {
-                    tracing::error!("Failed to send heartbeat: {addr}: {e}");
+            if let Err(e) = state
+                .send_datagram(&self.socket, &ping.serialize(), &addr)
+                .await
+            {
+                tracing::error!("Failed to send ping: {addr}: {e}");
+                failed.push(player_id);
+            }
+        }
+        for player_id in failed {
+            state.record_send_failure(&player_id);
+        }
+        unresponsive.len()
+    }
+}
+
+/// Sends what handlers queued in an [`OutboundQueue`], so they never wait on the socket.
+pub struct OutboundSender {
+    socket: Arc<UdpSocket>,
+    queue: Arc<OutboundQueue>,
+}
+
+impl OutboundSender {
+    pub fn new(socket: Arc<UdpSocket>, queue: Arc<OutboundQueue>) -> Self {
+        Self { socket, queue }
+    }
+
+    pub async fn run(&self) {
+        loop {
+            self.queue.notified().await;
+            for (addr, data) in self.queue.take_all() {
+                if let Err(e) = self.socket.send_to(&data, addr.as_str()).await {
+                    tracing::error!("Failed to send queued datagram: {addr}: {e}");
                 }
             }
         }
     }
 }
-pub struct HeartbeatManager {
+
+/// Sends what a [`Coalescer`] held back once its window is over.
+pub struct CoalesceFlusher {
     socket: Arc<UdpSocket>,
     game_state: Arc<Mutex<GameState>>,
+    coalescer: Arc<Coalescer>,
 }
 
-impl HeartbeatManager {
-    pub fn new(socket: Arc<UdpSocket>, game_state: Arc<Mutex<GameState>>) -> Self {
-        Self { socket, game_state }
+impl CoalesceFlusher {
+    pub fn new(
+        socket: Arc<UdpSocket>,
+        game_state: Arc<Mutex<GameState>>,
+        coalescer: Arc<Coalescer>,
+    ) -> Self {
+        Self {
+            socket,
+            game_state,
+            coalescer,
+        }
     }
 
     pub async fn run(&self) {
-        let interval = tokio::time::interval(Duration::from_secs(3));
-        tokio::pin!(interval);
+        loop {
+            let Some(deadline) = self.coalescer.next_deadline() else {
+                self.coalescer.notified().await;
+                continue;
+            };
+            time::sleep_until(deadline).await;
+            let due = self.coalescer.take_due(time::Instant::now());
+            if due.is_empty() {
+                continue;
+            }
+            let state = lock_timed(&self.game_state, "coalesce_flush").await;
+            for (addr, data) in due {
+                if let Err(e) = state.transmit(&self.socket, &data, addr.as_str()).await {
+                    tracing::error!("Failed to send coalesced datagram: {addr}: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// Embedder code run on the game state at the start of every simulation tick.
+pub type TickHook = Arc<dyn Fn(&mut GameState) + Send + Sync>;
+
+/// Fixed-rate simulation step, decoupled from packet arrival.
+///
+/// Handlers stage changes in the [`GameState`]; every tick the loop runs the per-tick
+/// hooks, broadcasts whatever was staged since the previous tick and drops players
+/// that stopped being reachable. Ticks taking longer than the tick interval are
+/// logged and counted in the `ServerMetrics`, along with the rate actually reached.
+pub struct SimulationLoop {
+    socket: Arc<UdpSocket>,
+    game_state: Arc<Mutex<GameState>>,
+    config: ServerConfig,
+    hook: Option<TickHook>,
+}
 
+impl SimulationLoop {
+    pub fn new(
+        socket: Arc<UdpSocket>,
+        game_state: Arc<Mutex<GameState>>,
+        config: ServerConfig,
+    ) -> Self {
+        Self {
+            socket,
+            game_state,
+            config,
+            hook: None,
+        }
+    }
+    /// Runs `hook` at the start of every tick, after [`GameState::advance_tick`].
+    #[must_use]
+    pub fn with_tick_hook(mut self, hook: TickHook) -> Self {
+        self.hook = Some(hook);
+        self
+    }
+
+    pub async fn run(&self) {
+        let metrics = Arc::clone(&self.game_state.lock().await.metrics);
+        metrics.set_target_tick_rate(self.config.effective_tick_rate_hz());
+        let mut interval = time::interval(self.config.tick_interval());
+        // After an overrun the ticks that were missed are dropped rather than run back
+        // to back: catching up would add load right when the server is overloaded, and
+        // stale ticks have nothing new to broadcast anyway. The drift shows up in
+        // `ServerMetrics::actual_tick_rate` instead.
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Skip);
+
+        let mut last_started: Option<time::Instant> = None;
         loop {
             interval.tick().await;
-            if let Err(e) = self.send_heartbeats().await {
-                tracing::error!("Failed to send heartbeats: {e}");
+            let started = time::Instant::now();
+            if let Some(last) = last_started.replace(started) {
+                metrics.record_tick_period(started.saturating_duration_since(last));
             }
+            self.tick().await;
         }
     }
 
-    async fn send_heartbeats(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let state = self.game_state.lock().await;
-        for (addr, player) in &state.players {
-            let reply = GamePacket::new(
-                MessageType::Heartbeat,
-                0,
-                vec![],
-                player.id.as_bytes().to_vec(),
+    /// Runs one tick, warning if it takes longer than the tick interval.
+    pub async fn tick(&self) {
+        let started = time::Instant::now();
+        let mut state = self.game_state.lock().await;
+        self.step(&mut state).await;
+        let took = started.elapsed();
+        let budget = self.config.tick_interval();
+        if took > budget {
+            tracing::warn!(
+                "Simulation tick {} took {:?}, over its {:?} budget",
+                state.tick,
+                took,
+                budget
             );
-            let data = reply.serialize();
+            state.metrics.record_tick_overrun();
+        }
+    }
+
+    async fn step(&self, state: &mut GameState) {
+        state.advance_tick();
+        if let Some(hook) = &self.hook {
+            hook(state);
+        }
+        let updates = state
+            .take_pending_position_updates()
+            .into_iter()
+            .map(|update| PlayerPosition::new(update.client_id, update.position))
+            .collect::<Vec<_>>();
+
+        if let Some(radius) = self.config.interest_radius {
+            let events = state.update_interest(radius);
+            self.send_interest_events(state, events).await;
+        }
+        if !updates.is_empty() {
+            self.send_position_batches(state, &updates).await;
+        }
+        state
+            .remove_unreachable_players(self.config.max_send_failures, &self.socket)
+            .await;
+    }
+
+    /// Sends every player the staged updates of the other players it is interested in,
+    /// and every spectator all of them.
+    async fn send_position_batches(&self, state: &mut GameState, updates: &[PlayerPosition]) {
+        // Updates of players that have left since reach nobody
+        let updates = updates
+            .iter()
+            .filter(|update| {
+                std::str::from_utf8(&update.id).is_ok_and(|id| state.get_player_by_id(id).is_some())
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let interest_radius = self.config.interest_radius;
+        let scope = BroadcastScope::everyone();
+        state
+            .broadcast(
+                &self.socket,
+                &scope,
+                MessageType::PositionBatch,
+                |state, recipient| {
+                    let Some(player_id) = recipient else {
+                        // Spectators see every update regardless of interest
+                        return PositionBatch::split(&updates)
+                            .iter()
+                            .map(PositionBatch::serialize)
+                            .collect();
+                    };
+                    // The ids of the players in the interest radius, if there is one
+                    let interest = interest_radius.zip(state.get_player_by_id(player_id)).map(
+                        |(radius, player)| {
+                            let scope = BroadcastScope::Within {
+                                center: player.position.clone(),
+                                radius,
+                            };
+                            state
+                                .scope_recipients(&scope)
+                                .into_iter()
+                                .map(|(_, id)| id)
+                                .collect::<HashSet<_>>()
+                        },
+                    );
+                    let positions = updates
+                        .iter()
+                        .filter(|update| update.id != player_id.as_bytes())
+                        .filter(|update| {
+                            interest.as_ref().is_none_or(|interest| {
+                                std::str::from_utf8(&update.id)
+                                    .is_ok_and(|id| interest.contains(id))
+                            })
+                        })
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    if state.uses_f64_positions(player_id) {
+                        PositionBatch::split_f64(&positions)
+                            .iter()
+                            .map(PositionBatch::serialize_f64)
+                            .collect()
+                    } else {
+                        PositionBatch::split(&positions)
+                            .iter()
+                            .map(PositionBatch::serialize)
+                            .collect()
+                    }
+                },
+            )
+            .await;
+    }
+
+    /// Tells each observer about players entering or leaving its interest radius, the
+    /// players entering first.
+    async fn send_interest_events(&self, state: &mut GameState, events: Vec<InterestEvent>) {
+        let mut entered: HashMap<PlayerId, Vec<PlayerId>> = HashMap::new();
+        let mut exited: HashMap<PlayerId, Vec<PlayerId>> = HashMap::new();
+        for event in events {
+            match event {
+                InterestEvent::Enter { observer, target } => {
+                    entered.entry(observer).or_default().push(target);
+                }
+                InterestEvent::Exit { observer, target } => {
+                    exited.entry(observer).or_default().push(target);
+                }
+            }
+        }
+
+        let scope = BroadcastScope::Only(entered.keys().cloned().collect());
+        state
+            .broadcast(
+                &self.socket,
+                &scope,
+                MessageType::InterestEnter,
+                |state, recipient| {
+                    let Some(targets) = recipient.and_then(|observer| entered.get(observer)) else {
+                        return Vec::new();
+                    };
+                    let f64_positions = recipient.is_some_and(|id| state.uses_f64_positions(id));
+                    targets
+                        .iter()
+                        .filter_map(|target| {
+                            let position = state.get_player_position(target)?;
+                            let record =
+                                PlayerPosition::new(target.as_bytes().to_vec(), position.clone());
+                            Some(if f64_positions {
+                                record.serialize_f64()
+                            } else {
+                                record.serialize()
+                            })
+                        })
+                        .collect()
+                },
+            )
+            .await;
+        let scope = BroadcastScope::Only(exited.keys().cloned().collect());
+        state
+            .broadcast(
+                &self.socket,
+                &scope,
+                MessageType::InterestExit,
+                |_, recipient| {
+                    recipient
+                        .and_then(|observer| exited.get(observer))
+                        .into_iter()
+                        .flatten()
+                        .map(|target| PlayerLeft::new(target.clone()).serialize())
+                        .collect()
+                },
+            )
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        game_state::{Clock, Player, Position, Timestamp, PLAYER_ID_LEN},
+        packet::PositionGamePacket,
+        testing::CapturedLogs,
+    };
+
+    /// Counts how often the time is read, i.e. how often cleanup runs.
+    #[derive(Debug, Default)]
+    struct CountingClock(std::sync::atomic::AtomicU64);
+    impl Clock for CountingClock {
+        fn now(&self) -> Timestamp {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Timestamp::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cleanup_pauses_while_idle() {
+        let clock = Arc::new(CountingClock::default());
+        let shared_clock: Arc<dyn Clock> = clock.clone();
+        let game_state = Arc::new(Mutex::new(GameState::with_clock(100, 100, shared_clock)));
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let handle = tokio::spawn(handle_cleanup_task(Arc::clone(&game_state), socket));
+
+        // Several cleanup intervals pass without a single run
+        tokio::time::sleep(Duration::from_secs(6 * CLEANUP_INTERVAL_SECS)).await;
+        assert_eq!(clock.0.load(std::sync::atomic::Ordering::Relaxed), 0);
+
+        let player = Player {
+            id: "a".repeat(PLAYER_ID_LEN),
+            ..Player::default()
+        };
+        game_state
+            .lock()
+            .await
+            .add_player(player, "127.0.0.1:1".to_string());
+        // Back to the normal cadence, counting from the join
+        tokio::time::sleep(Duration::from_secs(CLEANUP_INTERVAL_SECS) + Duration::from_millis(10))
+            .await;
+        assert_eq!(clock.0.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        handle.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_simulation_loop_tick_rate() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let game_state = Arc::new(Mutex::new(GameState::default()));
+        let config = ServerConfig {
+            tick_rate_hz: 20,
+            ..ServerConfig::default()
+        };
+        let simulation_loop = SimulationLoop::new(socket, Arc::clone(&game_state), config);
+        let handle = tokio::spawn(async move { simulation_loop.run().await });
+
+        // One tick right away, then one per 50ms step. Each step yields until the loop
+        // had a chance to run its tick, a bounded number of times so a missing tick
+        // fails rather than hangs.
+        for expected in 1..=11 {
+            if expected > 1 {
+                tokio::time::advance(Duration::from_millis(50)).await;
+            }
+            for _ in 0..100 {
+                if game_state.lock().await.tick >= expected {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(game_state.lock().await.tick, expected);
+        }
+        handle.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tick_rate_is_capped_and_reported() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let game_state = Arc::new(Mutex::new(GameState::default()));
+        let metrics = Arc::clone(&game_state.lock().await.metrics);
+        let config = ServerConfig {
+            tick_rate_hz: 1000,
+            max_tick_rate_hz: 50,
+            ..ServerConfig::default()
+        };
+        let simulation_loop = SimulationLoop::new(socket, Arc::clone(&game_state), config);
+        let handle = tokio::spawn(async move { simulation_loop.run().await });
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        handle.abort();
+
+        assert_eq!(metrics.target_tick_rate(), 50);
+        assert!((metrics.actual_tick_rate() - 50.0).abs() < 1.0);
+        assert_eq!(metrics.tick_overruns(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_slow_tick_logs_overrun() {
+        let captured = CapturedLogs::default();
+        let _default = captured.install();
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let game_state = Arc::new(Mutex::new(GameState::default()));
+        let metrics = Arc::clone(&game_state.lock().await.metrics);
+        let config = ServerConfig {
+            tick_rate_hz: 100,
+            ..ServerConfig::default()
+        };
+        let simulation_loop = SimulationLoop::new(socket, Arc::clone(&game_state), config)
+            .with_tick_hook(Arc::new(|_state: &mut GameState| {
+                std::thread::sleep(Duration::from_millis(30));
+            }));
+
+        simulation_loop.tick().await;
+        assert_eq!(metrics.tick_overruns(), 1);
+        let logs = captured.contents();
+        assert!(logs.contains("WARN"), "{logs}");
+        assert!(logs.contains("Simulation tick 1 took"), "{logs}");
+        assert!(logs.contains("over its 10ms budget"), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn test_two_movers_produce_single_batch() {
+        let server_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let game_state = Arc::new(Mutex::new(GameState::default()));
+        let clients = [
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let ids = [
+            crate::game_state::generate_player_id(),
+            crate::game_state::generate_player_id(),
+            crate::game_state::generate_player_id(),
+        ];
+        {
+            let mut state = game_state.lock().await;
+            for (client, id) in clients.iter().zip(&ids) {
+                let player = Player {
+                    id: id.clone(),
+                    heartbeat: state.now(),
+                    connected_at: state.now(),
+                    ..Player::default()
+                };
+                state.add_player(player, client.local_addr().unwrap().to_string());
+            }
+            for id in &ids[..2] {
+                state.stage_position_update(PositionGamePacket {
+                    msg_type: MessageType::PositionUpdate,
+                    version: 1,
+                    client_id: id.as_bytes().to_vec(),
+                    seq_num: 1,
+                    position: Position::new(10.0, 20.0),
+                });
+            }
+        }
+
+        let simulation_loop = SimulationLoop::new(
+            server_socket,
+            Arc::clone(&game_state),
+            ServerConfig::default(),
+        );
+        simulation_loop.tick().await;
+
+        let observer = &clients[2];
+        let mut buf = vec![0; 1500];
+        let (len, _) = time::timeout(Duration::from_secs(1), observer.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(packet.msg_type, MessageType::PositionBatch);
+        assert_eq!(packet.client_id, ids[2].as_bytes());
+        let batch = PositionBatch::deserialize(&packet.payload).unwrap();
+        let mut received = batch
+            .positions
+            .iter()
+            .map(|p| p.id.clone())
+            .collect::<Vec<_>>();
+        received.sort();
+        let mut expected = vec![ids[0].as_bytes().to_vec(), ids[1].as_bytes().to_vec()];
+        expected.sort();
+        assert_eq!(received, expected);
+
+        // Nothing else was sent to the observer this tick
+        assert!(
+            time::timeout(Duration::from_millis(100), observer.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_updates_of_unknown_players_reach_no_room() {
+        let server_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let game_state = Arc::new(Mutex::new(GameState::default()));
+        let observer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        {
+            let mut state = game_state.lock().await;
+            let player = Player {
+                id: crate::game_state::generate_player_id(),
+                ..Player::default()
+            };
+            state.add_player(player, observer.local_addr().unwrap().to_string());
+            // Staged for a player that has since left, or never joined
+            state.stage_position_update(PositionGamePacket {
+                msg_type: MessageType::PositionUpdate,
+                version: 1,
+                client_id: crate::game_state::generate_player_id().into_bytes(),
+                seq_num: 1,
+                position: Position::new(10.0, 20.0),
+            });
+        }
+
+        SimulationLoop::new(server_socket, game_state, ServerConfig::default())
+            .tick()
+            .await;
+
+        let mut buf = vec![0; 1500];
+        assert!(
+            time::timeout(Duration::from_millis(100), observer.recv_from(&mut buf))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_player_removed_after_repeated_send_failures() {
+        let server_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let game_state = Arc::new(Mutex::new(GameState::default()));
+        let mover = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mover_id = crate::game_state::generate_player_id();
+        let unreachable_id = crate::game_state::generate_player_id();
+        {
+            let mut state = game_state.lock().await;
+            for (id, addr) in [
+                (&mover_id, mover.local_addr().unwrap().to_string()),
+                // An IPv6 destination can't be reached from the IPv4 server socket
+                (&unreachable_id, "[::1]:9".to_string()),
+            ] {
+                let player = Player {
+                    id: id.clone(),
+                    heartbeat: state.now(),
+                    connected_at: state.now(),
+                    ..Player::default()
+                };
+                state.add_player(player, addr);
+            }
+        }
+        let config = ServerConfig {
+            max_send_failures: 3,
+            ..ServerConfig::default()
+        };
+        let simulation_loop = SimulationLoop::new(server_socket, Arc::clone(&game_state), config);
+
+        for tick in 1..=3 {
+            game_state
+                .lock()
+                .await
+                .stage_position_update(PositionGamePacket {
+                    msg_type: MessageType::PositionUpdate,
+                    version: 1,
+                    client_id: mover_id.as_bytes().to_vec(),
+                    seq_num: tick,
+                    position: Position::new(1.0, 1.0),
+                });
+            simulation_loop.tick().await;
+            let still_present = game_state
+                .lock()
+                .await
+                .get_player_by_id(&unreachable_id)
+                .is_some();
+            // A single failure is tolerated, the third one removes the player
+            assert_eq!(still_present, tick < 3);
+        }
+
+        let mut buf = vec![0; 1500];
+        let (len, _) = time::timeout(Duration::from_secs(1), mover.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let packet = GamePacket::deserialize(&buf[..len]).unwrap();
+        assert_eq!(packet.msg_type, MessageType::PlayerLeft);
+        let left = PlayerLeft::deserialize(&packet.payload).unwrap();
+        assert_eq!(left.player_id, unreachable_id);
+    }
+
+    /// Reads every datagram waiting on `socket`.
+    async fn drain(socket: &UdpSocket) -> Vec<GamePacket> {
+        let mut packets = Vec::new();
+        let mut buf = vec![0; 1500];
+        while let Ok(Ok((len, _))) =
+            time::timeout(Duration::from_millis(100), socket.recv_from(&mut buf)).await
+        {
+            packets.push(GamePacket::deserialize(&buf[..len]).unwrap());
+        }
+        packets
+    }
+
+    #[tokio::test]
+    async fn test_interest_enter_and_exit_fire_once() {
+        let server_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let game_state = Arc::new(Mutex::new(GameState::default()));
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (a_id, b_id) = (
+            crate::game_state::generate_player_id(),
+            crate::game_state::generate_player_id(),
+        );
+        {
+            let mut state = game_state.lock().await;
+            for (id, socket, position) in [
+                (&a_id, &a, Position::new(0.0, 0.0)),
+                (&b_id, &b, Position::new(500.0, 500.0)),
+            ] {
+                let player = Player {
+                    id: id.clone(),
+                    position,
+                    heartbeat: state.now(),
+                    connected_at: state.now(),
+                    ..Player::default()
+                };
+                state.add_player(player, socket.local_addr().unwrap().to_string());
+            }
+        }
+        let config = ServerConfig {
+            interest_radius: Some(100.0),
+            ..ServerConfig::default()
+        };
+        let simulation_loop = SimulationLoop::new(server_socket, Arc::clone(&game_state), config);
+        let move_b = |position: Position| {
+            let game_state = Arc::clone(&game_state);
+            let b_id = b_id.clone();
+            async move {
+                let mut state = game_state.lock().await;
+                state.update_player_position(&b_id, position.clone());
+                state.stage_position_update(PositionGamePacket {
+                    msg_type: MessageType::PositionUpdate,
+                    version: 1,
+                    client_id: b_id.as_bytes().to_vec(),
+                    seq_num: 0,
+                    position,
+                });
+            }
+        };
+
+        // Out of range: nothing is sent, not even B's movement
+        move_b(Position::new(400.0, 400.0)).await;
+        simulation_loop.tick().await;
+        assert!(drain(&a).await.is_empty());
+        assert!(drain(&b).await.is_empty());
+
+        // B walks into range: one enter event each, A also gets B's position
+        move_b(Position::new(50.0, 50.0)).await;
+        simulation_loop.tick().await;
+        let a_packets = drain(&a).await;
+        let enters = a_packets
+            .iter()
+            .filter(|p| p.msg_type == MessageType::InterestEnter)
+            .collect::<Vec<_>>();
+        assert_eq!(enters.len(), 1);
+        let entered = PlayerPosition::deserialize(&enters[0].payload).unwrap();
+        assert_eq!(entered.id, b_id.as_bytes());
+        assert!(a_packets
+            .iter()
+            .any(|p| p.msg_type == MessageType::PositionBatch));
+        let b_packets = drain(&b).await;
+        assert_eq!(b_packets.len(), 1);
+        assert_eq!(b_packets[0].msg_type, MessageType::InterestEnter);
+
+        // Staying in range doesn't repeat the event
+        simulation_loop.tick().await;
+        assert!(drain(&a).await.is_empty());
+        assert!(drain(&b).await.is_empty());
+
+        // B walks out of range: one exit event each
+        move_b(Position::new(300.0, 300.0)).await;
+        simulation_loop.tick().await;
+        let a_packets = drain(&a).await;
+        assert_eq!(a_packets.len(), 1);
+        assert_eq!(a_packets[0].msg_type, MessageType::InterestExit);
+        let exited = PlayerLeft::deserialize(&a_packets[0].payload).unwrap();
+        assert_eq!(exited.player_id, b_id);
+        let b_packets = drain(&b).await;
+        assert_eq!(b_packets.len(), 1);
+        assert_eq!(b_packets[0].msg_type, MessageType::InterestExit);
+
+        simulation_loop.tick().await;
+        assert!(drain(&a).await.is_empty());
+        assert!(drain(&b).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_broadcasts_carry_increasing_outbound_seq() {
+        let server_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let game_state = Arc::new(Mutex::new(GameState::default()));
+        let mover = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let observer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (mover_id, observer_id) = (
+            crate::game_state::generate_player_id(),
+            crate::game_state::generate_player_id(),
+        );
+        {
+            let mut state = game_state.lock().await;
+            for (id, socket) in [(&mover_id, &mover), (&observer_id, &observer)] {
+                let player = Player {
+                    id: id.clone(),
+                    heartbeat: state.now(),
+                    connected_at: state.now(),
+                    ..Player::default()
+                };
+                state.add_player(player, socket.local_addr().unwrap().to_string());
+            }
+        }
+        let simulation_loop = SimulationLoop::new(
+            Arc::clone(&server_socket),
+            Arc::clone(&game_state),
+            ServerConfig::default(),
+        );
+        let heartbeats = HeartbeatManager::new(
+            server_socket,
+            Arc::clone(&game_state),
+            ServerConfig::default(),
+        );
+
+        for seq_num in 1..=3 {
+            game_state
+                .lock()
+                .await
+                .stage_position_update(PositionGamePacket {
+                    msg_type: MessageType::PositionUpdate,
+                    version: 1,
+                    client_id: mover_id.as_bytes().to_vec(),
+                    seq_num,
+                    position: Position::new(1.0, 1.0),
+                });
+            simulation_loop.tick().await;
+        }
+        heartbeats.send_heartbeats().await;
+
+        let received = drain(&observer)
+            .await
+            .iter()
+            .map(|packet| (packet.msg_type, packet.seq_num))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            received,
+            vec![
+                (MessageType::PositionBatch, 1),
+                (MessageType::PositionBatch, 2),
+                (MessageType::PositionBatch, 3),
+                (MessageType::Heartbeat, 4),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_status_payload_is_opt_in() {
+        let server_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let game_state = Arc::new(Mutex::new(GameState::default()));
+        let clients = [
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        {
+            let mut state = game_state.lock().await;
+            for client in &clients {
+                let player = Player {
+                    id: crate::game_state::generate_player_id(),
+                    heartbeat: state.now(),
+                    connected_at: state.now(),
+                    ..Player::default()
+                };
+                state.add_player(player, client.local_addr().unwrap().to_string());
+            }
+            state.tick = 42;
+        }
+
+        for heartbeat_status in [true, false] {
+            let config = ServerConfig {
+                heartbeat_status,
+                ..ServerConfig::default()
+            };
+            HeartbeatManager::new(Arc::clone(&server_socket), Arc::clone(&game_state), config)
+                .send_heartbeats()
+                .await;
+            let packets = drain(&clients[0]).await;
+            assert_eq!(packets.len(), 1);
+            assert_eq!(packets[0].msg_type, MessageType::Heartbeat);
+            if heartbeat_status {
+                let status = HeartbeatStatus::deserialize(&packets[0].payload).unwrap();
+                assert_eq!(status, HeartbeatStatus::new(2, 42));
+                assert_eq!(status.player_count, 2);
+            } else {
+                assert!(packets[0].payload.is_empty());
+            }
+        }
+    }
 
-            if let Ok(addr) = addr.parse::<std::net::SocketAddr>() {
-                self.socket.send_to(&data, addr).await?;
+    #[tokio::test]
+    async fn test_send_heartbeats_reports_count() {
+        let server_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let game_state = Arc::new(Mutex::new(GameState::default()));
+        {
+            let mut state = game_state.lock().await;
+            for port in [9001, 9002] {
+                let player = Player {
+                    id: crate::game_state::generate_player_id(),
+                    heartbeat: state.now(),
+                    connected_at: state.now(),
+                    ..Player::default()
+                };
+                state.add_player(player, format!("127.0.0.1:{port}"));
             }
         }
-        Ok(())
+        let heartbeats = HeartbeatManager::new(
+            server_socket,
+            Arc::clone(&game_state),
+            ServerConfig::default(),
+        );
+        assert_eq!(heartbeats.send_heartbeats().await, 2);
     }
 }
@@ -1,56 +1,237 @@
+use std::path::PathBuf;
+
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
-use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{fmt::MakeWriter, layer::SubscriberExt};
 
-/// # Panics
+/// Where and how the server writes its log files.
 ///
-/// if the global default subscriber cannot be set
-/// and if the connection to the database fails
+/// The default writes `server.log` into `./logs`, rotated daily, at `info` level.
+#[derive(Debug, Clone)]
+#[allow(clippy::module_name_repetitions)]
+pub struct TelemetryConfig {
+    pub dir: PathBuf,
+    pub rotation: Rotation,
+    pub level: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            dir: PathBuf::from("logs"),
+            rotation: Rotation::DAILY,
+            level: "info".to_string(),
+        }
+    }
+}
+
+/// Why the subscriber couldn't be built or installed.
+#[derive(Debug)]
+#[allow(clippy::module_name_repetitions)]
+pub enum TelemetryError {
+    /// `TelemetryConfig::level` isn't a valid `EnvFilter` directive.
+    InvalidLevel(tracing_subscriber::filter::ParseError),
+    /// Another global default subscriber is already installed.
+    AlreadyInstalled(tracing::subscriber::SetGlobalDefaultError),
+}
+
+impl std::fmt::Display for TelemetryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TelemetryError::InvalidLevel(e) => write!(f, "invalid log level: {e}"),
+            TelemetryError::AlreadyInstalled(e) => write!(f, "{e}"),
+        }
+    }
+}
+impl std::error::Error for TelemetryError {}
+
+/// Builds the subscriber with the default log directory and rotation.
 ///
-#[must_use]
-pub fn get_subscriber(debug: bool) -> impl tracing::Subscriber + Send + Sync {
-    let env_filter = if debug {
-        "trace".to_string()
-    } else {
-        "info".to_string()
+/// `level` is any `EnvFilter` directive (`"warn"`, `"debug"`, `"server_dot=trace"`, ...)
+/// and is only used when `RUST_LOG` is not set. `json` adds a JSON formatted stdout layer.
+///
+/// # Errors
+///
+/// Returns [`TelemetryError::InvalidLevel`] if `level` can't be parsed.
+pub fn get_subscriber(
+    level: &str,
+    json: bool,
+) -> Result<impl tracing::Subscriber + Send + Sync, TelemetryError> {
+    let config = TelemetryConfig {
+        level: level.to_string(),
+        ..TelemetryConfig::default()
     };
-    let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(env_filter));
+    get_subscriber_with_config(&config, json)
+}
+
+/// Builds the subscriber using the log directory, rotation and level from `config`.
+///
+/// If the log directory can't be created or written to, e.g. in a read-only container,
+/// a warning is printed to stderr and the subscriber only logs to stdout.
+///
+/// # Errors
+///
+/// Returns [`TelemetryError::InvalidLevel`] if `config.level` can't be parsed.
+pub fn get_subscriber_with_config(
+    config: &TelemetryConfig,
+    json: bool,
+) -> Result<impl tracing::Subscriber + Send + Sync, TelemetryError> {
+    build_subscriber(config, json, std::io::stdout)
+}
+
+/// [`get_subscriber_with_config`], with the pretty printed layer written to `stdout`
+/// instead of the process' stdout.
+fn build_subscriber<W>(
+    config: &TelemetryConfig,
+    json: bool,
+    stdout: W,
+) -> Result<impl tracing::Subscriber + Send + Sync, TelemetryError>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let env_filter = match tracing_subscriber::EnvFilter::try_from_default_env() {
+        Ok(env_filter) => env_filter,
+        Err(_) => tracing_subscriber::EnvFilter::try_new(&config.level)
+            .map_err(TelemetryError::InvalidLevel)?,
+    };
+    let env_filter = env_filter.add_directive(
+        "actix_http=info"
+            .parse()
+            .map_err(TelemetryError::InvalidLevel)?,
+    );
     let env_filter =
-        env_filter.add_directive("actix_http=info".parse().expect("Invalid directive"));
-    let env_filter = env_filter.add_directive("hyper=info".parse().expect("Invalid directive"));
+        env_filter.add_directive("hyper=info".parse().map_err(TelemetryError::InvalidLevel)?);
 
     // Create stdout layer
-    let stdout_layer = tracing_subscriber::fmt::layer().pretty();
-
-    // Create file appender
-    let file_appender = RollingFileAppender::new(Rotation::DAILY, "logs", "server.log");
+    let stdout_layer = tracing_subscriber::fmt::layer()
+        .pretty()
+        .with_writer(stdout);
 
-    // Create file layer
-    let file_layer = tracing_subscriber::fmt::layer()
-        .with_writer(file_appender)
-        .with_ansi(false)
-        .with_thread_ids(true)
-        .with_thread_names(true)
-        .with_file(true)
-        .with_line_number(true);
+    // Create file layer, unless the log directory is unusable
+    let file_layer = match file_appender(config) {
+        Ok(file_appender) => Some(
+            tracing_subscriber::fmt::layer()
+                .with_writer(file_appender)
+                .with_ansi(false)
+                .with_thread_ids(true)
+                .with_thread_names(true)
+                .with_file(true)
+                .with_line_number(true),
+        ),
+        Err(e) => {
+            eprintln!(
+                "warning: not writing log files to {}, logging to stdout only: {e}",
+                config.dir.display()
+            );
+            None
+        }
+    };
 
     let subscriber = tracing_subscriber::Registry::default()
         .with(env_filter)
         .with(stdout_layer)
         .with(file_layer);
 
-    let json_log = if debug {
+    let json_log = if json {
         let json_log = tracing_subscriber::fmt::layer().json();
         Some(json_log)
     } else {
         None
     };
-    subscriber.with(json_log)
+    Ok(subscriber.with(json_log))
+}
+
+fn file_appender(
+    config: &TelemetryConfig,
+) -> Result<RollingFileAppender, Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(&config.dir)?;
+    Ok(RollingFileAppender::builder()
+        .rotation(config.rotation.clone())
+        .filename_prefix("server.log")
+        .build(&config.dir)?)
 }
-/// # Panics
+
+/// Installs `subscriber` as the global default.
+///
+/// # Errors
 ///
-/// if the global default subscriber cannot be set
-/// and if the connection to the database fails
-pub fn init_subscriber(subscriber: impl tracing::Subscriber + Send + Sync) {
-    tracing::subscriber::set_global_default(subscriber).expect("Failed to set subscriber");
+/// Returns [`TelemetryError::AlreadyInstalled`] if a global default is already set.
+pub fn init_subscriber(
+    subscriber: impl tracing::Subscriber + Send + Sync,
+) -> Result<(), TelemetryError> {
+    tracing::subscriber::set_global_default(subscriber).map_err(TelemetryError::AlreadyInstalled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::CapturedLogs;
+
+    #[test]
+    fn test_subscriber_writes_to_configured_dir() {
+        let dir = std::env::temp_dir().join(format!("server_dot_logs_{}", nanoid::nanoid!(8)));
+        let config = TelemetryConfig {
+            dir: dir.clone(),
+            rotation: Rotation::NEVER,
+            level: "info".to_string(),
+        };
+        let subscriber = get_subscriber_with_config(&config, false).unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("telemetry test");
+        });
+
+        let log_file = dir.join("server.log");
+        assert!(log_file.exists());
+        let contents = std::fs::read_to_string(&log_file).unwrap();
+        assert!(contents.contains("telemetry test"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_subscriber_at_warn_level() {
+        let dir = std::env::temp_dir().join(format!("server_dot_logs_{}", nanoid::nanoid!(8)));
+        let config = TelemetryConfig {
+            dir: dir.clone(),
+            level: "warn".to_string(),
+            ..TelemetryConfig::default()
+        };
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = build_subscriber(&config, true, move || writer.clone()).unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!("warn level test");
+            tracing::info!("info level test");
+        });
+
+        let contents = logs.contents();
+        assert!(contents.contains("warn level test"));
+        assert!(!contents.contains("info level test"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unwritable_log_dir_falls_back_to_stdout() {
+        // A directory can't be created below a regular file, whoever runs the test
+        let file = std::env::temp_dir().join(format!("server_dot_logs_{}", nanoid::nanoid!(8)));
+        std::fs::write(&file, b"").unwrap();
+        let config = TelemetryConfig {
+            dir: file.join("logs"),
+            ..TelemetryConfig::default()
+        };
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = build_subscriber(&config, false, move || writer.clone()).unwrap();
+        let guard = tracing::subscriber::set_default(subscriber);
+
+        let server = crate::server::GameServer::new(Some("127.0.0.1:0"))
+            .await
+            .unwrap();
+        tracing::info!("started on {:?}", server.local_addr().unwrap());
+        drop(guard);
+
+        assert!(logs.contents().contains("started on 127.0.0.1"));
+        assert!(!config.dir.exists());
+
+        std::fs::remove_file(&file).unwrap();
+    }
 }